@@ -0,0 +1,421 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Copyright © 2019 Corporation for Digital Scholarship
+
+//! `FromStr` for everything that appears as an attribute value in a style, spelled the way CSL
+//! spells it.
+
+use crate::element::*;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownAttributeValue(pub String);
+
+macro_rules! attr_from_str {
+    ($ty:ident { $($text:literal => $variant:ident),* $(,)? }) => {
+        impl FromStr for $ty {
+            type Err = UnknownAttributeValue;
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                match s {
+                    $($text => Ok($ty::$variant),)*
+                    _ => Err(UnknownAttributeValue(s.to_string())),
+                }
+            }
+        }
+    };
+}
+
+attr_from_str!(StyleClass {
+    "in-text" => InText,
+    "note" => Note,
+});
+
+attr_from_str!(PageRangeFormat {
+    "chicago" => Chicago,
+    "chicago-15" => Chicago,
+    "chicago-16" => Chicago16,
+    "expanded" => Expanded,
+    "minimal" => Minimal,
+    "minimal-two" => MinimalTwo,
+});
+
+attr_from_str!(DemoteNonDroppingParticle {
+    "never" => Never,
+    "sort-only" => SortOnly,
+    "display-and-sort" => DisplayAndSort,
+});
+
+attr_from_str!(GivenNameDisambiguationRule {
+    "all-names" => AllNames,
+    "all-names-with-initials" => AllNamesWithInitials,
+    "primary-name" => PrimaryName,
+    "primary-name-with-initials" => PrimaryNameWithInitials,
+    "by-cite" => ByCite,
+});
+
+attr_from_str!(Collapse {
+    "citation-number" => CitationNumber,
+    "year" => Year,
+    "year-suffix" => YearSuffix,
+    "year-suffix-ranged" => YearSuffixRanged,
+});
+
+attr_from_str!(SubsequentAuthorSubstituteRule {
+    "complete-all" => CompleteAll,
+    "complete-each" => CompleteEach,
+    "partial-each" => PartialEach,
+    "partial-first" => PartialFirst,
+});
+
+attr_from_str!(SecondFieldAlign {
+    "margin" => Margin,
+    "flush" => Flush,
+});
+
+attr_from_str!(SortDirection {
+    "ascending" => Ascending,
+    "descending" => Descending,
+});
+
+attr_from_str!(TermForm {
+    "long" => Long,
+    "short" => Short,
+    "symbol" => Symbol,
+});
+
+attr_from_str!(TermFormExtended {
+    "long" => Long,
+    "short" => Short,
+    "symbol" => Symbol,
+    "verb" => Verb,
+    "verb-short" => VerbShort,
+});
+
+attr_from_str!(NumericForm {
+    "numeric" => Numeric,
+    "ordinal" => Ordinal,
+    "long-ordinal" => LongOrdinal,
+    "roman" => Roman,
+});
+
+attr_from_str!(DateForm {
+    "numeric" => Numeric,
+    "text" => Text,
+});
+
+attr_from_str!(DateParts {
+    "year-month-day" => YearMonthDay,
+    "year-month" => YearMonth,
+    "year" => Year,
+});
+
+attr_from_str!(DatePartName {
+    "year" => Year,
+    "month" => Month,
+    "day" => Day,
+});
+
+attr_from_str!(DatePartForm {
+    "long" => Long,
+    "short" => Short,
+    "numeric" => Numeric,
+    "numeric-leading-zeros" => NumericLeadingZeros,
+    "ordinal" => Ordinal,
+});
+
+attr_from_str!(VariableForm {
+    "long" => Long,
+    "short" => Short,
+});
+
+attr_from_str!(Match {
+    "any" => Any,
+    "all" => All,
+    "none" => None,
+});
+
+attr_from_str!(Position {
+    "first" => First,
+    "ibid" => Ibid,
+    "ibid-with-locator" => IbidWithLocator,
+    "subsequent" => Subsequent,
+    "near-note" => NearNote,
+    "far-note" => FarNote,
+});
+
+attr_from_str!(NameAsSortOrder {
+    "first" => First,
+    "all" => All,
+});
+
+attr_from_str!(DelimiterPrecedes {
+    "contextual" => Contextual,
+    "after-inverted-name" => AfterInvertedName,
+    "always" => Always,
+    "never" => Never,
+});
+
+attr_from_str!(NameAnd {
+    "text" => Text,
+    "symbol" => Symbol,
+});
+
+attr_from_str!(NameForm {
+    "long" => Long,
+    "short" => Short,
+    "count" => Count,
+});
+
+attr_from_str!(NamePartName {
+    "given" => Given,
+    "family" => Family,
+});
+
+attr_from_str!(LocatorType {
+    "book" => Book,
+    "chapter" => Chapter,
+    "column" => Column,
+    "figure" => Figure,
+    "folio" => Folio,
+    "issue" => Issue,
+    "line" => Line,
+    "note" => Note,
+    "opus" => Opus,
+    "page" => Page,
+    "paragraph" => Paragraph,
+    "part" => Part,
+    "section" => Section,
+    "sub verbo" => SubVerbo,
+    "sub-verbo" => SubVerbo,
+    "verse" => Verse,
+    "volume" => Volume,
+    "article" => Article,
+    "subparagraph" => Subparagraph,
+    "rule" => Rule,
+    "subsection" => Subsection,
+    "schedule" => Schedule,
+    "title" => Title,
+    "supplement" => Supplement,
+});
+
+attr_from_str!(MiscTerm {
+    "accessed" => Accessed,
+    "ad" => Ad,
+    "and" => And,
+    "and others" => AndOthers,
+    "anonymous" => Anonymous,
+    "at" => At,
+    "available at" => AvailableAt,
+    "bc" => Bc,
+    "by" => By,
+    "circa" => Circa,
+    "cited" => Cited,
+    "et-al" => EtAl,
+    "forthcoming" => Forthcoming,
+    "from" => From,
+    "ibid" => Ibid,
+    "in" => In,
+    "in press" => InPress,
+    "internet" => Internet,
+    "interview" => Interview,
+    "letter" => Letter,
+    "no date" => NoDate,
+    "online" => Online,
+    "presented at" => PresentedAt,
+    "reference" => Reference,
+    "retrieved" => Retrieved,
+    "scale" => Scale,
+    "version" => Version,
+    "page-range-delimiter" => PageRangeDelimiter,
+    "year-range-delimiter" => YearRangeDelimiter,
+});
+
+// `Dummy` is internal, so it has no spelling.
+attr_from_str!(Variable {
+    "journalAbbreviation" => JournalAbbreviation,
+    "abstract" => Abstract,
+    "annote" => Annote,
+    "archive" => Archive,
+    "archive_location" => ArchiveLocation,
+    "archive-place" => ArchivePlace,
+    "authority" => Authority,
+    "call-number" => CallNumber,
+    "citation-label" => CitationLabel,
+    "collection-title" => CollectionTitle,
+    "container-title" => ContainerTitle,
+    "container-title-short" => ContainerTitleShort,
+    "dimensions" => Dimensions,
+    "DOI" => DOI,
+    "event" => Event,
+    "event-place" => EventPlace,
+    "genre" => Genre,
+    "ISBN" => ISBN,
+    "ISSN" => ISSN,
+    "jurisdiction" => Jurisdiction,
+    "keyword" => Keyword,
+    "medium" => Medium,
+    "note" => Note,
+    "original-publisher" => OriginalPublisher,
+    "original-publisher-place" => OriginalPublisherPlace,
+    "original-title" => OriginalTitle,
+    "PMCID" => PMCID,
+    "PMID" => PMID,
+    "publisher" => Publisher,
+    "publisher-place" => PublisherPlace,
+    "references" => References,
+    "reviewed-title" => ReviewedTitle,
+    "scale" => Scale,
+    "section" => Section,
+    "source" => Source,
+    "status" => Status,
+    "title" => Title,
+    "title-short" => TitleShort,
+    "URL" => URL,
+    "version" => Version,
+    "year-suffix" => YearSuffix,
+    "hereinafter" => Hereinafter,
+    "locator-extra" => LocatorExtra,
+    "volume-title" => VolumeTitle,
+    "committee" => Committee,
+    "document-name" => DocumentName,
+    "gazette-flag" => GazetteFlag,
+    "language" => Language,
+});
+
+attr_from_str!(NumberVariable {
+    "chapter-number" => ChapterNumber,
+    "collection-number" => CollectionNumber,
+    "edition" => Edition,
+    "issue" => Issue,
+    "number" => Number,
+    "number-of-pages" => NumberOfPages,
+    "number-of-volumes" => NumberOfVolumes,
+    "volume" => Volume,
+    "locator" => Locator,
+    "page" => Page,
+    "page-first" => PageFirst,
+    "first-reference-note-number" => FirstReferenceNoteNumber,
+    "citation-number" => CitationNumber,
+    "publication-number" => PublicationNumber,
+    "supplement" => Supplement,
+    "authority" => Authority,
+});
+
+attr_from_str!(NameVariable {
+    "author" => Author,
+    "collection-editor" => CollectionEditor,
+    "composer" => Composer,
+    "container-author" => ContainerAuthor,
+    "director" => Director,
+    "editor" => Editor,
+    "editorial-director" => EditorialDirector,
+    "illustrator" => Illustrator,
+    "interviewer" => Interviewer,
+    "original-author" => OriginalAuthor,
+    "recipient" => Recipient,
+    "reviewed-author" => ReviewedAuthor,
+    "translator" => Translator,
+    "editortranslator" => EditorTranslator,
+    "authority" => Authority,
+    "dummy" => Dummy,
+});
+
+attr_from_str!(DateVariable {
+    "accessed" => Accessed,
+    "container" => Container,
+    "event-date" => EventDate,
+    "issued" => Issued,
+    "original-date" => OriginalDate,
+    "submitted" => Submitted,
+    "locator-date" => LocatorDate,
+    "publication-date" => PublicationDate,
+    "available-date" => AvailableDate,
+});
+
+/// Where a name is valid as more than one kind of variable, a `<text>` treats it as ordinary.
+impl FromStr for StandardVariable {
+    type Err = UnknownAttributeValue;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Variable::from_str(s)
+            .map(StandardVariable::Ordinary)
+            .or_else(|_| NumberVariable::from_str(s).map(StandardVariable::Number))
+    }
+}
+
+impl FromStr for AnyVariable {
+    type Err = UnknownAttributeValue;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match StandardVariable::from_str(s) {
+            Ok(sv) => Ok(AnyVariable::from(&sv)),
+            Err(_) => NameVariable::from_str(s)
+                .map(AnyVariable::Name)
+                .or_else(|_| DateVariable::from_str(s).map(AnyVariable::Date)),
+        }
+    }
+}
+
+/// Accepts `1.0`, `1.0.1`, and CSL-M's `1.1mlz1`, ignoring anything after the numbers.
+impl FromStr for CslVersionReq {
+    type Err = UnknownAttributeValue;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let unknown = || UnknownAttributeValue(s.to_string());
+        let mut parts = s.splitn(3, '.').map(|part| {
+            let digits = part
+                .find(|c: char| !c.is_ascii_digit())
+                .map_or(part, |end| &part[..end]);
+            digits.parse::<u32>()
+        });
+        let major = parts.next().ok_or_else(unknown)?.map_err(|_| unknown())?;
+        let minor = parts.next().ok_or_else(unknown)?.map_err(|_| unknown())?;
+        let patch = match parts.next() {
+            Some(patch) => patch.map_err(|_| unknown())?,
+            None => 0,
+        };
+        Ok(CslVersionReq {
+            major,
+            minor,
+            patch,
+        })
+    }
+}
+
+#[test]
+fn parses_attribute_values() {
+    assert_eq!("note".parse(), Ok(StyleClass::Note));
+    assert_eq!("chicago-15".parse(), Ok(PageRangeFormat::Chicago));
+    assert_eq!("minimal-two".parse(), Ok(PageRangeFormat::MinimalTwo));
+    assert_eq!("sort-only".parse(), Ok(DemoteNonDroppingParticle::SortOnly));
+    assert_eq!(
+        "nonsense".parse::<StyleClass>(),
+        Err(UnknownAttributeValue("nonsense".into()))
+    );
+    assert_eq!(
+        "authority".parse(),
+        Ok(AnyVariable::Ordinary(Variable::Authority))
+    );
+    assert_eq!(
+        "locator".parse(),
+        Ok(AnyVariable::Number(NumberVariable::Locator))
+    );
+    assert_eq!(
+        "issued".parse(),
+        Ok(AnyVariable::Date(DateVariable::Issued))
+    );
+    assert_eq!("sub verbo".parse(), Ok(LocatorType::SubVerbo));
+}
+
+#[test]
+fn parses_versions() {
+    let v = |major, minor, patch| CslVersionReq {
+        major,
+        minor,
+        patch,
+    };
+    assert_eq!("1.0".parse(), Ok(v(1, 0, 0)));
+    assert_eq!("1.0.1".parse(), Ok(v(1, 0, 1)));
+    assert_eq!("1.1mlz1".parse(), Ok(v(1, 1, 0)));
+    assert!("one".parse::<CslVersionReq>().is_err());
+    assert!("1".parse::<CslVersionReq>().is_err());
+}
@@ -0,0 +1,237 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Copyright © 2019 Corporation for Digital Scholarship
+
+use crate::disamb::names::NameDisamb;
+use crate::disamb::pipeline::{fill_placeholders, plain_text};
+use crate::disamb::{ref_sequence, ref_sequence_replacing_names};
+use crate::jurisdiction::module_for;
+use crate::element::*;
+use crate::names::{first_names, NamesRun};
+use crate::parse::StyleError;
+#[cfg(test)]
+use crate::reference::PersonName;
+use crate::reference::Reference;
+use crate::{IrDatabase, RefContext};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BibEntry {
+    pub ref_id: String,
+    pub output: String,
+}
+
+/// The layout settings a consumer needs to typeset the entries.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BibliographyMeta {
+    pub hanging_indent: bool,
+    pub second_field_align: Option<SecondFieldAlign>,
+    pub line_spacing: u32,
+    pub entry_spacing: u32,
+}
+
+impl BibliographyMeta {
+    pub fn new(bib: &Bibliography) -> Self {
+        BibliographyMeta {
+            hanging_indent: bib.hanging_indent,
+            second_field_align: bib.second_field_align,
+            line_spacing: bib.line_spacing.unwrap_or(1),
+            entry_spacing: bib.entry_spacing.unwrap_or(1),
+        }
+    }
+}
+
+/// Renders an entry for each of `references`, which must already be in bibliography order.
+pub fn render_entries(
+    db: &dyn IrDatabase,
+    style: &Style,
+    bib: &Bibliography,
+    references: &[Arc<Reference>],
+    year_suffixes: &HashMap<String, String>,
+) -> Result<Vec<BibEntry>, StyleError> {
+    let locale = db.locale(&style.default_locale);
+    let name_el = Arc::new(style.inherited_name(&bib.name_inheritance));
+    let names_delimiter = style.inherited_names_delimiter(&bib.names_delimiter);
+    let mut previous: Option<NamesRun> = None;
+    let mut entries = Vec::with_capacity(references.len());
+    for (ix, reference) in references.iter().enumerate() {
+        let suffix = year_suffixes.get(&reference.id).map(|s| s.as_str());
+        let ctx = RefContext {
+            style,
+            reference,
+            locator_type: None,
+            position: Position::First,
+            year_suffix: suffix.is_some(),
+            names_delimiter: names_delimiter.clone(),
+            name_el: name_el.clone(),
+            disamb_count: 0,
+            name_disamb: NameDisamb::default(),
+            in_sort_key: false,
+            suppress_author: false,
+            hereinafter: db.hereinafter(&reference.id),
+            module: module_for(db, style, reference),
+        };
+        let layout = bib.layout_for(reference.language());
+        let names = first_names(db, &ctx, &layout.elements)?;
+        let replacement = match (&bib.subsequent_author_substitute, &previous, &names) {
+            (Some(substitute), Some(prev), Some(names)) => {
+                names.substituted(prev, bib.subsequent_author_substitute_rule, substitute)
+            }
+            _ => None,
+        };
+        let (ir, _) = match replacement {
+            Some(replacement) => {
+                ref_sequence_replacing_names(db, &ctx, &layout.elements, replacement)?
+            }
+            None => ref_sequence(db, &ctx, &layout.elements)?,
+        };
+        let output = plain_text(
+            &fill_placeholders(&ir, suffix, Some(ix as u32 + 1)),
+            None,
+            None,
+            &locale,
+        );
+        previous = names;
+        entries.push(BibEntry {
+            ref_id: reference.id.clone(),
+            output,
+        });
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+fn test_db(substitute_rule: SubsequentAuthorSubstituteRule) -> crate::db::Database {
+    let names = Element::Names(Arc::new(Names {
+        variables: vec![NameVariable::Author],
+        name: Some(Name {
+            name_as_sort_order: Some(NameAsSortOrder::All),
+            ..Name::empty()
+        }),
+        delimiter: None,
+        formatting: None,
+        affixes: None,
+        display: None,
+        substitute: None,
+    }));
+    let title = Element::Text(TextElement {
+        source: TextSource::Variable(
+            StandardVariable::Ordinary(Variable::Title),
+            VariableForm::Long,
+        ),
+        formatting: None,
+        affixes: Some(Affixes {
+            prefix: ". ".into(),
+            suffix: "".into(),
+        }),
+        quotes: false,
+        strip_periods: false,
+        text_case: TextCase,
+        display: None,
+    });
+    let key = |source| SortKey {
+        source,
+        direction: SortDirection::Ascending,
+    };
+    let mut db = crate::db::Database::new(Style {
+        bibliography: Some(Bibliography {
+            layout: Layout {
+                elements: vec![names, title],
+                ..Default::default()
+            },
+            sort: Some(Sort {
+                keys: vec![
+                    key(SortSource::Variable(AnyVariable::Name(
+                        NameVariable::Author,
+                    ))),
+                    key(SortSource::Variable(AnyVariable::Ordinary(Variable::Title))),
+                ],
+            }),
+            subsequent_author_substitute: Some("---".into()),
+            subsequent_author_substitute_rule: substitute_rule,
+            ..Default::default()
+        }),
+        ..Default::default()
+    });
+    let make = |id: &str, authors: &[(&str, &str)], title: &str| {
+        let mut r = Reference::empty(id, "book");
+        r.name.insert(
+            NameVariable::Author,
+            authors.iter().map(|(g, f)| PersonName::new(g, f)).collect(),
+        );
+        r.ordinary.insert(Variable::Title, title.into());
+        r
+    };
+    db.set_references(vec![
+        make("a", &[("John", "Smith")], "Beta"),
+        make("b", &[("Jane", "Doe")], "Zeta"),
+        make("c", &[("John", "Smith")], "Alpha"),
+        make("d", &[("John", "Smith"), ("Jane", "Doe")], "Gamma"),
+    ]);
+    db
+}
+
+#[cfg(test)]
+fn outputs(db: &crate::db::Database) -> Vec<String> {
+    db.render_bibliography()
+        .unwrap()
+        .iter()
+        .map(|e| e.output.clone())
+        .collect()
+}
+
+#[test]
+fn sorted_with_author_substitute() {
+    let db = test_db(SubsequentAuthorSubstituteRule::CompleteAll);
+    assert_eq!(
+        outputs(&db),
+        vec![
+            "Doe, Jane. Zeta",
+            "Smith, John. Alpha",
+            "---. Beta",
+            "Smith, John, Doe, Jane. Gamma",
+        ]
+    );
+}
+
+#[test]
+fn partial_author_substitute() {
+    let db = test_db(SubsequentAuthorSubstituteRule::PartialEach);
+    assert_eq!(
+        outputs(&db),
+        vec![
+            "Doe, Jane. Zeta",
+            "Smith, John. Alpha",
+            "---. Beta",
+            "---, Doe, Jane. Gamma",
+        ]
+    );
+}
+
+#[test]
+fn author_substitute_replaces_the_names_not_matching_text() {
+    let style: Style = r#"<style class="in-text">
+      <citation><layout><text variable="title"/></layout></citation>
+      <bibliography subsequent-author-substitute="---">
+        <layout>
+          <text variable="title" suffix=". "/>
+          <names variable="author"><name form="short"/></names>
+        </layout>
+      </bibliography>
+    </style>"#
+        .parse()
+        .unwrap();
+    let mut db = crate::db::Database::new(style);
+    let make = |id: &str, title: &str| {
+        let mut r = Reference::empty(id, "book");
+        r.ordinary.insert(Variable::Title, title.into());
+        r.name
+            .insert(NameVariable::Author, vec![PersonName::new("John", "Smith")]);
+        r
+    };
+    db.set_references(vec![make("a", "Alpha"), make("b", "Smith")]);
+    assert_eq!(outputs(&db), vec!["Alpha. Smith", "Smith. ---"]);
+}
@@ -0,0 +1,1340 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Copyright © 2019 Corporation for Digital Scholarship
+
+//! The command line, which renders a document's citations and bibliography from files.
+//!
+//! Arguments are parsed by hand, so the crate stays free of dependencies.
+
+use crate::cluster::{Cluster, ClusterId};
+use crate::csl_json::{self, CslJsonError, Edit};
+use crate::db::{Database, QueryKey};
+use crate::disamb::pipeline::debug_reference;
+use crate::disamb::{EventLog, IrTracer, NoTrace};
+use crate::element::{Style, Variable};
+use crate::json::{self, JsonError};
+use crate::jurisdiction::JurisdictionModule;
+use crate::lint;
+use crate::locale::{primary_language, Locale};
+use crate::output::OutputFormat;
+use crate::parse::StyleError;
+use crate::reference::Reference;
+use crate::test_suite;
+use std::fmt::{self, Write};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+pub const USAGE: &str = "\
+usage: minimal render --style STYLE.csl --refs REFS.json [options]
+       minimal debug --style STYLE.csl --refs REFS.json [--ref ID]... [options]
+       minimal replay --style STYLE.csl --refs REFS.json --edits EDITS.json [options]
+       minimal test-suite DIR [--passing PASSING.txt [--update]]
+       minimal lint STYLE.csl
+
+render: renders every cluster in the cites file, one per line, then the bibliography.
+debug: shows how each reference is built up for disambiguation: its IR, the group vars of
+each level of the IR, and the NFA and minimised DFA as Graphviz DOT.
+replay: renders every cluster in the cites file, then makes each change in the edits file in
+turn, showing the clusters it changed. Only what a change affects is rendered again.
+test-suite: runs the CSL test suite's fixtures in DIR, showing a diff for each one that fails.
+With --passing, fails if a fixture listed in PASSING.txt no longer passes; with --update as
+well, lists the fixtures that pass there instead.
+lint: lists the parts of the style that can't do what they look like they do, one per line,
+each starting with a code that never changes, like L001.
+
+options:
+  --cites CITES.json     render, replay: the clusters to render, in document order; without
+                         it, render only renders the bibliography
+  --edits EDITS.json     replay: the changes to make, as described in src/csl_json.rs
+  --format FORMAT        render: html, rtf or plain (default plain); html and rtf escape the
+                         text and lay out the document, but have no italics, bold or other
+                         formatting
+  --module-dir DIR       render: where to find CSL-M jurisdiction modules, named like
+                         juris-us+ca.csl for the jurisdiction us:ca
+  --trace TRACE.txt      render: write down each step of building the disambiguation IR,
+                         one per line
+  --ref ID               debug: show only this reference; repeat for more
+  --locale LANG          use this language instead of the style's default-locale
+  --locale-dir DIR       where to find locales-LANG.xml files; without it, or when there is
+                         no file for the language, the built-in en-US terms are used
+";
+
+#[derive(Debug)]
+pub enum CliError {
+    /// The arguments didn't make sense. Comes with the usage.
+    Usage(String),
+    Io {
+        path: PathBuf,
+        error: io::Error,
+    },
+    Style {
+        path: PathBuf,
+        error: StyleError,
+    },
+    Locale {
+        path: PathBuf,
+        error: StyleError,
+    },
+    Json {
+        path: PathBuf,
+        error: JsonError,
+    },
+    CslJson {
+        path: PathBuf,
+        error: CslJsonError,
+    },
+    /// `debug --ref` named a reference the library doesn't have.
+    UnknownReference {
+        id: String,
+    },
+    /// `test-suite --passing` listed fixtures that didn't pass. Comes with the report, to print
+    /// before the error.
+    Regressions {
+        report: String,
+        passing: PathBuf,
+        names: Vec<String>,
+    },
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CliError::Usage(message) => f.write_str(message),
+            CliError::Io { path, error } => write!(f, "{}: {}", path.display(), error),
+            CliError::Style { path, error } | CliError::Locale { path, error } => {
+                write!(f, "{}: {}", path.display(), error)
+            }
+            CliError::Json { path, error } => write!(f, "{}: {}", path.display(), error),
+            CliError::CslJson { path, error } => write!(f, "{}: {}", path.display(), error),
+            CliError::UnknownReference { id } => write!(f, "no reference has the id `{}`", id),
+            CliError::Regressions { passing, names, .. } => write!(
+                f,
+                "listed in {} but no longer passing: {}",
+                passing.display(),
+                names.join(", ")
+            ),
+        }
+    }
+}
+
+/// What every command reads: a style, a library, and which locale to use.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Inputs {
+    pub style: PathBuf,
+    pub refs: PathBuf,
+    pub locale: Option<String>,
+    pub locale_dir: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RenderOptions {
+    pub inputs: Inputs,
+    pub cites: Option<PathBuf>,
+    pub format: OutputFormat,
+    pub module_dir: Option<PathBuf>,
+    pub trace: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DebugOptions {
+    pub inputs: Inputs,
+    /// The references to show, or all of them if empty.
+    pub ids: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplayOptions {
+    pub inputs: Inputs,
+    pub cites: Option<PathBuf>,
+    pub edits: PathBuf,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TestSuiteOptions {
+    pub dir: PathBuf,
+    /// The snapshot of fixtures that pass.
+    pub passing: Option<PathBuf>,
+    /// Rewrite the snapshot rather than checking against it.
+    pub update: bool,
+}
+
+/// Runs the command line, given the arguments after the program name, and returns what to
+/// print. `--help` returns the usage.
+pub fn run(args: &[String]) -> Result<String, CliError> {
+    match args.first().map(String::as_str) {
+        Some("render") => render(&parse_render_args(&args[1..])?),
+        Some("debug") => debug(&parse_debug_args(&args[1..])?),
+        Some("replay") => replay(&parse_replay_args(&args[1..])?),
+        Some("test-suite") => test_suite(&parse_test_suite_args(&args[1..])?),
+        Some("lint") => lint(&parse_lint_args(&args[1..])?),
+        Some("help") | Some("--help") | Some("-h") => Ok(USAGE.into()),
+        Some(other) => Err(CliError::Usage(format!("unknown command `{}`", other))),
+        None => Err(CliError::Usage("expected a command".into())),
+    }
+}
+
+const INPUT_FLAGS: [&str; 4] = ["--style", "--refs", "--locale", "--locale-dir"];
+
+/// Pairs each flag with its value, in order. Every flag takes a value, and only `known` flags
+/// and the `INPUT_FLAGS` are allowed.
+fn flags(args: &[String], known: &[&str]) -> Result<Vec<(String, String)>, CliError> {
+    let mut flags = Vec::new();
+    let mut args = args.iter();
+    while let Some(flag) = args.next() {
+        if !INPUT_FLAGS.contains(&flag.as_str()) && !known.contains(&flag.as_str()) {
+            return Err(CliError::Usage(format!("unknown option `{}`", flag)));
+        }
+        match args.next() {
+            Some(value) => flags.push((flag.clone(), value.clone())),
+            None => return Err(CliError::Usage(format!("`{}` needs a value", flag))),
+        }
+    }
+    Ok(flags)
+}
+
+fn inputs(flags: &[(String, String)]) -> Result<Inputs, CliError> {
+    let last = |name: &str| {
+        flags
+            .iter()
+            .rev()
+            .find(|(flag, _)| flag == name)
+            .map(|(_, value)| value.clone())
+    };
+    let required =
+        |name: &str| last(name).ok_or_else(|| CliError::Usage(format!("`{}` is required", name)));
+    Ok(Inputs {
+        style: required("--style")?.into(),
+        refs: required("--refs")?.into(),
+        locale: last("--locale"),
+        locale_dir: last("--locale-dir").map(PathBuf::from),
+    })
+}
+
+fn parse_render_args(args: &[String]) -> Result<RenderOptions, CliError> {
+    let flags = flags(args, &["--cites", "--format", "--module-dir", "--trace"])?;
+    let mut options = RenderOptions {
+        inputs: inputs(&flags)?,
+        cites: None,
+        format: OutputFormat::Plain,
+        module_dir: None,
+        trace: None,
+    };
+    for (flag, value) in flags {
+        match flag.as_str() {
+            "--cites" => options.cites = Some(value.into()),
+            "--format" => options.format = value.parse().map_err(CliError::Usage)?,
+            "--module-dir" => options.module_dir = Some(value.into()),
+            "--trace" => options.trace = Some(value.into()),
+            _ => {}
+        }
+    }
+    Ok(options)
+}
+
+fn parse_debug_args(args: &[String]) -> Result<DebugOptions, CliError> {
+    let flags = flags(args, &["--ref"])?;
+    Ok(DebugOptions {
+        inputs: inputs(&flags)?,
+        ids: flags
+            .into_iter()
+            .filter(|(flag, _)| flag == "--ref")
+            .map(|(_, value)| value)
+            .collect(),
+    })
+}
+
+fn parse_replay_args(args: &[String]) -> Result<ReplayOptions, CliError> {
+    let flags = flags(args, &["--cites", "--edits"])?;
+    let last = |name: &str| {
+        flags
+            .iter()
+            .rev()
+            .find(|(flag, _)| flag == name)
+            .map(|(_, value)| PathBuf::from(value))
+    };
+    Ok(ReplayOptions {
+        inputs: inputs(&flags)?,
+        cites: last("--cites"),
+        edits: last("--edits").ok_or_else(|| CliError::Usage("`--edits` is required".into()))?,
+    })
+}
+
+/// Unlike the other commands, takes a directory and a flag with no value.
+fn parse_test_suite_args(args: &[String]) -> Result<TestSuiteOptions, CliError> {
+    let mut dir = None;
+    let mut passing = None;
+    let mut update = false;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--passing" => match args.next() {
+                Some(path) => passing = Some(PathBuf::from(path)),
+                None => return Err(CliError::Usage("`--passing` needs a value".into())),
+            },
+            "--update" => update = true,
+            flag if flag.starts_with("--") => {
+                return Err(CliError::Usage(format!("unknown option `{}`", flag)))
+            }
+            _ if dir.is_some() => {
+                return Err(CliError::Usage("expected one fixture directory".into()))
+            }
+            path => dir = Some(PathBuf::from(path)),
+        }
+    }
+    if update && passing.is_none() {
+        return Err(CliError::Usage("`--update` needs `--passing`".into()));
+    }
+    Ok(TestSuiteOptions {
+        dir: dir.ok_or_else(|| CliError::Usage("expected a fixture directory".into()))?,
+        passing,
+        update,
+    })
+}
+
+/// Only the style, which is all the lints look at.
+fn parse_lint_args(args: &[String]) -> Result<PathBuf, CliError> {
+    match args {
+        [flag, ..] if flag.starts_with("--") => {
+            Err(CliError::Usage(format!("unknown option `{}`", flag)))
+        }
+        [path] => Ok(PathBuf::from(path)),
+        [] => Err(CliError::Usage("expected a style".into())),
+        _ => Err(CliError::Usage("expected one style".into())),
+    }
+}
+
+fn read(path: &Path) -> Result<String, CliError> {
+    std::fs::read_to_string(path).map_err(|error| CliError::Io {
+        path: path.into(),
+        error,
+    })
+}
+
+fn read_json(path: &Path) -> Result<json::Value, CliError> {
+    json::parse(&read(path)?).map_err(|error| CliError::Json {
+        path: path.into(),
+        error,
+    })
+}
+
+/// The style, with the locale the options ask for, and the locale files for it.
+fn load_style(inputs: &Inputs) -> Result<(Style, Vec<Locale>), CliError> {
+    let mut style: Style = read(&inputs.style)?
+        .parse()
+        .map_err(|error| CliError::Style {
+            path: inputs.style.clone(),
+            error,
+        })?;
+    if let Some(lang) = &inputs.locale {
+        style.default_locale = lang.clone();
+    }
+    let locales = match &inputs.locale_dir {
+        Some(dir) => load_locales(dir, &style.default_locale)?,
+        None => Vec::new(),
+    };
+    Ok((style, locales))
+}
+
+fn load_references(inputs: &Inputs) -> Result<Vec<Reference>, CliError> {
+    csl_json::references(&read_json(&inputs.refs)?).map_err(|error| CliError::CslJson {
+        path: inputs.refs.clone(),
+        error,
+    })
+}
+
+fn load_clusters(cites: &Option<PathBuf>) -> Result<Vec<Cluster>, CliError> {
+    match cites {
+        Some(path) => csl_json::clusters(&read_json(path)?).map_err(|error| CliError::CslJson {
+            path: path.clone(),
+            error,
+        }),
+        None => Ok(Vec::new()),
+    }
+}
+
+fn render(options: &RenderOptions) -> Result<String, CliError> {
+    let (style, locales) = load_style(&options.inputs)?;
+    let references = load_references(&options.inputs)?;
+    let clusters = load_clusters(&options.cites)?;
+    let modules = match &options.module_dir {
+        Some(dir) => load_modules(dir)?,
+        None => Vec::new(),
+    };
+    let log = Arc::new(EventLog::default());
+    let tracer: Arc<dyn IrTracer> = match options.trace {
+        Some(_) => log.clone(),
+        None => Arc::new(NoTrace),
+    };
+    let document = render_document(
+        style,
+        locales,
+        references,
+        modules,
+        clusters,
+        tracer,
+        options.format,
+    )
+    .map_err(|error| CliError::Style {
+        path: options.inputs.style.clone(),
+        error,
+    })?;
+    if let Some(path) = &options.trace {
+        let mut trace = log.lines().join("\n");
+        trace.push('\n');
+        std::fs::write(path, trace).map_err(|error| CliError::Io {
+            path: path.clone(),
+            error,
+        })?;
+    }
+    Ok(document)
+}
+
+fn debug(options: &DebugOptions) -> Result<String, CliError> {
+    let (style, locales) = load_style(&options.inputs)?;
+    let references = load_references(&options.inputs)?;
+    if let Some(id) = options
+        .ids
+        .iter()
+        .find(|id| !references.iter().any(|r| &r.id == *id))
+    {
+        return Err(CliError::UnknownReference { id: id.clone() });
+    }
+    Ok(debug_document(style, locales, references, &options.ids))
+}
+
+fn replay(options: &ReplayOptions) -> Result<String, CliError> {
+    let (style, locales) = load_style(&options.inputs)?;
+    let references = load_references(&options.inputs)?;
+    let clusters = load_clusters(&options.cites)?;
+    let cites = clusters.iter().map(|cluster| cluster.cites.len() as u32).sum();
+    let edits = csl_json::edits(&read_json(&options.edits)?, cites).map_err(|error| {
+        CliError::CslJson {
+            path: options.edits.clone(),
+            error,
+        }
+    })?;
+    replay_document(style, locales, references, clusters, edits).map_err(|error| {
+        CliError::Style {
+            path: options.inputs.style.clone(),
+            error,
+        }
+    })
+}
+
+fn test_suite(options: &TestSuiteOptions) -> Result<String, CliError> {
+    let results = test_suite::run_dir(&options.dir).map_err(|error| CliError::Io {
+        path: options.dir.clone(),
+        error,
+    })?;
+    let mut out = test_suite::report(&results);
+    let passing = match &options.passing {
+        Some(passing) => passing,
+        None => return Ok(out),
+    };
+    if options.update {
+        // The first snapshot has no comments to keep
+        let previous = if passing.exists() { read(passing)? } else { String::new() };
+        let snapshot = test_suite::write_snapshot(&results, &previous);
+        std::fs::write(passing, snapshot).map_err(|error| CliError::Io {
+            path: passing.clone(),
+            error,
+        })?;
+        writeln!(out, "updated {}", passing.display()).unwrap();
+        return Ok(out);
+    }
+    let snapshot = test_suite::read_snapshot(&read(passing)?);
+    let names = test_suite::regressions(&results, &snapshot);
+    if !names.is_empty() {
+        return Err(CliError::Regressions {
+            report: out,
+            passing: passing.clone(),
+            names,
+        });
+    }
+    let newly_passing: Vec<&str> = results
+        .iter()
+        .filter(|(name, outcome)| {
+            *outcome == test_suite::Outcome::Passed && !snapshot.contains(name)
+        })
+        .map(|(name, _)| name.as_str())
+        .collect();
+    if !newly_passing.is_empty() {
+        writeln!(
+            out,
+            "passing but not listed in {}: {}",
+            passing.display(),
+            newly_passing.join(", ")
+        )
+        .unwrap();
+    }
+    Ok(out)
+}
+
+/// The locale files for `lang` and its primary language, like `locales-de-AT.xml` and
+/// `locales-de.xml`, that are in `dir`.
+fn load_locales(dir: &Path, lang: &str) -> Result<Vec<Locale>, CliError> {
+    let mut locales = Vec::new();
+    for candidate in Some(lang).into_iter().chain(primary_language(lang)) {
+        let path = dir.join(format!("locales-{}.xml", candidate));
+        if !path.is_file() {
+            continue;
+        }
+        let mut locale: Locale = read(&path)?.parse().map_err(|error| CliError::Locale {
+            path: path.clone(),
+            error,
+        })?;
+        // Stored under the name it was looked up by, whatever the file says
+        locale.lang = candidate.into();
+        locales.push(locale);
+    }
+    Ok(locales)
+}
+
+/// Every `juris-*.csl` module in `dir`, with the jurisdiction it is for. Juris-M names them
+/// after the jurisdiction, with `+` for `:`, so `juris-us+ca.csl` is for `us:ca`.
+fn load_modules(dir: &Path) -> Result<Vec<(String, JurisdictionModule)>, CliError> {
+    let entries = std::fs::read_dir(dir).map_err(|error| CliError::Io {
+        path: dir.into(),
+        error,
+    })?;
+    let mut modules = Vec::new();
+    for entry in entries {
+        let path = entry
+            .map_err(|error| CliError::Io {
+                path: dir.into(),
+                error,
+            })?
+            .path();
+        let jurisdiction = match path.file_name().and_then(|name| name.to_str()) {
+            Some(name) if name.starts_with("juris-") && name.ends_with(".csl") => {
+                name["juris-".len()..name.len() - ".csl".len()].replace('+', ":")
+            }
+            _ => continue,
+        };
+        let module = read(&path)?.parse().map_err(|error| CliError::Style {
+            path: path.clone(),
+            error,
+        })?;
+        modules.push((jurisdiction, module));
+    }
+    Ok(modules)
+}
+
+pub fn render_document(
+    style: Style,
+    locales: Vec<Locale>,
+    mut references: Vec<Reference>,
+    modules: Vec<(String, JurisdictionModule)>,
+    clusters: Vec<Cluster>,
+    tracer: Arc<dyn IrTracer>,
+    format: OutputFormat,
+) -> Result<String, StyleError> {
+    let mut db = Database::new(style);
+    db.set_tracer(tracer);
+    for locale in locales {
+        db.set_locale(locale);
+    }
+    for reference in &mut references {
+        take_hereinafter(&mut db, reference);
+    }
+    db.set_references(references);
+    for (jurisdiction, module) in modules {
+        db.set_jurisdiction_module(jurisdiction, Some(module));
+    }
+    for cluster in clusters {
+        db.set_cluster(cluster);
+    }
+    let rendered: Vec<(ClusterId, String)> = db
+        .cluster_ids()
+        .iter()
+        .map(|&id| Ok((id, (*db.render_cluster(id)?).clone())))
+        .collect::<Result<_, StyleError>>()?;
+    Ok(format.document(&rendered, &db.render_bibliography()?, &db.bibliography_meta()))
+}
+
+/// The refs file is the only document input there is, so a reference's CSL-M `hereinafter` is
+/// taken as what the document calls it.
+fn take_hereinafter(db: &mut Database, reference: &mut Reference) {
+    if let Some(short) = reference.ordinary.remove(&Variable::Hereinafter) {
+        db.set_hereinafter(reference.id.clone(), Some(short));
+    }
+}
+
+/// Every cluster as `[id] text`, and then under each edit how many clusters had to be rendered
+/// again, the clusters it changed or added, and `[id] removed` for those it removed.
+pub fn replay_document(
+    style: Style,
+    locales: Vec<Locale>,
+    mut references: Vec<Reference>,
+    clusters: Vec<Cluster>,
+    edits: Vec<Edit>,
+) -> Result<String, StyleError> {
+    let mut db = Database::new(style);
+    for locale in locales {
+        db.set_locale(locale);
+    }
+    for reference in &mut references {
+        take_hereinafter(&mut db, reference);
+    }
+    db.set_references(references);
+    for cluster in clusters {
+        db.set_cluster(cluster);
+    }
+    let render_all = |db: &Database| {
+        db.cluster_ids()
+            .iter()
+            .map(|&id| Ok((id, db.render_cluster(id)?)))
+            .collect::<Result<Vec<_>, StyleError>>()
+    };
+    let mut out = String::new();
+    let mut before = render_all(&db)?;
+    for (id, text) in &before {
+        writeln!(out, "[{}] {}", id.0, text).unwrap();
+    }
+    let renders = |db: &Database, id: ClusterId| db.execution_count(&QueryKey::ClusterOutput(id));
+    for (ix, edit) in edits.into_iter().enumerate() {
+        let counts: Vec<(ClusterId, u32)> =
+            before.iter().map(|&(id, _)| (id, renders(&db, id))).collect();
+        match edit {
+            Edit::InsertReference(mut reference) => {
+                take_hereinafter(&mut db, &mut reference);
+                db.insert_reference(reference);
+            }
+            Edit::RemoveReference(id) => db.remove_reference(&id),
+            Edit::SetCluster(cluster) => db.set_cluster(cluster),
+            Edit::RemoveCluster(id) => db.remove_cluster(id),
+        }
+        let after = render_all(&db)?;
+        let rendered = after
+            .iter()
+            .filter(|&&(id, _)| !counts.contains(&(id, renders(&db, id))))
+            .count();
+        writeln!(
+            out,
+            "\nedit {}: rendered {} of {} clusters again",
+            ix + 1,
+            rendered,
+            after.len()
+        )
+        .unwrap();
+        for (id, text) in &after {
+            if !before.contains(&(*id, text.clone())) {
+                writeln!(out, "[{}] {}", id.0, text).unwrap();
+            }
+        }
+        for (id, _) in &before {
+            if !after.iter().any(|(other, _)| other == id) {
+                writeln!(out, "[{}] removed", id.0).unwrap();
+            }
+        }
+        before = after;
+    }
+    Ok(out)
+}
+
+fn lint(path: &Path) -> Result<String, CliError> {
+    let style: Style = read(path)?.parse().map_err(|error| CliError::Style {
+        path: path.into(),
+        error,
+    })?;
+    Ok(lint_document(&style))
+}
+
+/// One diagnostic per line, in the order `lint::lint` finds them.
+pub fn lint_document(style: &Style) -> String {
+    lint::lint(style)
+        .iter()
+        .map(|diagnostic| format!("{}\n", diagnostic))
+        .collect()
+}
+
+/// For each reference in `ids`, or every reference if there are none: its IR for a first cite
+/// with no locator, the `GroupVars` of each level of the IR, and the automata disambiguation
+/// compares cites against, as Graphviz DOT.
+pub fn debug_document(
+    style: Style,
+    locales: Vec<Locale>,
+    references: Vec<Reference>,
+    ids: &[String],
+) -> String {
+    let mut db = Database::new(style);
+    for locale in locales {
+        db.set_locale(locale);
+    }
+    let shown: Vec<Reference> = references
+        .iter()
+        .filter(|r| ids.is_empty() || ids.contains(&r.id))
+        .cloned()
+        .collect();
+    db.set_references(references);
+    let mut out = String::new();
+    for reference in &shown {
+        let debug = debug_reference(&db, reference);
+        if !out.is_empty() {
+            out.push('\n');
+        }
+        writeln!(out, "reference `{}`", reference.id).unwrap();
+        match db.ref_ir(&reference.id) {
+            Ok(ir) => writeln!(out, "ir: {}", ir.debug(&db)).unwrap(),
+            Err(e) => writeln!(out, "ir: error: {}", e).unwrap(),
+        }
+        writeln!(out, "group vars:").unwrap();
+        for level in &debug.levels {
+            let indent = "  ".repeat(level.depth + 1);
+            let elements = level.elements.join(", ");
+            writeln!(out, "{}{:?}: {}", indent, level.gv, elements).unwrap();
+        }
+        if debug.nfa.is_empty() {
+            // No IR, or it renders nothing at all, so there's nothing to draw
+            writeln!(out, "nfa: empty").unwrap();
+        } else {
+            writeln!(out, "nfa:\n{}", debug.nfa.to_dot()).unwrap();
+            writeln!(out, "dfa:\n{}", debug.dfa.to_dot()).unwrap();
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+fn args(args: &str) -> Vec<String> {
+    args.split_whitespace().map(String::from).collect()
+}
+
+#[test]
+fn parses_arguments() {
+    assert_eq!(
+        parse_render_args(&args(
+            "--style s.csl --refs r.json --cites c.json --format html --locale de-DE \
+             --locale-dir locales --module-dir modules --trace trace.txt"
+        ))
+        .unwrap(),
+        RenderOptions {
+            inputs: Inputs {
+                style: "s.csl".into(),
+                refs: "r.json".into(),
+                locale: Some("de-DE".into()),
+                locale_dir: Some("locales".into()),
+            },
+            cites: Some("c.json".into()),
+            format: OutputFormat::Html,
+            module_dir: Some("modules".into()),
+            trace: Some("trace.txt".into()),
+        }
+    );
+    assert_eq!(
+        parse_debug_args(&args("--ref a --style s.csl --refs r.json --ref b")).unwrap(),
+        DebugOptions {
+            inputs: Inputs {
+                style: "s.csl".into(),
+                refs: "r.json".into(),
+                locale: None,
+                locale_dir: None,
+            },
+            ids: vec!["a".into(), "b".into()],
+        }
+    );
+    let usage = |line: &str| match run(&args(line)) {
+        Err(CliError::Usage(message)) => message,
+        other => panic!("expected a usage error, got {:?}", other),
+    };
+    assert_eq!(usage("render --refs r.json"), "`--style` is required");
+    assert_eq!(
+        usage("render --style s.csl --refs"),
+        "`--refs` needs a value"
+    );
+    assert_eq!(
+        usage("render --style s.csl --refs r.json --format pdf"),
+        "unknown format `pdf`, expected html, rtf or plain"
+    );
+    assert_eq!(usage("render --verbose"), "unknown option `--verbose`");
+    assert_eq!(
+        usage("debug --style s.csl --refs r.json --format html"),
+        "unknown option `--format`"
+    );
+    assert_eq!(
+        parse_test_suite_args(&args("--passing p.txt fixtures --update")).unwrap(),
+        TestSuiteOptions {
+            dir: "fixtures".into(),
+            passing: Some("p.txt".into()),
+            update: true,
+        }
+    );
+    assert_eq!(
+        usage("test-suite fixtures --update"),
+        "`--update` needs `--passing`"
+    );
+    assert_eq!(usage("test-suite"), "expected a fixture directory");
+    assert_eq!(parse_lint_args(&args("s.csl")).unwrap(), PathBuf::from("s.csl"));
+    assert_eq!(usage("lint"), "expected a style");
+    assert_eq!(usage("lint a.csl b.csl"), "expected one style");
+    assert_eq!(usage("draw"), "unknown command `draw`");
+    assert_eq!(run(&args("--help")).unwrap(), USAGE);
+}
+
+#[test]
+fn renders_files() {
+    let dir = std::env::temp_dir().join(format!("minimal-cli-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let write = |name: &str, contents: &str| {
+        let path = dir.join(name);
+        std::fs::write(&path, contents).unwrap();
+        path.to_str().unwrap().to_string()
+    };
+    let style = write(
+        "style.csl",
+        r#"<style class="in-text" default-locale="de-DE">
+          <citation>
+            <layout prefix="(" suffix=")" delimiter="; ">
+              <group delimiter=", ">
+                <names variable="author"><name form="short" and="text"/></names>
+                <group delimiter=" "><label variable="locator"/><text variable="locator"/></group>
+              </group>
+            </layout>
+          </citation>
+          <bibliography>
+            <layout><text variable="title"/></layout>
+          </bibliography>
+        </style>"#,
+    );
+    let refs = write(
+        "refs.json",
+        r#"[{"id": "a", "type": "book", "title": "Fish & Chips",
+             "author": [{"family": "Smith"}, {"family": "Jones"}]}]"#,
+    );
+    let cites = write(
+        "cites.json",
+        r#"[{"cites": [{"id": "a", "locator": "5"}]}]"#,
+    );
+    write(
+        "locales-de-DE.xml",
+        r#"<locale xml:lang="de-DE"><terms>
+          <term name="and">und</term>
+          <term name="page" form="short"><single>S.</single><multiple>S.</multiple></term>
+        </terms></locale>"#,
+    );
+    let render = |extra: &str| {
+        let line = format!(
+            "render --style {} --refs {} --cites {} {}",
+            style, refs, cites, extra
+        );
+        run(&args(&line)).unwrap()
+    };
+    assert_eq!(
+        render(&format!("--locale-dir {}", dir.display())),
+        "(Smith und Jones, S. 5)\n\nFish & Chips\n"
+    );
+    assert_eq!(
+        render(&format!("--locale-dir {} --locale fr-FR", dir.display())),
+        "(Smith and Jones, p. 5)\n\nFish & Chips\n"
+    );
+    assert_eq!(
+        render("--format html"),
+        "<p class=\"csl-citation\">(Smith and Jones, p. 5)</p>\n\
+         <div class=\"csl-bib-body\">\n  <div class=\"csl-entry\">Fish &amp; Chips</div>\n\
+         </div>\n"
+    );
+    let trace = dir.join("trace.txt");
+    assert_eq!(
+        render(&format!("--trace {}", trace.display())),
+        "(Smith and Jones, p. 5)\n\nFish & Chips\n"
+    );
+    let trace = std::fs::read_to_string(&trace).unwrap();
+    assert!(trace.starts_with("0 sequence [group]\n1 element group\n"));
+    assert!(trace.contains("2 edge Output(\"Smith and Jones\")\n"));
+    let missing = run(&args("render --style nowhere.csl --refs r.json")).unwrap_err();
+    assert!(missing.to_string().starts_with("nowhere.csl: "));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn takes_hereinafter_from_the_refs_file() {
+    let dir = std::env::temp_dir().join(format!("minimal-cli-mlz-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let write = |name: &str, contents: &str| {
+        let path = dir.join(name);
+        std::fs::write(&path, contents).unwrap();
+        path.to_str().unwrap().to_string()
+    };
+    let style = write(
+        "style.csl",
+        r#"<style class="note" version="1.1mlz1">
+          <citation>
+            <layout delimiter="; ">
+              <choose>
+                <if variable="hereinafter"><text variable="hereinafter"/></if>
+                <else><text variable="title"/></else>
+              </choose>
+            </layout>
+          </citation>
+        </style>"#,
+    );
+    let refs = write(
+        "refs.json",
+        r#"[{"id": "a", "type": "legal_case", "title": "Brown v. Board", "hereinafter": "Brown"},
+            {"id": "b", "type": "legal_case", "title": "Lüth"}]"#,
+    );
+    let cites = write("cites.json", r#"[{"cites": [{"id": "a"}, {"id": "b"}]}]"#);
+    let line = format!("render --style {} --refs {} --cites {}", style, refs, cites);
+    assert_eq!(run(&args(&line)).unwrap(), "Brown; Lüth\n");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn renders_with_jurisdiction_modules() {
+    let dir = std::env::temp_dir().join(format!("minimal-cli-juris-{}", std::process::id()));
+    let modules = dir.join("modules");
+    std::fs::create_dir_all(&modules).unwrap();
+    let write = |name: &str, contents: &str| {
+        let path = dir.join(name);
+        std::fs::write(&path, contents).unwrap();
+        path.to_str().unwrap().to_string()
+    };
+    let style = write(
+        "style.csl",
+        r#"<style class="note" version="1.1mlz1">
+          <macro name="juris-main"><text variable="title"/></macro>
+          <citation><layout delimiter="; "><text macro="juris-main"/></layout></citation>
+        </style>"#,
+    );
+    write(
+        "modules/juris-us+ca.csl",
+        r#"<style class="note" version="1.1mlz1">
+          <macro name="juris-main">
+            <group delimiter=" "><text variable="title"/><text variable="authority"/></group>
+          </macro>
+          <citation><layout/></citation>
+        </style>"#,
+    );
+    write("modules/README", "not a module");
+    let refs = write(
+        "refs.json",
+        r#"[{"id": "a", "type": "legal_case", "title": "People v. Anderson",
+             "authority": "Cal.", "jurisdiction": "us:ca:sf|San Francisco"},
+            {"id": "b", "type": "legal_case", "title": "R v. Brown", "authority": "UKHL",
+             "jurisdiction": "gb"}]"#,
+    );
+    let cites = write("cites.json", r#"[{"cites": [{"id": "a"}, {"id": "b"}]}]"#);
+    let render = |extra: &str| {
+        let line = format!("render --style {} --refs {} --cites {} {}", style, refs, cites, extra);
+        run(&args(&line))
+    };
+    assert_eq!(render("").unwrap(), "People v. Anderson; R v. Brown\n");
+    assert_eq!(
+        render(&format!("--module-dir {}", modules.display())).unwrap(),
+        "People v. Anderson Cal.; R v. Brown\n"
+    );
+    write("modules/juris-gb.csl", "<locale/>");
+    let broken = render(&format!("--module-dir {}", modules.display())).unwrap_err();
+    assert!(broken.to_string().ends_with("juris-gb.csl: expected <style>, found <locale> at 1:1"));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn replays_edits() {
+    let dir = std::env::temp_dir().join(format!("minimal-cli-replay-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let write = |name: &str, contents: &str| {
+        let path = dir.join(name);
+        std::fs::write(&path, contents).unwrap();
+        path.to_str().unwrap().to_string()
+    };
+    let style = write(
+        "style.csl",
+        r#"<style class="in-text">
+          <citation disambiguate-add-givenname="true" givenname-disambiguation-rule="by-cite">
+            <layout delimiter="; ">
+              <names variable="author"><name form="short" initialize-with=". "/></names>
+            </layout>
+          </citation>
+        </style>"#,
+    );
+    let refs = write(
+        "refs.json",
+        r#"[{"id": "a", "type": "book", "author": [{"family": "Smith", "given": "John"}]},
+            {"id": "b", "type": "book", "author": [{"family": "Jones", "given": "Sam"}]}]"#,
+    );
+    let cites = write(
+        "cites.json",
+        r#"[{"cites": [{"id": "a"}]}, {"cites": [{"id": "b"}]}]"#,
+    );
+    let edits = write(
+        "edits.json",
+        r#"[{"insert-reference": {"id": "c", "type": "book",
+                                 "author": [{"family": "Smith", "given": "Anne"}]}},
+            {"set-cluster": {"id": 3, "cites": [{"id": "c"}]}},
+            {"remove-reference": "c"},
+            {"remove-cluster": 3}]"#,
+    );
+    let line = format!(
+        "replay --style {} --refs {} --cites {} --edits {}",
+        style, refs, cites, edits
+    );
+    assert_eq!(
+        run(&args(&line)).unwrap(),
+        "[1] Smith\n[2] Jones\n\
+         \nedit 1: rendered 2 of 2 clusters again\n[1] J. Smith\n\
+         \nedit 2: rendered 1 of 3 clusters again\n[3] A. Smith\n\
+         \nedit 3: rendered 3 of 3 clusters again\n[1] Smith\n[3] \n\
+         \nedit 4: rendered 0 of 2 clusters again\n[3] removed\n"
+    );
+    assert!(matches!(
+        run(&args("replay --style s.csl --refs r.json")),
+        Err(CliError::Usage(message)) if message == "`--edits` is required"
+    ));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn debugs_references() {
+    let style: Style = r#"<style class="in-text">
+      <macro name="author"><names variable="author"/></macro>
+      <citation>
+        <layout>
+          <group delimiter=" "><text macro="author"/><text variable="title"/></group>
+          <group><text value="vol. "/><text variable="volume"/></group>
+        </layout>
+      </citation>
+    </style>"#
+        .parse()
+        .unwrap();
+    let mut doe = Reference::empty("doe", "book");
+    doe.name.insert(
+        crate::element::NameVariable::Author,
+        vec![crate::reference::PersonName::new("Jane", "Doe")],
+    );
+    let other = Reference::empty("other", "book");
+    let out = debug_document(style, vec![], vec![doe, other], &["doe".into()]);
+    assert_eq!(
+        out,
+        r#"reference `doe`
+ir: [[[Output("Jane Doe")]]]
+group vars:
+  Important: group, group
+    Important: text macro="author", text variable
+      Important: names
+    Missing: text value, text variable
+nfa:
+digraph {
+    0 [ label = "0" ]
+    1 [ label = "1", shape = doublecircle ]
+    0 -> 1 [ label = "Token(Output(\"Jane Doe\"))" ]
+    start_0 [ shape = point ]
+    start_0 -> 0
+}
+dfa:
+digraph {
+    0 [ label = "0" ]
+    1 [ label = "1", shape = doublecircle ]
+    0 -> 1 [ label = "Output(\"Jane Doe\")" ]
+    start_0 [ shape = point ]
+    start_0 -> 0
+}
+"#
+    );
+}
+
+#[test]
+fn debug_shows_why_a_reference_has_no_ir() {
+    use crate::element::{Element, TextCase, TextElement, TextSource};
+    let mut style: Style = r#"<style class="in-text">
+      <macro name="author"><names variable="author"/></macro>
+      <citation><layout><text macro="author"/></layout></citation>
+    </style>"#
+        .parse()
+        .unwrap();
+    // Parsing would have caught this, so the style has to be broken afterwards.
+    let missing = Element::Text(TextElement {
+        source: TextSource::Macro("missing".into()),
+        formatting: None,
+        affixes: None,
+        quotes: false,
+        strip_periods: false,
+        text_case: TextCase,
+        display: None,
+    });
+    style.macros.insert("author".into(), vec![missing]);
+    let doe = Reference::empty("doe", "book");
+    let out = debug_document(style, vec![], vec![doe], &[]);
+    assert!(
+        out.starts_with("reference `doe`\nir: error: undefined macro `missing`\n"),
+        "{}",
+        out
+    );
+    assert!(out.ends_with("\nnfa: empty\n"), "{}", out);
+}
+
+#[test]
+fn lints_styles() {
+    let style: Style = r#"<style class="in-text">
+      <macro name="unused"><text variable="title"/></macro>
+      <citation><layout><group><text value="see"/></group></layout></citation>
+    </style>"#
+        .parse()
+        .unwrap();
+    assert_eq!(
+        lint_document(&style),
+        "L002 in the citation layout: a <group> with no variables in it is never suppressed\n\
+         L001 in macro `unused`: nothing calls this macro\n"
+    );
+}
+
+#[test]
+fn renders_an_upstream_style() {
+    let dir = std::env::temp_dir().join(format!("minimal-cli-apa-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let write = |name: &str, contents: &str| {
+        let path = dir.join(name);
+        std::fs::write(&path, contents).unwrap();
+        path.to_str().unwrap().to_string()
+    };
+    // Cut down from the APA style in the CSL styles repository, keeping its namespace, info,
+    // locale overrides and the way its macros are built
+    let style = write(
+        "apa.csl",
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<style xmlns="http://purl.org/net/xbiblio/csl" class="in-text" version="1.0" demote-non-dropping-particle="never" page-range-format="expanded">
+  <info>
+    <title>American Psychological Association 6th edition</title>
+    <title-short>APA</title-short>
+    <id>http://www.zotero.org/styles/apa</id>
+    <link href="http://www.zotero.org/styles/apa" rel="self"/>
+    <link href="http://owl.english.purdue.edu/owl/resource/560/01/" rel="documentation"/>
+    <author>
+      <name>Simon Kornblith</name>
+      <email>simon@simonster.com</email>
+    </author>
+    <category citation-format="author-date"/>
+    <category field="psychology"/>
+    <updated>2016-09-28T13:09:49+00:00</updated>
+    <rights license="http://creativecommons.org/licenses/by-sa/3.0/">This work is licensed under a Creative Commons Attribution-ShareAlike 3.0 License</rights>
+  </info>
+  <locale xml:lang="en">
+    <terms>
+      <term name="editortranslator" form="short">
+        <single>ed. &amp; trans.</single>
+        <multiple>eds. &amp; trans.</multiple>
+      </term>
+      <term name="translator" form="short">trans.</term>
+    </terms>
+  </locale>
+  <macro name="container-contributors">
+    <choose>
+      <if type="chapter paper-conference entry-dictionary entry-encyclopedia" match="any">
+        <names variable="editor translator" delimiter=", ">
+          <name and="symbol" initialize-with=". " delimiter=", "/>
+          <label form="short" prefix=" (" text-case="title" suffix=")"/>
+        </names>
+      </if>
+    </choose>
+  </macro>
+  <macro name="author">
+    <names variable="author">
+      <name name-as-sort-order="all" and="symbol" sort-separator=", " initialize-with=". " delimiter=", " delimiter-precedes-last="always"/>
+      <label form="short" prefix=" (" suffix=")" text-case="capitalize-first"/>
+      <substitute>
+        <names variable="editor"/>
+        <names variable="translator"/>
+        <text macro="title"/>
+      </substitute>
+    </names>
+  </macro>
+  <macro name="author-short">
+    <names variable="author">
+      <name form="short" and="symbol" delimiter=", " initialize-with=". "/>
+      <substitute>
+        <names variable="editor"/>
+        <names variable="translator"/>
+        <choose>
+          <if type="bill book graphic legal_case legislation motion_picture report song" match="any">
+            <text variable="title" form="short" font-style="italic"/>
+          </if>
+          <else>
+            <text variable="title" form="short" quotes="true"/>
+          </else>
+        </choose>
+      </substitute>
+    </names>
+  </macro>
+  <macro name="title">
+    <choose>
+      <if type="book report" match="any">
+        <text variable="title" font-style="italic"/>
+      </if>
+      <else>
+        <text variable="title"/>
+      </else>
+    </choose>
+  </macro>
+  <macro name="publisher">
+    <choose>
+      <if type="report" match="any">
+        <group delimiter=": ">
+          <text variable="publisher-place"/>
+          <text variable="publisher"/>
+        </group>
+      </if>
+      <else-if type="book chapter" match="any">
+        <group delimiter=": ">
+          <text variable="publisher-place"/>
+          <text variable="publisher"/>
+        </group>
+      </else-if>
+    </choose>
+  </macro>
+  <macro name="issued">
+    <choose>
+      <if variable="issued">
+        <group>
+          <date variable="issued">
+            <date-part name="year"/>
+          </date>
+          <text variable="year-suffix"/>
+          <choose>
+            <if type="article-magazine article-newspaper" match="any">
+              <date variable="issued">
+                <date-part prefix=", " name="month"/>
+                <date-part prefix=" " name="day"/>
+              </date>
+            </if>
+          </choose>
+        </group>
+      </if>
+      <else>
+        <group>
+          <text term="no date" form="short"/>
+          <text variable="year-suffix" prefix="-"/>
+        </group>
+      </else>
+    </choose>
+  </macro>
+  <macro name="issued-sort">
+    <date variable="issued">
+      <date-part name="year"/>
+      <date-part name="month"/>
+      <date-part name="day"/>
+    </date>
+  </macro>
+  <macro name="issued-year">
+    <choose>
+      <if variable="issued">
+        <group>
+          <date variable="issued">
+            <date-part name="year"/>
+          </date>
+          <text variable="year-suffix"/>
+        </group>
+      </if>
+      <else>
+        <group>
+          <text term="no date" form="short"/>
+          <text variable="year-suffix" prefix="-"/>
+        </group>
+      </else>
+    </choose>
+  </macro>
+  <macro name="locators">
+    <choose>
+      <if type="article-journal article-magazine article-newspaper" match="any">
+        <group prefix=", " delimiter=", ">
+          <group>
+            <text variable="volume" font-style="italic"/>
+            <text variable="issue" prefix="(" suffix=")"/>
+          </group>
+          <text variable="page"/>
+        </group>
+      </if>
+    </choose>
+  </macro>
+  <macro name="citation-locator">
+    <group>
+      <choose>
+        <if locator="chapter">
+          <label variable="locator" form="long" text-case="capitalize-first"/>
+        </if>
+        <else>
+          <label variable="locator" form="short"/>
+        </else>
+      </choose>
+      <text variable="locator" prefix=" "/>
+    </group>
+  </macro>
+  <citation et-al-min="6" et-al-use-first="1" et-al-subsequent-min="3" et-al-subsequent-use-first="1" disambiguate-add-year-suffix="true" disambiguate-add-names="true" disambiguate-add-givenname="true" collapse="year" givenname-disambiguation-rule="primary-name">
+    <sort>
+      <key macro="author"/>
+      <key macro="issued-sort"/>
+    </sort>
+    <layout prefix="(" suffix=")" delimiter="; ">
+      <group delimiter=", ">
+        <text macro="author-short"/>
+        <text macro="issued-year"/>
+        <text macro="citation-locator"/>
+      </group>
+    </layout>
+  </citation>
+  <bibliography hanging-indent="true" et-al-min="8" et-al-use-first="6" et-al-use-last="true" entry-spacing="0" line-spacing="2">
+    <sort>
+      <key macro="author"/>
+      <key macro="issued-sort" sort="ascending"/>
+      <key macro="title"/>
+    </sort>
+    <layout>
+      <group suffix=".">
+        <group delimiter=". ">
+          <text macro="author"/>
+          <group prefix="(" suffix=")">
+            <text macro="issued"/>
+          </group>
+          <group delimiter=". ">
+            <text macro="title"/>
+            <text macro="container-contributors"/>
+          </group>
+        </group>
+        <text macro="locators"/>
+        <group delimiter=", " prefix=". ">
+          <text macro="publisher"/>
+        </group>
+      </group>
+    </layout>
+  </bibliography>
+</style>"#,
+    );
+    let refs = write(
+        "refs.json",
+        r#"[
+          {"id": "kahneman", "type": "book", "title": "Thinking, fast and slow",
+           "author": [{"family": "Kahneman", "given": "Daniel"}],
+           "issued": {"date-parts": [[2011]]},
+           "publisher": "Farrar, Straus and Giroux", "publisher-place": "New York"},
+          {"id": "tversky", "type": "article-journal",
+           "title": "Judgment under uncertainty: Heuristics and biases",
+           "author": [{"family": "Tversky", "given": "Amos"},
+                      {"family": "Kahneman", "given": "Daniel"}],
+           "container-title": "Science", "volume": "185", "issue": "4157",
+           "page": "1124-1131", "issued": {"date-parts": [[1974, 9, 27]]}},
+          {"id": "kahneman-a", "type": "article-journal", "title": "A perspective on judgment",
+           "author": [{"family": "Kahneman", "given": "Daniel"}],
+           "container-title": "American Psychologist", "volume": "58", "page": "697-720",
+           "issued": {"date-parts": [[2011, 9]]}}
+        ]"#,
+    );
+    let cites = write(
+        "cites.json",
+        r#"[{"cites": [{"id": "tversky", "locator": "1125"}]},
+            {"cites": [{"id": "kahneman"}, {"id": "kahneman-a"}]}]"#,
+    );
+    let line = format!("render --style {} --refs {} --cites {}", style, refs, cites);
+    assert_eq!(
+        run(&args(&line)).unwrap(),
+        "(Tversky & Kahneman, 1974, p. 1125)\n\
+         (Kahneman, 2011a, 2011b)\n\
+         \n\
+         Kahneman, D. (2011a). Thinking, fast and slow. New York: Farrar, Straus and Giroux.\n\
+         Kahneman, D. (2011b). A perspective on judgment, 58, 697–720.\n\
+         Tversky, A., & Kahneman, D. (1974). Judgment under uncertainty: Heuristics and \
+         biases, 185(4157), 1124–1131.\n"
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,233 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Copyright © 2019 Corporation for Digital Scholarship
+
+use crate::element::{LocatorType, Position};
+use crate::CiteId;
+use std::collections::HashMap;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ClusterId(pub u32);
+
+/// The CSL default for `near-note-distance`.
+pub const NEAR_NOTE_DISTANCE: u32 = 5;
+
+/// One citation in the document: a group of cites rendered together, e.g. `(Smith 2001; Doe
+/// 2002)`, or one footnote's worth.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cluster {
+    pub id: ClusterId,
+    pub cites: Vec<Cite>,
+    /// The footnote the cluster sits in. `None` for in-text citations, which are never
+    /// `near-note`. Several clusters may share a note.
+    pub note_number: Option<u32>,
+}
+
+impl Cluster {
+    pub fn new(id: ClusterId, cites: Vec<Cite>) -> Self {
+        Cluster {
+            id,
+            cites,
+            note_number: None,
+        }
+    }
+
+    pub fn in_note(id: ClusterId, note_number: u32, cites: Vec<Cite>) -> Self {
+        Cluster {
+            id,
+            cites,
+            note_number: Some(note_number),
+        }
+    }
+}
+
+/// One reference cited within a cluster.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cite {
+    pub id: CiteId,
+    pub ref_id: String,
+    /// Text to put before the rendered cite, like "see ".
+    pub prefix: Option<String>,
+    /// Text to put after the rendered cite.
+    pub suffix: Option<String>,
+    pub locator: Option<String>,
+    pub locator_type: Option<LocatorType>,
+    /// Leave out the author, for when the text already names them.
+    pub suppress_author: bool,
+}
+
+impl Cite {
+    pub fn basic(id: CiteId, ref_id: impl Into<String>) -> Self {
+        Cite {
+            id,
+            ref_id: ref_id.into(),
+            prefix: None,
+            suffix: None,
+            locator: None,
+            locator_type: None,
+            suppress_author: false,
+        }
+    }
+
+    pub fn with_locator(mut self, locator_type: LocatorType, locator: impl Into<String>) -> Self {
+        self.locator_type = Some(locator_type);
+        self.locator = Some(locator.into());
+        self
+    }
+
+    fn same_locator(&self, other: &Cite) -> bool {
+        self.locator == other.locator && self.locator_type == other.locator_type
+    }
+}
+
+/// Works out every cite's position, given the clusters in document order.
+///
+/// A cite is `ibid` when the cite just before it was of the same reference: either the previous
+/// cite in the same cluster, or, for the first cite of a cluster, the previous cluster if that
+/// cited nothing else. Otherwise it is `subsequent` if the reference has been cited before. In
+/// notes, both are further split into near and far by how many notes back the last cite of the
+/// reference was.
+pub fn cite_positions<'a>(
+    clusters: impl IntoIterator<Item = &'a Cluster>,
+    near_note_distance: u32,
+) -> HashMap<CiteId, Position> {
+    let mut positions = HashMap::new();
+    // ref_id => the note number of its most recent cite
+    let mut last_note: HashMap<&str, Option<u32>> = HashMap::new();
+    let mut prev_cluster: Option<&Cluster> = None;
+    for cluster in clusters {
+        let mut prev_cite: Option<&Cite> = match prev_cluster {
+            Some(prev) if prev.cites.len() == 1 => prev.cites.first(),
+            _ => None,
+        };
+        for cite in &cluster.cites {
+            let position = match last_note.get(cite.ref_id.as_str()) {
+                None => Position::First,
+                Some(&last) => {
+                    let near = match (cluster.note_number, last) {
+                        (Some(this), Some(last)) => {
+                            Some(this.saturating_sub(last) <= near_note_distance)
+                        }
+                        _ => None,
+                    };
+                    let ibid = prev_cite
+                        .filter(|prev| prev.ref_id == cite.ref_id)
+                        .and_then(|prev| {
+                            if cite.same_locator(prev) {
+                                Some(false)
+                            } else if cite.locator.is_some() {
+                                Some(true)
+                            } else {
+                                // Dropping the locator would point the reader at the wrong page
+                                None
+                            }
+                        });
+                    match (ibid, near) {
+                        (Some(false), Some(true)) => Position::IbidNear,
+                        (Some(false), _) => Position::Ibid,
+                        (Some(true), Some(true)) => Position::IbidWithLocatorNear,
+                        (Some(true), _) => Position::IbidWithLocator,
+                        (None, Some(true)) => Position::NearNote,
+                        (None, Some(false)) => Position::FarNote,
+                        (None, None) => Position::Subsequent,
+                    }
+                }
+            };
+            positions.insert(cite.id, position);
+            last_note.insert(&cite.ref_id, cluster.note_number);
+            prev_cite = Some(cite);
+        }
+        // An empty cluster doesn't interrupt an ibid chain
+        if !cluster.cites.is_empty() {
+            prev_cluster = Some(cluster);
+        }
+    }
+    positions
+}
+
+#[cfg(test)]
+fn positions_of(clusters: &[Cluster]) -> Vec<Position> {
+    let positions = cite_positions(clusters, NEAR_NOTE_DISTANCE);
+    clusters
+        .iter()
+        .flat_map(|c| c.cites.iter())
+        .map(|cite| positions[&cite.id])
+        .collect()
+}
+
+#[test]
+fn in_text_positions() {
+    let clusters = vec![
+        Cluster::new(ClusterId(1), vec![Cite::basic(CiteId(1), "a")]),
+        Cluster::new(ClusterId(2), vec![Cite::basic(CiteId(2), "a")]),
+        Cluster::new(
+            ClusterId(3),
+            vec![
+                Cite::basic(CiteId(3), "a").with_locator(LocatorType::Page, "5"),
+                Cite::basic(CiteId(4), "b"),
+                Cite::basic(CiteId(5), "a"),
+            ],
+        ),
+        // Previous cluster had more than one cite
+        Cluster::new(ClusterId(4), vec![Cite::basic(CiteId(6), "a")]),
+    ];
+    use Position::*;
+    assert_eq!(
+        positions_of(&clusters),
+        vec![First, Ibid, IbidWithLocator, First, Subsequent, Subsequent]
+    );
+}
+
+#[test]
+fn ibid_locators() {
+    let page = |id, p: &str| Cite::basic(CiteId(id), "a").with_locator(LocatorType::Page, p);
+    let clusters = vec![Cluster::new(
+        ClusterId(1),
+        vec![
+            page(1, "1"),
+            page(2, "1"),
+            page(3, "2"),
+            Cite::basic(CiteId(4), "a"),
+            Cite::basic(CiteId(5), "a"),
+        ],
+    )];
+    use Position::*;
+    assert_eq!(
+        positions_of(&clusters),
+        vec![First, Ibid, IbidWithLocator, Subsequent, Ibid]
+    );
+}
+
+#[test]
+fn note_positions() {
+    let clusters = vec![
+        Cluster::in_note(ClusterId(1), 1, vec![Cite::basic(CiteId(1), "a")]),
+        Cluster::in_note(ClusterId(2), 2, vec![Cite::basic(CiteId(2), "a")]),
+        Cluster::in_note(ClusterId(3), 3, vec![Cite::basic(CiteId(3), "b")]),
+        Cluster::in_note(ClusterId(4), 7, vec![Cite::basic(CiteId(4), "a")]),
+        Cluster::in_note(ClusterId(5), 20, vec![Cite::basic(CiteId(5), "b")]),
+        Cluster::in_note(
+            ClusterId(6),
+            30,
+            vec![Cite::basic(CiteId(6), "b").with_locator(LocatorType::Page, "3")],
+        ),
+    ];
+    use Position::*;
+    assert_eq!(
+        positions_of(&clusters),
+        vec![First, IbidNear, First, NearNote, FarNote, IbidWithLocator]
+    );
+}
+
+#[test]
+fn position_conditions() {
+    use Position::*;
+    assert!(IbidWithLocatorNear.matches(Ibid));
+    assert!(IbidWithLocatorNear.matches(NearNote));
+    assert!(IbidWithLocatorNear.matches(Subsequent));
+    assert!(!Ibid.matches(NearNote));
+    assert!(!First.matches(Subsequent));
+    assert!(!Subsequent.matches(Ibid));
+}
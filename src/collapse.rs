@@ -0,0 +1,313 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Copyright © 2019 Corporation for Digital Scholarship
+
+//! Joining the cites of a cluster, with cite grouping and `collapse`.
+//!
+//! This works on cites that have already been disambiguated and rendered. Grouping compares the
+//! names each cite actually shows, so two Smiths that disambiguation told apart by their given
+//! names are never grouped, and a year suffix is only ever collapsed if disambiguation gave out.
+//!
+//! It doesn't go the other way: disambiguation never looks at how a cite will be collapsed, so
+//! a cite is told apart from the others by what it would show in full, even when grouping then
+//! leaves its names out.
+
+use crate::element::{Citation, Collapse, Delimiter};
+use crate::CiteId;
+
+/// One cite of a cluster, rendered each way grouping and collapsing might need it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CiteParts {
+    pub id: CiteId,
+    /// The cite as disambiguated, with its prefix and suffix.
+    pub full: String,
+    /// What the cite's names rendered as. `None` if it has none, or they were suppressed.
+    pub author: Option<String>,
+    /// The cite with its names left out, for the later cites in a group.
+    pub without_author: String,
+    /// As `without_author`, without the year suffix either. Two cites in a group with the same
+    /// value here differ only by their suffixes.
+    pub without_author_or_suffix: String,
+    pub year_suffix: Option<String>,
+    pub citation_number: Option<u32>,
+    /// A cite with a locator, prefix or suffix is never collapsed into a range.
+    pub has_extras: bool,
+}
+
+/// Joins the cites, which must already be sorted, into the text of the whole cluster.
+pub fn render_cluster(citation: &Citation, cites: &[CiteParts]) -> String {
+    let layout = &citation.layout;
+    let delimiter = delimiter_or(&layout.delimiter, "");
+    let after_collapse = delimiter_or(&citation.after_collapse_delimiter, delimiter);
+    let pieces: Vec<(String, bool)> = match citation.collapse {
+        Some(Collapse::CitationNumber) => collapse_numbers(cites),
+        collapse if collapse.is_some() || citation.cite_group_delimiter.is_some() => {
+            group_by_author(cites)
+                .iter()
+                .map(|group| render_group(citation, collapse, group))
+                .collect()
+        }
+        _ => cites.iter().map(|c| (c.full.clone(), false)).collect(),
+    };
+    let mut out = String::new();
+    if let Some(affixes) = &layout.affixes {
+        out.push_str(&affixes.prefix);
+    }
+    for (i, (text, _)) in pieces.iter().enumerate() {
+        if i > 0 {
+            out.push_str(if pieces[i - 1].1 {
+                after_collapse
+            } else {
+                delimiter
+            });
+        }
+        out.push_str(text);
+    }
+    if let Some(affixes) = &layout.affixes {
+        out.push_str(&affixes.suffix);
+    }
+    out
+}
+
+fn delimiter_or<'a>(delimiter: &'a Option<Delimiter>, default: &'a str) -> &'a str {
+    delimiter.as_ref().map_or(default, |d| d.0.as_str())
+}
+
+/// Runs of three or more consecutive citation numbers become ranges. The bool is whether the
+/// piece collapsed.
+fn collapse_numbers(cites: &[CiteParts]) -> Vec<(String, bool)> {
+    let mut pieces = Vec::new();
+    let mut i = 0;
+    while i < cites.len() {
+        let mut j = i;
+        while j + 1 < cites.len() && follows(&cites[j], &cites[j + 1]) {
+            j += 1;
+        }
+        if let (true, Some(first), Some(last)) =
+            (j - i >= 2, cites[i].citation_number, cites[j].citation_number)
+        {
+            // Only the numbers, whatever else the layout puts around them
+            pieces.push((format!("{}–{}", first, last), true));
+            i = j + 1;
+        } else {
+            pieces.push((cites[i].full.clone(), false));
+            i += 1;
+        }
+    }
+    pieces
+}
+
+fn follows(a: &CiteParts, b: &CiteParts) -> bool {
+    match (a.citation_number, b.citation_number) {
+        (Some(a_num), Some(b_num)) => b_num == a_num + 1 && !a.has_extras && !b.has_extras,
+        _ => false,
+    }
+}
+
+/// Cites by the same author are pulled together, at the position of the first of them.
+fn group_by_author(cites: &[CiteParts]) -> Vec<Vec<&CiteParts>> {
+    let mut groups: Vec<Vec<&CiteParts>> = Vec::new();
+    for cite in cites {
+        if cite.author.is_some() {
+            if let Some(group) = groups.iter_mut().find(|g| g[0].author == cite.author) {
+                group.push(cite);
+                continue;
+            }
+        }
+        groups.push(vec![cite]);
+    }
+    groups
+}
+
+fn render_group(
+    citation: &Citation,
+    collapse: Option<Collapse>,
+    group: &[&CiteParts],
+) -> (String, bool) {
+    if group.len() == 1 {
+        return (group[0].full.clone(), false);
+    }
+    let group_delimiter = delimiter_or(&citation.cite_group_delimiter, ", ");
+    let layout_delimiter = delimiter_or(&citation.layout.delimiter, "");
+    let suffix_delimiter = delimiter_or(&citation.year_suffix_delimiter, layout_delimiter);
+    let by_suffix = matches!(
+        collapse,
+        Some(Collapse::YearSuffix) | Some(Collapse::YearSuffixRanged)
+    );
+
+    // (text, whether it's only a year suffix following the previous cite's year)
+    let mut pieces: Vec<(String, bool)> = vec![(group[0].full.clone(), false)];
+    for pair in group.windows(2) {
+        let (prev, cite) = (pair[0], pair[1]);
+        let same_year = by_suffix
+            && prev.year_suffix.is_some()
+            && !prev.has_extras
+            && !cite.has_extras
+            && prev.without_author_or_suffix == cite.without_author_or_suffix;
+        let piece = match (&cite.year_suffix, collapse) {
+            (Some(suffix), _) if same_year => (suffix.clone(), true),
+            (_, None) => (cite.full.clone(), false),
+            _ => (cite.without_author.clone(), false),
+        };
+        pieces.push(piece);
+    }
+
+    let mut out = String::new();
+    let mut i = 0;
+    while i < pieces.len() {
+        if i > 0 {
+            out.push_str(if pieces[i].1 {
+                suffix_delimiter
+            } else {
+                group_delimiter
+            });
+        }
+        out.push_str(&pieces[i].0);
+        let mut j = i;
+        if collapse == Some(Collapse::YearSuffixRanged) {
+            while j + 1 < pieces.len() && pieces[j + 1].1 && consecutive(group[j], group[j + 1]) {
+                j += 1;
+            }
+        }
+        if j - i >= 2 {
+            out.push('–');
+            out.push_str(&pieces[j].0);
+            i = j + 1;
+        } else {
+            i += 1;
+        }
+    }
+    (out, collapse.is_some())
+}
+
+fn consecutive(a: &CiteParts, b: &CiteParts) -> bool {
+    let index = |c: &CiteParts| c.year_suffix.as_deref().and_then(suffix_index);
+    match (index(a), index(b)) {
+        (Some(a), Some(b)) => b == a + 1,
+        _ => false,
+    }
+}
+
+/// The inverse of `year_suffix`: "a" => 0, "aa" => 26.
+fn suffix_index(suffix: &str) -> Option<u32> {
+    let mut n = 0u32;
+    for c in suffix.chars() {
+        if !c.is_ascii_lowercase() {
+            return None;
+        }
+        n = n * 26 + (c as u32 - 'a' as u32 + 1);
+    }
+    n.checked_sub(1)
+}
+
+#[cfg(test)]
+fn numbered(n: u32) -> CiteParts {
+    CiteParts {
+        id: CiteId(n),
+        full: n.to_string(),
+        author: None,
+        without_author: n.to_string(),
+        without_author_or_suffix: n.to_string(),
+        year_suffix: None,
+        citation_number: Some(n),
+        has_extras: false,
+    }
+}
+
+#[cfg(test)]
+fn dated(id: u32, author: &str, year: &str, suffix: Option<&str>) -> CiteParts {
+    let suffix_str = suffix.unwrap_or("");
+    CiteParts {
+        id: CiteId(id),
+        full: format!("{} {}{}", author, year, suffix_str),
+        author: Some(author.into()),
+        without_author: format!("{}{}", year, suffix_str),
+        without_author_or_suffix: year.into(),
+        year_suffix: suffix.map(String::from),
+        citation_number: None,
+        has_extras: false,
+    }
+}
+
+#[cfg(test)]
+fn citation(collapse: Option<Collapse>, delimiter: &str) -> Citation {
+    use crate::element::{Affixes, Layout};
+    Citation {
+        layout: Layout {
+            elements: vec![],
+            delimiter: Some(Delimiter(delimiter.into())),
+            affixes: Some(Affixes {
+                prefix: "(".into(),
+                suffix: ")".into(),
+            }),
+            ..Default::default()
+        },
+        collapse,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn citation_number_ranges() {
+    let cites: Vec<_> = [1, 2, 3, 4, 7, 9, 10]
+        .iter()
+        .map(|&n| numbered(n))
+        .collect();
+    let c = citation(Some(Collapse::CitationNumber), ", ");
+    assert_eq!(render_cluster(&c, &cites), "(1–4, 7, 9, 10)");
+
+    let mut with_locator = cites.clone();
+    with_locator[1].has_extras = true;
+    assert_eq!(render_cluster(&c, &with_locator), "(1, 2, 3, 4, 7, 9, 10)");
+
+    let mut decorated = cites.clone();
+    for cite in &mut decorated {
+        cite.full = format!("ref. {}", cite.full);
+    }
+    assert_eq!(
+        render_cluster(&c, &decorated),
+        "(1–4, ref. 7, ref. 9, ref. 10)"
+    );
+}
+
+#[test]
+fn year_collapsing() {
+    let cites = vec![
+        dated(1, "Smith", "2001", Some("a")),
+        dated(2, "Doe", "1999", None),
+        dated(3, "Smith", "2001", Some("b")),
+        dated(4, "Smith", "2003", None),
+    ];
+    let c = citation(Some(Collapse::Year), "; ");
+    assert_eq!(
+        render_cluster(&c, &cites),
+        "(Smith 2001a, 2001b, 2003; Doe 1999)"
+    );
+    let c = Citation {
+        year_suffix_delimiter: Some(Delimiter(",".into())),
+        ..citation(Some(Collapse::YearSuffix), "; ")
+    };
+    assert_eq!(
+        render_cluster(&c, &cites),
+        "(Smith 2001a,b, 2003; Doe 1999)"
+    );
+}
+
+#[test]
+fn year_suffix_ranges() {
+    let cites = vec![
+        dated(1, "Smith", "2001", Some("a")),
+        dated(2, "Smith", "2001", Some("b")),
+        dated(3, "Smith", "2001", Some("c")),
+        dated(4, "Smith", "2001", Some("e")),
+        dated(5, "Doe", "1999", None),
+    ];
+    let c = Citation {
+        after_collapse_delimiter: Some(Delimiter("; ".into())),
+        ..citation(Some(Collapse::YearSuffixRanged), ", ")
+    };
+    assert_eq!(render_cluster(&c, &cites), "(Smith 2001a–c, e; Doe 1999)");
+    assert_eq!(suffix_index("aa"), Some(26));
+}
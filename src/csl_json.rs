@@ -0,0 +1,449 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Copyright © 2019 Corporation for Digital Scholarship
+
+//! References from CSL-JSON, and the clusters citing them from the command line's cites and
+//! edits files.
+//!
+//! The cites file is a list of clusters in document order:
+//!
+//! ```json
+//! [{"note": 1, "cites": [{"id": "smith", "locator": "12", "label": "page", "prefix": "see "}]}]
+//! ```
+//!
+//! Clusters may have an `id`, and otherwise count up from 1. `note` puts the cluster in a
+//! footnote. Cites need the `id` of a reference, and may have a `locator` (with a `label`, which
+//! is `page` if left out), a `prefix`, a `suffix` and `suppress-author`.
+//!
+//! The edits file, for `minimal replay`, is a list of changes to make one after another, each
+//! an object with a single key:
+//!
+//! ```json
+//! [{"insert-reference": {"id": "jones", "type": "book", "title": "Another Book"}},
+//!  {"set-cluster": {"id": 2, "cites": [{"id": "jones"}]}},
+//!  {"remove-reference": "smith"},
+//!  {"remove-cluster": 1}]
+//! ```
+//!
+//! A cluster set by an edit needs an `id`, so it's clear which one it replaces.
+
+use crate::cluster::{Cite, Cluster, ClusterId};
+use crate::element::{AnyVariable, LocatorType};
+use crate::json::Value;
+use crate::reference::{Date, PersonName, Reference};
+use crate::CiteId;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CslJsonError {
+    /// Which item or cluster, and which field, was wrong.
+    pub message: String,
+}
+
+impl fmt::Display for CslJsonError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+type Result<T> = std::result::Result<T, CslJsonError>;
+
+fn invalid(message: impl Into<String>) -> CslJsonError {
+    CslJsonError {
+        message: message.into(),
+    }
+}
+
+fn error<T>(message: impl Into<String>) -> Result<T> {
+    Err(invalid(message))
+}
+
+fn array<'a>(value: &'a Value, what: &str) -> Result<&'a [Value]> {
+    match value.as_array() {
+        Some(values) => Ok(values),
+        None => error(format!("{} should be an array", what)),
+    }
+}
+
+/// A CSL-JSON library: an array of items. Fields that aren't CSL variables are ignored, like
+/// citeproc-js does.
+pub fn references(json: &Value) -> Result<Vec<Reference>> {
+    array(json, "the references")?
+        .iter()
+        .enumerate()
+        .map(|(ix, item)| reference(item, ix))
+        .collect()
+}
+
+fn reference(item: &Value, ix: usize) -> Result<Reference> {
+    let members = match item {
+        Value::Object(members) => members,
+        _ => return error(format!("reference {} should be an object", ix + 1)),
+    };
+    let id = match item.get("id").and_then(Value::as_text) {
+        Some(id) => id,
+        None => return error(format!("reference {} has no `id`", ix + 1)),
+    };
+    let csl_type = match item.get("type").and_then(Value::as_str) {
+        Some(csl_type) => csl_type,
+        None => return error(format!("reference `{}` has no `type`", id)),
+    };
+    let mut reference = Reference::empty(id, csl_type);
+    for (key, value) in members {
+        let field = |what: &str| format!("`{}` in reference `{}` should be {}", key, id, what);
+        let var = match key.parse::<AnyVariable>() {
+            Ok(var) => var,
+            Err(_) => continue,
+        };
+        match var {
+            AnyVariable::Ordinary(v) => {
+                let text = value.as_text().ok_or_else(|| invalid(field("a string")))?;
+                reference.ordinary.insert(v, text.into());
+            }
+            AnyVariable::Number(v) => {
+                let text = value
+                    .as_text()
+                    .ok_or_else(|| invalid(field("a string or a number")))?;
+                reference.number.insert(v, text.into());
+            }
+            AnyVariable::Name(v) => {
+                let names = value
+                    .as_array()
+                    .and_then(|names| names.iter().map(person_name).collect())
+                    .ok_or_else(|| invalid(field("an array of names")))?;
+                reference.name.insert(v, names);
+            }
+            // A date this processor can't read, like a season, is left out
+            AnyVariable::Date(v) => {
+                if let Some(date) = date(value).ok_or_else(|| invalid(field("a date")))? {
+                    reference.date.insert(v, date);
+                }
+            }
+        }
+    }
+    Ok(reference)
+}
+
+/// `None` if it isn't an object, or one of its parts isn't a string.
+fn person_name(value: &Value) -> Option<PersonName> {
+    if !matches!(value, Value::Object(_)) {
+        return None;
+    }
+    let part = |key: &str| match value.get(key) {
+        None | Some(Value::Null) => Some(None),
+        Some(part) => part.as_text().map(|s| Some(s.to_string())),
+    };
+    Some(PersonName {
+        family: part("family")?,
+        given: part("given")?,
+        non_dropping_particle: part("non-dropping-particle")?,
+        dropping_particle: part("dropping-particle")?,
+        suffix: part("suffix")?,
+        literal: part("literal")?,
+    })
+}
+
+/// The start of a date's `date-parts`, or failing that a `raw` date written `YYYY-MM-DD`, with
+/// the month and day optional. `None` if it isn't a date object at all, and `Some(None)` if it's
+/// one this processor can't read.
+fn date(value: &Value) -> Option<Option<Date>> {
+    if !matches!(value, Value::Object(_)) {
+        return None;
+    }
+    let parts: Vec<&str> = match (value.get("date-parts"), value.get("raw")) {
+        (Some(date_parts), _) => date_parts
+            .as_array()?
+            .first()
+            .and_then(Value::as_array)
+            .unwrap_or(&[])
+            .iter()
+            .map(Value::as_text)
+            .collect::<Option<_>>()?,
+        (None, Some(raw)) => raw.as_str()?.trim().split('-').collect(),
+        (None, None) => return Some(None),
+    };
+    let year = match parts.first().and_then(|y| y.trim().parse().ok()) {
+        Some(year) => year,
+        None => return Some(None),
+    };
+    let part = |ix: usize| -> Option<u32> {
+        match parts.get(ix) {
+            None => Some(0),
+            Some(p) => p.trim().parse().ok(),
+        }
+    };
+    Some(match (part(1), part(2)) {
+        (Some(month), Some(day)) if parts.len() <= 3 => Some(Date::new(year, month, day)),
+        _ => None,
+    })
+}
+
+/// The cites file, as described at the top of this module. Cite ids count up through the whole
+/// document.
+pub fn clusters(json: &Value) -> Result<Vec<Cluster>> {
+    let mut next_cite = 0;
+    let mut clusters = Vec::new();
+    for (ix, value) in array(json, "the cites")?.iter().enumerate() {
+        let name = format!("cluster {}", ix + 1);
+        let cluster = cluster(value, &name, Some(ClusterId(ix as u32 + 1)), &mut next_cite)?;
+        if clusters.iter().any(|c: &Cluster| c.id == cluster.id) {
+            return error(format!("{} has the same id as an earlier cluster", name));
+        }
+        clusters.push(cluster);
+    }
+    Ok(clusters)
+}
+
+/// `default_id` is the cluster's id if it doesn't give one; without it, the id is required.
+/// Each cite takes the id after `next_cite`.
+fn cluster(
+    value: &Value,
+    name: &str,
+    default_id: Option<ClusterId>,
+    next_cite: &mut u32,
+) -> Result<Cluster> {
+    if !matches!(value, Value::Object(_)) {
+        return error(format!("{} should be an object", name));
+    }
+    let number = |key: &str| -> Result<Option<u32>> {
+        match value.get(key) {
+            None => Ok(None),
+            Some(n) => match n.as_text().and_then(|n| n.parse().ok()) {
+                Some(n) => Ok(Some(n)),
+                None => error(format!("`{}` in {} should be a whole number", key, name)),
+            },
+        }
+    };
+    let id = match (number("id")?, default_id) {
+        (Some(id), _) => ClusterId(id),
+        (None, Some(id)) => id,
+        (None, None) => return error(format!("{} has no `id`", name)),
+    };
+    let note = number("note")?;
+    let mut cites = Vec::new();
+    let cite_values = match value.get("cites") {
+        Some(cites) => array(cites, &format!("`cites` in {}", name))?,
+        None => return error(format!("{} has no `cites`", name)),
+    };
+    for cite_value in cite_values {
+        *next_cite += 1;
+        cites.push(cite(cite_value, CiteId(*next_cite), name)?);
+    }
+    Ok(match note {
+        Some(note) => Cluster::in_note(id, note, cites),
+        None => Cluster::new(id, cites),
+    })
+}
+
+/// One change from the edits file.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Edit {
+    /// Adds or replaces a reference.
+    InsertReference(Reference),
+    RemoveReference(String),
+    /// Adds or replaces a cluster.
+    SetCluster(Cluster),
+    RemoveCluster(ClusterId),
+}
+
+/// The edits file, as described at the top of this module. Cite ids carry on counting from
+/// `cites_before`, the number of cites in the cites file, so they never clash with its cites.
+pub fn edits(json: &Value, cites_before: u32) -> Result<Vec<Edit>> {
+    let mut next_cite = cites_before;
+    let mut edits = Vec::new();
+    for (ix, value) in array(json, "the edits")?.iter().enumerate() {
+        let name = format!("edit {}", ix + 1);
+        let (key, value) = match value {
+            Value::Object(members) if members.len() == 1 => (&members[0].0, &members[0].1),
+            _ => return error(format!("{} should be an object with one key", name)),
+        };
+        edits.push(match key.as_str() {
+            "insert-reference" => Edit::InsertReference(reference(value, ix)?),
+            "remove-reference" => match value.as_text() {
+                Some(id) => Edit::RemoveReference(id.into()),
+                None => return error(format!("`remove-reference` in {} should be an id", name)),
+            },
+            "set-cluster" => Edit::SetCluster(cluster(value, &name, None, &mut next_cite)?),
+            "remove-cluster" => match value.as_text().and_then(|id| id.parse().ok()) {
+                Some(id) => Edit::RemoveCluster(ClusterId(id)),
+                None => {
+                    return error(format!("`remove-cluster` in {} should be a whole number", name))
+                }
+            },
+            other => return error(format!("unknown edit `{}` in {}", other, name)),
+        });
+    }
+    Ok(edits)
+}
+
+fn cite(value: &Value, id: CiteId, cluster: &str) -> Result<Cite> {
+    let text = |key: &str| -> Result<Option<String>> {
+        match value.get(key) {
+            None | Some(Value::Null) => Ok(None),
+            Some(v) => match v.as_text() {
+                Some(s) => Ok(Some(s.to_string())),
+                None => error(format!(
+                    "`{}` of a cite in {} should be a string",
+                    key, cluster
+                )),
+            },
+        }
+    };
+    let ref_id = match text("id")? {
+        Some(ref_id) => ref_id,
+        None => return error(format!("a cite in {} has no `id`", cluster)),
+    };
+    let mut cite = Cite::basic(id, ref_id);
+    cite.prefix = text("prefix")?;
+    cite.suffix = text("suffix")?;
+    if let Some(locator) = text("locator")? {
+        let label = text("label")?.unwrap_or_else(|| "page".into());
+        match label.parse::<LocatorType>() {
+            Ok(locator_type) => cite = cite.with_locator(locator_type, locator),
+            Err(_) => return error(format!("unknown locator label `{}` in {}", label, cluster)),
+        }
+    }
+    cite.suppress_author = match value.get("suppress-author") {
+        None | Some(Value::Null) => false,
+        Some(Value::Bool(b)) => *b,
+        Some(_) => {
+            return error(format!(
+                "`suppress-author` of a cite in {} should be true or false",
+                cluster
+            ))
+        }
+    };
+    Ok(cite)
+}
+
+#[test]
+fn reads_references() {
+    use crate::element::{DateVariable, NameVariable, NumberVariable, Variable};
+    let json = crate::json::parse(
+        r#"[{
+          "id": "smith", "type": "book", "title": "A Book", "volume": 3,
+          "author": [{"family": "Smith", "given": "John", "non-dropping-particle": "de"},
+                     {"literal": "ACME Corp."}],
+          "issued": {"date-parts": [["2001", 4]]},
+          "accessed": {"raw": "2019-02-03"},
+          "event-date": {"season": 2},
+          "custom": {"anything": "goes"}
+        }, {"id": 2, "type": "article"}]"#,
+    )
+    .unwrap();
+    let refs = references(&json).unwrap();
+    let smith = &refs[0];
+    assert_eq!(smith.csl_type, "book");
+    assert_eq!(smith.ordinary[&Variable::Title], "A Book");
+    assert_eq!(smith.number[&NumberVariable::Volume], "3");
+    assert_eq!(
+        smith.name[&NameVariable::Author],
+        vec![
+            PersonName {
+                non_dropping_particle: Some("de".into()),
+                ..PersonName::new("John", "Smith")
+            },
+            PersonName::literal("ACME Corp."),
+        ]
+    );
+    assert_eq!(smith.date[&DateVariable::Issued], Date::new(2001, 4, 0));
+    assert_eq!(smith.date[&DateVariable::Accessed], Date::new(2019, 2, 3));
+    assert!(!smith.date.contains_key(&DateVariable::EventDate));
+    assert_eq!(refs[1].id, "2");
+
+    let bad = |json: &str| {
+        references(&crate::json::parse(json).unwrap())
+            .unwrap_err()
+            .to_string()
+    };
+    assert_eq!(bad(r#"[{"type": "book"}]"#), "reference 1 has no `id`");
+    assert_eq!(bad(r#"[{"id": "a"}]"#), "reference `a` has no `type`");
+    assert_eq!(
+        bad(r#"[{"id": "a", "type": "book", "author": "Smith"}]"#),
+        "`author` in reference `a` should be an array of names"
+    );
+}
+
+#[test]
+fn reads_cites() {
+    let json = crate::json::parse(
+        r#"[{"note": 2, "cites": [{"id": "a", "locator": "12", "prefix": "see "},
+                                 {"id": "b", "locator": "3", "label": "chapter"}]},
+            {"id": 7, "cites": [{"id": "a", "suppress-author": true}]}]"#,
+    )
+    .unwrap();
+    assert_eq!(
+        clusters(&json).unwrap(),
+        vec![
+            Cluster::in_note(
+                ClusterId(1),
+                2,
+                vec![
+                    Cite {
+                        prefix: Some("see ".into()),
+                        ..Cite::basic(CiteId(1), "a").with_locator(LocatorType::Page, "12")
+                    },
+                    Cite::basic(CiteId(2), "b").with_locator(LocatorType::Chapter, "3"),
+                ]
+            ),
+            Cluster::new(
+                ClusterId(7),
+                vec![Cite {
+                    suppress_author: true,
+                    ..Cite::basic(CiteId(3), "a")
+                }]
+            ),
+        ]
+    );
+    let bad = crate::json::parse(r#"[{"cites": [{"id": "a", "locator": "1", "label": "x"}]}]"#);
+    assert_eq!(
+        clusters(&bad.unwrap()).unwrap_err().to_string(),
+        "unknown locator label `x` in cluster 1"
+    );
+    let bad = crate::json::parse(r#"[{"cites": []}, {"id": 1, "cites": []}]"#);
+    assert_eq!(
+        clusters(&bad.unwrap()).unwrap_err().to_string(),
+        "cluster 2 has the same id as an earlier cluster"
+    );
+}
+
+#[test]
+fn reads_edits() {
+    let json = crate::json::parse(
+        r#"[{"insert-reference": {"id": "c", "type": "book", "title": "C"}},
+            {"set-cluster": {"id": 2, "note": 3, "cites": [{"id": "c"}, {"id": "a"}]}},
+            {"remove-reference": "b"},
+            {"remove-cluster": 1}]"#,
+    )
+    .unwrap();
+    let edits = edits(&json, 4).unwrap();
+    match &edits[0] {
+        Edit::InsertReference(reference) => assert_eq!(reference.id, "c"),
+        other => panic!("expected a reference, got {:?}", other),
+    }
+    assert_eq!(
+        edits[1..],
+        [
+            Edit::SetCluster(Cluster::in_note(
+                ClusterId(2),
+                3,
+                vec![Cite::basic(CiteId(5), "c"), Cite::basic(CiteId(6), "a")]
+            )),
+            Edit::RemoveReference("b".into()),
+            Edit::RemoveCluster(ClusterId(1)),
+        ]
+    );
+    let bad = |json: &str| {
+        crate::csl_json::edits(&crate::json::parse(json).unwrap(), 0)
+            .unwrap_err()
+            .to_string()
+    };
+    assert_eq!(bad(r#"[{"set-cluster": {"cites": []}}]"#), "edit 1 has no `id`");
+    assert_eq!(
+        bad(r#"[{"remove-cluster": 1, "remove-reference": "a"}]"#),
+        "edit 1 should be an object with one key"
+    );
+    assert_eq!(bad(r#"[{"rename": "a"}]"#), "unknown edit `rename` in edit 1");
+}
@@ -0,0 +1,157 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Copyright © 2019 Corporation for Digital Scholarship
+
+//! Dates, for `<date>`.
+//!
+//! [Spec](https://docs.citationstyles.org/en/stable/specification.html#date): a date either
+//! lists its own `<date-part>`s, or takes them from the locale's `<date>` of the form it asks
+//! for. A localized date only gets to change the form of the locale's parts, and to leave some
+//! of them out with `date-parts`.
+
+use crate::element::{DateElement, DatePart, DatePartForm, DatePartName, Delimiter, MiscTerm};
+use crate::locale::Locale;
+use crate::reference::Date;
+
+/// The parts `el` renders, in order, and the delimiter between them. A locale with no `<date>`
+/// of the form asked for gets en-US's.
+pub fn layout(el: &DateElement, locale: &Locale) -> (Vec<DatePart>, Option<Delimiter>) {
+    let form = match el.form {
+        Some(form) => form,
+        None => return (el.parts.clone(), el.delimiter.clone()),
+    };
+    let format = match locale.dates.get(&form) {
+        Some(format) => format.clone(),
+        None => Locale::en_us().dates.remove(&form).expect("en-US has both forms"),
+    };
+    let parts = format
+        .parts
+        .into_iter()
+        .filter(|part| el.date_parts.shows(part.name))
+        .map(|part| match el.parts.iter().find(|own| own.name == part.name) {
+            Some(own) => DatePart {
+                affixes: part.affixes,
+                ..own.clone()
+            },
+            None => part,
+        })
+        .collect();
+    (parts, format.delimiter)
+}
+
+/// One part of `date`, without its affixes. `None` if the date doesn't go down to that part.
+/// A month the locale has no name for is written as a number.
+pub fn render_part(part: &DatePart, date: &Date, locale: &Locale) -> Option<String> {
+    let text = match part.name {
+        DatePartName::Year => match part.form {
+            Some(DatePartForm::Short) => format!("{:02}", date.year.rem_euclid(100)),
+            _ => long_year(date.year, locale),
+        },
+        DatePartName::Month if date.month == 0 => return None,
+        DatePartName::Month => {
+            let month = date.month;
+            match part.form {
+                Some(DatePartForm::Numeric) => month.to_string(),
+                Some(DatePartForm::NumericLeadingZeros) => format!("{:02}", month),
+                Some(DatePartForm::Short) => locale
+                    .month(month, true)
+                    .or_else(|| locale.month(month, false))
+                    .map_or_else(|| month.to_string(), String::from),
+                _ => locale
+                    .month(month, false)
+                    .map_or_else(|| month.to_string(), String::from),
+            }
+        }
+        DatePartName::Day if date.day == 0 => return None,
+        DatePartName::Day => match part.form {
+            Some(DatePartForm::NumericLeadingZeros) => format!("{:02}", date.day),
+            Some(DatePartForm::Ordinal) => {
+                format!("{}{}", date.day, locale.ordinal_suffix(date.day))
+            }
+            _ => date.day.to_string(),
+        },
+    };
+    Some(if part.strip_periods {
+        text.replace('.', "")
+    } else {
+        text
+    })
+}
+
+/// `2001`, with `BC` after years before the common era and `AD` after the first thousand
+/// years of it.
+fn long_year(year: i32, locale: &Locale) -> String {
+    if year < 0 {
+        format!("{}{}", -year, locale.misc_term(MiscTerm::Bc).unwrap_or("BC"))
+    } else if year > 0 && year < 1000 {
+        format!("{}{}", year, locale.misc_term(MiscTerm::Ad).unwrap_or("AD"))
+    } else {
+        year.to_string()
+    }
+}
+
+#[cfg(test)]
+fn date_element(src: &str) -> DateElement {
+    use crate::element::{Element, Style};
+    let style: Style = format!(
+        "<style class=\"in-text\"><citation><layout>{}</layout></citation></style>",
+        src
+    )
+    .parse()
+    .unwrap();
+    match &style.citation.layout.elements[0] {
+        Element::Date(date) => (**date).clone(),
+        other => panic!("expected a date, got {:?}", other),
+    }
+}
+
+#[cfg(test)]
+fn render(src: &str, date: Date, locale: &Locale) -> String {
+    let el = date_element(src);
+    let (parts, delimiter) = layout(&el, locale);
+    let rendered: Vec<String> = parts
+        .iter()
+        .filter_map(|part| {
+            let text = render_part(part, &date, locale)?;
+            Some(match &part.affixes {
+                Some(affixes) => format!("{}{}{}", affixes.prefix, text, affixes.suffix),
+                None => text,
+            })
+        })
+        .collect();
+    rendered.join(delimiter.as_ref().map_or("", |d| d.0.as_str()))
+}
+
+#[test]
+fn renders_own_parts() {
+    let locale = Locale::en_us();
+    let src = r#"<date variable="issued" delimiter=" ">
+      <date-part name="day" form="ordinal"/>
+      <date-part name="month" form="short" strip-periods="true"/>
+      <date-part name="year" form="short" prefix="'"/>
+    </date>"#;
+    assert_eq!(render(src, Date::new(2001, 4, 2), &locale), "2nd Apr '01");
+    assert_eq!(render(src, Date::year(2001), &locale), "'01");
+    let src = r#"<date variable="issued"><date-part name="year"/></date>"#;
+    assert_eq!(render(src, Date::year(-50), &locale), "50BC");
+    assert_eq!(render(src, Date::year(476), &locale), "476AD");
+}
+
+#[test]
+fn renders_localized_dates() {
+    let locale = Locale::en_us();
+    let text = r#"<date variable="issued" form="text"/>"#;
+    assert_eq!(render(text, Date::new(2001, 4, 1), &locale), "April 1, 2001");
+    let year_month = r#"<date variable="issued" form="text" date-parts="year-month">
+      <date-part name="month" form="short"/>
+    </date>"#;
+    assert_eq!(render(year_month, Date::new(2001, 4, 1), &locale), "Apr. 2001");
+    let numeric = r#"<date variable="issued" form="numeric"/>"#;
+    assert_eq!(render(numeric, Date::new(2001, 4, 1), &locale), "04/01/2001");
+
+    // A locale without dates or months falls back to en-US's layout and numbers
+    let bare = Locale::new("xx");
+    assert_eq!(render(text, Date::new(2001, 4, 1), &bare), "4 1, 2001");
+}
@@ -0,0 +1,1093 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Copyright © 2019 Corporation for Digital Scholarship
+
+//! A memoising query database, in the style of salsa.
+//!
+//! Inputs (the style, each reference, each cluster) are set from outside and stamped with the
+//! revision they last changed in. Derived queries remember the inputs and other queries they read
+//! while executing. When asked again in a later revision, a derived query first checks whether
+//! any of those dependencies changed since it was last verified, and only re-executes if so. If
+//! re-executing produces a value equal to the old one, it keeps its old `changed_at`, so the
+//! queries depending on *it* don't have to re-execute either. Editing a reference's abstract
+//! therefore re-renders that reference, but no clusters.
+
+use crate::bibliography::{self, BibEntry, BibliographyMeta};
+use crate::cluster::{cite_positions, Cite, Cluster, ClusterId, NEAR_NOTE_DISTANCE};
+use crate::collapse::{self, CiteParts};
+use crate::disamb::names::{global_name_expansions, GivenLevel};
+use crate::disamb::pipeline::{self, CiteInput, DfaKey, DisambResult, NamedCite, RenderOptions};
+use crate::disamb::{self, Dfa};
+use crate::disamb::{IrTracer, MacroFragment, NoTrace};
+use crate::element::{Bibliography, MacroId, Position, Style};
+use crate::jurisdiction::JurisdictionModule;
+use crate::locale::{primary_language, Locale, DEFAULT_LANG};
+use crate::parse::StyleError;
+use crate::reference::{PersonName, Reference};
+use crate::sort;
+use crate::{CiteId, IrDatabase, RefIR};
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+pub type Revision = u64;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum QueryKey {
+    // Inputs
+    Style,
+    ReferenceIds,
+    Reference(String),
+    ClusterIds,
+    Cluster(ClusterId),
+    Locale(String),
+    Hereinafter(String),
+    JurisdictionModule(String),
+
+    // Derived
+    MacroFragment(String, MacroId),
+    RefIr(String),
+    RefDfa(String, DfaKey),
+    Positions,
+    CitePosition(CiteId),
+    SortedReferences,
+    CitationNumber(String),
+    GlobalNames,
+    NameDisambiguation(ClusterId),
+    YearSuffixes,
+    YearSuffix(String),
+    Bibliography,
+    ClusterIr(ClusterId),
+    ClusterOutput(ClusterId),
+}
+
+impl QueryKey {
+    fn is_input(&self) -> bool {
+        matches!(
+            self,
+            QueryKey::Style
+                | QueryKey::ReferenceIds
+                | QueryKey::Reference(_)
+                | QueryKey::ClusterIds
+                | QueryKey::Cluster(_)
+                | QueryKey::Locale(_)
+                | QueryKey::Hereinafter(_)
+                | QueryKey::JurisdictionModule(_)
+        )
+    }
+}
+
+type AnyValue = Arc<dyn Any + Send + Sync>;
+type EqFn = fn(&dyn Any, &dyn Any) -> bool;
+
+fn eq_as<T: Any + PartialEq>(a: &dyn Any, b: &dyn Any) -> bool {
+    match (a.downcast_ref::<T>(), b.downcast_ref::<T>()) {
+        (Some(a), Some(b)) => a == b,
+        _ => false,
+    }
+}
+
+fn erase<T: Any + Send + Sync + PartialEq>(value: T) -> (AnyValue, EqFn) {
+    (Arc::new(value), eq_as::<T>)
+}
+
+struct Slot {
+    value: AnyValue,
+    eq: EqFn,
+    /// The last revision in which the value actually changed
+    changed_at: Revision,
+    /// The last revision in which the value was checked against its dependencies
+    verified_at: Revision,
+    /// Empty for inputs
+    deps: Vec<QueryKey>,
+}
+
+pub struct Database {
+    revision: Revision,
+    slots: RefCell<HashMap<QueryKey, Slot>>,
+    /// One frame per derived query currently executing, collecting what it reads.
+    active: RefCell<Vec<Vec<QueryKey>>>,
+    executions: RefCell<HashMap<QueryKey, u32>>,
+    tracer: Arc<dyn IrTracer>,
+}
+
+impl IrDatabase for Database {
+    fn style(&self) -> Arc<Style> {
+        self.query(QueryKey::Style)
+    }
+
+    fn reference(&self, id: &str) -> Option<Arc<Reference>> {
+        (*self.query::<Option<Arc<Reference>>>(QueryKey::Reference(id.into()))).clone()
+    }
+
+    fn locale(&self, lang: &str) -> Arc<Locale> {
+        let candidates = Some(lang)
+            .into_iter()
+            .chain(primary_language(lang))
+            .chain(Some(DEFAULT_LANG));
+        for candidate in candidates {
+            let set = self.query::<Option<Arc<Locale>>>(QueryKey::Locale(candidate.into()));
+            if let Some(locale) = &*set {
+                return locale.clone();
+            }
+        }
+        Arc::new(Locale::en_us())
+    }
+
+    fn cluster(&self, id: ClusterId) -> Arc<Cluster> {
+        self.query(QueryKey::Cluster(id))
+    }
+
+    fn cite_position(&self, id: CiteId) -> Position {
+        *self.query(QueryKey::CitePosition(id))
+    }
+
+    fn citation_number(&self, ref_id: &str) -> Option<u32> {
+        *self.query::<Option<u32>>(QueryKey::CitationNumber(ref_id.into()))
+    }
+
+    fn reference_ids(&self) -> Arc<Vec<String>> {
+        self.query(QueryKey::ReferenceIds)
+    }
+
+    fn ref_dfa(&self, id: &str, key: DfaKey) -> Option<Result<Arc<Dfa>, StyleError>> {
+        let key = QueryKey::RefDfa(id.into(), key);
+        (*self.query::<Option<Result<Arc<Dfa>, StyleError>>>(key)).clone()
+    }
+
+    fn global_names(&self) -> Arc<HashMap<PersonName, GivenLevel>> {
+        self.query(QueryKey::GlobalNames)
+    }
+
+    fn year_suffix(&self, ref_id: &str) -> Option<String> {
+        (*self.query::<Option<String>>(QueryKey::YearSuffix(ref_id.into()))).clone()
+    }
+
+    fn hereinafter(&self, ref_id: &str) -> Option<Arc<String>> {
+        (*self.query::<Option<Arc<String>>>(QueryKey::Hereinafter(ref_id.into()))).clone()
+    }
+
+    fn jurisdiction_module(&self, jurisdiction: &str) -> Option<Arc<JurisdictionModule>> {
+        let key = QueryKey::JurisdictionModule(jurisdiction.into());
+        (*self.query::<Option<Arc<JurisdictionModule>>>(key)).clone()
+    }
+
+    fn macro_fragment(
+        &self,
+        ref_id: &str,
+        id: MacroId,
+    ) -> Result<Option<Arc<MacroFragment>>, StyleError> {
+        let key = QueryKey::MacroFragment(ref_id.into(), id);
+        (*self.query::<Result<Option<Arc<MacroFragment>>, StyleError>>(key)).clone()
+    }
+
+    fn tracer(&self) -> &dyn IrTracer {
+        &*self.tracer
+    }
+}
+
+impl Database {
+    pub fn new(style: Style) -> Self {
+        let mut db = Database {
+            revision: 0,
+            slots: Default::default(),
+            active: Default::default(),
+            executions: Default::default(),
+            tracer: Arc::new(NoTrace),
+        };
+        db.set_style(style);
+        db
+    }
+
+    /// Reports the IR builder's steps to `tracer`. Only queries that run from now on are traced;
+    /// IR the database already has is reused without being built again.
+    pub fn set_tracer(&mut self, tracer: Arc<dyn IrTracer>) {
+        self.tracer = tracer;
+    }
+
+    // Inputs
+
+    /// Compiles the style's macros first, in case it was built in code rather than parsed.
+    pub fn set_style(&mut self, mut style: Style) {
+        style.compile_macros();
+        self.set_input(QueryKey::Style, style);
+    }
+
+    /// Replaces the whole library. The order given is the bibliography order, until the style
+    /// says otherwise.
+    pub fn set_references(&mut self, references: Vec<Reference>) {
+        let ids: Vec<String> = references.iter().map(|r| r.id.clone()).collect();
+        for old in self.reference_ids().iter() {
+            if !ids.contains(old) {
+                self.set_input(QueryKey::Reference(old.clone()), None::<Arc<Reference>>);
+            }
+        }
+        for reference in references {
+            self.set_input(
+                QueryKey::Reference(reference.id.clone()),
+                Some(Arc::new(reference)),
+            );
+        }
+        self.set_input(QueryKey::ReferenceIds, ids);
+    }
+
+    /// Adds or replaces a single reference.
+    pub fn insert_reference(&mut self, reference: Reference) {
+        let mut ids = (*self.reference_ids()).clone();
+        if !ids.contains(&reference.id) {
+            ids.push(reference.id.clone());
+            self.set_input(QueryKey::ReferenceIds, ids);
+        }
+        self.set_input(
+            QueryKey::Reference(reference.id.clone()),
+            Some(Arc::new(reference)),
+        );
+    }
+
+    pub fn remove_reference(&mut self, id: &str) {
+        let mut ids = (*self.reference_ids()).clone();
+        ids.retain(|x| x != id);
+        self.set_input(QueryKey::ReferenceIds, ids);
+        self.set_input(QueryKey::Reference(id.into()), None::<Arc<Reference>>);
+    }
+
+    /// Adds or replaces a cluster. New clusters go at the end of the document.
+    pub fn set_cluster(&mut self, cluster: Cluster) {
+        let mut ids = (*self.cluster_ids()).clone();
+        if !ids.contains(&cluster.id) {
+            ids.push(cluster.id);
+            self.set_input(QueryKey::ClusterIds, ids);
+        }
+        self.set_input(QueryKey::Cluster(cluster.id), cluster);
+    }
+
+    pub fn set_cluster_order(&mut self, ids: Vec<ClusterId>) {
+        self.set_input(QueryKey::ClusterIds, ids);
+    }
+
+    /// Adds or replaces the locale for `locale.lang`.
+    pub fn set_locale(&mut self, locale: Locale) {
+        self.set_input(
+            QueryKey::Locale(locale.lang.clone()),
+            Some(Arc::new(locale)),
+        );
+    }
+
+    /// Sets or clears the short form the document uses for a reference after citing it, which
+    /// CSL-M styles render through the `hereinafter` variable.
+    pub fn set_hereinafter(&mut self, ref_id: impl Into<String>, value: Option<String>) {
+        self.set_input(QueryKey::Hereinafter(ref_id.into()), value.map(Arc::new));
+    }
+
+    /// Loads or unloads the CSL-M module for a jurisdiction, like `us:ca`.
+    pub fn set_jurisdiction_module(
+        &mut self,
+        jurisdiction: impl Into<String>,
+        module: Option<JurisdictionModule>,
+    ) {
+        self.set_input(
+            QueryKey::JurisdictionModule(jurisdiction.into()),
+            module.map(Arc::new),
+        );
+    }
+
+    pub fn remove_cluster(&mut self, id: ClusterId) {
+        let mut ids = (*self.cluster_ids()).clone();
+        ids.retain(|&x| x != id);
+        self.set_input(QueryKey::ClusterIds, ids);
+        self.set_input(QueryKey::Cluster(id), Cluster::new(id, Vec::new()));
+    }
+
+    // Queries
+
+    pub fn cluster_ids(&self) -> Arc<Vec<ClusterId>> {
+        self.query(QueryKey::ClusterIds)
+    }
+
+    // Each of these fails if the style can't render something it needs: a macro that is
+    // undefined, which only a style built in code can have, or one that calls itself.
+
+    /// The IR for a first cite of the reference, before disambiguation.
+    pub fn ref_ir(&self, id: &str) -> Result<Arc<RefIR>, StyleError> {
+        (*self.query::<Result<Arc<RefIR>, StyleError>>(QueryKey::RefIr(id.into()))).clone()
+    }
+
+    /// Every reference id in bibliography order: sorted by the bibliography's `<sort>`, and
+    /// otherwise in the order they are first cited, with uncited references last.
+    pub fn sorted_reference_ids(&self) -> Result<Arc<Vec<String>>, StyleError> {
+        (*self.query::<Result<Arc<Vec<String>>, StyleError>>(QueryKey::SortedReferences)).clone()
+    }
+
+    /// An entry for every reference in the library, in bibliography order. Empty if the style has
+    /// no `<bibliography>`.
+    pub fn render_bibliography(&self) -> Result<Arc<Vec<BibEntry>>, StyleError> {
+        (*self.query::<Result<Arc<Vec<BibEntry>>, StyleError>>(QueryKey::Bibliography)).clone()
+    }
+
+    /// How to lay out `render_bibliography`'s entries: the defaults if the style has no
+    /// `<bibliography>`.
+    pub fn bibliography_meta(&self) -> BibliographyMeta {
+        let default = Bibliography::default();
+        BibliographyMeta::new(self.style().bibliography.as_ref().unwrap_or(&default))
+    }
+
+    /// How far names and `disambiguate="true"` got with each cite of one cluster, or why it
+    /// couldn't be rendered. Cites of missing references are left out.
+    pub fn name_disambiguation(&self, id: ClusterId) -> Arc<Vec<Result<NamedCite, StyleError>>> {
+        self.query(QueryKey::NameDisambiguation(id))
+    }
+
+    /// The year suffix of every reference that got one.
+    pub fn year_suffixes(&self) -> Arc<HashMap<String, String>> {
+        self.query(QueryKey::YearSuffixes)
+    }
+
+    /// The disambiguated cites of one cluster, in order. Cites of missing references are left out.
+    pub fn cluster_ir(&self, id: ClusterId) -> Result<Arc<Vec<DisambResult>>, StyleError> {
+        let key = QueryKey::ClusterIr(id);
+        (*self.query::<Result<Arc<Vec<DisambResult>>, StyleError>>(key)).clone()
+    }
+
+    /// The text of a whole cluster, with its cites sorted, grouped and collapsed.
+    pub fn render_cluster(&self, id: ClusterId) -> Result<Arc<String>, StyleError> {
+        let key = QueryKey::ClusterOutput(id);
+        (*self.query::<Result<Arc<String>, StyleError>>(key)).clone()
+    }
+
+    /// How many times a derived query has executed, rather than being reused.
+    pub fn execution_count(&self, key: &QueryKey) -> u32 {
+        self.executions.borrow().get(key).cloned().unwrap_or(0)
+    }
+
+    fn execute(&self, key: &QueryKey) -> (AnyValue, EqFn) {
+        match key {
+            QueryKey::MacroFragment(ref_id, id) => {
+                let style = self.style();
+                erase(match self.reference(ref_id) {
+                    Some(reference) => disamb::macro_fragment(self, &style, &reference, *id)
+                        .map(|fragment| fragment.map(Arc::new)),
+                    None => Ok(None),
+                })
+            }
+            QueryKey::RefIr(id) => erase(match self.reference(id) {
+                Some(reference) => pipeline::base_ref_ir(self, &reference).map(Arc::new),
+                None => Ok(Arc::new(RefIR::default())),
+            }),
+            QueryKey::RefDfa(id, key) => erase(
+                self.reference(id)
+                    .map(|reference| pipeline::ref_dfa(self, &reference, key).map(Arc::new)),
+            ),
+            QueryKey::Positions => erase(self.compute_positions()),
+            QueryKey::CitePosition(id) => {
+                let positions = self.query::<HashMap<CiteId, Position>>(QueryKey::Positions);
+                erase(positions.get(id).cloned().unwrap_or(Position::First))
+            }
+            QueryKey::SortedReferences => erase(self.compute_sorted_references().map(Arc::new)),
+            QueryKey::CitationNumber(ref_id) => erase(
+                self.sorted_reference_ids()
+                    .ok()
+                    .and_then(|order| order.iter().position(|id| id == ref_id))
+                    .map(|ix| ix as u32 + 1),
+            ),
+            QueryKey::GlobalNames => {
+                let style = self.style();
+                erase(match pipeline::global_rule(&style) {
+                    Some(rule) => {
+                        let references: Vec<Reference> = self
+                            .reference_ids()
+                            .iter()
+                            .filter_map(|id| self.reference(id))
+                            .map(|r| (*r).clone())
+                            .collect();
+                        global_name_expansions(rule, &references)
+                    }
+                    None => HashMap::new(),
+                })
+            }
+            QueryKey::NameDisambiguation(id) => {
+                let cluster = self.cluster(*id);
+                let cited: Vec<(&Cite, Arc<Reference>)> = cluster
+                    .cites
+                    .iter()
+                    .filter_map(|cite| Some((cite, self.reference(&cite.ref_id)?)))
+                    .collect();
+                let cites: Vec<CiteInput> = cited
+                    .iter()
+                    .map(|(cite, reference)| self.cite_input(cite, reference))
+                    .collect();
+                erase(pipeline::disambiguate_names(self, &cites))
+            }
+            QueryKey::YearSuffixes => erase(self.compute_year_suffixes()),
+            QueryKey::YearSuffix(ref_id) => erase(self.year_suffixes().get(ref_id).cloned()),
+            QueryKey::Bibliography => erase(self.compute_bibliography().map(Arc::new)),
+            QueryKey::ClusterIr(id) => erase(self.compute_cluster_ir(*id).map(Arc::new)),
+            QueryKey::ClusterOutput(id) => erase(self.compute_cluster_output(*id).map(Arc::new)),
+            _ => unreachable!("{:?} is an input", key),
+        }
+    }
+
+    fn cite_input<'r>(&self, cite: &Cite, reference: &'r Reference) -> CiteInput<'r> {
+        CiteInput {
+            id: cite.id,
+            reference,
+            position: self.cite_position(cite.id),
+            locator: cite.locator.clone(),
+            locator_type: cite.locator_type.clone(),
+        }
+    }
+
+    /// Only the references names couldn't tell apart get suffixes, so unless some cite is still
+    /// ambiguous this doesn't change as the document is edited.
+    fn compute_year_suffixes(&self) -> HashMap<String, String> {
+        if !self.style().citation.disambiguate_add_year_suffix {
+            return HashMap::new();
+        }
+        let order = match self.sorted_reference_ids() {
+            Ok(order) => order,
+            Err(_) => return HashMap::new(),
+        };
+        let named: Vec<Arc<Vec<Result<NamedCite, StyleError>>>> = self
+            .cluster_ids()
+            .iter()
+            .map(|&id| self.name_disambiguation(id))
+            .collect();
+        pipeline::year_suffixes(&order, named.iter().flat_map(|n| n.iter().flatten()))
+    }
+
+    /// A cluster whose references got no year suffixes is done once its names are, so it only
+    /// has to be looked at again when one of them gets a suffix.
+    fn compute_cluster_ir(&self, id: ClusterId) -> Result<Vec<DisambResult>, StyleError> {
+        let named = self.name_disambiguation(id);
+        let cluster = self.cluster(id);
+        let mut results = Vec::with_capacity(named.len());
+        for named in named.iter() {
+            let named = named.as_ref().map_err(Clone::clone)?;
+            let year_suffix = match self.year_suffix(&named.ref_id) {
+                Some(suffix) => suffix,
+                None => {
+                    results.push(named.without_year_suffix());
+                    continue;
+                }
+            };
+            let cite = cluster.cites.iter().find(|cite| cite.id == named.id);
+            let reference = self.reference(&named.ref_id);
+            let (cite, reference) = match (cite, reference) {
+                (Some(cite), Some(reference)) => (cite, reference),
+                _ => continue,
+            };
+            let input = self.cite_input(cite, &reference);
+            results.push(pipeline::add_year_suffix(self, &input, named, &year_suffix)?);
+        }
+        Ok(results)
+    }
+
+    fn compute_cluster_output(&self, id: ClusterId) -> Result<String, StyleError> {
+        let style = self.style();
+        let cluster = self.cluster(id);
+        let results = self.cluster_ir(id)?;
+        // Citation numbers go missing when the bibliography can't be sorted
+        self.sorted_reference_ids()?;
+        let mut keyed = Vec::with_capacity(cluster.cites.len());
+        for cite in &cluster.cites {
+            let result = results.iter().find(|result| result.id == cite.id);
+            let (reference, result) = match (self.reference(&cite.ref_id), result) {
+                (Some(reference), Some(result)) => (reference, result),
+                _ => continue,
+            };
+            let input = self.cite_input(cite, &reference);
+            let citation_number = self.citation_number(&cite.ref_id);
+            let render = |year_suffix: Option<&str>, suppress_author: bool| {
+                let options = RenderOptions {
+                    year_suffix,
+                    citation_number,
+                    suppress_author,
+                };
+                Ok::<_, StyleError>(format!(
+                    "{}{}{}",
+                    cite.prefix.as_deref().unwrap_or(""),
+                    pipeline::render_cite(self, &style, &input, result, options)?,
+                    cite.suffix.as_deref().unwrap_or(""),
+                ))
+            };
+            let year_suffix = result.year_suffix.as_deref();
+            let parts = CiteParts {
+                id: cite.id,
+                full: render(year_suffix, cite.suppress_author)?,
+                author: if cite.suppress_author {
+                    None
+                } else {
+                    pipeline::cite_author(self, &style, &input, result)?.map(|names| names.text())
+                },
+                without_author: render(year_suffix, true)?,
+                without_author_or_suffix: render(None, true)?,
+                year_suffix: result.year_suffix.clone(),
+                citation_number,
+                has_extras: cite.locator.is_some()
+                    || cite.prefix.is_some()
+                    || cite.suffix.is_some(),
+            };
+            let sort_values: Vec<_> = style
+                .citation
+                .sort
+                .iter()
+                .flat_map(|sort| sort.keys.iter())
+                .map(|key| {
+                    let citation = &style.citation;
+                    let name = &citation.name_inheritance;
+                    let delimiter = &citation.names_delimiter;
+                    let number = citation_number;
+                    sort::sort_value(self, &style, name, delimiter, &reference, number, key)
+                })
+                .collect::<Result<_, _>>()?;
+            keyed.push((sort_values, parts));
+        }
+        if let Some(sort) = &style.citation.sort {
+            keyed.sort_by(|(a, _), (b, _)| sort::compare_values(&sort.keys, a, b));
+        }
+        let parts: Vec<CiteParts> = keyed.into_iter().map(|(_, parts)| parts).collect();
+        Ok(collapse::render_cluster(&style.citation, &parts))
+    }
+
+    fn compute_sorted_references(&self) -> Result<Vec<String>, StyleError> {
+        let mut ids: Vec<String> = Vec::new();
+        for &cluster in self.cluster_ids().iter() {
+            for cite in &self.cluster(cluster).cites {
+                if !ids.contains(&cite.ref_id) && self.reference(&cite.ref_id).is_some() {
+                    ids.push(cite.ref_id.clone());
+                }
+            }
+        }
+        for id in self.reference_ids().iter() {
+            if !ids.contains(id) {
+                ids.push(id.clone());
+            }
+        }
+        let style = self.style();
+        let (bib, sort) = match &style.bibliography {
+            Some(bib) => match &bib.sort {
+                Some(sort) => (bib, sort),
+                None => return Ok(ids),
+            },
+            None => return Ok(ids),
+        };
+        let mut references: Vec<Arc<Reference>> =
+            ids.iter().filter_map(|id| self.reference(id)).collect();
+        sort::sort_references(self, &style, bib, sort, &mut references)?;
+        Ok(references.iter().map(|r| r.id.clone()).collect())
+    }
+
+    fn compute_bibliography(&self) -> Result<Vec<BibEntry>, StyleError> {
+        let style = self.style();
+        let bib = match &style.bibliography {
+            Some(bib) => bib,
+            None => return Ok(Vec::new()),
+        };
+        let references: Vec<Arc<Reference>> = self
+            .sorted_reference_ids()?
+            .iter()
+            .filter_map(|id| self.reference(id))
+            .collect();
+        let year_suffixes = self.year_suffixes();
+        bibliography::render_entries(self, &style, bib, &references, &year_suffixes)
+    }
+
+    fn compute_positions(&self) -> HashMap<CiteId, Position> {
+        let clusters: Vec<Arc<Cluster>> = self
+            .cluster_ids()
+            .iter()
+            .map(|&c| self.cluster(c))
+            .collect();
+        let distance = self
+            .style()
+            .citation
+            .near_note_distance
+            .unwrap_or(NEAR_NOTE_DISTANCE);
+        cite_positions(clusters.iter().map(|c| &**c), distance)
+    }
+
+    fn input_default(key: &QueryKey) -> (AnyValue, EqFn) {
+        match key {
+            QueryKey::ReferenceIds => erase(Vec::<String>::new()),
+            QueryKey::Reference(_) => erase(None::<Arc<Reference>>),
+            QueryKey::ClusterIds => erase(Vec::<ClusterId>::new()),
+            QueryKey::Cluster(id) => erase(Cluster::new(*id, Vec::new())),
+            QueryKey::Locale(_) => erase(None::<Arc<Locale>>),
+            QueryKey::Hereinafter(_) => erase(None::<Arc<String>>),
+            QueryKey::JurisdictionModule(_) => erase(None::<Arc<JurisdictionModule>>),
+            _ => unreachable!("{:?} has no default", key),
+        }
+    }
+
+    fn set_input<T: Any + Send + Sync + PartialEq>(&mut self, key: QueryKey, value: T) {
+        let slots = self.slots.get_mut();
+        if let Some(old) = slots.get(&key) {
+            if (old.eq)(&*old.value, &value) {
+                return;
+            }
+        }
+        self.revision += 1;
+        slots.insert(
+            key,
+            Slot {
+                value: Arc::new(value),
+                eq: eq_as::<T>,
+                changed_at: self.revision,
+                verified_at: self.revision,
+                deps: Vec::new(),
+            },
+        );
+    }
+
+    fn query<T: Any + Send + Sync>(&self, key: QueryKey) -> Arc<T> {
+        if let Some(frame) = self.active.borrow_mut().last_mut() {
+            frame.push(key.clone());
+        }
+        self.bring_up_to_date(&key);
+        let value = self.slots.borrow()[&key].value.clone();
+        value
+            .downcast::<T>()
+            .unwrap_or_else(|_| panic!("query {:?} read as the wrong type", key))
+    }
+
+    /// Makes sure the slot for `key` is valid in the current revision, and returns the revision
+    /// its value last changed in.
+    fn bring_up_to_date(&self, key: &QueryKey) -> Revision {
+        let current = self.revision;
+        let existing = self
+            .slots
+            .borrow()
+            .get(key)
+            .map(|slot| (slot.verified_at, slot.changed_at, slot.deps.clone()));
+        match existing {
+            Some((_, changed_at, _)) if key.is_input() => return changed_at,
+            None if key.is_input() => {
+                let (value, eq) = Self::input_default(key);
+                self.slots.borrow_mut().insert(
+                    key.clone(),
+                    Slot {
+                        value,
+                        eq,
+                        changed_at: 0,
+                        verified_at: current,
+                        deps: Vec::new(),
+                    },
+                );
+                return 0;
+            }
+            Some((verified_at, changed_at, deps))
+                if verified_at == current
+                    || !deps
+                        .iter()
+                        .any(|dep| self.bring_up_to_date(dep) > verified_at) =>
+            {
+                if let Some(slot) = self.slots.borrow_mut().get_mut(key) {
+                    slot.verified_at = current;
+                }
+                return changed_at;
+            }
+            _ => {}
+        }
+
+        *self.executions.borrow_mut().entry(key.clone()).or_insert(0) += 1;
+        self.active.borrow_mut().push(Vec::new());
+        let (value, eq) = self.execute(key);
+        let deps = self.active.borrow_mut().pop().unwrap_or_default();
+        let mut slots = self.slots.borrow_mut();
+        let changed_at = match slots.get(key) {
+            Some(old) if eq(&*old.value, &*value) => old.changed_at,
+            _ => current,
+        };
+        slots.insert(
+            key.clone(),
+            Slot {
+                value,
+                eq,
+                changed_at,
+                verified_at: current,
+                deps,
+            },
+        );
+        changed_at
+    }
+}
+
+#[cfg(test)]
+fn test_db() -> Database {
+    use crate::cluster::Cite;
+    use crate::element::*;
+    let names = Element::Names(Arc::new(Names {
+        variables: vec![NameVariable::Author],
+        name: Some(Name {
+            form: Some(NameForm::Short),
+            ..Name::empty()
+        }),
+        delimiter: None,
+        formatting: None,
+        affixes: None,
+        display: None,
+        substitute: None,
+    }));
+    let mut db = Database::new(Style {
+        citation: Citation {
+            layout: Layout {
+                elements: vec![names],
+                ..Default::default()
+            },
+            disambiguate_add_givenname: true,
+            ..Default::default()
+        },
+        ..Default::default()
+    });
+    db.set_references(vec![
+        test_reference("a", "John", "Smith"),
+        test_reference("b", "Jane", "Doe"),
+    ]);
+    db.set_cluster(Cluster::new(
+        ClusterId(1),
+        vec![Cite::basic(CiteId(1), "a")],
+    ));
+    db.set_cluster(Cluster::new(
+        ClusterId(2),
+        vec![Cite::basic(CiteId(2), "b")],
+    ));
+    db
+}
+
+#[cfg(test)]
+fn test_reference(id: &str, given: &str, family: &str) -> Reference {
+    use crate::element::{NameVariable, Variable};
+    use crate::reference::PersonName;
+    let mut r = Reference::empty(id, "book");
+    r.name
+        .insert(NameVariable::Author, vec![PersonName::new(given, family)]);
+    r.ordinary
+        .insert(Variable::Abstract, "An abstract".to_string());
+    r
+}
+
+#[cfg(test)]
+fn outputs(db: &Database, id: ClusterId) -> Vec<String> {
+    db.cluster_ir(id)
+        .unwrap()
+        .iter()
+        .map(|r| r.output.clone())
+        .collect()
+}
+
+#[test]
+fn reuses_unaffected_queries() {
+    let mut db = test_db();
+    assert_eq!(outputs(&db, ClusterId(1)), vec!["Smith"]);
+    assert_eq!(outputs(&db, ClusterId(2)), vec!["Doe"]);
+
+    // Nothing in the style renders the abstract
+    let mut edited = test_reference("b", "Jane", "Doe");
+    edited
+        .ordinary
+        .insert(crate::element::Variable::Abstract, "Edited".into());
+    db.insert_reference(edited);
+    assert_eq!(outputs(&db, ClusterId(1)), vec!["Smith"]);
+    assert_eq!(outputs(&db, ClusterId(2)), vec!["Doe"]);
+    let dfa = |id: &str| QueryKey::RefDfa(id.into(), DfaKey::first());
+    assert_eq!(db.execution_count(&dfa("a")), 1);
+    assert_eq!(db.execution_count(&dfa("b")), 2);
+    // b's DFA came out the same, so cluster 1 wasn't checked against it again
+    assert_eq!(db.execution_count(&QueryKey::NameDisambiguation(ClusterId(1))), 1);
+    assert_eq!(db.execution_count(&QueryKey::NameDisambiguation(ClusterId(2))), 2);
+    assert_eq!(db.execution_count(&QueryKey::ClusterIr(ClusterId(1))), 1);
+    assert_eq!(db.execution_count(&QueryKey::ClusterIr(ClusterId(2))), 1);
+}
+
+#[test]
+fn recomputes_affected_queries() {
+    let mut db = test_db();
+    assert_eq!(outputs(&db, ClusterId(1)), vec!["Smith"]);
+    assert_eq!(outputs(&db, ClusterId(2)), vec!["Doe"]);
+
+    // Now a and b are both Smiths
+    db.insert_reference(test_reference("b", "Jane", "Smith"));
+    assert_eq!(outputs(&db, ClusterId(1)), vec!["John Smith"]);
+    assert_eq!(outputs(&db, ClusterId(2)), vec!["Jane Smith"]);
+
+    assert_eq!(db.execution_count(&QueryKey::ClusterIr(ClusterId(1))), 2);
+
+    // Ambiguity is against the whole library, not the cites in the document, so cluster 1
+    // doesn't depend on cluster 2 at all.
+    db.set_cluster(Cluster::new(ClusterId(2), vec![]));
+    assert_eq!(outputs(&db, ClusterId(1)), vec!["John Smith"]);
+    assert_eq!(outputs(&db, ClusterId(2)), Vec::<String>::new());
+    assert_eq!(db.execution_count(&QueryKey::NameDisambiguation(ClusterId(1))), 2);
+    assert_eq!(db.execution_count(&QueryKey::ClusterIr(ClusterId(1))), 2);
+
+    db.remove_reference("b");
+    assert_eq!(outputs(&db, ClusterId(1)), vec!["Smith"]);
+    assert_eq!(db.execution_count(&QueryKey::ClusterIr(ClusterId(1))), 3);
+}
+
+#[test]
+fn locale_fallback() {
+    let mut db = test_db();
+    assert_eq!(db.locale("de-AT").lang, "en-US");
+    db.set_locale(Locale::new("de"));
+    assert_eq!(db.locale("de-AT").lang, "de");
+    db.set_locale(Locale::new("de-AT"));
+    assert_eq!(db.locale("de-AT").lang, "de-AT");
+    assert_eq!(db.locale("fr-FR").lang, "en-US");
+}
+
+#[test]
+fn positions_follow_document_order() {
+    use crate::cluster::Cite;
+    let mut db = test_db();
+    db.set_cluster(Cluster::new(
+        ClusterId(3),
+        vec![Cite::basic(CiteId(3), "b"), Cite::basic(CiteId(4), "a")],
+    ));
+    assert_eq!(db.cite_position(CiteId(1)), Position::First);
+    assert_eq!(db.cite_position(CiteId(3)), Position::Ibid);
+    db.set_cluster_order(vec![ClusterId(3), ClusterId(1), ClusterId(2)]);
+    assert_eq!(db.cite_position(CiteId(1)), Position::Subsequent);
+    assert_eq!(db.cite_position(CiteId(4)), Position::First);
+    assert_eq!(db.cite_position(CiteId(99)), Position::First);
+}
+
+#[test]
+fn numeric_clusters_sort_and_collapse() {
+    use crate::cluster::Cite;
+    use crate::element::*;
+    let number = Element::Text(TextElement {
+        source: TextSource::Variable(
+            StandardVariable::Number(NumberVariable::CitationNumber),
+            VariableForm::Long,
+        ),
+        formatting: None,
+        affixes: None,
+        quotes: false,
+        strip_periods: false,
+        text_case: TextCase,
+        display: None,
+    });
+    let mut db = Database::new(Style {
+        citation: Citation {
+            layout: Layout {
+                elements: vec![number],
+                delimiter: Some(Delimiter(", ".into())),
+                affixes: Some(Affixes {
+                    prefix: "[".into(),
+                    suffix: "]".into(),
+                }),
+                ..Default::default()
+            },
+            sort: Some(Sort {
+                keys: vec![SortKey {
+                    source: SortSource::Variable(AnyVariable::Number(
+                        NumberVariable::CitationNumber,
+                    )),
+                    direction: SortDirection::Ascending,
+                }],
+            }),
+            collapse: Some(Collapse::CitationNumber),
+            ..Default::default()
+        },
+        ..Default::default()
+    });
+    let ids = ["a", "b", "c", "d", "e", "f", "g"];
+    db.set_references(ids.iter().map(|id| Reference::empty(*id, "book")).collect());
+    // Numbered in order of first citation
+    db.set_cluster(Cluster::new(
+        ClusterId(1),
+        ids.iter()
+            .enumerate()
+            .map(|(i, id)| Cite::basic(CiteId(i as u32), *id))
+            .collect(),
+    ));
+    let cites = ["g", "b", "a", "d", "c"];
+    db.set_cluster(Cluster::new(
+        ClusterId(2),
+        cites
+            .iter()
+            .enumerate()
+            .map(|(i, id)| Cite::basic(CiteId(10 + i as u32), *id))
+            .collect(),
+    ));
+    assert_eq!(*db.render_cluster(ClusterId(1)).unwrap(), "[1–7]");
+    assert_eq!(*db.render_cluster(ClusterId(2)).unwrap(), "[1–4, 7]");
+    // The shared DFA has the number filled in, like the ones disambiguation builds
+    let dfa = db.ref_dfa("c", DfaKey::first()).unwrap().unwrap();
+    assert!(dfa.accepts(&[crate::EdgeData::Output("3".into())]));
+    assert!(!dfa.accepts(&[crate::EdgeData::CitationNumber]));
+}
+
+#[test]
+fn csl_m_layouts_and_hereinafter() {
+    use crate::cluster::Cite;
+    use crate::element::Variable;
+    let style: Style = r#"<style class="note" version="1.1mlz1">
+      <citation>
+        <layout locale="de"><text variable="title" prefix="de: "/></layout>
+        <layout delimiter="; ">
+          <choose>
+            <if variable="hereinafter"><text variable="hereinafter"/></if>
+            <else><text variable="title"/></else>
+          </choose>
+        </layout>
+      </citation>
+    </style>"#
+        .parse()
+        .unwrap();
+    let mut db = Database::new(style);
+    let make = |id: &str, title: &str, lang: &str| {
+        let mut r = Reference::empty(id, "legal_case");
+        r.ordinary.insert(Variable::Title, title.into());
+        r.ordinary.insert(Variable::Language, lang.into());
+        // Only the document's hereinafter counts
+        r.ordinary.insert(Variable::Hereinafter, "ignored".into());
+        r
+    };
+    db.set_references(vec![
+        make("a", "Brown v. Board", "en-US"),
+        make("b", "Lüth", "de-DE"),
+    ]);
+    db.set_cluster(Cluster::new(
+        ClusterId(1),
+        vec![Cite::basic(CiteId(1), "a"), Cite::basic(CiteId(2), "b")],
+    ));
+    assert_eq!(*db.render_cluster(ClusterId(1)).unwrap(), "Brown v. Board; de: Lüth");
+    db.set_hereinafter("a", Some("Brown".into()));
+    assert_eq!(*db.render_cluster(ClusterId(1)).unwrap(), "Brown; de: Lüth");
+}
+
+#[test]
+fn macro_cycle_in_unparsed_style() {
+    use crate::cluster::Cite;
+    use crate::element::{Element, TextCase, TextElement, TextSource, Variable};
+    let mut style: Style = r#"<style class="in-text">
+      <macro name="a"><text variable="title"/></macro>
+      <citation><layout><text macro="a"/></layout></citation>
+      <bibliography><layout><text macro="a"/></layout></bibliography>
+    </style>"#
+        .parse()
+        .unwrap();
+    let calls = |name: &str| {
+        vec![Element::Text(TextElement {
+            source: TextSource::Macro(name.into()),
+            formatting: None,
+            affixes: None,
+            quotes: false,
+            strip_periods: false,
+            text_case: TextCase,
+            display: None,
+        })]
+    };
+    style.macros.insert("a".into(), calls("b"));
+    style.macros.insert("b".into(), calls("a"));
+    let mut db = Database::new(style);
+    let mut r = Reference::empty("a", "book");
+    r.ordinary.insert(Variable::Title, "Title".into());
+    db.set_references(vec![r]);
+    db.set_cluster(Cluster::new(ClusterId(1), vec![Cite::basic(CiteId(1), "a")]));
+    let cycle = StyleError::MacroCycle {
+        chain: vec!["a".into(), "b".into(), "a".into()],
+    };
+    assert_eq!(db.render_cluster(ClusterId(1)), Err(cycle.clone()));
+    assert_eq!(db.render_bibliography(), Err(cycle));
+}
+
+#[test]
+fn suppress_author_leaves_other_names() {
+    use crate::cluster::Cite;
+    use crate::element::{NameVariable, Variable};
+    let style: Style = r#"<style class="in-text">
+      <citation>
+        <layout delimiter="; ">
+          <group delimiter=", ">
+            <names variable="author">
+              <name form="short"/>
+              <substitute><names variable="editor"/></substitute>
+            </names>
+            <text variable="title"/>
+            <names variable="translator" prefix="trans. "><name form="short"/></names>
+          </group>
+        </layout>
+      </citation>
+    </style>"#
+        .parse()
+        .unwrap();
+    let mut db = Database::new(style);
+    let make = |id: &str, author: NameVariable| {
+        let mut r = Reference::empty(id, "book");
+        r.ordinary.insert(Variable::Title, id.to_uppercase());
+        r.name.insert(author, vec![PersonName::new("Ann", "Smith")]);
+        r.name
+            .insert(NameVariable::Translator, vec![PersonName::new("Bo", "Jones")]);
+        r
+    };
+    db.set_references(vec![
+        make("a", NameVariable::Author),
+        make("b", NameVariable::Editor),
+    ]);
+    let suppressed = |id: u32, ref_id: &str| Cite {
+        suppress_author: true,
+        ..Cite::basic(CiteId(id), ref_id)
+    };
+    db.set_cluster(Cluster::new(
+        ClusterId(1),
+        vec![suppressed(1, "a"), suppressed(2, "b")],
+    ));
+    assert_eq!(*db.render_cluster(ClusterId(1)).unwrap(), "A, trans. Jones; B, trans. Jones");
+}
+
+#[test]
+fn dates_types_and_implicit_year_suffixes() {
+    use crate::cluster::Cite;
+    use crate::element::{DateVariable, LocatorType, NameVariable, Variable};
+    use crate::reference::Date;
+    let style: Style = r#"<style class="in-text">
+      <citation disambiguate-add-year-suffix="true">
+        <layout prefix="(" suffix=")" delimiter="; ">
+          <group delimiter=", ">
+            <names variable="author"><name form="short"/></names>
+            <date variable="issued"><date-part name="year"/></date>
+            <choose>
+              <if type="book chapter" match="any"><text variable="title"/></if>
+            </choose>
+            <choose>
+              <if locator="page"><text variable="locator" prefix="p. "/></if>
+              <else-if locator="chapter"><text variable="locator" prefix="ch. "/></else-if>
+            </choose>
+          </group>
+        </layout>
+      </citation>
+    </style>"#
+        .parse()
+        .unwrap();
+    let mut db = Database::new(style);
+    let make = |id: &str, csl_type: &str| {
+        let mut r = Reference::empty(id, csl_type);
+        r.ordinary.insert(Variable::Title, "Title".into());
+        r.name
+            .insert(NameVariable::Author, vec![PersonName::new("Ann", "Smith")]);
+        r.date.insert(DateVariable::Issued, Date::new(2001, 4, 1));
+        r
+    };
+    db.set_references(vec![
+        make("a", "book"),
+        make("b", "article-journal"),
+        make("c", "chapter"),
+    ]);
+    db.set_cluster(Cluster::new(
+        ClusterId(1),
+        vec![
+            Cite::basic(CiteId(1), "a").with_locator(LocatorType::Page, "5"),
+            Cite::basic(CiteId(2), "b").with_locator(LocatorType::Chapter, "2"),
+            Cite::basic(CiteId(3), "c"),
+        ],
+    ));
+    assert_eq!(
+        *db.render_cluster(ClusterId(1)).unwrap(),
+        "(Smith, 2001a, Title, p. 5; Smith, 2001, ch. 2; Smith, 2001b, Title)"
+    );
+}
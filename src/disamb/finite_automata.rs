@@ -5,9 +5,7 @@
 // Copyright © 2019 Corporation for Digital Scholarship
 
 use super::EdgeData;
-use petgraph::dot::Dot;
-use petgraph::graph::{Graph, NodeIndex};
-use petgraph::visit::EdgeRef;
+use super::graph::{AutomatonDot, Dot, Graph, NodeIndex};
 use std::collections::BTreeSet;
 use std::collections::HashMap;
 
@@ -28,8 +26,7 @@ pub type DfaGraph = Graph<(), EdgeData>;
 
 fn epsilon_closure(nfa: &NfaGraph, closure: &mut BTreeSet<NodeIndex>) {
     let mut work: Vec<_> = closure.iter().cloned().collect();
-    while !work.is_empty() {
-        let s = work.pop().unwrap();
+    while let Some(s) = work.pop() {
         for edge in nfa.edges(s) {
             let is_epsilon = *edge.weight() == NfaEdge::Epsilon;
             let target = edge.target();
@@ -61,20 +58,15 @@ impl Default for Nfa {
 }
 
 /// https://github.com/petgraph/petgraph/issues/199#issuecomment-484077775
-fn graph_eq<N, E, Ty, Ix>(
-    a: &petgraph::graph::Graph<N, E, Ty, Ix>,
-    b: &petgraph::graph::Graph<N, E, Ty, Ix>,
-) -> bool
+fn graph_eq<N, E>(a: &Graph<N, E>, b: &Graph<N, E>) -> bool
 where
     N: PartialEq,
     E: PartialEq,
-    Ty: petgraph::EdgeType,
-    Ix: petgraph::graph::IndexType + PartialEq,
 {
-    let a_ns = a.raw_nodes().iter().map(|n| &n.weight);
-    let b_ns = b.raw_nodes().iter().map(|n| &n.weight);
-    let a_es = a.raw_edges().iter().map(|e| (e.source(), e.target(), &e.weight));
-    let b_es = b.raw_edges().iter().map(|e| (e.source(), e.target(), &e.weight));
+    let a_ns = a.raw_nodes().iter();
+    let b_ns = b.raw_nodes().iter();
+    let a_es = a.raw_edges().iter().map(|e| (e.source(), e.target(), e.weight()));
+    let b_es = b.raw_edges().iter().map(|e| (e.source(), e.target(), e.weight()));
     a_ns.eq(b_ns) && a_es.eq(b_es)
 }
 
@@ -105,14 +97,6 @@ impl std::cmp::PartialEq for Dfa {
     }
 }
 
-#[derive(Debug)]
-enum DebugNode {
-    Node,
-    Start,
-    Accepting,
-    StartAndAccepting,
-}
-
 impl Nfa {
     pub fn new() -> Self {
         Nfa::default()
@@ -133,17 +117,14 @@ impl Nfa {
         self.accepting.insert(cursor);
     }
 
-    /// A Names block, for instance, is given start & end nodes, and simply fills in a segment a
-    /// few times with increasing given-name counts etc.
-    pub fn add_sequence_between(&mut self, a: NodeIndex, b: NodeIndex, tokens: Vec<EdgeData>) {
-        let mut cursor = self.graph.add_node(());
-        self.graph.add_edge(a, cursor, NfaEdge::Epsilon);
-        for token in tokens {
-            let next = self.graph.add_node(());
-            self.graph.add_edge(cursor, next, NfaEdge::Token(token));
-            cursor = next;
-        }
-        self.graph.add_edge(cursor, b, NfaEdge::Epsilon);
+    /// Graphviz DOT, with arrows into the start states and double circles for accepting ones.
+    pub fn to_dot(&self) -> String {
+        let dot = AutomatonDot {
+            graph: &self.graph,
+            start: &self.start,
+            accepting: &self.accepting,
+        };
+        format!("{:?}", dot)
     }
 
     pub fn brzozowski_minimise(mut self: Nfa) -> Dfa {
@@ -171,7 +152,7 @@ impl Nfa {
     }
 }
 
-use std::fmt::{self, Formatter};
+use std::fmt::{self, Debug, Formatter};
 
 impl Debug for Dfa {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
@@ -180,7 +161,7 @@ impl Debug for Dfa {
             "start:{:?}\naccepting:{:?}\n{:?}\n---\n",
             &self.start,
             &self.accepting,
-            Dot::with_config(&self.graph, &[])
+            Dot(&self.graph)
         )
     }
 }
@@ -204,28 +185,29 @@ pub fn to_dfa(nfa: &Nfa) -> Dfa {
     let mut dfa_states = HashMap::new();
     dfa_states.insert(start_set, dfa_start_node);
 
-    while !work.is_empty() {
-        let (dfa_state, current_node) = work.pop().unwrap();
-        let mut by_edge_weight = HashMap::<EdgeData, BTreeSet<NodeIndex>>::new();
+    while let Some((dfa_state, current_node)) = work.pop() {
+        // In the order the edges were added, so the same NFA always numbers its DFA the same
+        // way, and the bit-for-bit Eq above holds for equal inputs.
+        let mut by_edge_weight = Vec::<(EdgeData, BTreeSet<NodeIndex>)>::new();
         for nfa_node in dfa_state {
             for edge in nfa.graph.edges(nfa_node) {
                 let weight = edge.weight();
                 let target = edge.target();
                 if let NfaEdge::Token(t) = weight {
-                    match by_edge_weight.get_mut(t) {
+                    match by_edge_weight.iter_mut().find(|(k, _)| k == t) {
                         None => {
                             let mut set = BTreeSet::new();
                             set.insert(target);
-                            by_edge_weight.insert(t.clone(), set);
+                            by_edge_weight.push((t.clone(), set));
                         }
-                        Some(set) => {
+                        Some((_, set)) => {
                             set.insert(target);
                         }
                     }
                 }
             }
         }
-        for (k, mut set) in by_edge_weight.drain() {
+        for (k, mut set) in by_edge_weight {
             epsilon_closure(&nfa.graph, &mut set);
             if !dfa_states.contains_key(&set) {
                 let node = dfa.add_node(());
@@ -252,13 +234,22 @@ pub fn to_dfa(nfa: &Nfa) -> Dfa {
 }
 
 impl Dfa {
+    /// Like `Nfa::to_dot`.
+    pub fn to_dot(&self) -> String {
+        let dot = AutomatonDot {
+            graph: &self.graph,
+            start: &std::iter::once(self.start).collect(),
+            accepting: &self.accepting,
+        };
+        format!("{:?}", dot)
+    }
+
     pub fn accepts_data(&self, data: &[EdgeData]) -> bool {
         let mut cursors = Vec::new();
         cursors.push((self.start, None, data));
-        while !cursors.is_empty() {
-            let (cursor, prepended, chunk) = cursors.pop().unwrap();
-            let first = prepended.as_ref().or_else(|| chunk.get(0));
-            if first == None && self.accepting.contains(&cursor) {
+        while let Some((cursor, prepended, chunk)) = cursors.pop() {
+            let first = prepended.as_ref().or_else(|| chunk.first());
+            if first.is_none() && self.accepting.contains(&cursor) {
                 // we did it!
                 return true;
             }
@@ -302,21 +293,15 @@ impl Dfa {
     pub fn accepts(&self, tokens: &[EdgeData]) -> bool {
         let mut cursor = self.start;
         for token in tokens {
-            let mut found = false;
-            for neighbour in self.graph.neighbors(cursor) {
-                let weight = self
-                    .graph
-                    .find_edge(cursor, neighbour)
-                    .and_then(|e| self.graph.edge_weight(e))
-                    .map(|w| w == token);
-                if let Some(true) = weight {
-                    cursor = neighbour;
-                    found = true;
-                    break;
-                }
-            }
-            if !found {
-                return false;
+            // there can be many edges between the same two nodes, so don't go via neighbors()
+            let next = self
+                .graph
+                .edges(cursor)
+                .find(|edge| edge.weight() == token)
+                .map(|edge| edge.target());
+            match next {
+                Some(next) => cursor = next,
+                None => return false,
             }
         }
         self.accepting.contains(&cursor)
@@ -340,14 +325,14 @@ fn nfa() {
         let target = nfa.add_node(());
         let abc = nfa.add_node(());
         let acc = nfa.add_node(());
-        nfa.add_edge(initial, forwards1, reuben.into());
-        nfa.add_edge(forwards1, target, peters.into());
-        nfa.add_edge(initial, backwards1, peters.into());
-        nfa.add_edge(backwards1, backwards2, comma.into());
-        nfa.add_edge(backwards2, target, reuben.into());
-        nfa.add_edge(initial, target, peters.into());
-        nfa.add_edge(target, abc, comma.into());
-        nfa.add_edge(abc, acc, twenty.into());
+        nfa.add_edge(initial, forwards1, reuben.clone().into());
+        nfa.add_edge(forwards1, target, peters.clone().into());
+        nfa.add_edge(initial, backwards1, peters.clone().into());
+        nfa.add_edge(backwards1, backwards2, comma.clone().into());
+        nfa.add_edge(backwards2, target, reuben.clone().into());
+        nfa.add_edge(initial, target, peters.clone().into());
+        nfa.add_edge(target, abc, comma.clone().into());
+        nfa.add_edge(abc, acc, twenty.clone().into());
         let mut accepting = BTreeSet::new();
         accepting.insert(acc);
         let mut start = BTreeSet::new();
@@ -368,14 +353,14 @@ fn nfa() {
         let target = nfa.add_node(());
         let abc = nfa.add_node(());
         let acc = nfa.add_node(());
-        nfa.add_edge(initial, forwards1, andy.into());
-        nfa.add_edge(forwards1, target, peters.into());
-        nfa.add_edge(initial, backwards1, peters.into());
-        nfa.add_edge(backwards1, backwards2, comma.into());
-        nfa.add_edge(backwards2, target, andy.into());
-        nfa.add_edge(initial, target, peters.into());
-        nfa.add_edge(target, abc, comma.into());
-        nfa.add_edge(abc, acc, twenty.into());
+        nfa.add_edge(initial, forwards1, andy.clone().into());
+        nfa.add_edge(forwards1, target, peters.clone().into());
+        nfa.add_edge(initial, backwards1, peters.clone().into());
+        nfa.add_edge(backwards1, backwards2, comma.clone().into());
+        nfa.add_edge(backwards2, target, andy.clone().into());
+        nfa.add_edge(initial, target, peters.clone().into());
+        nfa.add_edge(target, abc, comma.clone().into());
+        nfa.add_edge(abc, acc, twenty.clone().into());
         let mut accepting = BTreeSet::new();
         accepting.insert(acc);
         let mut start = BTreeSet::new();
@@ -394,24 +379,24 @@ fn nfa() {
     let dfa2_brz = nfa2.brzozowski_minimise();
 
     println!("{:?}", dfa.start);
-    println!("dfa {:?}", Dot::with_config(&dfa.graph, &[]));
-    println!("dfa2 {:?}", Dot::with_config(&dfa2.graph, &[]));
-    println!("dfa_brz {:?}", Dot::with_config(&dfa2_brz.graph, &[]));
-    println!("dfa2_brz {:?}", Dot::with_config(&dfa2_brz.graph, &[]));
+    println!("dfa {:?}", Dot(&dfa.graph));
+    println!("dfa2 {:?}", Dot(&dfa2.graph));
+    println!("dfa_brz {:?}", Dot(&dfa2_brz.graph));
+    println!("dfa2_brz {:?}", Dot(&dfa2_brz.graph));
 
     let test_dfa = |dfa: &Dfa| {
-        assert!(dfa.accepts(&[peters, comma, twenty]));
-        assert!(dfa.accepts(&[reuben, peters, comma, twenty]));
-        assert!(dfa.accepts(&[peters, comma, reuben, comma, twenty]));
-        assert!(!dfa.accepts(&[peters, comma, andy, comma, twenty]));
-        assert!(!dfa.accepts(&[andy, comma, peters, comma, twenty]));
+        assert!(dfa.accepts(&[peters.clone(), comma.clone(), twenty.clone()]));
+        assert!(dfa.accepts(&[reuben.clone(), peters.clone(), comma.clone(), twenty.clone()]));
+        assert!(dfa.accepts(&[peters.clone(), comma.clone(), reuben.clone(), comma.clone(), twenty.clone()]));
+        assert!(!dfa.accepts(&[peters.clone(), comma.clone(), andy.clone(), comma.clone(), twenty.clone()]));
+        assert!(!dfa.accepts(&[andy.clone(), comma.clone(), peters.clone(), comma.clone(), twenty.clone()]));
     };
 
     let test_dfa2 = |dfa2: &Dfa| {
-        assert!(dfa2.accepts(&[peters, comma, twenty]));
-        assert!(dfa2.accepts(&[andy, peters, comma, twenty]));
-        assert!(!dfa2.accepts(&[peters, comma, reuben, comma, twenty]));
-        assert!(!dfa2.accepts(&[reuben, peters, comma, twenty]));
+        assert!(dfa2.accepts(&[peters.clone(), comma.clone(), twenty.clone()]));
+        assert!(dfa2.accepts(&[andy.clone(), peters.clone(), comma.clone(), twenty.clone()]));
+        assert!(!dfa2.accepts(&[peters.clone(), comma.clone(), reuben.clone(), comma.clone(), twenty.clone()]));
+        assert!(!dfa2.accepts(&[reuben.clone(), peters.clone(), comma.clone(), twenty.clone()]));
     };
 
     test_dfa(&dfa);
@@ -429,16 +414,16 @@ fn test_brzozowski_minimise() {
     let e = EdgeData::Output("e".into());
     let nfa = {
         let mut nfa = Nfa::new();
-        nfa.add_complete_sequence(vec![a, b, c, e]);
-        nfa.add_complete_sequence(vec![a, b, e]);
-        nfa.add_complete_sequence(vec![b, c, d, e]);
-        nfa.add_complete_sequence(vec![b, d, e]);
+        nfa.add_complete_sequence(vec![a.clone(), b.clone(), c.clone(), e.clone()]);
+        nfa.add_complete_sequence(vec![a.clone(), b.clone(), e.clone()]);
+        nfa.add_complete_sequence(vec![b.clone(), c.clone(), d.clone(), e.clone()]);
+        nfa.add_complete_sequence(vec![b.clone(), d.clone(), e.clone()]);
         nfa
     };
 
     let dfa = nfa.brzozowski_minimise();
-    println!("abcde {:?}", Dot::with_config(&dfa.graph, &[]));
+    println!("abcde {:?}", Dot(&dfa.graph));
 
-    assert!(dfa.accepts(&[a, b, e]));
-    assert!(!dfa.accepts(&[a, b, c, d, e]));
+    assert!(dfa.accepts(&[a.clone(), b.clone(), e.clone()]));
+    assert!(!dfa.accepts(&[a.clone(), b.clone(), c.clone(), d.clone(), e.clone()]));
 }
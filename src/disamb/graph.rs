@@ -0,0 +1,222 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Copyright © 2020 Corporation for Digital Scholarship
+
+//! Just enough of a directed graph for the finite automata, so that they don't need petgraph.
+//! The method names follow petgraph's, so `finite_automata` reads the same as it used to.
+
+use std::collections::BTreeSet;
+use std::fmt::{self, Debug, Formatter};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct NodeIndex(u32);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct EdgeIndex(u32);
+
+impl NodeIndex {
+    pub fn index(self) -> usize {
+        self.0 as usize
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Edge<E> {
+    source: NodeIndex,
+    target: NodeIndex,
+    weight: E,
+}
+
+impl<E> Edge<E> {
+    pub fn weight(&self) -> &E {
+        &self.weight
+    }
+    pub fn source(&self) -> NodeIndex {
+        self.source
+    }
+    pub fn target(&self) -> NodeIndex {
+        self.target
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Graph<N, E> {
+    nodes: Vec<N>,
+    edges: Vec<Edge<E>>,
+    /// Outgoing edges for each node, in insertion order.
+    outgoing: Vec<Vec<EdgeIndex>>,
+}
+
+impl<N, E> Default for Graph<N, E> {
+    fn default() -> Self {
+        Graph::new()
+    }
+}
+
+impl<N, E> Graph<N, E> {
+    pub fn new() -> Self {
+        Graph::with_capacity(0, 0)
+    }
+
+    pub fn with_capacity(nodes: usize, edges: usize) -> Self {
+        Graph {
+            nodes: Vec::with_capacity(nodes),
+            edges: Vec::with_capacity(edges),
+            outgoing: Vec::with_capacity(nodes),
+        }
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn edge_count(&self) -> usize {
+        self.edges.len()
+    }
+
+    pub fn add_node(&mut self, weight: N) -> NodeIndex {
+        let ix = NodeIndex(self.nodes.len() as u32);
+        self.nodes.push(weight);
+        self.outgoing.push(Vec::new());
+        ix
+    }
+
+    pub fn add_edge(&mut self, source: NodeIndex, target: NodeIndex, weight: E) -> EdgeIndex {
+        let ix = EdgeIndex(self.edges.len() as u32);
+        self.edges.push(Edge {
+            source,
+            target,
+            weight,
+        });
+        self.outgoing[source.index()].push(ix);
+        ix
+    }
+
+    /// Outgoing edges of `node`.
+    pub fn edges(&self, node: NodeIndex) -> impl Iterator<Item = &Edge<E>> + '_ {
+        self.outgoing[node.index()]
+            .iter()
+            .map(move |e| &self.edges[e.0 as usize])
+    }
+
+    pub fn neighbors(&self, node: NodeIndex) -> impl Iterator<Item = NodeIndex> + '_ {
+        self.edges(node).map(|e| e.target)
+    }
+
+    pub fn find_edge(&self, a: NodeIndex, b: NodeIndex) -> Option<EdgeIndex> {
+        self.outgoing[a.index()]
+            .iter()
+            .cloned()
+            .find(|e| self.edges[e.0 as usize].target == b)
+    }
+
+    pub fn edge_weight(&self, e: EdgeIndex) -> Option<&E> {
+        self.edges.get(e.0 as usize).map(|e| &e.weight)
+    }
+
+    pub fn raw_nodes(&self) -> &[N] {
+        &self.nodes
+    }
+
+    pub fn raw_edges(&self) -> &[Edge<E>] {
+        &self.edges
+    }
+
+    /// Flips the direction of every edge.
+    pub fn reverse(&mut self) {
+        for list in self.outgoing.iter_mut() {
+            list.clear();
+        }
+        for (i, edge) in self.edges.iter_mut().enumerate() {
+            std::mem::swap(&mut edge.source, &mut edge.target);
+            self.outgoing[edge.source.index()].push(EdgeIndex(i as u32));
+        }
+    }
+
+    pub fn map<N2, E2>(
+        &self,
+        mut node_map: impl FnMut(NodeIndex, &N) -> N2,
+        mut edge_map: impl FnMut(EdgeIndex, &E) -> E2,
+    ) -> Graph<N2, E2> {
+        Graph {
+            nodes: self
+                .nodes
+                .iter()
+                .enumerate()
+                .map(|(i, n)| node_map(NodeIndex(i as u32), n))
+                .collect(),
+            edges: self
+                .edges
+                .iter()
+                .enumerate()
+                .map(|(i, e)| Edge {
+                    source: e.source,
+                    target: e.target,
+                    weight: edge_map(EdgeIndex(i as u32), &e.weight),
+                })
+                .collect(),
+            outgoing: self.outgoing.clone(),
+        }
+    }
+}
+
+/// Renders a graph in Graphviz DOT format, labelling edges with their `Debug` output.
+pub struct Dot<'a, N, E>(pub &'a Graph<N, E>);
+
+impl<'a, N, E: Debug> Debug for Dot<'a, N, E> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write_dot(f, self.0, |_| "")?;
+        write!(f, "}}")
+    }
+}
+
+/// Like `Dot`, but for an automaton: each start node gets an arrow in from nowhere, and
+/// accepting nodes are drawn with two circles.
+pub struct AutomatonDot<'a, N, E> {
+    pub graph: &'a Graph<N, E>,
+    pub start: &'a BTreeSet<NodeIndex>,
+    pub accepting: &'a BTreeSet<NodeIndex>,
+}
+
+impl<'a, N, E: Debug> Debug for AutomatonDot<'a, N, E> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let accepting = |i: usize| {
+            if self.accepting.contains(&NodeIndex(i as u32)) {
+                ", shape = doublecircle"
+            } else {
+                ""
+            }
+        };
+        write_dot(f, self.graph, accepting)?;
+        for start in self.start {
+            writeln!(f, "    start_{} [ shape = point ]", start.0)?;
+            writeln!(f, "    start_{0} -> {0}", start.0)?;
+        }
+        write!(f, "}}")
+    }
+}
+
+/// Everything but the closing brace, so `AutomatonDot` can add to it.
+fn write_dot<N, E: Debug>(
+    f: &mut Formatter,
+    graph: &Graph<N, E>,
+    node_attributes: impl Fn(usize) -> &'static str,
+) -> fmt::Result {
+    writeln!(f, "digraph {{")?;
+    for i in 0..graph.node_count() {
+        writeln!(f, "    {} [ label = \"{}\"{} ]", i, i, node_attributes(i))?;
+    }
+    for edge in graph.raw_edges() {
+        let label = format!("{:?}", edge.weight)
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"");
+        writeln!(
+            f,
+            "    {} -> {} [ label = \"{}\" ]",
+            edge.source.0, edge.target.0, label
+        )?;
+    }
+    Ok(())
+}
@@ -6,13 +6,21 @@
 
 use crate::prelude::*;
 
-// I'm just keeping this around because add_to_graph below is where I originally found the segfault
-#[cfg(feature = "petgraph")]
 mod finite_automata;
-#[cfg(feature = "petgraph")]
-pub use finite_automata::{Dfa, Nfa, NfaEdge};
+mod graph;
+pub mod names;
+pub mod pipeline;
+pub mod trace;
+pub mod year_suffix;
+
+pub use finite_automata::{Dfa, Nfa};
+#[cfg(test)]
+pub(crate) use {finite_automata::NfaEdge, graph::NodeIndex};
+pub use trace::{EventLog, IrTracer, NoTrace, SequenceTrace, TraceEvent};
 
 use crate::element::*;
+use crate::parse::StyleError;
+use std::collections::HashSet;
 
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub enum EdgeData {
@@ -30,50 +38,597 @@ pub enum EdgeData {
     Accessed,
 }
 
-pub fn element_ref_ir_impl(el: &Element, db: &dyn IrDatabase, ctx: &RefContext) -> (RefIR, GroupVars) {
-    match el {
+/// What one cite has rendered so far, carried along while its IR is built in order.
+#[derive(Clone)]
+pub struct IrState<'t> {
+    /// Variables a `<substitute>` has used, which render as empty for the rest of the cite.
+    suppressed: HashSet<AnyVariable>,
+    /// Every variable that has rendered something, in order.
+    rendered: Vec<AnyVariable>,
+    /// The macros being expanded, outermost first. Parsing rejects styles whose macros call
+    /// each other in a loop, but a style built in code or a jurisdiction module can still have
+    /// one, and it should fail here rather than overflow the stack.
+    macros: Vec<String>,
+    tracer: &'t dyn IrTracer,
+    /// Expand every macro, even the ones the database has IR for, so the tracer sees inside them.
+    expand_all: bool,
+    /// How many sequences deep the builder is. See `trace`.
+    depth: usize,
+    /// What the first `<names>` with any names renders instead of them, for the bibliography's
+    /// `subsequent-author-substitute`. Taken when that element is built.
+    names_replacement: Option<String>,
+    /// A `<date>` has put the year suffix after its year, for a style that doesn't render it
+    /// anywhere itself.
+    year_suffix_taken: bool,
+    /// A cite with `suppress_author` has met its author: the first `<names>` to render anything,
+    /// its substitute included. Only that one is left out.
+    author_found: bool,
+}
+
+impl Default for IrState<'_> {
+    fn default() -> Self {
+        IrState::new(&NoTrace)
+    }
+}
+
+impl<'t> IrState<'t> {
+    pub fn new(tracer: &'t dyn IrTracer) -> Self {
+        IrState {
+            suppressed: HashSet::new(),
+            rendered: Vec::new(),
+            macros: Vec::new(),
+            tracer,
+            expand_all: false,
+            depth: 0,
+            names_replacement: None,
+            author_found: false,
+            year_suffix_taken: false,
+        }
+    }
+
+    pub fn is_suppressed(&self, var: AnyVariable) -> bool {
+        self.suppressed.contains(&var)
+    }
+
+    fn mark_rendered(&mut self, var: AnyVariable) {
+        self.rendered.push(var);
+    }
+
+    /// Passes on what a leaf element built, reporting its edge if it has one.
+    fn emit(&self, ir: RefIR) -> RefIR {
+        if let RefIR::Edge(Some(edge)) = &ir {
+            self.tracer.event(&TraceEvent::EdgeEmitted {
+                depth: self.depth,
+                edge,
+            });
+        }
+        ir
+    }
+}
+
+pub fn element_ref_ir_impl(
+    el: &Element,
+    db: &dyn IrDatabase,
+    ctx: &RefContext,
+    state: &mut IrState,
+) -> Result<(RefIR, GroupVars), StyleError> {
+    state.tracer.event(&TraceEvent::ElementEntered {
+        depth: state.depth,
+        element: el,
+    });
+    Ok(match el {
         Element::Text(text) => match text.source {
-            TextSource::Macro(ref name) => {
-                let macro_elements = ctx.style.macros.get(name).unwrap();
-                ref_sequence(db, ctx, &macro_elements)
+            TextSource::Macro(ref call) => {
+                let fragment = reusable_fragment(db, ctx, state, call)?;
+                state.tracer.event(&TraceEvent::MacroExpanded {
+                    depth: state.depth,
+                    name: &call.name,
+                    reused: fragment.is_some(),
+                });
+                if let Some(fragment) = fragment {
+                    state.rendered.extend(fragment.rendered.iter().cloned());
+                    let ir = with_affixes(fragment.ir.clone(), text.affixes.as_ref());
+                    return Ok((ir, fragment.gv));
+                }
+                let macro_elements = ctx.macro_elements(call).ok_or_else(|| {
+                    StyleError::UndefinedMacro {
+                        name: call.name.clone(),
+                        location: None,
+                    }
+                })?;
+                if let Some(ix) = state.macros.iter().position(|called| *called == call.name) {
+                    let mut chain = state.macros[ix..].to_vec();
+                    chain.push(call.name.clone());
+                    return Err(StyleError::MacroCycle { chain });
+                }
+                state.macros.push(call.name.clone());
+                let result = ref_sequence_with(db, ctx, state, macro_elements);
+                state.macros.pop();
+                let (ir, gv) = result?;
+                (with_affixes(ir, text.affixes.as_ref()), gv)
+            }
+            TextSource::Value(ref value) => {
+                (state.emit(output(value, text.affixes.as_ref())), GroupVars::Plain)
+            }
+            TextSource::Variable(StandardVariable::Ordinary(var), form) => {
+                let (ir, gv) = ordinary_ref_ir(ctx, state, var, form);
+                (with_affixes(state.emit(ir), text.affixes.as_ref()), gv)
+            }
+            TextSource::Variable(StandardVariable::Number(var), _) => {
+                let (ir, gv) = number_ref_ir(db, ctx, state, var, None);
+                (with_affixes(state.emit(ir), text.affixes.as_ref()), gv)
             }
             _ => {
                 (RefIR::Edge(None), GroupVars::new())
             }
         },
+        Element::Number(number) => {
+            let (ir, gv) = number_ref_ir(db, ctx, state, number.variable, Some(number.form));
+            (with_affixes(state.emit(ir), number.affixes.as_ref()), gv)
+        }
         Element::Label(label) => {
             let var = label.variable;
             let custom = match var {
                 NumberVariable::Locator if ctx.locator_type.is_some() => {
-                    eprintln!("added LocatorLabel");
                     Some(EdgeData::LocatorLabel)
                 }
                 _ => None,
             };
             if let Some(edge_data) = custom {
-                let edge = edge_data;
-                return (RefIR::Edge(Some(edge)), GroupVars::Important);
+                let edge = state.emit(RefIR::Edge(Some(edge_data)));
+                return Ok((
+                    with_affixes(edge, label.affixes.as_ref()),
+                    GroupVars::Important,
+                ));
             }
             (RefIR::Edge(None), GroupVars::Plain)
         }
-        _ => {
-            (RefIR::Edge(None), GroupVars::Plain)
+        Element::Group(group) => {
+            let (ir, gv) = ref_sequence_with(db, ctx, state, &group.elements)?;
+            match gv {
+                GroupVars::Missing | GroupVars::UnresolvedMissing => (RefIR::Edge(None), gv),
+                _ => match ir {
+                    RefIR::Seq(mut seq) => {
+                        seq.delimiter = group.delimiter.0.clone();
+                        seq.affixes = group.affixes.clone();
+                        (RefIR::Seq(seq), gv)
+                    }
+                    ir => (ir, gv),
+                },
+            }
+        }
+        Element::Choose(choose) => {
+            let Choose(head, rest, Else(otherwise)) = &**choose;
+            let mut saw_disambiguate = false;
+            let mut branch = None;
+            for IfThen(conditions, elements) in std::iter::once(head).chain(rest.iter()) {
+                let (matched, disambiguate) = eval_conditions(conditions, ctx);
+                saw_disambiguate |= disambiguate;
+                if matched {
+                    branch = Some(elements);
+                    break;
+                }
+            }
+            let (ir, gv) = ref_sequence_with(db, ctx, state, branch.unwrap_or(otherwise))?;
+            if saw_disambiguate && ctx.disamb_count == 0 && ir == RefIR::Edge(None) {
+                (ir, GroupVars::Unresolved)
+            } else {
+                (ir, gv)
+            }
+        }
+        Element::Names(names) if ctx.suppress_author && !state.author_found => {
+            // Names inside this one's substitute are part of the author
+            state.author_found = true;
+            let (ir, gv) = names_ref_ir(db, ctx, state, names)?;
+            if ir == RefIR::Edge(None) {
+                // Not the author after all, as it had nothing to render
+                state.author_found = false;
+                (ir, gv)
+            } else {
+                (RefIR::Edge(None), GroupVars::Missing)
+            }
         }
+        Element::Names(names) => names_ref_ir(db, ctx, state, names)?,
+        Element::Date(date) => date_ref_ir(db, ctx, state, date),
+    })
+}
+
+fn date_ref_ir(
+    db: &dyn IrDatabase,
+    ctx: &RefContext,
+    state: &mut IrState,
+    el: &DateElement,
+) -> (RefIR, GroupVars) {
+    let var = AnyVariable::Date(el.variable);
+    let date = match ctx.reference.date.get(&el.variable) {
+        Some(date) if !state.is_suppressed(var) => date,
+        _ => return (RefIR::Edge(None), GroupVars::Missing),
+    };
+    state.mark_rendered(var);
+    let locale = db.locale(&ctx.style.default_locale);
+    let (parts, delimiter) = crate::date::layout(el, &locale);
+    let mut contents = Vec::new();
+    for part in &parts {
+        let text = match crate::date::render_part(part, date, &locale) {
+            Some(text) => text,
+            None => continue,
+        };
+        let takes_suffix = part.name == DatePartName::Year
+            && ctx.year_suffix
+            && !state.year_suffix_taken
+            && !ctx.style.renders_year_suffix();
+        let ir = if takes_suffix {
+            state.year_suffix_taken = true;
+            let suffix = state.emit(RefIR::Edge(Some(EdgeData::YearSuffix)));
+            with_affixes(
+                RefIR::Seq(RefIrSeq {
+                    contents: vec![output(&text, None), suffix],
+                    ..Default::default()
+                }),
+                part.affixes.as_ref(),
+            )
+        } else {
+            output(&text, part.affixes.as_ref())
+        };
+        contents.push(state.emit(ir));
+    }
+    let seq = RefIrSeq {
+        contents,
+        affixes: el.affixes.clone(),
+        delimiter: delimiter.map_or_else(String::new, |d| d.0),
+        ..Default::default()
+    };
+    (RefIR::Seq(seq), GroupVars::Important)
+}
+
+/// The IR of a `<names>`, or of its substitute if none of its variables has names.
+fn names_ref_ir(
+    db: &dyn IrDatabase,
+    ctx: &RefContext,
+    state: &mut IrState,
+    names: &Names,
+) -> Result<(RefIR, GroupVars), StyleError> {
+    let mut name_el = match &names.name {
+        Some(local) => ctx.name_el.merge(local),
+        None => (*ctx.name_el).clone(),
+    }
+    .for_position(ctx.position);
+    if ctx.in_sort_key {
+        name_el.name_as_sort_order = Some(NameAsSortOrder::All);
+    }
+    let (vars, lists): (Vec<_>, Vec<_>) = names
+        .variables
+        .iter()
+        .filter(|&&var| !state.is_suppressed(AnyVariable::Name(var)))
+        .filter_map(|&var| Some(var).zip(crate::names::name_list(ctx.reference, var)))
+        .unzip();
+    if lists.is_empty() {
+        return match &names.substitute {
+            Some(substitute) => substitute_ref_ir(db, ctx, state, names, substitute),
+            None => Ok((RefIR::Edge(None), GroupVars::Missing)),
+        };
+    }
+    for var in vars {
+        state.mark_rendered(AnyVariable::Name(var));
+    }
+    let text = if let Some(replacement) = state.names_replacement.take() {
+        replacement
+    } else if name_el.form == Some(NameForm::Count) {
+        // One number for all the variables together
+        let count: usize = lists
+            .iter()
+            .map(|list| crate::names::name_count(&name_el, list, &ctx.name_disamb))
+            .sum();
+        count.to_string()
+    } else {
+        let locale = db.locale(&ctx.style.default_locale);
+        let cx = crate::names::NameContext::new(ctx.style, &locale, ctx.in_sort_key);
+        let rendered: Vec<String> = lists
+            .iter()
+            .map(|list| crate::names::render_names(&cx, &name_el, list, &ctx.name_disamb))
+            .collect();
+        let delimiter = names
+            .delimiter
+            .as_ref()
+            .or(ctx.names_delimiter.as_ref())
+            .map_or("", |d| d.0.as_str());
+        rendered.join(delimiter)
+    };
+    let ir = output(&text, names.affixes.as_ref());
+    Ok((state.emit(ir), GroupVars::Important))
+}
+
+/// A `cite_independent` macro's IR for a reference, built once with no cite at all.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MacroFragment {
+    pub ir: RefIR,
+    pub gv: GroupVars,
+    /// The variables it rendered, for `IrState::rendered`.
+    rendered: Vec<AnyVariable>,
+}
+
+/// The IR of a `cite_independent` macro for the cite's reference, as the database has it, or
+/// `None` if `element_ref_ir_impl` has to expand the macro itself: because the reference has a
+/// jurisdiction module, whose macros are never compiled, or because a `<substitute>` earlier in
+/// the cite has suppressed something the macro renders.
+fn reusable_fragment(
+    db: &dyn IrDatabase,
+    ctx: &RefContext,
+    state: &IrState,
+    call: &MacroRef,
+) -> Result<Option<Arc<MacroFragment>>, StyleError> {
+    let id = match call.id {
+        Some(id) if !state.expand_all && ctx.module.is_none() => id,
+        _ => return Ok(None),
+    };
+    if !ctx.style.macros.get_by_id(id).is_some_and(|m| m.cite_independent) {
+        return Ok(None);
     }
+    Ok(db
+        .macro_fragment(&ctx.reference.id, id)?
+        .filter(|fragment| !fragment.rendered.iter().any(|&var| state.is_suppressed(var))))
 }
-fn ref_sequence<'c>(
+
+/// Builds what `reusable_fragment` reuses. The context is a first cite with no locator, which
+/// makes no difference to a `cite_independent` macro.
+pub fn macro_fragment(
+    db: &dyn IrDatabase,
+    style: &Style,
+    reference: &Reference,
+    id: MacroId,
+) -> Result<Option<MacroFragment>, StyleError> {
+    let elements = match style.macros.get_by_id(id).filter(|m| m.cite_independent) {
+        Some(m) => &m.elements,
+        None => return Ok(None),
+    };
+    let ctx = RefContext {
+        style,
+        reference,
+        locator_type: None,
+        position: Position::First,
+        year_suffix: false,
+        names_delimiter: style.inherited_names_delimiter(&style.citation.names_delimiter),
+        name_el: Arc::new(style.inherited_name(&style.citation.name_inheritance)),
+        disamb_count: 0,
+        name_disamb: names::NameDisamb::default(),
+        in_sort_key: false,
+        suppress_author: false,
+        hereinafter: db.hereinafter(&reference.id),
+        module: crate::jurisdiction::module_for(db, style, reference),
+    };
+    if ctx.module.is_some() {
+        return Ok(None);
+    }
+    let mut state = IrState::new(db.tracer());
+    let (ir, gv) = ref_sequence_with(db, &ctx, &mut state, elements)?;
+    Ok(Some(MacroFragment {
+        ir,
+        gv,
+        rendered: state.rendered,
+    }))
+}
+
+/// Renders the first element of `<substitute>` that has any output, and suppresses the
+/// variables it used.
+fn substitute_ref_ir(
+    db: &dyn IrDatabase,
+    ctx: &RefContext,
+    state: &mut IrState,
+    names: &Names,
+    substitute: &Substitute,
+) -> Result<(RefIR, GroupVars), StyleError> {
+    for el in crate::names::substitute_elements(names, substitute) {
+        let start = state.rendered.len();
+        let (ir, gv) = element_ref_ir_impl(&el, db, ctx, state)?;
+        if ir != RefIR::Edge(None) {
+            let used: Vec<_> = state.rendered[start..].to_vec();
+            state.suppressed.extend(used);
+            return Ok((ir, gv));
+        }
+    }
+    Ok((RefIR::Edge(None), GroupVars::Missing))
+}
+
+fn ordinary_ref_ir(
+    ctx: &RefContext,
+    state: &mut IrState,
+    var: Variable,
+    form: VariableForm,
+) -> (RefIR, GroupVars) {
+    if var == Variable::YearSuffix {
+        return if ctx.year_suffix {
+            (RefIR::Edge(Some(EdgeData::YearSuffixExplicit)), GroupVars::Important)
+        } else {
+            (RefIR::Edge(None), GroupVars::UnresolvedMissing)
+        };
+    }
+    let short = match (var, form) {
+        (Variable::Title, VariableForm::Short) => Some(Variable::TitleShort),
+        (Variable::ContainerTitle, VariableForm::Short) => Some(Variable::ContainerTitleShort),
+        _ => None,
+    };
+    if state.is_suppressed(AnyVariable::Ordinary(var)) {
+        return (RefIR::Edge(None), GroupVars::Missing);
+    }
+    let value = match var {
+        Variable::Hereinafter => ctx.hereinafter.as_deref(),
+        Variable::LocatorExtra => None,
+        _ => short
+            .and_then(|s| ctx.reference.ordinary.get(&s))
+            .or_else(|| ctx.reference.ordinary.get(&var)),
+    }
+    .filter(|v| !v.is_empty());
+    match value {
+        Some(value) => {
+            state.mark_rendered(AnyVariable::Ordinary(var));
+            (output(value, None), GroupVars::Important)
+        }
+        None => (RefIR::Edge(None), GroupVars::Missing),
+    }
+}
+
+/// Locators and the like are not known until a cite comes along, so they get placeholder edges.
+/// `form` is only given for a `<number>`; a `<text>` writes the value out as it is.
+fn number_ref_ir(
+    db: &dyn IrDatabase,
+    ctx: &RefContext,
+    state: &mut IrState,
+    var: NumberVariable,
+    form: Option<NumericForm>,
+) -> (RefIR, GroupVars) {
+    if state.is_suppressed(AnyVariable::Number(var)) {
+        return (RefIR::Edge(None), GroupVars::Missing);
+    }
+    let edge = match var {
+        NumberVariable::Locator if ctx.locator_type.is_some() => Some(EdgeData::Locator),
+        NumberVariable::Locator => None,
+        NumberVariable::CitationNumber => Some(EdgeData::CitationNumber),
+        NumberVariable::FirstReferenceNoteNumber if ctx.position != Position::First => {
+            Some(EdgeData::Frnn)
+        }
+        NumberVariable::FirstReferenceNoteNumber => None,
+        _ => ctx
+            .reference
+            .number
+            .get(&var)
+            .filter(|v| !v.is_empty())
+            .map(|v| {
+                let locale = db.locale(&ctx.style.default_locale);
+                EdgeData::Output(match (var, form) {
+                    (NumberVariable::Page, _) => {
+                        crate::page_range::format_pages(ctx.style, &locale, v)
+                    }
+                    (_, Some(form)) => crate::numeric::render_numeric(v, form, &locale),
+                    (_, None) => v.clone(),
+                })
+            }),
+    };
+    match edge {
+        Some(edge) => {
+            state.mark_rendered(AnyVariable::Number(var));
+            (RefIR::Edge(Some(edge)), GroupVars::Important)
+        }
+        None => (RefIR::Edge(None), GroupVars::Missing),
+    }
+}
+
+/// Returns whether the conditions matched, and whether any of them was `disambiguate`.
+pub(crate) fn eval_conditions(conditions: &Conditions, ctx: &RefContext) -> (bool, bool) {
+    let Conditions(match_type, conds) = conditions;
+    let disambiguate = conds.iter().any(|c| matches!(c, Cond::Disambiguate(_)));
+    let mut results = conds.iter().map(|cond| match cond {
+        Cond::Type(csl_type) => ctx.reference.csl_type == *csl_type,
+        Cond::Variable(var) => ctx.has_variable(*var),
+        Cond::Position(pos) => ctx.position.matches(*pos),
+        Cond::IsNumeric(var) => ctx.is_numeric(*var),
+        Cond::Disambiguate(d) => (ctx.disamb_count > 0) == *d,
+        Cond::Locator(locator_type) => ctx.locator_type.as_ref() == Some(locator_type),
+    });
+    let matched = match match_type {
+        Match::All => results.all(|x| x),
+        Match::Any => results.any(|x| x),
+        Match::None => !results.any(|x| x),
+    };
+    (matched, disambiguate)
+}
+
+fn output(value: &str, affixes: Option<&Affixes>) -> RefIR {
+    let s = match affixes {
+        Some(a) => format!("{}{}{}", a.prefix, value, a.suffix),
+        None => value.to_string(),
+    };
+    RefIR::Edge(if s.is_empty() { None } else { Some(EdgeData::Output(s)) })
+}
+
+fn with_affixes(ir: RefIR, affixes: Option<&Affixes>) -> RefIR {
+    match (ir, affixes) {
+        (RefIR::Edge(None), _) => RefIR::Edge(None),
+        (ir, None) => ir,
+        (ir, Some(affixes)) => RefIR::Seq(RefIrSeq {
+            contents: vec![ir],
+            affixes: Some(affixes.clone()),
+            ..Default::default()
+        }),
+    }
+}
+
+/// Builds the IR for a whole layout, or anything else that starts a cite afresh.
+///
+/// Fails if the elements call a macro the style doesn't have, or macros that call each other in
+/// a loop. Parsing a style checks for both, so this only happens with styles built some other
+/// way, or with a jurisdiction module.
+pub(crate) fn ref_sequence<'c>(
     db: &dyn IrDatabase,
     ctx: &RefContext<'c>,
     els: &[Element],
-) -> (RefIR, GroupVars) {
+) -> Result<(RefIR, GroupVars), StyleError> {
+    ref_sequence_with(db, ctx, &mut IrState::new(db.tracer()), els)
+}
+
+/// Like `ref_sequence`, but the first `<names>` that has any names to render puts
+/// `replacement` in their place. That is the element `names::first_names` finds.
+pub(crate) fn ref_sequence_replacing_names<'c>(
+    db: &dyn IrDatabase,
+    ctx: &RefContext<'c>,
+    els: &[Element],
+    replacement: String,
+) -> Result<(RefIR, GroupVars), StyleError> {
+    let mut state = IrState {
+        names_replacement: Some(replacement),
+        ..IrState::new(db.tracer())
+    };
+    ref_sequence_with(db, ctx, &mut state, els)
+}
+
+/// Like `ref_sequence`, but reports to `tracer` instead of the database's tracer. Macros are
+/// always expanded rather than reused from the database, so the tracer sees all of them.
+pub fn ref_sequence_traced<'c>(
+    db: &dyn IrDatabase,
+    ctx: &RefContext<'c>,
+    els: &[Element],
+    tracer: &dyn IrTracer,
+) -> Result<(RefIR, GroupVars), StyleError> {
+    let mut state = IrState {
+        expand_all: true,
+        ..IrState::new(tracer)
+    };
+    ref_sequence_with(db, ctx, &mut state, els)
+}
+
+fn ref_sequence_with<'c>(
+    db: &dyn IrDatabase,
+    ctx: &RefContext<'c>,
+    state: &mut IrState,
+    els: &[Element],
+) -> Result<(RefIR, GroupVars), StyleError> {
+    state.tracer.event(&TraceEvent::SequenceEntered {
+        depth: state.depth,
+        elements: els,
+    });
+    state.depth += 1;
+    let result = build_sequence(db, ctx, state, els);
+    state.depth -= 1;
+    if let Ok((_, gv)) = &result {
+        state.tracer.event(&TraceEvent::SequenceExited {
+            depth: state.depth,
+            gv: *gv,
+        });
+    }
+    result
+}
+
+fn build_sequence<'c>(
+    db: &dyn IrDatabase,
+    ctx: &RefContext<'c>,
+    state: &mut IrState,
+    els: &[Element],
+) -> Result<(RefIR, GroupVars), StyleError> {
 
     let mut contents = Vec::with_capacity(els.len());
     let mut overall_gv = GroupVars::new();
 
     for el in els {
-        let (got_ir, gv) =
-            crate::disamb::element_ref_ir_impl(el, db, ctx);
-            eprintln!("{:?}", got_ir);
+        let (got_ir, gv) = crate::disamb::element_ref_ir_impl(el, db, ctx, state)?;
         match got_ir {
             RefIR::Edge(None) => {
                 overall_gv = overall_gv.neighbour(gv);
@@ -83,12 +638,17 @@ fn ref_sequence<'c>(
                 overall_gv = overall_gv.neighbour(gv)
             }
         }
+        state.tracer.event(&TraceEvent::GroupVarsMerged {
+            depth: state.depth,
+            element: gv,
+            merged: overall_gv,
+        });
     }
 
     if !contents.iter().any(|x| *x != RefIR::Edge(None)) {
-        (RefIR::Edge(None), overall_gv)
+        Ok((RefIR::Edge(None), overall_gv))
     } else {
-        (
+        Ok((
             RefIR::Seq(RefIrSeq {
                 contents,
                 formatting: Default::default(),
@@ -97,106 +657,6 @@ fn ref_sequence<'c>(
                 text_case: Default::default(),
             }),
             overall_gv,
-        )
-    }
-}
-
-#[cfg(feature = "petgraph")]
-use petgraph::{visit::EdgeRef, graph::NodeIndex};
-#[cfg(feature = "petgraph")]
-pub fn graph_with_stack(
-    fmt: &Markup,
-    nfa: &mut Nfa,
-    formatting: Option<Formatting>,
-    affixes: Option<&Affixes>,
-    mut spot: NodeIndex,
-    f: impl FnOnce(&mut Nfa, NodeIndex) -> NodeIndex,
-) -> NodeIndex {
-    let stack = fmt.tag_stack(formatting.unwrap_or_else(Default::default), None);
-    let mut open_tags = String::new();
-    let mut close_tags = String::new();
-    fmt.stack_preorder(&mut open_tags, &stack);
-    fmt.stack_postorder(&mut close_tags, &stack);
-    let mkedge = |s: String| {
-        RefIR::Edge(if !s.is_empty() {
-            Some(EdgeData::Output(s))
-        } else {
-            None
-        })
-    };
-    let mkedge_esc = |s: &str| {
-        RefIR::Edge(if !s.is_empty() {
-            Some(EdgeData::Output(
-                // TODO: fmt.ingest
-                fmt.output_in_context(fmt.plain(s), Default::default(), None),
-            ))
-        } else {
-            None
-        })
-    };
-    let open_tags = &mkedge(open_tags);
-    let close_tags = &mkedge(close_tags);
-    if let Some(pre) = affixes.as_ref().map(|a| mkedge_esc(&*a.prefix)) {
-        spot = add_to_graph(fmt, nfa, &pre, spot);
-    }
-    spot = add_to_graph(fmt, nfa, open_tags, spot);
-    spot = f(nfa, spot);
-    spot = add_to_graph(fmt, nfa, close_tags, spot);
-    if let Some(suf) = affixes.as_ref().map(|a| mkedge_esc(&*a.suffix)) {
-        spot = add_to_graph(fmt, nfa, &suf, spot);
-    }
-    spot
-}
-
-#[cfg(feature = "petgraph")]
-pub fn add_to_graph(
-    fmt: &Markup,
-    nfa: &mut Nfa,
-    ir: &RefIR,
-    spot: NodeIndex,
-) -> NodeIndex {
-    match ir {
-        RefIR::Edge(None) => spot,
-        RefIR::Edge(Some(e)) => {
-            let to = nfa.graph.add_node(());
-            nfa.graph.add_edge(spot, to, NfaEdge::Token(e.clone()));
-            to
-        }
-        RefIR::Seq(ref seq) => {
-            let RefIrSeq {
-                formatting,
-                ref contents,
-                ref affixes,
-                ref delimiter,
-                // TODO: use these
-            } = *seq;
-            let affixes = affixes.as_ref();
-            let mkedge = |s: &str| {
-                RefIR::Edge(if !s.is_empty() {
-                    Some(EdgeData::Output(fmt.output_in_context(
-                        fmt.plain(s),
-                        Default::default(),
-                        None,
-                    )))
-                } else {
-                    None
-                })
-            };
-            let delim = &mkedge(&*delimiter);
-            graph_with_stack(fmt, nfa, formatting, affixes, spot, |nfa, mut spot| {
-                let mut seen = false;
-                for x in contents {
-                    if !matches!(x, RefIR::Edge(None)) {
-                        if seen {
-                            spot = add_to_graph(fmt, nfa, delim, spot);
-                        }
-                        seen = true;
-                    }
-                    spot = add_to_graph(fmt, nfa, x, spot);
-                }
-                spot
-            })
-        }
+        ))
     }
 }
-
@@ -0,0 +1,99 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Copyright © 2019 Corporation for Digital Scholarship
+
+use crate::element::GivenNameDisambiguationRule;
+use crate::names::initials;
+use crate::reference::{PersonName, Reference};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// How much of a given name to show. A name never renders with less than its `<name form>` asks
+/// for; these only ever expand it.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum GivenLevel {
+    Family,
+    Initials,
+    Full,
+}
+
+/// The name-related half of a cite's disambiguation state. Lives on `RefContext`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct NameDisamb {
+    /// Extra names to show beyond `et-al-use-first`. (`disambiguate-add-names`)
+    pub add_names: u32,
+    /// Per-position given name expansions, applied to the nth name of every name variable.
+    /// (`givenname-disambiguation-rule="by-cite"`)
+    pub given: Vec<GivenLevel>,
+    /// Expansions for names that are ambiguous anywhere in the document. These apply to every
+    /// cite. (The other four `givenname-disambiguation-rule`s.)
+    pub global: Arc<HashMap<PersonName, GivenLevel>>,
+    /// Restricts `global` to the first name in each list, for the `primary-name` rules.
+    pub primary_only: bool,
+}
+
+impl NameDisamb {
+    pub fn level_for(&self, index: usize, name: &PersonName) -> Option<GivenLevel> {
+        let by_cite = self.given.get(index).cloned();
+        let global = if index == 0 || !self.primary_only {
+            self.global.get(name).cloned()
+        } else {
+            None
+        };
+        by_cite.max(global)
+    }
+}
+
+/// For the document-wide rules, works out which names share a family name with a different
+/// person, and how far each must be expanded to tell them apart. Returns an empty map for
+/// `by-cite`, which is handled cite-by-cite instead.
+pub fn global_name_expansions(
+    rule: GivenNameDisambiguationRule,
+    references: &[Reference],
+) -> HashMap<PersonName, GivenLevel> {
+    use GivenNameDisambiguationRule::*;
+    let (primary_only, max) = match rule {
+        ByCite => return HashMap::new(),
+        AllNames => (false, GivenLevel::Full),
+        AllNamesWithInitials => (false, GivenLevel::Initials),
+        PrimaryName => (true, GivenLevel::Full),
+        PrimaryNameWithInitials => (true, GivenLevel::Initials),
+    };
+    let mut by_family: HashMap<&str, Vec<&PersonName>> = HashMap::new();
+    for reference in references {
+        for list in reference.name.values() {
+            let considered = if primary_only {
+                &list[..1.min(list.len())]
+            } else {
+                &list[..]
+            };
+            for name in considered {
+                if let (Some(family), None) = (&name.family, &name.literal) {
+                    let people = by_family.entry(family.as_str()).or_default();
+                    if !people.contains(&name) {
+                        people.push(name);
+                    }
+                }
+            }
+        }
+    }
+    let mut expansions = HashMap::new();
+    for people in by_family.values().filter(|ps| ps.len() > 1) {
+        for &person in people {
+            let inits = person.given.as_deref().map(|g| initials(g, "."));
+            let initials_suffice = people
+                .iter()
+                .filter(|&&other| other != person)
+                .all(|other| other.given.as_deref().map(|g| initials(g, ".")) != inits);
+            let level = if initials_suffice {
+                GivenLevel::Initials
+            } else {
+                GivenLevel::Full
+            };
+            expansions.insert(person.clone(), level.min(max));
+        }
+    }
+    expansions
+}
@@ -0,0 +1,1065 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Copyright © 2019 Corporation for Digital Scholarship
+
+//! The disambiguation loop. Each reference gets a DFA that accepts every rendering a cite of it
+//! could possibly produce, at any level of name expansion. A cite is ambiguous if its own output
+//! is accepted by any other reference's DFA. Ambiguous cites are then put through each of the
+//! methods the style enables, in the order the spec gives:
+//!
+//! 1. `disambiguate-add-names`
+//! 2. `disambiguate-add-givenname`, per the `givenname-disambiguation-rule`
+//! 3. `disambiguate="true"` conditionals
+//! 4. `disambiguate-add-year-suffix`
+//!
+//! until each is unique.
+//!
+//! The first three only compare a cite against the library, so the database runs them one
+//! cluster at a time. Year suffixes are the only step that looks at the whole document.
+
+use super::names::{GivenLevel, NameDisamb};
+use super::year_suffix::YearSuffixAllocator;
+use super::trace::SequenceRecorder;
+use super::{ref_sequence, ref_sequence_traced, Dfa, Nfa, SequenceTrace};
+use crate::element::*;
+use crate::jurisdiction::module_for;
+use crate::locale::Locale;
+use crate::page_range::format_pages;
+use crate::parse::StyleError;
+use crate::names::{first_names, NamesRun};
+use crate::prelude::*;
+use crate::reference::Reference;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+/// One cite to disambiguate, with the bits of context that come from the cite rather than the
+/// reference.
+#[derive(Debug, Clone)]
+pub struct CiteInput<'a> {
+    pub id: CiteId,
+    pub reference: &'a Reference,
+    pub position: Position,
+    pub locator: Option<String>,
+    pub locator_type: Option<LocatorType>,
+}
+
+/// Which step of the process made a cite unique.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum DisambStep {
+    /// It was never ambiguous.
+    NotAmbiguous,
+    AddNames,
+    AddGivenName,
+    /// Rendering `disambiguate="true"` branches.
+    Conditionals,
+    YearSuffix,
+    /// Still ambiguous after everything the style allows.
+    Unresolved,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DisambResult {
+    pub id: CiteId,
+    pub ref_id: String,
+    pub resolved_by: DisambStep,
+    pub name_disamb: NameDisamb,
+    pub disamb_count: u32,
+    pub year_suffix: Option<String>,
+    pub output: String,
+}
+
+/// Which DFA of a reference to compare a cite against. A DFA covers every rendering of one shape
+/// of cite, so it only has to be built again when the document decides something that changes
+/// the renderings.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct DfaKey {
+    pub position: Position,
+    pub locator_type: Option<LocatorType>,
+    /// Whether names get the expansions of a global `givenname-disambiguation-rule`.
+    pub global_names: bool,
+    /// Whether the reference's year suffix, if it has one, is written out.
+    pub year_suffix: bool,
+}
+
+impl DfaKey {
+    /// A first cite with no locator, before anything document-wide is known.
+    pub fn first() -> Self {
+        DfaKey {
+            position: Position::First,
+            locator_type: None,
+            global_names: false,
+            year_suffix: false,
+        }
+    }
+}
+
+/// A cite after every method but year suffixes, which is as far as it gets without looking at
+/// the rest of the document.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NamedCite {
+    pub id: CiteId,
+    pub ref_id: String,
+    state: CiteState,
+    /// `None` while it's still ambiguous.
+    step: Option<DisambStep>,
+    /// The other references it could still be confused with.
+    ambiguity: Vec<String>,
+    /// Without a year suffix.
+    output: String,
+}
+
+impl NamedCite {
+    /// The result for a cite that doesn't get a year suffix.
+    pub fn without_year_suffix(&self) -> DisambResult {
+        DisambResult {
+            id: self.id,
+            ref_id: self.ref_id.clone(),
+            resolved_by: self.step.unwrap_or(DisambStep::Unresolved),
+            name_disamb: self.state.name_disamb.clone(),
+            disamb_count: self.state.disamb_count,
+            year_suffix: None,
+            output: self.output.clone(),
+        }
+    }
+}
+
+/// Disambiguates each of `cites` by names and `disambiguate="true"`, against every other
+/// reference in the library. A cite the style can't render gets the reason instead of a result,
+/// and doesn't stop the others.
+pub fn disambiguate_names(
+    db: &dyn IrDatabase,
+    cites: &[CiteInput],
+) -> Vec<Result<NamedCite, StyleError>> {
+    let style = db.style();
+    let disambiguator = Disambiguator::new(db, &style);
+    cites
+        .iter()
+        .map(|cite| disambiguator.disambiguate_names(cite))
+        .collect()
+}
+
+/// Letters the references that names couldn't tell apart, in bibliography order within each
+/// group of references that could be confused with each other.
+pub fn year_suffixes<'n>(
+    bibliography_order: &[String],
+    named: impl IntoIterator<Item = &'n NamedCite>,
+) -> HashMap<String, String> {
+    let mut allocator = YearSuffixAllocator::new(bibliography_order);
+    // A cite that was never ambiguous would otherwise make a group of one, and get a suffix
+    for cite in named.into_iter().filter(|cite| !cite.ambiguity.is_empty()) {
+        let group = std::iter::once(&cite.ref_id).chain(&cite.ambiguity);
+        allocator.ambiguous_group(group.map(String::as_str));
+    }
+    allocator.allocate()
+}
+
+/// Finishes off a cite whose reference got `year_suffix`. If the cite was still ambiguous, it
+/// is checked again against the other references with their suffixes.
+pub fn add_year_suffix(
+    db: &dyn IrDatabase,
+    cite: &CiteInput,
+    named: &NamedCite,
+    year_suffix: &str,
+) -> Result<DisambResult, StyleError> {
+    let style = db.style();
+    let disambiguator = Disambiguator::new(db, &style);
+    let mut step = named.step;
+    if step.is_none()
+        && disambiguator
+            .ambiguous_with(cite, &named.state, Some(year_suffix))?
+            .is_empty()
+    {
+        step = Some(DisambStep::YearSuffix);
+    }
+    Ok(DisambResult {
+        resolved_by: step.unwrap_or(DisambStep::Unresolved),
+        year_suffix: Some(year_suffix.into()),
+        output: disambiguator.cite_output(cite, &named.state, Some(year_suffix))?,
+        ..named.without_year_suffix()
+    })
+}
+
+/// The `givenname-disambiguation-rule`, if the style adds given names and the rule is one of
+/// those that expand names wherever they appear rather than cite by cite.
+pub fn global_rule(style: &Style) -> Option<GivenNameDisambiguationRule> {
+    let citation = &style.citation;
+    let rule = citation.givenname_disambiguation_rule;
+    if citation.disambiguate_add_givenname && rule != GivenNameDisambiguationRule::ByCite {
+        Some(rule)
+    } else {
+        None
+    }
+}
+
+/// The IR for a first cite of `reference` with no locator, before any disambiguation.
+pub fn base_ref_ir(db: &dyn IrDatabase, reference: &Reference) -> Result<RefIR, StyleError> {
+    let style = db.style();
+    Disambiguator::new(db, &style).render(
+        reference,
+        Position::First,
+        None,
+        &CiteState::default(),
+        None,
+    )
+}
+
+/// The DFA other references' cites of shape `key` are compared against.
+pub fn ref_dfa(
+    db: &dyn IrDatabase,
+    reference: &Reference,
+    key: &DfaKey,
+) -> Result<Dfa, StyleError> {
+    let style = db.style();
+    Disambiguator::new(db, &style).build_dfa(reference, key)
+}
+
+/// How `minimal debug` shows a reference's IR being built, for a first cite with no locator.
+/// The IR itself is the database's `ref_ir`.
+#[derive(Debug, Clone)]
+pub struct RefDebug {
+    /// Every level of the IR builder, outermost first.
+    pub levels: Vec<SequenceTrace>,
+    /// Every way the cite could render while being disambiguated, before minimising. Empty when
+    /// there's no IR.
+    pub nfa: Nfa,
+    pub dfa: Dfa,
+}
+
+pub fn debug_reference(db: &dyn IrDatabase, reference: &Reference) -> RefDebug {
+    let style = db.style();
+    let disambiguator = Disambiguator::new(db, &style);
+    let ctx = disambiguator.context(
+        reference,
+        Position::First,
+        None,
+        &CiteState::default(),
+        false,
+    );
+    let layout = style.citation.layout_for(reference.language());
+    let recorder = SequenceRecorder::default();
+    let nfa = ref_sequence_traced(db, &ctx, &layout.elements, &recorder)
+        .and_then(|_| disambiguator.build_nfa(reference, &DfaKey::first()))
+        .unwrap_or_else(|_| Nfa::new());
+    RefDebug {
+        levels: recorder.into_levels(),
+        dfa: nfa.clone().brzozowski_minimise(),
+        nfa,
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+struct CiteState {
+    name_disamb: NameDisamb,
+    disamb_count: u32,
+}
+
+struct Disambiguator<'a> {
+    db: &'a dyn IrDatabase,
+    style: &'a Style,
+    locale: Arc<Locale>,
+    /// What every `<names>` in the citation inherits.
+    name_el: Arc<Name>,
+    names_delimiter: Option<Delimiter>,
+}
+
+impl<'a> Disambiguator<'a> {
+    fn new(db: &'a dyn IrDatabase, style: &'a Style) -> Self {
+        Disambiguator {
+            db,
+            style,
+            locale: db.locale(&style.default_locale),
+            name_el: Arc::new(style.inherited_name(&style.citation.name_inheritance)),
+            names_delimiter: style.inherited_names_delimiter(&style.citation.names_delimiter),
+        }
+    }
+
+    /// Everything up to year suffixes, which is all a cite needs apart from the rest of the
+    /// document.
+    fn disambiguate_names(&self, cite: &CiteInput) -> Result<NamedCite, StyleError> {
+        let citation = &self.style.citation;
+        let mut state = CiteState::default();
+        let mut ambiguity = self.ambiguous_with(cite, &state, None)?;
+        let mut step = if ambiguity.is_empty() {
+            Some(DisambStep::NotAmbiguous)
+        } else {
+            None
+        };
+
+        if citation.disambiguate_add_names && !ambiguity.is_empty() {
+            let max = max_names(cite.reference) as u32;
+            let mut best = (ambiguity.clone(), 0);
+            for extra in 1..=max {
+                state.name_disamb.add_names = extra;
+                let amb = self.ambiguous_with(cite, &state, None)?;
+                if amb.len() < best.0.len() {
+                    best = (amb, extra);
+                }
+                if best.0.is_empty() {
+                    break;
+                }
+            }
+            state.name_disamb.add_names = best.1;
+            ambiguity = best.0;
+            if ambiguity.is_empty() {
+                step = Some(DisambStep::AddNames);
+            }
+        }
+
+        if citation.disambiguate_add_givenname {
+            if global_rule(self.style).is_none() {
+                if !ambiguity.is_empty() {
+                    self.expand_given_by_cite(cite, &mut state, &mut ambiguity)?;
+                    if ambiguity.is_empty() {
+                        step = Some(DisambStep::AddGivenName);
+                    }
+                }
+            } else {
+                // These apply to every cite, ambiguous or not, because they're about names that
+                // are ambiguous, not cites.
+                self.apply_global(&mut state);
+                let was_ambiguous = !ambiguity.is_empty();
+                ambiguity = self.ambiguous_with(cite, &state, None)?;
+                if was_ambiguous && ambiguity.is_empty() {
+                    step = Some(DisambStep::AddGivenName);
+                }
+            }
+        }
+
+        if !ambiguity.is_empty() {
+            state.disamb_count = 1;
+            ambiguity = self.ambiguous_with(cite, &state, None)?;
+            if ambiguity.is_empty() {
+                step = Some(DisambStep::Conditionals);
+            }
+        }
+        Ok(NamedCite {
+            id: cite.id,
+            ref_id: cite.reference.id.clone(),
+            output: self.cite_output(cite, &state, None)?,
+            state,
+            step,
+            ambiguity,
+        })
+    }
+
+    /// Expands given names one at a time, first to initials and then in full, keeping whichever
+    /// expansion left the fewest other references matching.
+    fn expand_given_by_cite(
+        &self,
+        cite: &CiteInput,
+        state: &mut CiteState,
+        ambiguity: &mut Vec<String>,
+    ) -> Result<(), StyleError> {
+        let mut best = (ambiguity.clone(), state.name_disamb.given.clone());
+        let n = max_names(cite.reference);
+        'names: for i in 0..n {
+            for &level in &[GivenLevel::Initials, GivenLevel::Full] {
+                let given = &mut state.name_disamb.given;
+                if given.len() <= i {
+                    given.resize(i + 1, GivenLevel::Family);
+                }
+                given[i] = level;
+                let amb = self.ambiguous_with(cite, state, None)?;
+                if amb.len() < best.0.len() {
+                    best = (amb, state.name_disamb.given.clone());
+                }
+                if best.0.is_empty() {
+                    break 'names;
+                }
+            }
+        }
+        *ambiguity = best.0;
+        state.name_disamb.given = best.1;
+        Ok(())
+    }
+
+    fn apply_global(&self, state: &mut CiteState) {
+        state.name_disamb.global = self.db.global_names();
+        state.name_disamb.primary_only = matches!(
+            global_rule(self.style),
+            Some(GivenNameDisambiguationRule::PrimaryName)
+                | Some(GivenNameDisambiguationRule::PrimaryNameWithInitials)
+        );
+    }
+
+    /// The ids of the other references whose DFAs accept this cite's output.
+    fn ambiguous_with(
+        &self,
+        cite: &CiteInput,
+        state: &CiteState,
+        year_suffix: Option<&str>,
+    ) -> Result<Vec<String>, StyleError> {
+        let ir = self.render(
+            cite.reference,
+            cite.position,
+            cite.locator_type.clone(),
+            state,
+            year_suffix,
+        )?;
+        let tokens = self.match_tokens(&ir, cite.reference, year_suffix);
+        let key = DfaKey {
+            position: cite.position,
+            locator_type: cite.locator_type.clone(),
+            global_names: !state.name_disamb.global.is_empty(),
+            year_suffix: year_suffix.is_some(),
+        };
+        let mut matched = Vec::new();
+        for id in self.db.reference_ids().iter() {
+            if *id == cite.reference.id {
+                continue;
+            }
+            // A reference the style can't render can't be confused with anything; its own cites
+            // report why.
+            if let Some(Ok(dfa)) = self.db.ref_dfa(id, key.clone()) {
+                if dfa.accepts(&tokens) {
+                    matched.push(id.clone());
+                }
+            }
+        }
+        Ok(matched)
+    }
+
+    /// Every rendering a cite of `reference` could produce, however far its names get expanded
+    /// and whether or not `disambiguate="true"` has kicked in. That includes the names cut short
+    /// by both the first and the subsequent et-al settings, since the same reference cited
+    /// elsewhere in the document could show either.
+    fn build_dfa(&self, reference: &Reference, key: &DfaKey) -> Result<Dfa, StyleError> {
+        Ok(self.build_nfa(reference, key)?.brzozowski_minimise())
+    }
+
+    fn build_nfa(&self, reference: &Reference, key: &DfaKey) -> Result<Nfa, StyleError> {
+        let year_suffix = if key.year_suffix {
+            self.db.year_suffix(&reference.id)
+        } else {
+            None
+        };
+        let (add_names_range, given_range) = self.name_expansions(reference);
+        // Deduplicated, but kept in order so the DFA comes out the same every time
+        let mut seen = HashSet::new();
+        let mut sequences = Vec::new();
+        for &position in &et_al_positions(key.position) {
+            for disamb_count in 0..=1 {
+                for add_names in add_names_range.clone() {
+                    for given in given_range.iter().cloned() {
+                        let mut state = CiteState {
+                            name_disamb: NameDisamb {
+                                add_names,
+                                given,
+                                ..Default::default()
+                            },
+                            disamb_count,
+                        };
+                        if key.global_names {
+                            self.apply_global(&mut state);
+                        }
+                        let ir = self.render(
+                            reference,
+                            position,
+                            key.locator_type.clone(),
+                            &state,
+                            year_suffix.as_deref(),
+                        )?;
+                        let tokens = self.match_tokens(&ir, reference, year_suffix.as_deref());
+                        if seen.insert(tokens.clone()) {
+                            sequences.push(tokens);
+                        }
+                    }
+                }
+            }
+        }
+        let mut nfa = Nfa::new();
+        for tokens in sequences {
+            nfa.add_complete_sequence(tokens);
+        }
+        Ok(nfa)
+    }
+
+    /// The names a cite of `reference` can be left with by the methods the style enables: names
+    /// added, and given names expanded cite by cite. A global rule's expansions come from the
+    /// `DfaKey` instead. Each method can go as far as the longest list of names, so a style with
+    /// neither renders every cite's names one way rather than in every combination.
+    fn name_expansions(
+        &self,
+        reference: &Reference,
+    ) -> (std::ops::RangeInclusive<u32>, Vec<Vec<GivenLevel>>) {
+        let citation = &self.style.citation;
+        let n = max_names(reference);
+        let add_names = if citation.disambiguate_add_names {
+            0..=n as u32
+        } else {
+            0..=0
+        };
+        let given = if citation.disambiguate_add_givenname && global_rule(self.style).is_none() {
+            given_patterns(n)
+        } else {
+            vec![Vec::new()]
+        };
+        (add_names, given)
+    }
+
+    fn render(
+        &self,
+        reference: &Reference,
+        position: Position,
+        locator_type: Option<LocatorType>,
+        state: &CiteState,
+        year_suffix: Option<&str>,
+    ) -> Result<RefIR, StyleError> {
+        let ctx = self.context(
+            reference,
+            position,
+            locator_type,
+            state,
+            year_suffix.is_some(),
+        );
+        let layout = self.style.citation.layout_for(reference.language());
+        ref_sequence(self.db, &ctx, &layout.elements).map(|(ir, _)| ir)
+    }
+
+    fn context<'r>(
+        &'r self,
+        reference: &'r Reference,
+        position: Position,
+        locator_type: Option<LocatorType>,
+        state: &CiteState,
+        year_suffix: bool,
+    ) -> RefContext<'r> {
+        RefContext {
+            style: self.style,
+            reference,
+            locator_type,
+            position,
+            year_suffix,
+            names_delimiter: self.names_delimiter.clone(),
+            name_el: self.name_el.clone(),
+            disamb_count: state.disamb_count,
+            name_disamb: state.name_disamb.clone(),
+            in_sort_key: false,
+            suppress_author: false,
+            hereinafter: self.db.hereinafter(&reference.id),
+            module: module_for(self.db, self.style, reference),
+        }
+    }
+
+    /// Tokens for matching against DFAs. Year suffixes are written out, because they're there
+    /// to make cites differ; locators are left as placeholders, because they shouldn't.
+    fn match_tokens(
+        &self,
+        ir: &RefIR,
+        reference: &Reference,
+        year_suffix: Option<&str>,
+    ) -> Vec<EdgeData> {
+        // Only a style that renders the number cares where the reference sorts
+        let citation_number = if ir.flatten().contains(&EdgeData::CitationNumber) {
+            self.db.citation_number(&reference.id)
+        } else {
+            None
+        };
+        fill_placeholders(ir, year_suffix, citation_number)
+    }
+
+    fn cite_output(
+        &self,
+        cite: &CiteInput,
+        state: &CiteState,
+        year_suffix: Option<&str>,
+    ) -> Result<String, StyleError> {
+        let ir = self.render(
+            cite.reference,
+            cite.position,
+            cite.locator_type.clone(),
+            state,
+            year_suffix,
+        )?;
+        Ok(plain_text(
+            &self.match_tokens(&ir, cite.reference, year_suffix),
+            cite_locator(self.style, cite, &self.locale).as_deref(),
+            cite.locator_type.as_ref(),
+            &self.locale,
+        ))
+    }
+}
+
+/// Flattens `ir`, writing out the year suffix and citation number placeholders where the
+/// reference has them.
+pub fn fill_placeholders(
+    ir: &RefIR,
+    year_suffix: Option<&str>,
+    citation_number: Option<u32>,
+) -> Vec<EdgeData> {
+    let mut tokens = Vec::new();
+    for token in ir.flatten() {
+        match token {
+            EdgeData::YearSuffix | EdgeData::YearSuffixExplicit | EdgeData::YearSuffixPlain => {
+                if let Some(suffix) = year_suffix {
+                    push_token(&mut tokens, EdgeData::Output(suffix.into()));
+                }
+            }
+            EdgeData::CitationNumber => match citation_number {
+                Some(n) => push_token(&mut tokens, EdgeData::Output(n.to_string())),
+                None => push_token(&mut tokens, EdgeData::CitationNumber),
+            },
+            token => push_token(&mut tokens, token),
+        }
+    }
+    tokens
+}
+
+/// What to fill in when rendering a cite after disambiguation.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct RenderOptions<'a> {
+    pub year_suffix: Option<&'a str>,
+    pub citation_number: Option<u32>,
+    pub suppress_author: bool,
+}
+
+fn result_context<'a>(
+    db: &dyn IrDatabase,
+    style: &'a Style,
+    cite: &CiteInput<'a>,
+    result: &DisambResult,
+    options: RenderOptions,
+) -> RefContext<'a> {
+    RefContext {
+        style,
+        reference: cite.reference,
+        locator_type: cite.locator_type.clone(),
+        position: cite.position,
+        year_suffix: options.year_suffix.is_some(),
+        names_delimiter: style.inherited_names_delimiter(&style.citation.names_delimiter),
+        name_el: Arc::new(style.inherited_name(&style.citation.name_inheritance)),
+        disamb_count: result.disamb_count,
+        name_disamb: result.name_disamb.clone(),
+        in_sort_key: false,
+        suppress_author: options.suppress_author,
+        hereinafter: db.hereinafter(&cite.reference.id),
+        module: module_for(db, style, cite.reference),
+    }
+}
+
+/// Renders a cite the way disambiguation left it.
+pub fn render_cite(
+    db: &dyn IrDatabase,
+    style: &Style,
+    cite: &CiteInput,
+    result: &DisambResult,
+    options: RenderOptions,
+) -> Result<String, StyleError> {
+    let ctx = result_context(db, style, cite, result, options);
+    let layout = style.citation.layout_for(cite.reference.language());
+    let (ir, _) = ref_sequence(db, &ctx, &layout.elements)?;
+    let locale = db.locale(&style.default_locale);
+    Ok(plain_text(
+        &fill_placeholders(&ir, options.year_suffix, options.citation_number),
+        cite_locator(style, cite, &locale).as_deref(),
+        cite.locator_type.as_ref(),
+        &locale,
+    ))
+}
+
+/// Page locators are page ranges like any other.
+fn cite_locator(style: &Style, cite: &CiteInput, locale: &Locale) -> Option<String> {
+    let locator = cite.locator.as_deref()?;
+    Some(match cite.locator_type {
+        Some(LocatorType::Page) => format_pages(style, locale, locator),
+        _ => locator.to_string(),
+    })
+}
+
+/// The names a cite shows once disambiguated, which is what cites are grouped by.
+pub fn cite_author(
+    db: &dyn IrDatabase,
+    style: &Style,
+    cite: &CiteInput,
+    result: &DisambResult,
+) -> Result<Option<NamesRun>, StyleError> {
+    let ctx = result_context(db, style, cite, result, RenderOptions::default());
+    let layout = style.citation.layout_for(cite.reference.language());
+    first_names(db, &ctx, &layout.elements)
+}
+
+/// The text of a flattened IR, with locators filled in from the cite. Other placeholders
+/// render as nothing.
+pub fn plain_text(
+    tokens: &[EdgeData],
+    locator: Option<&str>,
+    locator_type: Option<&LocatorType>,
+    locale: &Locale,
+) -> String {
+    let mut out = String::new();
+    for token in tokens {
+        match token {
+            EdgeData::Output(s) => out.push_str(s),
+            EdgeData::Locator => out.push_str(locator.unwrap_or("")),
+            EdgeData::LocatorLabel => out.push_str(
+                locator_type
+                    .and_then(|lt| locale.locator_term(lt))
+                    .unwrap_or(""),
+            ),
+            _ => {}
+        }
+    }
+    out
+}
+
+/// The cite's own position, and one that takes its et-al settings from the other side of
+/// `Name::for_position`: `First` uses `et-al-min`, and everything else `et-al-subsequent-min`.
+fn et_al_positions(position: Position) -> [Position; 2] {
+    match position {
+        Position::First => [Position::First, Position::Subsequent],
+        other => [other, Position::First],
+    }
+}
+
+/// The longest list of names on the reference, which bounds how many names can be added or
+/// expanded.
+fn max_names(reference: &Reference) -> usize {
+    reference.name.values().map(|l| l.len()).max().unwrap_or(0)
+}
+
+/// The given name expansions that `expand_given_by_cite` can produce for `n` names: some number
+/// of names in full, optionally followed by one in initials; or some number in initials.
+fn given_patterns(n: usize) -> Vec<Vec<GivenLevel>> {
+    let mut patterns = vec![Vec::new()];
+    for k in 1..=n {
+        patterns.push(vec![GivenLevel::Initials; k]);
+        patterns.push(vec![GivenLevel::Full; k]);
+        let mut mixed = vec![GivenLevel::Full; k - 1];
+        mixed.push(GivenLevel::Initials);
+        patterns.push(mixed);
+    }
+    patterns
+}
+
+#[cfg(test)]
+fn test_style(citation: Citation, layout: Vec<Element>) -> crate::db::Database {
+    crate::db::Database::new(Style {
+        citation: Citation {
+            layout: Layout {
+                elements: layout,
+                ..Default::default()
+            },
+            ..citation
+        },
+        ..Default::default()
+    })
+}
+
+#[cfg(test)]
+fn short_names(et_al: Option<(u32, u32)>) -> Element {
+    Element::Names(Arc::new(Names {
+        variables: vec![NameVariable::Author],
+        name: Some(Name {
+            form: Some(NameForm::Short),
+            et_al_min: et_al.map(|x| x.0),
+            et_al_use_first: et_al.map(|x| x.1),
+            ..Name::empty()
+        }),
+        delimiter: None,
+        formatting: None,
+        affixes: None,
+        display: None,
+        substitute: None,
+    }))
+}
+
+#[cfg(test)]
+fn text_var(var: Variable, prefix: &str) -> Element {
+    Element::Text(TextElement {
+        source: TextSource::Variable(StandardVariable::Ordinary(var), VariableForm::Long),
+        formatting: None,
+        affixes: Some(Affixes {
+            prefix: prefix.into(),
+            suffix: "".into(),
+        }),
+        quotes: false,
+        strip_periods: false,
+        text_case: TextCase,
+        display: None,
+    })
+}
+
+#[cfg(test)]
+fn authored(id: &str, names: &[(&str, &str)]) -> Reference {
+    let mut r = Reference::empty(id, "book");
+    r.name.insert(
+        NameVariable::Author,
+        names.iter().map(|(g, f)| PersonName::new(g, f)).collect(),
+    );
+    r.ordinary.insert(Variable::Title, format!("Title {}", id));
+    r
+}
+
+/// Puts a cluster citing `ids` at the end of the document, and disambiguates it.
+#[cfg(test)]
+fn cite_cluster(db: &mut crate::db::Database, cluster: u32, ids: &[&str]) -> Vec<DisambResult> {
+    use crate::cluster::{Cite, Cluster, ClusterId};
+    let cites = ids
+        .iter()
+        .enumerate()
+        .map(|(i, id)| Cite::basic(CiteId(cluster * 100 + i as u32), *id))
+        .collect();
+    db.set_cluster(Cluster::new(ClusterId(cluster), cites));
+    (*db.cluster_ir(ClusterId(cluster)).unwrap()).clone()
+}
+
+#[test]
+fn add_names() {
+    let mut db = test_style(
+        Citation {
+            disambiguate_add_names: true,
+            ..Default::default()
+        },
+        vec![short_names(Some((2, 1)))],
+    );
+    let refs = vec![
+        authored("a", &[("John", "Smith"), ("Bob", "Jones")]),
+        authored("b", &[("John", "Smith"), ("Carol", "Brown")]),
+        authored("c", &[("Dan", "Green")]),
+    ];
+    db.set_references(refs);
+    let results = cite_cluster(&mut db, 1, &["a", "b", "c"]);
+    let outputs: Vec<_> = results.iter().map(|r| r.output.as_str()).collect();
+    assert_eq!(outputs, vec!["Smith, Jones", "Smith, Brown", "Green"]);
+    assert_eq!(results[0].resolved_by, DisambStep::AddNames);
+    assert_eq!(results[2].resolved_by, DisambStep::NotAmbiguous);
+}
+
+#[test]
+fn add_givenname_by_cite() {
+    let mut db = test_style(
+        Citation {
+            disambiguate_add_givenname: true,
+            ..Default::default()
+        },
+        vec![short_names(None)],
+    );
+    let refs = vec![
+        authored("a", &[("John", "Smith")]),
+        authored("b", &[("Alan", "Smith")]),
+        authored("c", &[("Jane", "Smith")]),
+    ];
+    db.set_references(refs);
+    let results = cite_cluster(&mut db, 1, &["a", "b", "c"]);
+    let outputs: Vec<_> = results.iter().map(|r| r.output.as_str()).collect();
+    assert_eq!(outputs, vec!["John Smith", "A. Smith", "Jane Smith"]);
+    assert!(results
+        .iter()
+        .all(|r| r.resolved_by == DisambStep::AddGivenName));
+}
+
+#[test]
+fn global_rule_then_year_suffix() {
+    let mut db = test_style(
+        Citation {
+            disambiguate_add_givenname: true,
+            givenname_disambiguation_rule: GivenNameDisambiguationRule::AllNamesWithInitials,
+            disambiguate_add_year_suffix: true,
+            ..Default::default()
+        },
+        vec![short_names(None), text_var(Variable::YearSuffix, " ")],
+    );
+    let refs = vec![
+        authored("a", &[("John", "Smith")]),
+        authored("b", &[("Jane", "Smith")]),
+        authored("c", &[("Alan", "Smith")]),
+        authored("d", &[("Dan", "Green")]),
+    ];
+    db.set_references(refs);
+    let results = cite_cluster(&mut db, 1, &["a", "b", "c", "d"]);
+    let outputs: Vec<_> = results.iter().map(|r| r.output.as_str()).collect();
+    assert_eq!(
+        outputs,
+        vec!["J. Smith a", "J. Smith b", "A. Smith", "Green"]
+    );
+    let steps: Vec<_> = results.iter().map(|r| r.resolved_by).collect();
+    assert_eq!(
+        steps,
+        vec![
+            DisambStep::YearSuffix,
+            DisambStep::YearSuffix,
+            DisambStep::AddGivenName,
+            DisambStep::NotAmbiguous
+        ]
+    );
+}
+
+#[test]
+fn disambiguate_condition() {
+    let choose = Element::Choose(Arc::new(Choose(
+        IfThen(
+            Conditions(Match::All, vec![Cond::Disambiguate(true)]),
+            vec![text_var(Variable::Title, ", ")],
+        ),
+        vec![],
+        Else(vec![]),
+    )));
+    let mut db = test_style(Citation::default(), vec![short_names(None), choose]);
+    let refs = vec![
+        authored("a", &[("John", "Smith")]),
+        authored("b", &[("John", "Smith")]),
+    ];
+    db.set_references(refs);
+    let results = cite_cluster(&mut db, 1, &["a", "b"]);
+    let outputs: Vec<_> = results.iter().map(|r| r.output.as_str()).collect();
+    assert_eq!(outputs, vec!["Smith, Title a", "Smith, Title b"]);
+    assert!(results
+        .iter()
+        .all(|r| r.resolved_by == DisambStep::Conditionals));
+}
+
+#[test]
+fn year_suffixes_follow_bibliography_order() {
+    let mut db = test_style(
+        Citation {
+            disambiguate_add_year_suffix: true,
+            ..Default::default()
+        },
+        vec![short_names(None), text_var(Variable::YearSuffix, " ")],
+    );
+    let mut style = (*db.style()).clone();
+    style.bibliography = Some(Bibliography {
+        sort: Some(Sort {
+            keys: vec![SortKey {
+                source: SortSource::Variable(AnyVariable::Ordinary(Variable::Title)),
+                direction: SortDirection::Ascending,
+            }],
+        }),
+        ..Default::default()
+    });
+    db.set_style(style);
+    db.set_references(vec![
+        authored("x", &[("John", "Smith")]),
+        authored("y", &[("John", "Smith")]),
+        authored("z", &[("Jane", "Jones")]),
+    ]);
+    // cited in reverse
+    let results = cite_cluster(&mut db, 1, &["y", "x", "z"]);
+    let outputs: Vec<_> = results.iter().map(|r| r.output.as_str()).collect();
+    assert_eq!(outputs, vec!["Smith b", "Smith a", "Jones"]);
+}
+
+#[test]
+fn subsequent_et_al() {
+    let names = Element::Names(Arc::new(Names {
+        variables: vec![NameVariable::Author],
+        name: Some(Name {
+            form: Some(NameForm::Short),
+            et_al_subsequent_min: Some(3),
+            et_al_subsequent_use_first: Some(1),
+            ..Name::empty()
+        }),
+        delimiter: None,
+        formatting: None,
+        affixes: None,
+        display: None,
+        substitute: None,
+    }));
+    let mut db = test_style(
+        Citation {
+            disambiguate_add_names: true,
+            ..Default::default()
+        },
+        vec![names],
+    );
+    db.set_references(vec![
+        authored("a", &[("", "Smith"), ("", "Jones"), ("", "Green")]),
+        authored("b", &[("", "Smith"), ("", "Brown"), ("", "Green")]),
+        authored("c", &[("", "White"), ("", "Black"), ("", "Grey")]),
+    ]);
+    let first = cite_cluster(&mut db, 1, &["a", "b", "c"]);
+    let outputs: Vec<_> = first.iter().map(|r| r.output.as_str()).collect();
+    assert_eq!(
+        outputs,
+        vec!["Smith, Jones, Green", "Smith, Brown, Green", "White, Black, Grey"]
+    );
+    let subsequent = cite_cluster(&mut db, 2, &["a", "b", "c"]);
+    let outputs: Vec<_> = subsequent.iter().map(|r| r.output.as_str()).collect();
+    assert_eq!(
+        outputs,
+        vec!["Smith, Jones, et al.", "Smith, Brown, et al.", "White et al."]
+    );
+    assert_eq!(subsequent[0].resolved_by, DisambStep::AddNames);
+    assert_eq!(subsequent[2].resolved_by, DisambStep::NotAmbiguous);
+}
+
+#[test]
+fn dfas_cover_both_et_al_forms() {
+    let names = Element::Names(Arc::new(Names {
+        variables: vec![NameVariable::Author],
+        name: Some(Name {
+            form: Some(NameForm::Short),
+            et_al_subsequent_min: Some(3),
+            et_al_subsequent_use_first: Some(1),
+            ..Name::empty()
+        }),
+        delimiter: None,
+        formatting: None,
+        affixes: None,
+        display: None,
+        substitute: None,
+    }));
+    let db = test_style(Citation::default(), vec![names]);
+    let style = db.style();
+    let reference = authored("a", &[("", "Smith"), ("", "Jones"), ("", "Green")]);
+    let disambiguator = Disambiguator::new(&db, &style);
+    let tokens = |position| {
+        let ir = disambiguator
+            .render(&reference, position, None, &CiteState::default(), None)
+            .unwrap();
+        disambiguator.match_tokens(&ir, &reference, None)
+    };
+    let (first, subsequent) = (tokens(Position::First), tokens(Position::Subsequent));
+    assert_ne!(first, subsequent);
+    for &position in &[Position::First, Position::Subsequent, Position::Ibid] {
+        let key = DfaKey {
+            position,
+            ..DfaKey::first()
+        };
+        let dfa = disambiguator.build_dfa(&reference, &key).unwrap();
+        assert!(dfa.accepts(&first), "{:?}", position);
+        assert!(dfa.accepts(&subsequent), "{:?}", position);
+    }
+}
+
+#[test]
+fn dfas_only_try_the_expansions_the_style_enables() {
+    use super::EventLog;
+    let names: Vec<(String, String)> = (0..20)
+        .map(|i| (format!("Given{}", i), format!("Family{}", i)))
+        .collect();
+    let names: Vec<(&str, &str)> = names.iter().map(|(g, f)| (&g[..], &f[..])).collect();
+    let reference = authored("a", &names);
+    let renders = |citation: Citation| {
+        let mut db = test_style(citation, vec![short_names(Some((3, 1)))]);
+        let log = Arc::new(EventLog::default());
+        db.set_tracer(log.clone());
+        let style = db.style();
+        Disambiguator::new(&db, &style)
+            .build_nfa(&reference, &DfaKey::first())
+            .unwrap();
+        let lines = log.lines();
+        lines.iter().filter(|line| line.starts_with("0 sequence")).count()
+    };
+    // Both et-al forms, with and without `disambiguate="true"`
+    assert_eq!(renders(Citation::default()), 4);
+    let add_names = Citation {
+        disambiguate_add_names: true,
+        ..Default::default()
+    };
+    assert_eq!(renders(add_names), 4 * 21);
+    let global = Citation {
+        disambiguate_add_givenname: true,
+        givenname_disambiguation_rule: GivenNameDisambiguationRule::AllNames,
+        ..Default::default()
+    };
+    assert_eq!(renders(global), 4);
+    let by_cite = Citation {
+        disambiguate_add_givenname: true,
+        givenname_disambiguation_rule: GivenNameDisambiguationRule::ByCite,
+        ..Default::default()
+    };
+    assert_eq!(renders(by_cite), 4 * 61);
+}
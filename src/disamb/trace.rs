@@ -0,0 +1,234 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Copyright © 2019 Corporation for Digital Scholarship
+
+//! Hooks for watching the IR builder work, for logging or debugging.
+//!
+//! The builder reports each step to an `IrTracer`. By default that's `NoTrace`, which ignores
+//! everything; `Database::set_tracer` swaps in another for every IR the database builds.
+//!
+//! Every event has a `depth`: how many sequences of elements (a layout, a group, a macro, a
+//! branch of a choose) are around it. A layout is at depth 0, and its elements at depth 1. When
+//! the database builds a macro's IR to reuse, that starts again from depth 0, in the middle of
+//! the cite that asked for it.
+
+use super::EdgeData;
+use crate::element::{Element, TextSource};
+use crate::group::GroupVars;
+use std::cell::RefCell;
+use std::sync::Mutex;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TraceEvent<'a> {
+    /// A list of elements is about to be built into a sequence.
+    SequenceEntered {
+        depth: usize,
+        elements: &'a [Element],
+    },
+    /// Building an element has started.
+    ElementEntered { depth: usize, element: &'a Element },
+    /// A `<text macro="...">`, whose IR was either built from its elements or, if `reused`,
+    /// taken as it was from the database.
+    MacroExpanded {
+        depth: usize,
+        name: &'a str,
+        reused: bool,
+    },
+    /// An element like `<text variable="...">` or `<label>` produced an edge.
+    EdgeEmitted { depth: usize, edge: &'a EdgeData },
+    /// An element's `GroupVars` folded into its sequence's, giving `merged`.
+    GroupVarsMerged {
+        depth: usize,
+        element: GroupVars,
+        merged: GroupVars,
+    },
+    /// A sequence is finished. Not sent if building it failed.
+    SequenceExited { depth: usize, gv: GroupVars },
+}
+
+pub trait IrTracer {
+    /// Takes `&self`, so a tracer can be shared by a whole database; keep any state in a `Cell`
+    /// or `RefCell`.
+    fn event(&self, event: &TraceEvent) {
+        let _ = event;
+    }
+}
+
+/// Ignores every event.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct NoTrace;
+
+impl IrTracer for NoTrace {}
+
+/// The `GroupVars` one sequence of elements worked out.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SequenceTrace {
+    pub depth: usize,
+    /// What the elements are, like `group` or `text macro="author"`.
+    pub elements: Vec<String>,
+    pub gv: GroupVars,
+}
+
+/// Keeps a `SequenceTrace` for every sequence, outermost first, for `minimal debug`.
+#[derive(Debug, Default)]
+pub struct SequenceRecorder {
+    levels: RefCell<Vec<SequenceTrace>>,
+    /// The levels entered and not yet exited, innermost last.
+    open: RefCell<Vec<usize>>,
+}
+
+impl SequenceRecorder {
+    pub fn into_levels(self) -> Vec<SequenceTrace> {
+        self.levels.into_inner()
+    }
+}
+
+impl IrTracer for SequenceRecorder {
+    fn event(&self, event: &TraceEvent) {
+        let mut levels = self.levels.borrow_mut();
+        match *event {
+            TraceEvent::SequenceEntered { depth, elements } => {
+                self.open.borrow_mut().push(levels.len());
+                levels.push(SequenceTrace {
+                    depth,
+                    elements: elements.iter().map(describe).collect(),
+                    gv: GroupVars::new(),
+                });
+            }
+            TraceEvent::SequenceExited { gv, .. } => {
+                if let Some(ix) = self.open.borrow_mut().pop() {
+                    levels[ix].gv = gv;
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// A short name for an element, for traces.
+pub fn describe(el: &Element) -> String {
+    match el {
+        Element::Text(text) => match &text.source {
+            TextSource::Macro(call) => format!("text macro=\"{}\"", call.name),
+            TextSource::Value(_) => "text value".into(),
+            TextSource::Variable(..) => "text variable".into(),
+            TextSource::Term(..) => "text term".into(),
+        },
+        Element::Number(_) => "number".into(),
+        Element::Label(_) => "label".into(),
+        Element::Group(_) => "group".into(),
+        Element::Choose(_) => "choose".into(),
+        Element::Names(_) => "names".into(),
+        Element::Date(_) => "date".into(),
+    }
+}
+
+/// Writes down every event as a line, like `1 edge Output("Title")`, for `minimal render
+/// --trace`.
+#[derive(Debug, Default)]
+pub struct EventLog(Mutex<Vec<String>>);
+
+impl EventLog {
+    pub fn lines(&self) -> Vec<String> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+impl IrTracer for EventLog {
+    fn event(&self, event: &TraceEvent) {
+        let line = match event {
+            TraceEvent::SequenceEntered { depth, elements } => {
+                let elements: Vec<_> = elements.iter().map(describe).collect();
+                format!("{} sequence [{}]", depth, elements.join(", "))
+            }
+            TraceEvent::ElementEntered { depth, element } => {
+                format!("{} element {}", depth, describe(element))
+            }
+            TraceEvent::MacroExpanded {
+                depth,
+                name,
+                reused,
+            } => format!("{} macro {} reused={}", depth, name, reused),
+            TraceEvent::EdgeEmitted { depth, edge } => format!("{} edge {:?}", depth, edge),
+            TraceEvent::GroupVarsMerged {
+                depth,
+                element,
+                merged,
+            } => format!("{} merge {:?} -> {:?}", depth, element, merged),
+            TraceEvent::SequenceExited { depth, gv } => format!("{} exit {:?}", depth, gv),
+        };
+        self.0.lock().unwrap().push(line);
+    }
+}
+
+#[test]
+fn database_reports_each_step() {
+    use crate::cluster::{Cite, Cluster, ClusterId};
+    use crate::db::Database;
+    use crate::element::{LocatorType, Style};
+    use crate::reference::Reference;
+    use crate::CiteId;
+    use std::sync::Arc;
+
+    let style: Style = r#"<style class="note">
+      <macro name="title"><text variable="title"/></macro>
+      <citation>
+        <layout>
+          <text macro="title" suffix=" "/>
+          <group delimiter=" "><label variable="locator" form="short"/><text variable="locator"/></group>
+        </layout>
+      </citation>
+    </style>"#
+        .parse()
+        .unwrap();
+    let mut db = Database::new(style);
+    let log = Arc::new(EventLog::default());
+    db.set_tracer(log.clone());
+    let mut reference = Reference::empty("a", "book");
+    reference
+        .ordinary
+        .insert(crate::element::Variable::Title, "Title".into());
+    db.set_references(vec![reference]);
+    db.set_cluster(Cluster::new(
+        ClusterId(1),
+        vec![Cite::basic(CiteId(1), "a").with_locator(LocatorType::Page, "5")],
+    ));
+    assert_eq!(*db.render_cluster(ClusterId(1)).unwrap(), "Title p. 5");
+
+    let log = log.lines();
+    // The first cite's IR. The database builds the macro's IR the first time it's asked for,
+    // from depth 0 again, and after that it's reused.
+    assert_eq!(
+        log[..20].to_vec(),
+        vec![
+            "0 sequence [text macro=\"title\", group]",
+            "1 element text macro=\"title\"",
+            "0 sequence [text variable]",
+            "1 element text variable",
+            "1 edge Output(\"Title\")",
+            "1 merge Important -> Important",
+            "0 exit Important",
+            "1 macro title reused=true",
+            "1 merge Important -> Important",
+            "1 element group",
+            "1 sequence [label, text variable]",
+            "2 element label",
+            "2 edge LocatorLabel",
+            "2 merge Important -> Important",
+            "2 element text variable",
+            "2 edge Locator",
+            "2 merge Important -> Important",
+            "1 exit Important",
+            "1 merge Important -> Important",
+            "0 exit Important",
+        ]
+    );
+    assert_eq!(
+        log.iter()
+            .filter(|line| *line == "0 sequence [text variable]")
+            .count(),
+        1
+    );
+}
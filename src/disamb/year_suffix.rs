@@ -0,0 +1,153 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Copyright © 2019 Corporation for Digital Scholarship
+
+use std::collections::{BTreeSet, HashMap};
+
+/// Hands out year suffixes to the references that are still ambiguous once names have done all
+/// they can.
+///
+/// References are grouped with everything they could be confused with (transitively), and each
+/// group is lettered from "a" in bibliography order. The result only depends on which references
+/// were reported ambiguous and how the bibliography sorts them, not on the order cites were
+/// processed in, so re-running it over an edited document only moves suffixes that have to move.
+#[derive(Debug, Clone)]
+pub struct YearSuffixAllocator {
+    ids: Vec<String>,
+    /// Reference id => its position in the sorted bibliography
+    order: HashMap<String, usize>,
+    /// Union-find over bibliography positions
+    parent: Vec<usize>,
+    involved: BTreeSet<usize>,
+}
+
+impl YearSuffixAllocator {
+    /// `bibliography_order` is every reference id, as the bibliography would sort them.
+    pub fn new<S: AsRef<str>>(bibliography_order: &[S]) -> Self {
+        let ids: Vec<String> = bibliography_order
+            .iter()
+            .map(|s| s.as_ref().to_string())
+            .collect();
+        let mut order = HashMap::with_capacity(ids.len());
+        for (i, id) in ids.iter().enumerate() {
+            order.entry(id.clone()).or_insert(i);
+        }
+        YearSuffixAllocator {
+            parent: (0..ids.len()).collect(),
+            ids,
+            order,
+            involved: BTreeSet::new(),
+        }
+    }
+
+    /// Records that cites of `a` can't be told apart from `b`. Ids that aren't in the bibliography
+    /// are ignored.
+    pub fn ambiguous(&mut self, a: &str, b: &str) {
+        if let (Some(&a), Some(&b)) = (self.order.get(a), self.order.get(b)) {
+            self.involved.insert(a);
+            self.involved.insert(b);
+            let (a, b) = (self.root(a), self.root(b));
+            self.parent[a.max(b)] = a.min(b);
+        }
+    }
+
+    /// Records a whole group of mutually ambiguous references at once. As with `ambiguous`, ids
+    /// that aren't in the bibliography are ignored; the rest are still grouped together.
+    pub fn ambiguous_group<'s>(&mut self, ids: impl IntoIterator<Item = &'s str>) {
+        let order = &self.order;
+        let known: Vec<&str> = ids
+            .into_iter()
+            .filter(|id| order.contains_key(*id))
+            .collect();
+        let mut ids = known.into_iter();
+        if let Some(first) = ids.next() {
+            self.ambiguous(first, first);
+            for id in ids {
+                self.ambiguous(first, id);
+            }
+        }
+    }
+
+    /// Reference id => suffix, for every reference recorded as ambiguous.
+    pub fn allocate(&mut self) -> HashMap<String, String> {
+        let mut counters: HashMap<usize, u32> = HashMap::new();
+        let mut suffixes = HashMap::new();
+        let involved: Vec<usize> = self.involved.iter().cloned().collect();
+        for ix in involved {
+            let group = self.root(ix);
+            let counter = counters.entry(group).or_insert(0);
+            suffixes.insert(self.ids[ix].clone(), year_suffix(*counter));
+            *counter += 1;
+        }
+        suffixes
+    }
+
+    fn root(&mut self, mut i: usize) -> usize {
+        while self.parent[i] != i {
+            self.parent[i] = self.parent[self.parent[i]];
+            i = self.parent[i];
+        }
+        i
+    }
+}
+
+/// 0 => "a", 25 => "z", 26 => "aa", ...
+pub fn year_suffix(mut n: u32) -> String {
+    let mut s = Vec::new();
+    loop {
+        s.push(b'a' + (n % 26) as u8);
+        if n < 26 {
+            break;
+        }
+        n = n / 26 - 1;
+    }
+    s.reverse();
+    String::from_utf8(s).unwrap()
+}
+
+#[test]
+fn year_suffix_letters() {
+    assert_eq!(year_suffix(0), "a");
+    assert_eq!(year_suffix(25), "z");
+    assert_eq!(year_suffix(26), "aa");
+    assert_eq!(year_suffix(27), "ab");
+    assert_eq!(year_suffix(26 + 26 * 26), "aaa");
+}
+
+#[test]
+fn allocation_follows_bibliography_order() {
+    let bib = [
+        "smith2001x",
+        "jones",
+        "smith2001y",
+        "smith2001z",
+        "brown1",
+        "brown2",
+    ];
+    let mut alloc = YearSuffixAllocator::new(&bib);
+    // reported in a different order to the bibliography, and transitively
+    alloc.ambiguous("smith2001z", "smith2001y");
+    alloc.ambiguous("smith2001y", "smith2001x");
+    alloc.ambiguous_group(vec!["brown2", "brown1", "not-in-bib"]);
+    let suffixes = alloc.allocate();
+    let get = |id: &str| suffixes.get(id).map(|s| s.as_str());
+    assert_eq!(get("smith2001x"), Some("a"));
+    assert_eq!(get("smith2001y"), Some("b"));
+    assert_eq!(get("smith2001z"), Some("c"));
+    assert_eq!(get("brown1"), Some("a"));
+    assert_eq!(get("brown2"), Some("b"));
+    assert_eq!(get("jones"), None);
+    assert_eq!(suffixes.len(), 5);
+}
+
+#[test]
+fn unknown_id_first_in_a_group() {
+    let mut alloc = YearSuffixAllocator::new(&["doe1", "doe2"]);
+    alloc.ambiguous_group(vec!["not-in-bib", "doe2", "doe1"]);
+    let suffixes = alloc.allocate();
+    assert_eq!(suffixes.get("doe1").map(String::as_str), Some("a"));
+    assert_eq!(suffixes.get("doe2").map(String::as_str), Some("b"));
+    assert_eq!(suffixes.len(), 2);
+}
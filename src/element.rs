@@ -1,34 +1,341 @@
 #![allow(dead_code)]
-use std::collections::HashMap;
+#![allow(clippy::upper_case_acronyms)]
+pub use crate::macros::{MacroId, MacroRef, MacroTable};
 use std::sync::Arc;
 
 #[derive(Debug, Eq, Clone, PartialEq)]
 pub struct Style {
-    // pub class: StyleClass,
-    pub macros: HashMap<String, Vec<Element>>,
+    pub class: StyleClass,
+    pub macros: MacroTable,
     pub citation: Citation,
-    // pub bibliography: Option<Bibliography>,
-    // pub info: Info,
-    // pub features: Features,
-    // pub name_inheritance: Name,
-    // pub names_delimiter: Option<Delimiter>,
+    pub bibliography: Option<Bibliography>,
+    pub info: Info,
+    pub features: Features,
+    /// The `<name>` options set on `<style>` itself, for every `<names>` to inherit.
+    pub name_inheritance: Name,
+    /// `names-delimiter`, likewise inherited by every `<names>`.
+    pub names_delimiter: Option<Delimiter>,
     // pub locale_overrides: FnvHashMap<Option<Lang>, Locale>,
-    // pub default_locale: Lang,
-    // pub version_req: CslVersionReq,
-    // pub page_range_format: Option<PageRangeFormat>,
-    // pub demote_non_dropping_particle: DemoteNonDroppingParticle,
-    // pub initialize_with_hyphen: bool, // default is true
+    pub default_locale: String,
+    pub version_req: CslVersionReq,
+    pub variant: CslVariant,
+    pub page_range_format: Option<PageRangeFormat>,
+    pub demote_non_dropping_particle: DemoteNonDroppingParticle,
+    pub initialize_with_hyphen: bool, // default is true
 }
 
-#[derive(Debug, Eq, Clone, PartialEq)]
+impl Default for Style {
+    fn default() -> Self {
+        Style {
+            class: StyleClass::InText,
+            macros: MacroTable::default(),
+            citation: Citation::default(),
+            bibliography: None,
+            info: Info::default(),
+            features: Features::default(),
+            name_inheritance: Name::empty(),
+            names_delimiter: None,
+            default_locale: crate::locale::DEFAULT_LANG.to_string(),
+            version_req: CslVersionReq::default(),
+            variant: CslVariant::Csl,
+            page_range_format: None,
+            demote_non_dropping_particle: DemoteNonDroppingParticle::default(),
+            initialize_with_hyphen: true,
+        }
+    }
+}
+
+impl Style {
+    /// What a `<names>` inherits, given the `<name>` options on its `<citation>` or
+    /// `<bibliography>`: those override the style's, which override the CSL defaults.
+    pub fn inherited_name(&self, section: &Name) -> Name {
+        Name::root_default()
+            .merge(&self.name_inheritance)
+            .merge(section)
+    }
+
+    /// `names-delimiter` from the `<citation>` or `<bibliography>`, or else from the style.
+    pub fn inherited_names_delimiter(&self, section: &Option<Delimiter>) -> Option<Delimiter> {
+        section.clone().or_else(|| self.names_delimiter.clone())
+    }
+
+    /// Whether any layout or macro has a `<text variable="year-suffix">`. If none does, the
+    /// year suffix goes after the first year a `<date>` renders.
+    pub fn renders_year_suffix(&self) -> bool {
+        let layouts = std::iter::once(&self.citation.layout)
+            .chain(&self.citation.locale_layouts)
+            .chain(self.bibliography.iter().flat_map(|bib| {
+                std::iter::once(&bib.layout).chain(&bib.locale_layouts)
+            }));
+        let mut stack: Vec<&Element> = layouts
+            .flat_map(|layout| &layout.elements)
+            .chain(self.macros.iter().flat_map(|m| &m.elements))
+            .collect();
+        while let Some(el) = stack.pop() {
+            if let Element::Text(TextElement {
+                source: TextSource::Variable(StandardVariable::Ordinary(Variable::YearSuffix), _),
+                ..
+            }) = el
+            {
+                return true;
+            }
+            stack.extend(el.children());
+        }
+        false
+    }
+}
+
+#[derive(Default, Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum StyleClass {
+    #[default]
+    InText,
+    Note,
+}
+
+/// <cs:info>. Only the parts a processor has any use for.
+#[derive(Default, Debug, Eq, Clone, PartialEq)]
+pub struct Info {
+    pub id: String,
+    pub title: String,
+    pub updated: String,
+    /// The `href` of `<link rel="independent-parent">`, for dependent styles.
+    pub parent: Option<String>,
+}
+
+/// <cs:features>, from CSL 1.1. Each `<feature name="...">` opts in to a change in behaviour.
+#[derive(Default, Debug, Eq, Clone, PartialEq)]
+pub struct Features {
+    pub enabled: Vec<String>,
+}
+
+impl Features {
+    pub fn is_enabled(&self, feature: &str) -> bool {
+        self.enabled.iter().any(|f| f == feature)
+    }
+}
+
+/// Which dialect a style is written in. CSL-M styles say so with a version like `1.1mlz1`.
+#[derive(Default, Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum CslVariant {
+    #[default]
+    Csl,
+    /// [CSL-M](https://citeproc-js.readthedocs.io/en/latest/csl-m/), the Juris-M extensions for
+    /// legal and multilingual citation.
+    CslM,
+}
+
+/// The `version` attribute on `<style>`, e.g. `1.0` or `1.0.1`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct CslVersionReq {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+impl Default for CslVersionReq {
+    fn default() -> Self {
+        CslVersionReq {
+            major: 1,
+            minor: 0,
+            patch: 0,
+        }
+    }
+}
+
+/// [Spec](https://docs.citationstyles.org/en/stable/specification.html#appendix-v-page-range-formats)
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum PageRangeFormat {
+    /// `321–28`, following the Chicago Manual of Style, 15th edition. Also spelled `chicago-15`.
+    Chicago,
+    /// The 16th edition's revision of `Chicago`.
+    Chicago16,
+    /// `321–328`
+    Expanded,
+    /// `321–8`
+    Minimal,
+    /// `321–28`, never fewer than two digits
+    MinimalTwo,
+}
+
+/// [Spec](https://docs.citationstyles.org/en/stable/specification.html#name-particles)
+#[derive(Default, Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum DemoteNonDroppingParticle {
+    /// `Vincent van Gogh`, sorted and inverted as `van Gogh, Vincent`.
+    Never,
+    /// Inverted as `van Gogh, Vincent`, but sorted under G.
+    SortOnly,
+    /// Inverted as `Gogh, Vincent van`, and sorted under G.
+    #[default]
+    DisplayAndSort,
+}
+
+#[derive(Default, Debug, Eq, Clone, PartialEq)]
 pub struct Citation {
     pub layout: Layout,
+    /// CSL-M only. Layouts for references in particular languages, tried before `layout`.
+    pub locale_layouts: Vec<Layout>,
+    pub disambiguate_add_names: bool,
+    pub disambiguate_add_givenname: bool,
+    pub givenname_disambiguation_rule: GivenNameDisambiguationRule,
+    pub disambiguate_add_year_suffix: bool,
+    /// How many notes back a previous cite of the same reference can be and still count as
+    /// `near-note`. `None` means the CSL default of 5.
+    pub near_note_distance: Option<u32>,
+    /// Orders the cites within each cluster.
+    pub sort: Option<Sort>,
+    pub collapse: Option<Collapse>,
+    /// Between cites in a group of cites by the same author. `None` means ", ".
+    pub cite_group_delimiter: Option<Delimiter>,
+    /// Between collapsed year suffixes. `None` means the layout delimiter.
+    pub year_suffix_delimiter: Option<Delimiter>,
+    /// After a collapsed group. `None` means the layout delimiter.
+    pub after_collapse_delimiter: Option<Delimiter>,
+    /// Overrides the style's `<name>` options for the citation.
+    pub name_inheritance: Name,
+    pub names_delimiter: Option<Delimiter>,
+}
+
+/// [Spec](https://docs.citationstyles.org/en/stable/specification.html#cite-grouping)
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Collapse {
+    /// `[1, 2, 3, 5]` => `[1–3, 5]`
+    CitationNumber,
+    /// `Smith 2001, Smith 2002` => `Smith 2001, 2002`
+    Year,
+    /// `Smith 2001a, Smith 2001b` => `Smith 2001a, b`
+    YearSuffix,
+    /// `Smith 2001a, Smith 2001b, Smith 2001c` => `Smith 2001a–c`
+    YearSuffixRanged,
+}
+
+#[derive(Default, Debug, Eq, Clone, PartialEq)]
+pub struct Bibliography {
+    pub layout: Layout,
+    /// CSL-M only, as for `Citation`.
+    pub locale_layouts: Vec<Layout>,
+    pub sort: Option<Sort>,
+    /// Replaces the names of an entry when they are the same as the previous entry's.
+    pub subsequent_author_substitute: Option<String>,
+    pub subsequent_author_substitute_rule: SubsequentAuthorSubstituteRule,
+    pub hanging_indent: bool,
+    pub second_field_align: Option<SecondFieldAlign>,
+    /// In lines. `None` means 1.
+    pub line_spacing: Option<u32>,
+    /// In lines. `None` means 1.
+    pub entry_spacing: Option<u32>,
+    /// Overrides the style's `<name>` options for the bibliography.
+    pub name_inheritance: Name,
+    pub names_delimiter: Option<Delimiter>,
+}
+
+/// [Spec](https://docs.citationstyles.org/en/stable/specification.html#reference-grouping)
+#[derive(Default, Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum SubsequentAuthorSubstituteRule {
+    /// Replace the whole name list, if every name matches.
+    #[default]
+    CompleteAll,
+    /// As `CompleteAll`, but replace each name separately.
+    CompleteEach,
+    /// Replace each leading name that matches.
+    PartialEach,
+    /// Replace the first name, if it matches.
+    PartialFirst,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum SecondFieldAlign {
+    /// The first field hangs in the margin.
+    Margin,
+    /// The first field is flush with the margin, and the rest aligned after it.
+    Flush,
+}
+
+/// <cs:sort>
+#[derive(Default, Debug, Eq, Clone, PartialEq)]
+pub struct Sort {
+    pub keys: Vec<SortKey>,
+}
+
+/// <cs:key>
+#[derive(Debug, Eq, Clone, PartialEq)]
+pub struct SortKey {
+    pub source: SortSource,
+    pub direction: SortDirection,
+}
+
+#[derive(Debug, Eq, Clone, PartialEq)]
+pub enum SortSource {
+    Variable(AnyVariable),
+    Macro(MacroRef),
+}
+
+#[derive(Default, Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum SortDirection {
+    #[default]
+    Ascending,
+    Descending,
+}
+
+/// [Spec](https://docs.citationstyles.org/en/stable/specification.html#given-name-disambiguation-rule)
+#[derive(Default, Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum GivenNameDisambiguationRule {
+    /// Expand names that are ambiguous anywhere in the document, in every cite.
+    AllNames,
+    /// As `AllNames`, but never beyond initials.
+    AllNamesWithInitials,
+    /// As `AllNames`, but only the first name of each name list.
+    PrimaryName,
+    /// As `PrimaryName`, but never beyond initials.
+    PrimaryNameWithInitials,
+    /// Only expand names in cites that are themselves ambiguous.
+    #[default]
+    ByCite,
 }
 
-// TODO: Multiple layouts in CSL-M with locale="en es de" etc
 #[derive(Default, Debug, Eq, Clone, PartialEq)]
 pub struct Layout {
     pub elements: Vec<Element>,
+    /// Between cites in a cluster. Only meaningful on the citation layout.
+    pub delimiter: Option<Delimiter>,
+    /// Around the whole cluster, or the whole bibliography entry.
+    pub affixes: Option<Affixes>,
+    /// CSL-M's `locale="en es de"`, the languages this layout is for. Empty for the default
+    /// layout.
+    pub locale: Vec<String>,
+}
+
+impl Layout {
+    /// Whether this layout is for `lang`, either exactly or by its primary language, so a
+    /// layout for `en` is used for `en-GB`.
+    pub fn is_for(&self, lang: &str) -> bool {
+        let primary = crate::locale::primary_language(lang);
+        self.locale
+            .iter()
+            .any(|l| l == lang || Some(l.as_str()) == primary)
+    }
+}
+
+/// The first of `locale_layouts` that is for `lang`, or else `default`.
+fn select_layout<'a>(
+    default: &'a Layout,
+    locale_layouts: &'a [Layout],
+    lang: Option<&str>,
+) -> &'a Layout {
+    lang.and_then(|lang| locale_layouts.iter().find(|layout| layout.is_for(lang)))
+        .unwrap_or(default)
+}
+
+impl Citation {
+    /// The layout for a reference whose `language` is `lang`.
+    pub fn layout_for(&self, lang: Option<&str>) -> &Layout {
+        select_layout(&self.layout, &self.locale_layouts, lang)
+    }
+}
+
+impl Bibliography {
+    pub fn layout_for(&self, lang: Option<&str>) -> &Layout {
+        select_layout(&self.layout, &self.locale_layouts, lang)
+    }
 }
 
 #[derive(Debug, Eq, Clone, PartialEq)]
@@ -44,11 +351,53 @@ pub enum Element {
     /// <cs:choose>
     /// Arc because the IR needs a reference to one, cloning deep trees is costly, and IR has
     /// to be in a Salsa db that doesn't really support lifetimes.
-    Choose(Arc<()>),
+    Choose(Arc<Choose>),
     /// <cs:names>
-    Names(Arc<()>),
+    Names(Arc<Names>),
     /// <cs:date>
-    Date(Arc<()>),
+    Date(Arc<DateElement>),
+}
+
+impl Element {
+    /// The elements nested directly inside this one, from every branch of a `<choose>` and
+    /// from a `<substitute>`, whether or not they would render.
+    pub fn children(&self) -> Vec<&Element> {
+        match self {
+            Element::Group(group) => group.elements.iter().collect(),
+            Element::Choose(choose) => {
+                let Choose(head, rest, Else(otherwise)) = &**choose;
+                std::iter::once(head)
+                    .chain(rest.iter())
+                    .flat_map(|IfThen(_, elements)| elements.iter())
+                    .chain(otherwise.iter())
+                    .collect()
+            }
+            Element::Names(names) => names
+                .substitute
+                .iter()
+                .flat_map(|Substitute(elements)| elements.iter())
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// The macros `elements` call, at any depth but without following the macros themselves, in
+/// the order they appear.
+pub fn called_macros(elements: &[Element]) -> Vec<&str> {
+    let mut calls = Vec::new();
+    let mut stack: Vec<&Element> = elements.iter().rev().collect();
+    while let Some(el) = stack.pop() {
+        if let Element::Text(TextElement {
+            source: TextSource::Macro(call),
+            ..
+        }) = el
+        {
+            calls.push(call.name.as_str());
+        }
+        stack.extend(el.children().into_iter().rev());
+    }
+    calls
 }
 
 #[derive(Debug, Eq, Clone, PartialEq)]
@@ -83,12 +432,25 @@ pub enum TermForm {
 #[derive(Debug, Eq, Clone, PartialEq)]
 pub struct NumberElement {
     pub variable: NumberVariable,
+    pub form: NumericForm,
     pub formatting: Option<Formatting>,
     pub affixes: Option<Affixes>,
     pub text_case: TextCase,
     pub display: Option<DisplayMode>,
 }
 
+#[derive(Default, Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum NumericForm {
+    #[default]
+    Numeric,
+    /// `2nd`
+    Ordinal,
+    /// `second`, for 1 to 10
+    LongOrdinal,
+    /// `ii`
+    Roman,
+}
+
 #[derive(Debug, Eq, Clone, PartialEq)]
 pub struct Group {
     pub formatting: Option<Formatting>,
@@ -98,9 +460,144 @@ pub struct Group {
     pub display: Option<DisplayMode>,
 }
 
+#[derive(Debug, Eq, Clone, PartialEq)]
+pub struct Choose(pub IfThen, pub Vec<IfThen>, pub Else);
+
+/// [Spec](https://docs.citationstyles.org/en/stable/specification.html#date)
+#[derive(Debug, Eq, Clone, PartialEq)]
+pub struct DateElement {
+    pub variable: DateVariable,
+    /// A localized date, laid out as the locale's `<date>` of this form says. `None` if the
+    /// style lays the date out itself with `parts`.
+    pub form: Option<DateForm>,
+    /// Which of a localized date's parts show.
+    pub date_parts: DateParts,
+    /// For a localized date, these change the form of the locale's parts with the same name.
+    pub parts: Vec<DatePart>,
+    pub delimiter: Option<Delimiter>,
+    pub formatting: Option<Formatting>,
+    pub affixes: Option<Affixes>,
+    pub text_case: TextCase,
+    pub display: Option<DisplayMode>,
+}
+
+impl DateElement {
+    /// Whether a year could show, to take an implicit year suffix.
+    pub fn renders_year(&self) -> bool {
+        self.form.is_some() || self.parts.iter().any(|part| part.name == DatePartName::Year)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum DateForm {
+    /// `04/01/2001` in en-US
+    Numeric,
+    /// `April 1, 2001` in en-US
+    Text,
+}
+
+#[derive(Default, Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum DateParts {
+    #[default]
+    YearMonthDay,
+    YearMonth,
+    Year,
+}
+
+impl DateParts {
+    pub fn shows(self, name: DatePartName) -> bool {
+        match self {
+            DateParts::YearMonthDay => true,
+            DateParts::YearMonth => name != DatePartName::Day,
+            DateParts::Year => name == DatePartName::Year,
+        }
+    }
+}
+
+/// [Spec](https://docs.citationstyles.org/en/stable/specification.html#date-part)
+#[derive(Debug, Eq, Clone, PartialEq)]
+pub struct DatePart {
+    pub name: DatePartName,
+    /// `None` means `long` for a year or a month, and `numeric` for a day.
+    pub form: Option<DatePartForm>,
+    pub formatting: Option<Formatting>,
+    pub affixes: Option<Affixes>,
+    pub strip_periods: StripPeriods,
+    pub text_case: TextCase,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum DatePartName {
+    Year,
+    Month,
+    Day,
+}
+
+/// The forms that don't make sense for a part, like an ordinal month, are treated as its
+/// default.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum DatePartForm {
+    /// `2001`, `April`
+    Long,
+    /// `01`, `Apr.`
+    Short,
+    /// `4`
+    Numeric,
+    /// `04`
+    NumericLeadingZeros,
+    /// `1st`
+    Ordinal,
+}
+
+#[derive(Debug, Eq, Clone, PartialEq)]
+pub struct IfThen(pub Conditions, pub Vec<Element>);
+
+#[derive(Default, Debug, Eq, Clone, PartialEq)]
+pub struct Else(pub Vec<Element>);
+
+#[derive(Debug, Eq, Clone, PartialEq)]
+pub struct Conditions(pub Match, pub Vec<Cond>);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Match {
+    Any,
+    All,
+    None,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Cond {
+    /// The reference's CSL type, like `book`.
+    Type(String),
+    Variable(AnyVariable),
+    Position(Position),
+    IsNumeric(AnyVariable),
+    /// `disambiguate="true"` is only ever true once the disambiguation pass gets around to
+    /// trying it on a cite that is still ambiguous.
+    Disambiguate(bool),
+    /// The cite's locator type.
+    Locator(LocatorType),
+}
+
+#[derive(Debug, Eq, Clone, PartialEq)]
+pub struct Names {
+    pub variables: Vec<NameVariable>,
+    pub name: Option<Name>,
+    pub delimiter: Option<Delimiter>,
+    pub formatting: Option<Formatting>,
+    pub affixes: Option<Affixes>,
+    pub display: Option<DisplayMode>,
+    pub substitute: Option<Substitute>,
+}
+
+/// What a `<names>` renders when none of its variables has any names. The first element that
+/// renders anything is used, and the variables it used are suppressed for the rest of the cite.
+#[derive(Default, Debug, Eq, Clone, PartialEq)]
+pub struct Substitute(pub Vec<Element>);
+
 #[derive(Debug, Eq, Clone, PartialEq)]
 pub enum TextSource {
-    Macro(String),
+    Macro(MacroRef),
     Value(String),
     Variable(StandardVariable, VariableForm),
     Term(TextTermSelector, TermPlural),
@@ -180,15 +677,18 @@ type Quotes = bool;
 #[derive(Debug, Eq, Clone, PartialEq, Default, Hash, Copy)]
 pub struct Formatting;
 #[derive(Debug, Eq, Clone, PartialEq, Default, Hash)]
-pub struct Delimiter;
+pub struct Delimiter(pub String);
 #[derive(Debug, Eq, Clone, PartialEq, Default, Hash)]
 pub struct DisplayMode;
 #[derive(Debug, Eq, Clone, PartialEq, Default, Hash)]
-pub struct Affixes;
+pub struct Affixes {
+    pub prefix: String,
+    pub suffix: String,
+}
 #[derive(Debug, Eq, Clone, PartialEq, Default, Hash)]
 pub struct TextCase;
 
-#[derive(Debug, Eq, Clone, PartialEq)]
+#[derive(Debug, Eq, Clone, PartialEq, Hash)]
 pub enum LocatorType {
     Book,
     Chapter,
@@ -227,6 +727,28 @@ pub enum Position {
     FarNote,
 }
 
+impl Position {
+    /// Whether a cite in this position satisfies `<if position="...">`, where `condition` is
+    /// one of `First`, `Ibid`, `IbidWithLocator`, `Subsequent` or `NearNote`. A cite only ever
+    /// has one computed position, but several conditions can be true of it.
+    pub fn matches(self, condition: Position) -> bool {
+        use self::Position::*;
+        match condition {
+            First => self == First,
+            Ibid => matches!(
+                self,
+                Ibid | IbidNear | IbidWithLocator | IbidWithLocatorNear
+            ),
+            IbidWithLocator => matches!(self, IbidWithLocator | IbidWithLocatorNear),
+            Subsequent => self != First,
+            NearNote => matches!(self, NearNote | IbidNear | IbidWithLocatorNear),
+            FarNote => matches!(self, FarNote | Ibid | IbidWithLocator),
+            IbidNear => self == IbidNear,
+            IbidWithLocatorNear => self == IbidWithLocatorNear,
+        }
+    }
+}
+
 // This Source Code Form is subject to the terms of the Mozilla Public
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
@@ -252,6 +774,27 @@ pub enum StandardVariable {
     Number(NumberVariable),
 }
 
+impl AnyVariable {
+    /// The variables only CSL-M styles can use.
+    pub fn is_csl_m_only(self) -> bool {
+        matches!(
+            self,
+            AnyVariable::Ordinary(Variable::Hereinafter)
+                | AnyVariable::Ordinary(Variable::LocatorExtra)
+                | AnyVariable::Ordinary(Variable::VolumeTitle)
+                | AnyVariable::Ordinary(Variable::Committee)
+                | AnyVariable::Ordinary(Variable::DocumentName)
+                | AnyVariable::Ordinary(Variable::GazetteFlag)
+                | AnyVariable::Number(NumberVariable::PublicationNumber)
+                | AnyVariable::Number(NumberVariable::Supplement)
+                | AnyVariable::Name(NameVariable::Dummy)
+                | AnyVariable::Date(DateVariable::LocatorDate)
+                | AnyVariable::Date(DateVariable::PublicationDate)
+                | AnyVariable::Date(DateVariable::AvailableDate)
+        )
+    }
+}
+
 impl From<&StandardVariable> for AnyVariable {
     fn from(sv: &StandardVariable) -> Self {
         match sv {
@@ -351,10 +894,10 @@ pub enum Variable {
     YearSuffix,
 
     /// CSL-M only
-    // Intercept Hereinafter at CiteContext, as it isn't known at Reference-time.
-    // Global-per-document config should be its own thing separate from references.
-    // TODO: delete any noRef="true" and replace with serde directives not to read from
-    // CSL-JSON.
+    ///
+    /// Not read from the reference: the short form an author chooses for a reference is a
+    /// per-document setting, which `RefContext` carries at cite time. See
+    /// `Database::set_hereinafter`.
     Hereinafter,
     /// CSL-M only
     Dummy,
@@ -496,7 +1039,7 @@ pub enum DateVariable {
 
 #[derive(Debug, Eq, Clone, PartialEq, Hash)]
 pub struct Name {
-    pub and: Option<()>,
+    pub and: Option<NameAnd>,
     /// Between individual names for the same variable
     pub delimiter: Option<Delimiter>,
     pub delimiter_precedes_et_al: Option<DelimiterPrecedes>,
@@ -517,6 +1060,13 @@ pub struct Name {
     pub name_part_family: Option<NamePart>,
 }
 
+/// Whether the last name is joined by the `and` term or by `&`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum NameAnd {
+    Text,
+    Symbol,
+}
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub enum NameAsSortOrder {
     First,
@@ -565,14 +1115,14 @@ impl Name {
     pub fn root_default() -> Self {
         Name {
             and: None,
-            delimiter: Some(Delimiter),
+            delimiter: Some(Delimiter(", ".into())),
             delimiter_precedes_et_al: Some(DelimiterPrecedes::Contextual),
             delimiter_precedes_last: Some(DelimiterPrecedes::Contextual),
             et_al_min: None,
             et_al_use_first: None,
             et_al_use_last: Some(false),
-            et_al_subsequent_min: None, // must fall back to et_al_min
-            et_al_subsequent_use_first: None, // must fall back to et_al_use_first
+            et_al_subsequent_min: None, // falls back to et_al_min, see for_position
+            et_al_subsequent_use_first: None, // falls back to et_al_use_first
             // https://github.com/Juris-M/citeproc-js/blob/30ceaf50a0ef86517a9a8cd46362e450133c7f91/src/util_names_render.js#L710
             form: Some(NameForm::Long),
             initialize: Some(true),
@@ -597,7 +1147,7 @@ impl Name {
     ///
     pub fn merge(&self, overrider: &Self) -> Self {
         Name {
-            and: overrider.and.clone().or(self.and),
+            and: overrider.and.or(self.and),
             delimiter: overrider
                 .delimiter
                 .clone()
@@ -638,6 +1188,20 @@ impl Name {
     pub fn enable_et_al(&self) -> bool {
         self.et_al_min.is_some() && self.et_al_use_first.is_some()
     }
+
+    /// Cites in any position but `first` use `et-al-subsequent-min` and
+    /// `et-al-subsequent-use-first` in place of `et-al-min` and `et-al-use-first`, where they
+    /// are set.
+    pub fn for_position(&self, position: Position) -> Self {
+        if position == Position::First {
+            return self.clone();
+        }
+        Name {
+            et_al_min: self.et_al_subsequent_min.or(self.et_al_min),
+            et_al_use_first: self.et_al_subsequent_use_first.or(self.et_al_use_first),
+            ..self.clone()
+        }
+    }
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
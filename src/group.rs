@@ -77,6 +77,10 @@ impl GroupVars {
     /// assert_eq!(Plain.neighbour(Missing), Missing);
     /// assert_eq!(Important.neighbour(Missing), Important);
     /// ```
+    ///
+    /// The order elements are combined in doesn't matter: it's commutative and associative.
+    /// `Plain` leaves the other side as it is, except that `Unresolved` becomes
+    /// `UnresolvedPlain`, so a sequence folded from `new()` never ends up `Unresolved`.
     pub fn neighbour(self, other: Self) -> Self {
         match (self, other) {
             // if either is Important, the parent group will be too. For sure. Don't need to track
@@ -116,3 +120,103 @@ impl GroupVars {
         }
     }
 }
+
+#[cfg(test)]
+const ALL: [GroupVars; 6] = [
+    Plain,
+    Missing,
+    Important,
+    Unresolved,
+    UnresolvedMissing,
+    UnresolvedPlain,
+];
+
+#[test]
+fn documented_examples() {
+    assert_eq!(Plain.neighbour(Important), Important);
+    assert_eq!(Plain.neighbour(Missing), Missing);
+    assert_eq!(Important.neighbour(Missing), Important);
+}
+
+#[test]
+fn neighbour_is_commutative_and_associative() {
+    for &a in &ALL {
+        for &b in &ALL {
+            assert_eq!(a.neighbour(b), b.neighbour(a), "{:?} {:?}", a, b);
+            for &c in &ALL {
+                assert_eq!(
+                    a.neighbour(b).neighbour(c),
+                    a.neighbour(b.neighbour(c)),
+                    "{:?} {:?} {:?}",
+                    a,
+                    b,
+                    c
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn plain_is_an_identity_but_for_unresolved() {
+    for &a in &ALL {
+        let expected = if a == Unresolved { UnresolvedPlain } else { a };
+        assert_eq!(Plain.neighbour(a), expected, "{:?}", a);
+    }
+    assert_eq!(GroupVars::new(), Plain);
+}
+
+#[test]
+fn important_absorbs() {
+    for &a in &ALL {
+        assert_eq!(Important.neighbour(a), Important, "{:?}", a);
+    }
+}
+
+/// Folding every sequence of up to four children, the way a `<group>` combines its children,
+/// gives what the comment at the top of this file and the variants' docs describe.
+#[test]
+fn folding_matches_group_suppression() {
+    fn described(children: &[GroupVars]) -> GroupVars {
+        let any = |gvs: &[GroupVars]| children.iter().any(|c| gvs.contains(c));
+        let unresolved = any(&[Unresolved, UnresolvedMissing, UnresolvedPlain]);
+        if any(&[Important]) {
+            // A variable rendered, so the group does
+            Important
+        } else if any(&[Missing, UnresolvedMissing]) {
+            // Every variable was empty, so the group is suppressed, unless disambiguation might
+            // fill one in later
+            if unresolved {
+                UnresolvedMissing
+            } else {
+                Missing
+            }
+        } else if unresolved {
+            UnresolvedPlain
+        } else {
+            Plain
+        }
+    }
+    let mut sequences: Vec<Vec<GroupVars>> = vec![vec![]];
+    for _ in 0..4 {
+        let longer: Vec<Vec<GroupVars>> = sequences
+            .iter()
+            .filter(|seq| seq.len() == sequences.last().unwrap().len())
+            .flat_map(|seq| {
+                ALL.iter().map(move |&gv| {
+                    let mut seq = seq.clone();
+                    seq.push(gv);
+                    seq
+                })
+            })
+            .collect();
+        sequences.extend(longer);
+    }
+    assert_eq!(sequences.len(), 1 + 6 + 36 + 216 + 1296);
+    for children in &sequences {
+        let folded = children
+            .iter()
+            .fold(GroupVars::new(), |acc, &gv| acc.neighbour(gv));
+        assert_eq!(folded, described(children), "{:?}", children);
+    }
+}
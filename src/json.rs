@@ -0,0 +1,318 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Copyright © 2019 Corporation for Digital Scholarship
+
+//! Just enough of a JSON parser to read CSL-JSON references, and the cites the command line
+//! renders.
+//!
+//! Numbers are kept as they were written, because CSL-JSON uses them for things like volumes
+//! and page counts that are rendered as text rather than calculated with.
+
+use crate::xml::TextPos;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(String),
+    String(String),
+    Array(Vec<Value>),
+    /// In the order the keys were written. A repeated key keeps every value, and `get` finds
+    /// the first.
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Object(members) => members.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Value]> {
+        match self {
+            Value::Array(values) => Some(values),
+            _ => None,
+        }
+    }
+
+    /// The text of a string, or of a number as it was written.
+    pub fn as_text(&self) -> Option<&str> {
+        match self {
+            Value::String(s) | Value::Number(s) => Some(s),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JsonError {
+    pub message: String,
+    pub pos: TextPos,
+}
+
+impl fmt::Display for JsonError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at {}", self.message, self.pos)
+    }
+}
+
+/// Parses a whole document, which can be any JSON value.
+pub fn parse(input: &str) -> Result<Value, JsonError> {
+    let mut parser = Parser {
+        rest: input,
+        pos: TextPos { row: 1, col: 1 },
+    };
+    parser.skip_whitespace();
+    let value = parser.value()?;
+    parser.skip_whitespace();
+    if !parser.rest.is_empty() {
+        return Err(parser.error("unexpected content after the value"));
+    }
+    Ok(value)
+}
+
+struct Parser<'a> {
+    rest: &'a str,
+    pos: TextPos,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, message: impl Into<String>) -> JsonError {
+        JsonError {
+            message: message.into(),
+            pos: self.pos,
+        }
+    }
+
+    fn advance(&mut self, bytes: usize) -> &'a str {
+        let (taken, rest) = self.rest.split_at(bytes);
+        for c in taken.chars() {
+            if c == '\n' {
+                self.pos.row += 1;
+                self.pos.col = 1;
+            } else {
+                self.pos.col += 1;
+            }
+        }
+        self.rest = rest;
+        taken
+    }
+
+    fn eat(&mut self, prefix: &str) -> bool {
+        if self.rest.starts_with(prefix) {
+            self.advance(prefix.len());
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, prefix: &str) -> Result<(), JsonError> {
+        if self.eat(prefix) {
+            Ok(())
+        } else {
+            Err(self.error(format!("expected `{}`", prefix)))
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        let len = self.rest.len() - self.rest.trim_start_matches([' ', '\t', '\n', '\r']).len();
+        self.advance(len);
+    }
+
+    fn value(&mut self) -> Result<Value, JsonError> {
+        match self.rest.chars().next() {
+            Some('{') => self.object(),
+            Some('[') => self.array(),
+            Some('"') => Ok(Value::String(self.string()?)),
+            Some('-') | Some('0'..='9') => self.number(),
+            _ if self.eat("null") => Ok(Value::Null),
+            _ if self.eat("true") => Ok(Value::Bool(true)),
+            _ if self.eat("false") => Ok(Value::Bool(false)),
+            Some(_) => Err(self.error("expected a value")),
+            None => Err(self.error("unexpected end of input")),
+        }
+    }
+
+    fn object(&mut self) -> Result<Value, JsonError> {
+        self.expect("{")?;
+        let mut members = Vec::new();
+        self.skip_whitespace();
+        if self.eat("}") {
+            return Ok(Value::Object(members));
+        }
+        loop {
+            self.skip_whitespace();
+            if !self.rest.starts_with('"') {
+                return Err(self.error("expected a string key"));
+            }
+            let key = self.string()?;
+            self.skip_whitespace();
+            self.expect(":")?;
+            self.skip_whitespace();
+            members.push((key, self.value()?));
+            self.skip_whitespace();
+            if self.eat("}") {
+                return Ok(Value::Object(members));
+            }
+            self.expect(",")?;
+        }
+    }
+
+    fn array(&mut self) -> Result<Value, JsonError> {
+        self.expect("[")?;
+        let mut values = Vec::new();
+        self.skip_whitespace();
+        if self.eat("]") {
+            return Ok(Value::Array(values));
+        }
+        loop {
+            self.skip_whitespace();
+            values.push(self.value()?);
+            self.skip_whitespace();
+            if self.eat("]") {
+                return Ok(Value::Array(values));
+            }
+            self.expect(",")?;
+        }
+    }
+
+    fn number(&mut self) -> Result<Value, JsonError> {
+        let len = self
+            .rest
+            .find(|c: char| !matches!(c, '0'..='9' | '-' | '+' | '.' | 'e' | 'E'))
+            .unwrap_or(self.rest.len());
+        let text = &self.rest[..len];
+        if text.parse::<f64>().is_err() || text.starts_with('+') {
+            return Err(self.error(format!("invalid number `{}`", text)));
+        }
+        Ok(Value::Number(self.advance(len).to_string()))
+    }
+
+    fn string(&mut self) -> Result<String, JsonError> {
+        self.expect("\"")?;
+        let mut out = String::new();
+        loop {
+            let len = self
+                .rest
+                .find(|c: char| c == '"' || c == '\\' || c < ' ')
+                .unwrap_or(self.rest.len());
+            out.push_str(self.advance(len));
+            match self.rest.chars().next() {
+                Some('"') => {
+                    self.advance(1);
+                    return Ok(out);
+                }
+                Some('\\') => out.push(self.escape()?),
+                Some(_) => return Err(self.error("control character in string")),
+                None => return Err(self.error("unterminated string")),
+            }
+        }
+    }
+
+    /// A backslash escape, including both halves of a surrogate pair.
+    fn escape(&mut self) -> Result<char, JsonError> {
+        let pos = self.pos;
+        self.expect("\\")?;
+        let c = match self.rest.chars().next() {
+            Some(c @ ('"' | '\\' | '/')) => c,
+            Some('b') => '\u{8}',
+            Some('f') => '\u{c}',
+            Some('n') => '\n',
+            Some('r') => '\r',
+            Some('t') => '\t',
+            Some('u') => {
+                self.advance(1);
+                let high = self.hex4()?;
+                let invalid = JsonError {
+                    message: "invalid \\u escape".into(),
+                    pos,
+                };
+                let code = if (0xD800..0xDC00).contains(&high) && self.eat("\\u") {
+                    let low = self.hex4()?;
+                    if !(0xDC00..0xE000).contains(&low) {
+                        return Err(invalid);
+                    }
+                    0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00)
+                } else {
+                    high
+                };
+                return std::char::from_u32(code).ok_or(invalid);
+            }
+            _ => {
+                return Err(JsonError {
+                    message: "invalid escape".into(),
+                    pos,
+                })
+            }
+        };
+        self.advance(c.len_utf8());
+        Ok(c)
+    }
+
+    fn hex4(&mut self) -> Result<u32, JsonError> {
+        let digits = self
+            .rest
+            .get(..4)
+            .filter(|d| d.chars().all(|c| c.is_ascii_hexdigit()));
+        match digits.and_then(|d| u32::from_str_radix(d, 16).ok()) {
+            Some(code) => {
+                self.advance(4);
+                Ok(code)
+            }
+            None => Err(self.error("expected four hex digits")),
+        }
+    }
+}
+
+#[test]
+fn parses_values() {
+    let doc = parse(
+        r#" {"id": "a", "volume": 12, "page": -1.5e3, "flags": [true, false, null],
+  "title": "A \"B\" é😀\n", "empty": {}, "none": []} "#,
+    )
+    .unwrap();
+    assert_eq!(doc.get("id"), Some(&Value::String("a".into())));
+    assert_eq!(doc.get("volume").and_then(Value::as_text), Some("12"));
+    assert_eq!(doc.get("page"), Some(&Value::Number("-1.5e3".into())));
+    assert_eq!(
+        doc.get("flags").and_then(Value::as_array),
+        Some(&[Value::Bool(true), Value::Bool(false), Value::Null][..])
+    );
+    assert_eq!(
+        doc.get("title").and_then(Value::as_str),
+        Some("A \"B\" é😀\n")
+    );
+    assert_eq!(doc.get("empty"), Some(&Value::Object(vec![])));
+    assert_eq!(doc.get("none"), Some(&Value::Array(vec![])));
+}
+
+#[test]
+fn reports_errors_with_positions() {
+    let err = parse("[1,\n  2,,]").unwrap_err();
+    assert_eq!(err.pos, TextPos { row: 2, col: 5 });
+    assert_eq!(err.to_string(), "expected a value at 2:5");
+    assert!(parse(r#"{"a" 1}"#).is_err());
+    assert!(parse(r#""tab	inside""#).is_err());
+    assert!(parse(r#""\x""#).is_err());
+    assert!(parse("[1] 2").is_err());
+    assert!(parse("+1").is_err());
+    assert_eq!(
+        parse(r#""\uD83D\uDE00""#).unwrap(),
+        Value::String("😀".into())
+    );
+    let err = parse(r#""\uD800\u0041""#).unwrap_err();
+    assert_eq!(err.message, "invalid \\u escape");
+}
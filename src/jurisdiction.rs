@@ -0,0 +1,177 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Copyright © 2019 Corporation for Digital Scholarship
+
+//! CSL-M jurisdiction modules.
+//!
+//! Legal citation differs from one court system to the next, so a CSL-M style leaves the
+//! jurisdiction-specific parts to macros (`juris-title`, `juris-main`, and so on) and lets a
+//! module for each jurisdiction replace them. A reference's `jurisdiction` is a path like
+//! `us:ca:sf`, optionally followed by `|` and a display name. The most specific jurisdiction
+//! that has a module wins, so `us:ca:sf` falls back to `us:ca` and then to `us`. Macros the
+//! module doesn't define come from the style as usual.
+
+use crate::element::{CslVariant, MacroTable, Style, Variable};
+use crate::reference::Reference;
+use crate::IrDatabase;
+use std::sync::Arc;
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct JurisdictionModule {
+    /// From `<law-module types="...">`. The reference types the module is for; empty means all.
+    pub types: Vec<String>,
+    /// Looked up by name, and never compiled: calls inside them are resolved against the
+    /// style when they're rendered.
+    pub macros: MacroTable,
+}
+
+impl JurisdictionModule {
+    pub fn applies_to(&self, reference: &Reference) -> bool {
+        self.types.is_empty() || self.types.contains(&reference.csl_type)
+    }
+}
+
+/// `us:ca:sf|San Francisco` => `["us:ca:sf", "us:ca", "us"]`.
+pub fn jurisdiction_chain(jurisdiction: &str) -> Vec<&str> {
+    let path = jurisdiction.split('|').next().unwrap_or("").trim();
+    let mut chain = Vec::new();
+    let mut rest = path;
+    while !rest.is_empty() {
+        chain.push(rest);
+        rest = rest.rfind(':').map_or("", |ix| &rest[..ix]);
+    }
+    chain
+}
+
+/// The module whose macros a reference renders with, if the style is CSL-M and one has been
+/// loaded for the reference's jurisdiction.
+pub fn module_for(
+    db: &dyn IrDatabase,
+    style: &Style,
+    reference: &Reference,
+) -> Option<Arc<JurisdictionModule>> {
+    if style.variant != CslVariant::CslM {
+        return None;
+    }
+    let jurisdiction = reference.ordinary.get(&Variable::Jurisdiction)?;
+    jurisdiction_chain(jurisdiction)
+        .into_iter()
+        .filter_map(|j| db.jurisdiction_module(j))
+        .find(|module| module.applies_to(reference))
+}
+
+#[test]
+fn chains() {
+    assert_eq!(
+        jurisdiction_chain("us:ca:sf"),
+        vec!["us:ca:sf", "us:ca", "us"]
+    );
+    assert_eq!(
+        jurisdiction_chain("us:c9|US Ninth Circuit"),
+        vec!["us:c9", "us"]
+    );
+    assert!(jurisdiction_chain("").is_empty());
+}
+
+#[test]
+fn modules_override_macros() {
+    use crate::cluster::{Cite, Cluster, ClusterId};
+    use crate::CiteId;
+    let style: Style = r#"<style class="note" version="1.1mlz1">
+      <macro name="juris-main"><text variable="title"/></macro>
+      <macro name="juris-locator"><text variable="locator" prefix=", at "/></macro>
+      <citation><layout delimiter="; ">
+        <text macro="juris-main"/><text macro="juris-locator"/>
+      </layout></citation>
+    </style>"#
+        .parse()
+        .unwrap();
+    let module: JurisdictionModule = r#"<style class="note" version="1.1mlz1">
+      <info><law-module types="legal_case"/></info>
+      <macro name="juris-main">
+        <group delimiter=" "><text variable="title"/><text variable="authority"/></group>
+      </macro>
+      <citation><layout/></citation>
+    </style>"#
+        .parse()
+        .unwrap();
+    assert_eq!(module.types, vec!["legal_case"]);
+    let mut db = crate::db::Database::new(style);
+    let make = |id: &str, csl_type: &str, jurisdiction: &str| {
+        let mut r = Reference::empty(id, csl_type);
+        r.ordinary.insert(Variable::Title, format!("Title {}", id));
+        r.ordinary.insert(Variable::Authority, "Cal.".into());
+        r.ordinary
+            .insert(Variable::Jurisdiction, jurisdiction.into());
+        r
+    };
+    db.set_references(vec![
+        make("a", "legal_case", "us:ca:sf|San Francisco"),
+        make("b", "legislation", "us:ca"),
+        make("c", "legal_case", "gb"),
+    ]);
+    db.set_jurisdiction_module("us:ca", Some(module));
+    let rule = |cite: Cite| cite.with_locator(crate::element::LocatorType::Rule, "12");
+    db.set_cluster(Cluster::new(
+        ClusterId(1),
+        vec![
+            rule(Cite::basic(CiteId(1), "a")),
+            Cite::basic(CiteId(2), "b"),
+            Cite::basic(CiteId(3), "c"),
+        ],
+    ));
+    assert_eq!(
+        *db.render_cluster(ClusterId(1)).unwrap(),
+        "Title a Cal., at 12; Title b; Title c"
+    );
+    db.set_jurisdiction_module("us:ca", None);
+    assert_eq!(
+        *db.render_cluster(ClusterId(1)).unwrap(),
+        "Title a, at 12; Title b; Title c"
+    );
+}
+
+#[test]
+fn module_calling_an_undefined_macro() {
+    use crate::cluster::{Cite, Cluster, ClusterId};
+    use crate::CiteId;
+    let style: Style = r#"<style class="note" version="1.1mlz1">
+      <macro name="juris-main"><text variable="title"/></macro>
+      <citation><layout delimiter="; "><text macro="juris-main"/></layout></citation>
+    </style>"#
+        .parse()
+        .unwrap();
+    // The style's macros are only known once the module is used, so this parses
+    let module: JurisdictionModule = r#"<style class="note" version="1.1mlz1">
+      <macro name="juris-main"><text macro="juris-tail"/></macro>
+      <citation><layout/></citation>
+    </style>"#
+        .parse()
+        .unwrap();
+    let mut db = crate::db::Database::new(style);
+    let make = |id: &str, jurisdiction: &str| {
+        let mut r = Reference::empty(id, "legal_case");
+        r.ordinary.insert(Variable::Title, format!("Title {}", id));
+        r.ordinary
+            .insert(Variable::Jurisdiction, jurisdiction.into());
+        r
+    };
+    db.set_references(vec![make("a", "us"), make("b", "gb")]);
+    db.set_jurisdiction_module("us", Some(module));
+    db.set_cluster(Cluster::new(
+        ClusterId(1),
+        vec![Cite::basic(CiteId(1), "a")],
+    ));
+    db.set_cluster(Cluster::new(
+        ClusterId(2),
+        vec![Cite::basic(CiteId(2), "b")],
+    ));
+    // Only the cites that use the module fail
+    assert_eq!(
+        db.render_cluster(ClusterId(1)).unwrap_err().to_string(),
+        "undefined macro `juris-tail`"
+    );
+    assert_eq!(*db.render_cluster(ClusterId(2)).unwrap(), "Title b");
+}
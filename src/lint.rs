@@ -0,0 +1,351 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Copyright © 2019 Corporation for Digital Scholarship
+
+//! Warnings about parts of a style that can't do what they look like they do.
+//!
+//! Unlike `StyleError`s, none of these stop a style from rendering. Each has a `LintCode` that
+//! stays the same from release to release, so CI can allow or deny them one by one.
+
+use crate::element::*;
+use std::collections::HashSet;
+use std::fmt;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum LintCode {
+    /// A macro that nothing calls, directly or through other macros.
+    UncalledMacro,
+    /// A `<group>` with no variables in it, which is never suppressed.
+    GroupWithoutVariables,
+    /// A `<label>` with no `<group>` around it that has a variable.
+    LabelOutsideGroup,
+    /// `disambiguate="true"` in the bibliography, where nothing is disambiguated.
+    DisambiguateInBibliography,
+    /// `et-al-min` or `et-al-use-first` without the other, so names are never cut short.
+    IneffectiveEtAl,
+}
+
+impl LintCode {
+    /// Never renumbered, and never reused for a different lint.
+    pub fn code(self) -> &'static str {
+        match self {
+            LintCode::UncalledMacro => "L001",
+            LintCode::GroupWithoutVariables => "L002",
+            LintCode::LabelOutsideGroup => "L003",
+            LintCode::DisambiguateInBibliography => "L004",
+            LintCode::IneffectiveEtAl => "L005",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Diagnostic {
+    pub code: LintCode,
+    /// Where the problem is: a macro, or the layout of the citation or bibliography.
+    pub location: String,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} in {}: {}",
+            self.code.code(),
+            self.location,
+            self.message
+        )
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+enum Section {
+    Citation,
+    Bibliography,
+    SortKey,
+}
+
+struct Linter<'a> {
+    style: &'a Style,
+    called: HashSet<&'a str>,
+    /// Macros already walked, with the section and whether they were inside a group with a
+    /// variable, which is all that changes what they report.
+    walked: HashSet<(&'a str, Section, bool)>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+/// Everything wrong with the style, in the order the citation, then the bibliography, then the
+/// sort keys reach it, and then the macros nothing calls.
+pub fn lint(style: &Style) -> Vec<Diagnostic> {
+    let mut linter = Linter {
+        style,
+        called: HashSet::new(),
+        walked: HashSet::new(),
+        diagnostics: Vec::new(),
+    };
+    for layout in std::iter::once(&style.citation.layout).chain(&style.citation.locale_layouts) {
+        linter.walk(
+            &layout.elements,
+            "the citation layout",
+            Section::Citation,
+            false,
+        );
+    }
+    if let Some(bib) = &style.bibliography {
+        for layout in std::iter::once(&bib.layout).chain(&bib.locale_layouts) {
+            linter.walk(
+                &layout.elements,
+                "the bibliography layout",
+                Section::Bibliography,
+                false,
+            );
+        }
+    }
+    let sorts = style
+        .citation
+        .sort
+        .iter()
+        .chain(style.bibliography.iter().flat_map(|bib| bib.sort.as_ref()));
+    for key in sorts.flat_map(|sort| sort.keys.iter()) {
+        if let SortSource::Macro(call) = &key.source {
+            linter.call(call, Section::SortKey, false);
+        }
+    }
+    for m in style.macros.iter() {
+        if !linter.called.contains(m.name.as_str()) {
+            linter.report(
+                LintCode::UncalledMacro,
+                &macro_location(&m.name),
+                "nothing calls this macro".into(),
+            );
+        }
+    }
+    linter.diagnostics
+}
+
+fn macro_location(name: &str) -> String {
+    format!("macro `{}`", name)
+}
+
+impl<'a> Linter<'a> {
+    fn report(&mut self, code: LintCode, location: &str, message: String) {
+        let diagnostic = Diagnostic {
+            code,
+            location: location.into(),
+            message,
+        };
+        if !self.diagnostics.contains(&diagnostic) {
+            self.diagnostics.push(diagnostic);
+        }
+    }
+
+    fn call(&mut self, call: &'a MacroRef, section: Section, in_group: bool) {
+        let name = call.name.as_str();
+        self.called.insert(name);
+        if !self.walked.insert((name, section, in_group)) {
+            return;
+        }
+        if let Some(elements) = self.style.macros.get(name) {
+            self.walk(elements, &macro_location(name), section, in_group);
+        }
+    }
+
+    /// `in_group` is whether some `<group>` around `elements` has a variable.
+    fn walk(&mut self, elements: &'a [Element], location: &str, section: Section, in_group: bool) {
+        for el in elements {
+            match el {
+                Element::Text(TextElement {
+                    source: TextSource::Macro(call),
+                    ..
+                }) => self.call(call, section, in_group),
+                Element::Label(_) if !in_group => self.report(
+                    LintCode::LabelOutsideGroup,
+                    location,
+                    "a <label> isn't inside a <group> with a variable, so it can show on its own"
+                        .into(),
+                ),
+                Element::Group(group) => {
+                    let has_variables = self.has_variables(&group.elements, &mut HashSet::new());
+                    if !has_variables {
+                        self.report(
+                            LintCode::GroupWithoutVariables,
+                            location,
+                            "a <group> with no variables in it is never suppressed".into(),
+                        );
+                    }
+                    self.walk(
+                        &group.elements,
+                        location,
+                        section,
+                        in_group || has_variables,
+                    );
+                }
+                Element::Choose(choose) => {
+                    let Choose(head, rest, Else(otherwise)) = &**choose;
+                    for IfThen(Conditions(_, conds), elements) in std::iter::once(head).chain(rest)
+                    {
+                        let disambiguate = conds.iter().any(|c| matches!(c, Cond::Disambiguate(_)));
+                        if disambiguate && section == Section::Bibliography {
+                            self.report(
+                                LintCode::DisambiguateInBibliography,
+                                location,
+                                "`disambiguate` is never true in the bibliography".into(),
+                            );
+                        }
+                        self.walk(elements, location, section, in_group);
+                    }
+                    self.walk(otherwise, location, section, in_group);
+                }
+                Element::Names(names) => {
+                    self.check_et_al(names, location, section);
+                    if let Some(Substitute(elements)) = &names.substitute {
+                        self.walk(elements, location, section, in_group);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Whether a group around `elements` could be suppressed for having only empty variables.
+    /// `seen` stops a macro that calls itself going round forever.
+    fn has_variables(&self, elements: &'a [Element], seen: &mut HashSet<&'a str>) -> bool {
+        elements.iter().any(|el| match el {
+            Element::Text(text) => match &text.source {
+                TextSource::Variable(..) => true,
+                TextSource::Macro(call) => {
+                    seen.insert(&call.name)
+                        && self
+                            .style
+                            .macros
+                            .get(&call.name)
+                            .is_some_and(|elements| self.has_variables(elements, seen))
+                }
+                TextSource::Value(_) | TextSource::Term(..) => false,
+            },
+            Element::Number(_) | Element::Names(_) | Element::Date(_) => true,
+            Element::Label(_) => false,
+            Element::Group(group) => self.has_variables(&group.elements, seen),
+            Element::Choose(choose) => {
+                let Choose(head, rest, Else(otherwise)) = &**choose;
+                std::iter::once(head)
+                    .chain(rest)
+                    .any(|IfThen(_, elements)| self.has_variables(elements, seen))
+                    || self.has_variables(otherwise, seen)
+            }
+        })
+    }
+
+    fn check_et_al(&mut self, names: &Names, location: &str, section: Section) {
+        let inherited = match section {
+            Section::Citation => &self.style.citation.name_inheritance,
+            Section::Bibliography => match &self.style.bibliography {
+                Some(bib) => &bib.name_inheritance,
+                None => return,
+            },
+            Section::SortKey => &self.style.name_inheritance,
+        };
+        let mut name = self.style.inherited_name(inherited);
+        if let Some(local) = &names.name {
+            name = name.merge(local);
+        }
+        let message = |first: &str, second: &str| {
+            format!(
+                "`{}` without `{}` never shortens the list of names",
+                first, second
+            )
+        };
+        match (name.et_al_min, name.et_al_use_first) {
+            (Some(_), None) => self.report(
+                LintCode::IneffectiveEtAl,
+                location,
+                message("et-al-min", "et-al-use-first"),
+            ),
+            (None, Some(_)) => self.report(
+                LintCode::IneffectiveEtAl,
+                location,
+                message("et-al-use-first", "et-al-min"),
+            ),
+            _ => {}
+        }
+        if section != Section::Citation {
+            return;
+        }
+        let subsequent = name.for_position(Position::Subsequent);
+        match (subsequent.et_al_min, subsequent.et_al_use_first) {
+            (Some(_), None) if name.et_al_subsequent_min.is_some() => self.report(
+                LintCode::IneffectiveEtAl,
+                location,
+                message("et-al-subsequent-min", "et-al-subsequent-use-first"),
+            ),
+            (None, Some(_)) if name.et_al_subsequent_use_first.is_some() => self.report(
+                LintCode::IneffectiveEtAl,
+                location,
+                message("et-al-subsequent-use-first", "et-al-subsequent-min"),
+            ),
+            _ => {}
+        }
+    }
+}
+
+#[test]
+fn clean_style() {
+    let style: Style = r#"<style class="in-text">
+      <macro name="author"><names variable="author"/></macro>
+      <citation et-al-min="3" et-al-use-first="1">
+        <layout>
+          <group delimiter=" "><text macro="author"/><label variable="page"/></group>
+        </layout>
+      </citation>
+    </style>"#
+        .parse()
+        .unwrap();
+    assert_eq!(lint(&style), vec![]);
+}
+
+#[test]
+fn reports_each_lint() {
+    let style: Style = r#"<style class="in-text">
+      <macro name="author"><names variable="author"/></macro>
+      <macro name="unused"><text macro="also-unused"/></macro>
+      <macro name="also-unused"><text variable="title"/></macro>
+      <macro name="pages">
+        <label variable="page" suffix=" "/><text variable="page"/>
+      </macro>
+      <macro name="title">
+        <choose>
+          <if disambiguate="true"><text variable="title"/></if>
+          <else><text variable="title" form="short"/></else>
+        </choose>
+      </macro>
+      <citation et-al-subsequent-min="3">
+        <layout>
+          <group delimiter=" "><text macro="author"/><text macro="pages"/></group>
+          <group prefix="(" suffix=")"><text term="ibid"/></group>
+        </layout>
+      </citation>
+      <bibliography et-al-min="3">
+        <layout><text macro="author"/><text macro="title"/><text macro="pages"/></layout>
+      </bibliography>
+    </style>"#
+        .parse()
+        .unwrap();
+    let found: Vec<String> = lint(&style).iter().map(|d| d.to_string()).collect();
+    assert_eq!(
+        found,
+        vec![
+            "L005 in macro `author`: `et-al-subsequent-min` without \
+             `et-al-subsequent-use-first` never shortens the list of names",
+            "L002 in the citation layout: a <group> with no variables in it is never suppressed",
+            "L005 in macro `author`: `et-al-min` without `et-al-use-first` never shortens the \
+             list of names",
+            "L004 in macro `title`: `disambiguate` is never true in the bibliography",
+            "L003 in macro `pages`: a <label> isn't inside a <group> with a variable, so it can \
+             show on its own",
+            "L001 in macro `unused`: nothing calls this macro",
+            "L001 in macro `also-unused`: nothing calls this macro",
+        ]
+    );
+}
@@ -0,0 +1,215 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Copyright © 2018 Corporation for Digital Scholarship
+
+use crate::element::{
+    Affixes, DateForm, DatePart, DatePartForm, DatePartName, Delimiter, LocatorType, MiscTerm,
+    TextCase,
+};
+use std::collections::HashMap;
+
+/// Used when neither the style nor the caller asks for anything else, and as the last fallback
+/// for a locale the database doesn't have.
+pub const DEFAULT_LANG: &str = "en-US";
+
+/// The terms a style renders in a given language. Only the short forms of locator terms, the
+/// long forms of the miscellaneous ones, ordinals, months and date formats, so far.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Locale {
+    pub lang: String,
+    pub locators: HashMap<LocatorType, String>,
+    pub misc: HashMap<MiscTerm, String>,
+    /// The `ordinal` term, used when none of `ordinals` match.
+    pub ordinal: Option<String>,
+    /// `ordinal-00` to `ordinal-99`, by number.
+    pub ordinals: HashMap<u32, String>,
+    /// `long-ordinal-01` to `long-ordinal-10`, by number.
+    pub long_ordinals: HashMap<u32, String>,
+    /// `month-01` to `month-12`, by number.
+    pub months: HashMap<u32, String>,
+    /// The short forms of `months`.
+    pub short_months: HashMap<u32, String>,
+    pub dates: HashMap<DateForm, DateFormat>,
+}
+
+/// A locale's `<date>`, which lays out the dates a style asks for in one form.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DateFormat {
+    pub parts: Vec<DatePart>,
+    pub delimiter: Option<Delimiter>,
+}
+
+impl Locale {
+    pub fn new(lang: impl Into<String>) -> Self {
+        Locale {
+            lang: lang.into(),
+            locators: HashMap::new(),
+            misc: HashMap::new(),
+            ordinal: None,
+            ordinals: HashMap::new(),
+            long_ordinals: HashMap::new(),
+            months: HashMap::new(),
+            short_months: HashMap::new(),
+            dates: HashMap::new(),
+        }
+    }
+
+    /// The built-in `en-US` locale.
+    pub fn en_us() -> Self {
+        use LocatorType::*;
+        let locators = [
+            (Book, "bk."),
+            (Chapter, "chap."),
+            (Column, "col."),
+            (Figure, "fig."),
+            (Folio, "fol."),
+            (Issue, "no."),
+            (Line, "l."),
+            (Note, "n."),
+            (Opus, "op."),
+            (Page, "p."),
+            (Paragraph, "para."),
+            (Part, "pt."),
+            (Section, "sec."),
+            (SubVerbo, "s.v."),
+            (Verse, "v."),
+            (Volume, "vol."),
+            (Article, "art."),
+            (Subparagraph, "subpara."),
+            (Rule, "r."),
+            (Subsection, "subsec."),
+            (Schedule, "sch."),
+            (Title, "tit."),
+            (Supplement, "supp."),
+        ];
+        Locale {
+            lang: DEFAULT_LANG.into(),
+            locators: locators
+                .iter()
+                .map(|(loc, term)| (loc.clone(), term.to_string()))
+                .collect(),
+            misc: [
+                (MiscTerm::And, "and"),
+                (MiscTerm::AndOthers, "and others"),
+                (MiscTerm::EtAl, "et al."),
+            ]
+            .iter()
+            .map(|(term, s)| (*term, s.to_string()))
+            .collect(),
+            ordinal: Some("th".into()),
+            ordinals: [(1, "st"), (2, "nd"), (3, "rd"), (11, "th"), (12, "th"), (13, "th")]
+                .iter()
+                .map(|(n, s)| (*n, s.to_string()))
+                .collect(),
+            long_ordinals: [
+                "first", "second", "third", "fourth", "fifth", "sixth", "seventh", "eighth",
+                "ninth", "tenth",
+            ]
+            .iter()
+            .zip(1..)
+            .map(|(s, n)| (n, s.to_string()))
+            .collect(),
+            months: [
+                "January", "February", "March", "April", "May", "June", "July", "August",
+                "September", "October", "November", "December",
+            ]
+            .iter()
+            .zip(1..)
+            .map(|(s, n)| (n, s.to_string()))
+            .collect(),
+            short_months: [
+                "Jan.", "Feb.", "Mar.", "Apr.", "May", "June", "July", "Aug.", "Sep.", "Oct.",
+                "Nov.", "Dec.",
+            ]
+            .iter()
+            .zip(1..)
+            .map(|(s, n)| (n, s.to_string()))
+            .collect(),
+            dates: [
+                (
+                    DateForm::Numeric,
+                    en_us_date(&[
+                        (DatePartName::Month, DatePartForm::NumericLeadingZeros, "/"),
+                        (DatePartName::Day, DatePartForm::NumericLeadingZeros, "/"),
+                        (DatePartName::Year, DatePartForm::Long, ""),
+                    ]),
+                ),
+                (
+                    DateForm::Text,
+                    en_us_date(&[
+                        (DatePartName::Month, DatePartForm::Long, " "),
+                        (DatePartName::Day, DatePartForm::Numeric, ", "),
+                        (DatePartName::Year, DatePartForm::Long, ""),
+                    ]),
+                ),
+            ]
+            .iter()
+            .cloned()
+            .collect(),
+        }
+    }
+
+    pub fn locator_term(&self, locator_type: &LocatorType) -> Option<&str> {
+        self.locators.get(locator_type).map(|s| s.as_str())
+    }
+
+    pub fn misc_term(&self, term: MiscTerm) -> Option<&str> {
+        self.misc.get(&term).map(|s| s.as_str())
+    }
+
+    /// `ordinal-10` to `ordinal-99` match the last two digits, and take precedence over
+    /// `ordinal-00` to `ordinal-09`, which match the last one.
+    pub fn ordinal_suffix(&self, n: u32) -> &str {
+        let two_digits = Some(n % 100).filter(|&d| d >= 10);
+        two_digits
+            .and_then(|d| self.ordinals.get(&d))
+            .or_else(|| self.ordinals.get(&(n % 10)))
+            .or(self.ordinal.as_ref())
+            .map_or("", |s| s.as_str())
+    }
+
+    /// Only the numbers 1 to 10 have long ordinals.
+    pub fn long_ordinal(&self, n: u32) -> Option<&str> {
+        self.long_ordinals.get(&n).map(|s| s.as_str())
+    }
+
+    /// A month's name, or its short form. `None` if the locale doesn't have it.
+    pub fn month(&self, month: u32, short: bool) -> Option<&str> {
+        let months = if short { &self.short_months } else { &self.months };
+        months.get(&month).map(|s| s.as_str())
+    }
+
+    /// An en dash, unless the locale says otherwise.
+    pub fn page_range_delimiter(&self) -> &str {
+        self.misc_term(MiscTerm::PageRangeDelimiter).unwrap_or("–")
+    }
+}
+
+/// Each part with the form and suffix given.
+fn en_us_date(parts: &[(DatePartName, DatePartForm, &str)]) -> DateFormat {
+    let parts = parts
+        .iter()
+        .map(|&(name, form, suffix)| DatePart {
+            name,
+            form: Some(form),
+            formatting: None,
+            affixes: Some(Affixes {
+                prefix: "".into(),
+                suffix: suffix.into(),
+            }),
+            strip_periods: false,
+            text_case: TextCase,
+        })
+        .collect();
+    DateFormat {
+        parts,
+        delimiter: None,
+    }
+}
+
+/// `de-AT` => `de`. `None` if there's no region to strip.
+pub fn primary_language(lang: &str) -> Option<&str> {
+    lang.find('-').map(|ix| &lang[..ix])
+}
@@ -0,0 +1,358 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Copyright © 2019 Corporation for Digital Scholarship
+
+//! A style's macros, kept in an arena so that calls can refer to them by index.
+//!
+//! Compiling a style resolves every `<text macro="...">` and `<key macro="...">` to the
+//! `MacroId` of the macro it calls, and works out which macros render the same in every cite of
+//! a reference. The IR for those only has to be built once per reference, so the database
+//! caches it and the IR builder splices it in instead of expanding the macro again.
+
+use crate::element::*;
+use std::collections::HashMap;
+use std::ops::Index;
+use std::sync::Arc;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct MacroId(pub u32);
+
+/// A call to a macro: by name, as the style wrote it, and by id once the style is compiled.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MacroRef {
+    pub name: String,
+    pub id: Option<MacroId>,
+}
+
+impl From<&str> for MacroRef {
+    fn from(name: &str) -> Self {
+        MacroRef {
+            name: name.into(),
+            id: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Macro {
+    pub name: String,
+    pub elements: Vec<Element>,
+    /// Renders the same for every cite of a reference, whatever its position, locator or
+    /// disambiguation. Only known once the table is compiled.
+    pub cite_independent: bool,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct MacroTable {
+    macros: Vec<Macro>,
+    ids: HashMap<String, MacroId>,
+}
+
+impl MacroTable {
+    /// Adds a macro, or replaces the body of the one with the same name, which keeps its id.
+    /// Returns whether there was one already.
+    pub fn insert(&mut self, name: String, elements: Vec<Element>) -> bool {
+        if let Some(&id) = self.ids.get(&name) {
+            let existing = &mut self.macros[id.0 as usize];
+            existing.elements = elements;
+            existing.cite_independent = false;
+            return true;
+        }
+        self.ids
+            .insert(name.clone(), MacroId(self.macros.len() as u32));
+        self.macros.push(Macro {
+            name,
+            elements,
+            cite_independent: false,
+        });
+        false
+    }
+
+    pub fn id(&self, name: &str) -> Option<MacroId> {
+        self.ids.get(name).cloned()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&[Element]> {
+        self.id(name).map(|id| self[id].elements.as_slice())
+    }
+
+    pub fn get_by_id(&self, id: MacroId) -> Option<&Macro> {
+        self.macros.get(id.0 as usize)
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.ids.contains_key(name)
+    }
+
+    pub fn len(&self) -> usize {
+        self.macros.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.macros.is_empty()
+    }
+
+    /// In the order they were added, so by id.
+    pub fn iter(&self) -> impl Iterator<Item = &Macro> {
+        self.macros.iter()
+    }
+
+    /// The elements, with every call the table can resolve pointing at its macro's id.
+    fn resolve(&self, elements: &mut [Element]) {
+        for el in elements {
+            match el {
+                Element::Text(TextElement {
+                    source: TextSource::Macro(call),
+                    ..
+                }) => call.id = self.id(&call.name),
+                Element::Group(group) => self.resolve(&mut group.elements),
+                Element::Choose(choose) => {
+                    let Choose(head, rest, Else(otherwise)) = Arc::make_mut(choose);
+                    for IfThen(_, elements) in std::iter::once(head).chain(rest.iter_mut()) {
+                        self.resolve(elements);
+                    }
+                    self.resolve(otherwise);
+                }
+                Element::Names(names) => {
+                    if let Some(Substitute(elements)) = &mut Arc::make_mut(names).substitute {
+                        self.resolve(elements);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Resolves the calls inside the macros themselves, and works out which are
+    /// `cite_independent`.
+    fn compile(&mut self) {
+        let mut bodies: Vec<Vec<Element>> = self
+            .macros
+            .iter_mut()
+            .map(|m| std::mem::take(&mut m.elements))
+            .collect();
+        for elements in &mut bodies {
+            self.resolve(elements);
+        }
+        for (m, elements) in self.macros.iter_mut().zip(bodies) {
+            m.elements = elements;
+        }
+        let mut known = vec![Independence::Unknown; self.macros.len()];
+        for ix in 0..self.macros.len() {
+            let independent = self.independent(MacroId(ix as u32), &mut known);
+            self.macros[ix].cite_independent = independent;
+        }
+    }
+
+    fn independent(&self, id: MacroId, known: &mut [Independence]) -> bool {
+        match known[id.0 as usize] {
+            Independence::Known(independent) => return independent,
+            // Called from inside itself. The style is rejected when it's parsed, but it can
+            // still happen to a style built in code, and a cycle is never worth caching.
+            Independence::Visiting => return false,
+            Independence::Unknown => {}
+        }
+        known[id.0 as usize] = Independence::Visiting;
+        let independent = self.elements_independent(&self[id].elements, known);
+        known[id.0 as usize] = Independence::Known(independent);
+        independent
+    }
+
+    fn elements_independent(&self, elements: &[Element], known: &mut [Independence]) -> bool {
+        elements.iter().all(|el| match el {
+            Element::Text(text) => match &text.source {
+                TextSource::Macro(call) => call.id.is_some_and(|id| self.independent(id, known)),
+                TextSource::Variable(var, _) => !depends_on_cite(var.into()),
+                TextSource::Value(_) | TextSource::Term(..) => true,
+            },
+            Element::Number(number) => !depends_on_cite(AnyVariable::Number(number.variable)),
+            Element::Label(label) => !depends_on_cite(AnyVariable::Number(label.variable)),
+            Element::Group(group) => self.elements_independent(&group.elements, known),
+            Element::Choose(choose) => {
+                let Choose(head, rest, Else(otherwise)) = &**choose;
+                std::iter::once(head)
+                    .chain(rest.iter())
+                    .all(|IfThen(conditions, elements)| {
+                        conditions_independent(conditions)
+                            && self.elements_independent(elements, known)
+                    })
+                    && self.elements_independent(otherwise, known)
+            }
+            // Which names show depends on the cite's position and on disambiguation, and how
+            // they look on whether they're in a citation, a bibliography or a sort key
+            Element::Names(_) => false,
+            // A year might take the year suffix
+            Element::Date(date) => {
+                !depends_on_cite(AnyVariable::Date(date.variable)) && !date.renders_year()
+            }
+        })
+    }
+}
+
+impl Index<MacroId> for MacroTable {
+    type Output = Macro;
+    fn index(&self, id: MacroId) -> &Macro {
+        &self.macros[id.0 as usize]
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+enum Independence {
+    Unknown,
+    Visiting,
+    Known(bool),
+}
+
+/// Variables that come from the cite, or from where it is in the document, rather than from
+/// the reference.
+fn depends_on_cite(var: AnyVariable) -> bool {
+    matches!(
+        var,
+        AnyVariable::Ordinary(Variable::YearSuffix)
+            | AnyVariable::Ordinary(Variable::LocatorExtra)
+            | AnyVariable::Number(NumberVariable::Locator)
+            | AnyVariable::Number(NumberVariable::FirstReferenceNoteNumber)
+            | AnyVariable::Date(DateVariable::LocatorDate)
+    )
+}
+
+fn conditions_independent(Conditions(_, conds): &Conditions) -> bool {
+    conds.iter().all(|cond| match *cond {
+        Cond::Type(_) => true,
+        Cond::Variable(var) | Cond::IsNumeric(var) => !depends_on_cite(var),
+        Cond::Position(_) | Cond::Disambiguate(_) | Cond::Locator(_) => false,
+    })
+}
+
+impl Style {
+    /// Resolves every macro call in the style to the id of its macro, and works out which
+    /// macros are `cite_independent`. Parsing does this, and so does `Database::set_style`, for
+    /// styles built in code.
+    pub fn compile_macros(&mut self) {
+        self.macros.compile();
+        let macros = &self.macros;
+        let layouts =
+            std::iter::once(&mut self.citation.layout)
+                .chain(self.citation.locale_layouts.iter_mut())
+                .chain(self.bibliography.iter_mut().flat_map(|bib| {
+                    std::iter::once(&mut bib.layout).chain(&mut bib.locale_layouts)
+                }));
+        for layout in layouts {
+            macros.resolve(&mut layout.elements);
+        }
+        let sorts = self.citation.sort.iter_mut().chain(
+            self.bibliography
+                .iter_mut()
+                .flat_map(|bib| bib.sort.as_mut()),
+        );
+        for sort in sorts {
+            for key in &mut sort.keys {
+                if let SortSource::Macro(call) = &mut key.source {
+                    call.id = macros.id(&call.name);
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn resolves_calls_and_finds_independent_macros() {
+    let style: Style = r#"<style class="note">
+      <macro name="title"><text variable="title" font-style="italic"/></macro>
+      <macro name="edition">
+        <choose><if is-numeric="edition"><number variable="edition"/></if></choose>
+      </macro>
+      <macro name="pinpoint"><text variable="locator"/></macro>
+      <macro name="ibid">
+        <choose><if position="ibid"><text term="ibid"/></if></choose>
+      </macro>
+      <macro name="author"><names variable="author"/></macro>
+      <macro name="year"><date variable="issued"><date-part name="year"/></date></macro>
+      <macro name="month"><date variable="issued"><date-part name="month"/></date></macro>
+      <macro name="book">
+        <choose><if type="book"><text value="book"/></if></choose>
+      </macro>
+      <macro name="main"><text macro="title"/><text macro="edition"/></macro>
+      <macro name="cite"><text macro="main"/><text macro="pinpoint"/></macro>
+      <citation><layout><text macro="cite"/><text macro="ibid"/></layout></citation>
+    </style>"#
+        .parse()
+        .unwrap();
+    let independent: Vec<&str> = style
+        .macros
+        .iter()
+        .filter(|m| m.cite_independent)
+        .map(|m| m.name.as_str())
+        .collect();
+    assert_eq!(independent, vec!["title", "edition", "month", "book", "main"]);
+    match &style.citation.layout.elements[0] {
+        Element::Text(TextElement {
+            source: TextSource::Macro(call),
+            ..
+        }) => assert_eq!(call.id, style.macros.id("cite")),
+        other => panic!("expected a macro call, got {:?}", other),
+    }
+    match &style.macros.get("main").unwrap()[1] {
+        Element::Text(TextElement {
+            source: TextSource::Macro(call),
+            ..
+        }) => assert_eq!(call.id, Some(MacroId(1))),
+        other => panic!("expected a macro call, got {:?}", other),
+    }
+}
+
+#[test]
+fn reuses_fragments_across_cites() {
+    use crate::cluster::{Cite, Cluster, ClusterId};
+    use crate::db::{Database, QueryKey};
+    use crate::reference::{PersonName, Reference};
+    use crate::CiteId;
+    let style: Style = r#"<style class="note">
+      <macro name="title"><text variable="title"/></macro>
+      <citation><layout delimiter="; ">
+        <group delimiter=", ">
+          <names variable="author"><substitute><text macro="title"/></substitute></names>
+          <text macro="title"/>
+          <text variable="locator"/>
+        </group>
+      </layout></citation>
+    </style>"#
+        .parse()
+        .unwrap();
+    let title = style.macros.id("title").unwrap();
+    let mut db = Database::new(style);
+    let mut a = Reference::empty("a", "book");
+    a.ordinary.insert(Variable::Title, "Title a".into());
+    a.name
+        .insert(NameVariable::Author, vec![PersonName::new("Jo", "Smith")]);
+    let mut b = Reference::empty("b", "book");
+    b.ordinary.insert(Variable::Title, "Title b".into());
+    db.set_references(vec![a.clone(), b]);
+    let page = |cite: Cite, page: &str| cite.with_locator(LocatorType::Page, page);
+    db.set_cluster(Cluster::new(
+        ClusterId(1),
+        vec![
+            page(Cite::basic(CiteId(1), "a"), "1"),
+            page(Cite::basic(CiteId(2), "a"), "2"),
+            Cite::basic(CiteId(3), "b"),
+        ],
+    ));
+    // The title a substitute has used stays suppressed, fragment or not
+    assert_eq!(
+        *db.render_cluster(ClusterId(1)).unwrap(),
+        "Jo Smith, Title a, 1; Jo Smith, Title a, 2; Title b"
+    );
+    let executions =
+        |db: &Database, id: &str| db.execution_count(&QueryKey::MacroFragment(id.into(), title));
+    assert_eq!(executions(&db, "a"), 1);
+    a.ordinary.insert(Variable::Title, "New title".into());
+    db.insert_reference(a);
+    assert_eq!(
+        *db.render_cluster(ClusterId(1)).unwrap(),
+        "Jo Smith, New title, 1; Jo Smith, New title, 2; Title b"
+    );
+    assert_eq!(executions(&db, "a"), 2);
+    assert_eq!(executions(&db, "b"), 1);
+}
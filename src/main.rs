@@ -1,101 +1,177 @@
-use std::sync::Arc;
 use std::collections::HashMap;
+use std::sync::Arc;
 
 mod disamb;
 mod ref_ir;
 mod group;
 mod element;
+mod names;
+mod bibliography;
+mod cluster;
+mod collapse;
+mod db;
+mod locale;
+mod reference;
+mod sort;
+mod attr;
+mod parse;
+mod xml;
+mod page_range;
+mod numeric;
+mod date;
+mod jurisdiction;
+mod macros;
+mod lint;
+mod json;
+mod csl_json;
+mod output;
+mod cli;
+mod miscompile;
+mod test_suite;
 
 pub mod prelude {
     pub use super::*;
 }
 
 use element::*;
+use reference::{PersonName, Reference};
+use disamb::names::NameDisamb;
+use disamb::Dfa;
+use cluster::{Cluster, ClusterId};
+use locale::Locale;
 
 fn main() {
-    // use std::str::FromStr;
-    // let style = r#"<style class="note" version="1.0">
-    //   <macro name="a"><label variable="locator"/></macro>
-    //   <citation><layout> <text macro="a"/> </layout></citation>
-    // </style>"#;
-    // let style = Style::from_str(style).unwrap(); dbg!(&style);
-    let style = {
-        use element::*;
-        Style {
-            macros: {
-                let mut map = HashMap::default();
-                map.insert("a".into(), vec![
-                    Element::Label(LabelElement {
-                        variable: NumberVariable::Locator,
-                        form: TermForm::Long,
-                        formatting: Default::default(),
-                        affixes: Default::default(),
-                        strip_periods: false,
-                        text_case: Default::default(),
-                        plural: false,
-                    })
-                ]);
-                map
-            },
-            citation: Citation {
-                layout: Layout {
-                    elements: vec![
-                        Element::Text(TextElement {
-                            source: TextSource::Macro("a".into()),
-                            formatting: None,
-                            affixes: None,
-                            quotes: Default::default(),
-                            strip_periods: Default::default(),
-                            text_case: TextCase::default(),
-                            display: None,
-                        })
-                    ],
-                },
-            },
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match cli::run(&args) {
+        Ok(output) => print!("{}", output),
+        Err(e @ cli::CliError::Usage(_)) => {
+            eprintln!("error: {}\n\n{}", e, cli::USAGE);
+            std::process::exit(2);
         }
-    };
-    // dbg!(&style);
-    let db = MockDbForSegfault { style: Arc::new(style.clone()) };
-    let ctx = RefContext {
-        style: &style,
-        locator_type: Some(element::LocatorType::Page),
-        position: element::Position::First,
-        year_suffix: false,
-        names_delimiter: None,
-        name_el: Arc::new(element::Name::root_default()),
-        disamb_count: 0,
-    };
-    let _ir = crate::disamb::element_ref_ir_impl(&db.style.citation.layout.elements[0], &db, &ctx);
-
-    // let mut nfa = crate::disamb::Nfa::new();
-    // let first = nfa.graph.add_node(());
-    // nfa.start.insert(first);
-    // let last = crate::disamb::add_to_graph(&fmt, &mut nfa, &ir.0, first);
-    // nfa.accepting.insert(last);
+        Err(e) => {
+            if let cli::CliError::Regressions { report, .. } = &e {
+                print!("{}", report);
+            }
+            eprintln!("error: {}", e);
+            std::process::exit(1);
+        }
+    }
 }
 
 #[derive(Clone)]
 pub struct RefContext<'a> {
     pub style: &'a Style,
+    pub reference: &'a Reference,
     pub locator_type: Option<element::LocatorType>,
     pub position: element::Position,
     pub year_suffix: bool,
     pub names_delimiter: Option<Delimiter>,
     pub name_el: Arc<element::Name>,
     pub disamb_count: u32,
+    pub name_disamb: NameDisamb,
+    /// Rendering a `<key macro="...">`, where every name is in sort order.
+    pub in_sort_key: bool,
+    /// Leave out the author, for a cite with suppress-author, or the later cites in a group by
+    /// the same author.
+    pub suppress_author: bool,
+    /// CSL-M's `hereinafter`, which the document sets for a reference rather than the
+    /// reference itself.
+    pub hereinafter: Option<Arc<String>>,
+    /// The reference's jurisdiction module, whose macros replace the style's.
+    pub module: Option<Arc<jurisdiction::JurisdictionModule>>,
+}
+
+impl RefContext<'_> {
+    /// A macro, as overridden by the jurisdiction module if there is one. Modules are looked up
+    /// by name, because a call's id is only good for the style's own macros.
+    pub fn macro_elements(&self, call: &MacroRef) -> Option<&[Element]> {
+        if let Some(elements) = self.module.as_ref().and_then(|m| m.macros.get(&call.name)) {
+            return Some(elements);
+        }
+        match call.id {
+            Some(id) => self.style.macros.get_by_id(id).map(|m| m.elements.as_slice()),
+            None => self.style.macros.get(&call.name),
+        }
+    }
+
+    /// Like `Reference::has_variable`, but aware of the variables that depend on the cite.
+    pub fn has_variable(&self, var: AnyVariable) -> bool {
+        match var {
+            AnyVariable::Ordinary(Variable::YearSuffix) => self.year_suffix,
+            AnyVariable::Ordinary(Variable::Hereinafter) => self.hereinafter.is_some(),
+            // Cites don't carry these yet
+            AnyVariable::Ordinary(Variable::LocatorExtra)
+            | AnyVariable::Date(DateVariable::LocatorDate) => false,
+            AnyVariable::Number(NumberVariable::Locator) => self.locator_type.is_some(),
+            AnyVariable::Number(NumberVariable::CitationNumber) => true,
+            AnyVariable::Number(NumberVariable::FirstReferenceNoteNumber) => {
+                self.position != element::Position::First
+            }
+            _ => self.reference.has_variable(var),
+        }
+    }
+
+    /// `is-numeric`. The IR for a cite with a locator is shared by every locator, so a locator
+    /// is taken to be numeric whatever it turns out to be.
+    pub fn is_numeric(&self, var: AnyVariable) -> bool {
+        let value = match var {
+            AnyVariable::Number(NumberVariable::Locator) => return self.locator_type.is_some(),
+            AnyVariable::Number(NumberVariable::CitationNumber) => return true,
+            AnyVariable::Number(NumberVariable::FirstReferenceNoteNumber) => {
+                return self.position != element::Position::First
+            }
+            AnyVariable::Number(n) => self.reference.number.get(&n),
+            AnyVariable::Ordinary(Variable::Hereinafter) => self.hereinafter.as_deref(),
+            AnyVariable::Ordinary(v) => self.reference.ordinary.get(&v),
+            AnyVariable::Name(_) | AnyVariable::Date(_) => None,
+        };
+        value.is_some_and(|v| numeric::is_numeric(&numeric::parse_numeric(v)))
+    }
 }
 
 pub use group::*;
 pub use ref_ir::*;
 pub use crate::disamb::EdgeData;
 
-pub struct CiteId(u32);
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct CiteId(pub u32);
 pub struct IrGen;
-struct MockDbForSegfault { style: Arc<element::Style>, }
-pub trait IrDatabase { fn style(&self) -> Arc<Style>; }
-impl IrDatabase for MockDbForSegfault {
-    fn style(&self) -> Arc<Style> { self.style.clone() }
+pub trait IrDatabase {
+    fn style(&self) -> Arc<Style>;
+    fn reference(&self, id: &str) -> Option<Arc<Reference>>;
+    /// Falls back to the primary language, then to `en-US`.
+    fn locale(&self, lang: &str) -> Arc<Locale>;
+    fn cluster(&self, id: ClusterId) -> Arc<Cluster>;
+    /// `First` for cites the database doesn't know about.
+    fn cite_position(&self, id: CiteId) -> Position;
+    /// The reference's place in the bibliography, counting from 1.
+    fn citation_number(&self, ref_id: &str) -> Option<u32>;
+    /// Every reference id in the library, in the order they were given.
+    fn reference_ids(&self) -> Arc<Vec<String>>;
+    /// The reference's DFA for one shape of cite. `None` if there's no such reference, and an
+    /// error if the style can't render it.
+    fn ref_dfa(
+        &self,
+        id: &str,
+        key: disamb::pipeline::DfaKey,
+    ) -> Option<Result<Arc<Dfa>, parse::StyleError>>;
+    /// What a global `givenname-disambiguation-rule` expands each name to. Empty if the style
+    /// doesn't have one.
+    fn global_names(&self) -> Arc<HashMap<PersonName, disamb::names::GivenLevel>>;
+    /// The letter disambiguation gave the reference, if the style adds year suffixes.
+    fn year_suffix(&self, ref_id: &str) -> Option<String>;
+    /// What the document calls the reference after its first cite, for CSL-M's `hereinafter`.
+    fn hereinafter(&self, ref_id: &str) -> Option<Arc<String>>;
+    /// The module loaded for exactly this jurisdiction, like `us:ca`.
+    fn jurisdiction_module(&self, jurisdiction: &str) -> Option<Arc<jurisdiction::JurisdictionModule>>;
+    /// The IR of one of the style's `cite_independent` macros for a reference. `None` if the
+    /// IR builder should expand the macro as usual.
+    fn macro_fragment(
+        &self,
+        ref_id: &str,
+        id: MacroId,
+    ) -> Result<Option<Arc<disamb::MacroFragment>>, parse::StyleError>;
+    /// Told about each step of building IR. Ignores them unless the database says otherwise.
+    fn tracer(&self) -> &dyn disamb::IrTracer { &disamb::NoTrace }
 }
 
-
-
@@ -0,0 +1,430 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Copyright © 2020 Corporation for Digital Scholarship
+
+//! Tests for the release-mode miscompile in the README, where printing a `RefIR` built by
+//! `element_ref_ir_impl` and `ref_sequence` recursing through a macro read a bad discriminant.
+//!
+//! They build IR through nested macros and nested `RefIR::Seq`s, print it with `Debug` and
+//! `RefIR::debug`, and check the printed form reads back as the same tree. The miscompile only
+//! showed up with optimisations on, which is why `Cargo.toml` gives the test profile
+//! `opt-level = 3`.
+
+#![cfg(test)]
+
+use crate::cluster::{Cluster, ClusterId};
+use crate::db::Database;
+use crate::disamb::names::{GivenLevel, NameDisamb};
+use crate::disamb::pipeline::DfaKey;
+use crate::disamb::{Dfa, EdgeData, MacroFragment, Nfa, NfaEdge, NodeIndex};
+use crate::element::*;
+use crate::jurisdiction::JurisdictionModule;
+use crate::locale::Locale;
+use crate::parse::StyleError;
+use crate::ref_ir::RefIrSeq;
+use crate::reference::{PersonName, Reference};
+use crate::{CiteId, IrDatabase, RefContext, RefIR};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Has only a style, so the IR builder can't take a shortcut through the database.
+struct MockDbForSegfault {
+    style: Arc<Style>,
+}
+
+impl IrDatabase for MockDbForSegfault {
+    fn style(&self) -> Arc<Style> {
+        self.style.clone()
+    }
+    fn reference(&self, _id: &str) -> Option<Arc<Reference>> {
+        None
+    }
+    fn locale(&self, _lang: &str) -> Arc<Locale> {
+        Arc::new(Locale::en_us())
+    }
+    fn cluster(&self, id: ClusterId) -> Arc<Cluster> {
+        Arc::new(Cluster::new(id, Vec::new()))
+    }
+    fn cite_position(&self, _id: CiteId) -> Position {
+        Position::First
+    }
+    fn citation_number(&self, _ref_id: &str) -> Option<u32> {
+        None
+    }
+    fn reference_ids(&self) -> Arc<Vec<String>> {
+        Arc::new(Vec::new())
+    }
+    fn ref_dfa(&self, _id: &str, _key: DfaKey) -> Option<Result<Arc<Dfa>, StyleError>> {
+        None
+    }
+    fn global_names(&self) -> Arc<HashMap<PersonName, GivenLevel>> {
+        Arc::new(HashMap::new())
+    }
+    fn year_suffix(&self, _ref_id: &str) -> Option<String> {
+        None
+    }
+    fn hereinafter(&self, _ref_id: &str) -> Option<Arc<String>> {
+        None
+    }
+    fn jurisdiction_module(&self, _jurisdiction: &str) -> Option<Arc<JurisdictionModule>> {
+        None
+    }
+    fn macro_fragment(
+        &self,
+        _ref_id: &str,
+        _id: MacroId,
+    ) -> Result<Option<Arc<MacroFragment>>, StyleError> {
+        Ok(None)
+    }
+}
+
+fn context<'a>(style: &'a Style, reference: &'a Reference) -> RefContext<'a> {
+    RefContext {
+        style,
+        reference,
+        locator_type: Some(LocatorType::Page),
+        position: Position::First,
+        year_suffix: false,
+        names_delimiter: style.inherited_names_delimiter(&style.citation.names_delimiter),
+        name_el: Arc::new(style.inherited_name(&style.citation.name_inheritance)),
+        disamb_count: 0,
+        name_disamb: NameDisamb::default(),
+        in_sort_key: false,
+        suppress_author: false,
+        hereinafter: None,
+        module: None,
+    }
+}
+
+/// A style whose layout calls `m{depth}`, which calls `m{depth - 1}`, down to `m0`, which is
+/// `leaf`.
+fn nested_macros(depth: usize, leaf: &str) -> Style {
+    let mut csl = format!(r#"<style class="note"><macro name="m0">{}</macro>"#, leaf);
+    for level in 1..=depth {
+        csl += &format!(
+            r#"<macro name="m{}"><text macro="m{}"/></macro>"#,
+            level,
+            level - 1
+        );
+    }
+    csl += &format!(
+        r#"<citation><layout><text macro="m{}"/></layout></citation></style>"#,
+        depth
+    );
+    csl.parse().unwrap()
+}
+
+fn seq(contents: Vec<RefIR>) -> RefIR {
+    RefIR::Seq(RefIrSeq {
+        contents,
+        ..Default::default()
+    })
+}
+
+/// The tree with everything but the contents of each `Seq` left out, which is all that
+/// `RefIR::debug` shows.
+fn contents_only(ir: &RefIR) -> RefIR {
+    match ir {
+        RefIR::Edge(edge) => RefIR::Edge(edge.clone()),
+        RefIR::Seq(s) => seq(s.contents.iter().map(contents_only).collect()),
+    }
+}
+
+const UNIT_EDGES: [EdgeData; 11] = [
+    EdgeData::Locator,
+    EdgeData::NotUsed,
+    EdgeData::LocatorLabel,
+    EdgeData::YearSuffix,
+    EdgeData::YearSuffixExplicit,
+    EdgeData::YearSuffixPlain,
+    EdgeData::CitationNumber,
+    EdgeData::CitationNumberLabel,
+    EdgeData::Frnn,
+    EdgeData::FrnnLabel,
+    EdgeData::Accessed,
+];
+
+/// Reads back what `RefIR::debug` printed. Outputs can't contain `"` or `\`.
+fn parse_debug(s: &str) -> RefIR {
+    fn ir(s: &str) -> (RefIR, &str) {
+        if let Some(mut rest) = s.strip_prefix('[') {
+            let mut contents = Vec::new();
+            if let Some(rest) = rest.strip_prefix(']') {
+                return (seq(contents), rest);
+            }
+            loop {
+                let (item, after) = ir(rest);
+                contents.push(item);
+                match after.as_bytes()[0] {
+                    b',' => rest = &after[1..],
+                    b']' => return (seq(contents), &after[1..]),
+                    _ => panic!("unexpected {:?}", after),
+                }
+            }
+        }
+        if let Some(rest) = s.strip_prefix("Output(\"") {
+            let end = rest.find("\")").unwrap();
+            let edge = EdgeData::Output(rest[..end].into());
+            return (RefIR::Edge(Some(edge)), &rest[end + 2..]);
+        }
+        if let Some(rest) = s.strip_prefix("None") {
+            return (RefIR::Edge(None), rest);
+        }
+        let end = s.find([',', ']']).unwrap_or(s.len());
+        let edge = UNIT_EDGES
+            .iter()
+            .find(|e| format!("{:?}", e) == s[..end])
+            .unwrap_or_else(|| panic!("unknown edge {:?}", &s[..end]));
+        (RefIR::Edge(Some(edge.clone())), &s[end..])
+    }
+    let (parsed, rest) = ir(s);
+    assert_eq!(rest, "");
+    parsed
+}
+
+/// Prints `ir` both ways and checks each reads back as the same tree.
+fn assert_round_trips(ir: &RefIR, db: &dyn IrDatabase) {
+    let debug = ir.debug(db);
+    let parsed = parse_debug(&debug);
+    assert_eq!(parsed, contents_only(ir), "{}", debug);
+    assert_eq!(parsed.debug(db), debug);
+    assert_eq!(format!("{:?}", parsed), format!("{:?}", contents_only(ir)));
+    assert_eq!(format!("{:#?}", ir.clone()), format!("{:#?}", ir));
+}
+
+// I'm just keeping this around because add_to_graph below is where I originally found the segfault
+
+/// Runs `f` between the affixes of a sequence. Outputs carry no formatting yet, so there are no
+/// tags to open and close around it.
+fn graph_with_stack(
+    nfa: &mut Nfa,
+    affixes: Option<&Affixes>,
+    mut spot: NodeIndex,
+    f: impl FnOnce(&mut Nfa, NodeIndex) -> NodeIndex,
+) -> NodeIndex {
+    let mkedge = |s: &str| {
+        RefIR::Edge(if !s.is_empty() {
+            Some(EdgeData::Output(s.to_string()))
+        } else {
+            None
+        })
+    };
+    if let Some(pre) = affixes.map(|a| mkedge(&a.prefix)) {
+        spot = add_to_graph(nfa, &pre, spot);
+    }
+    spot = f(nfa, spot);
+    if let Some(suf) = affixes.map(|a| mkedge(&a.suffix)) {
+        spot = add_to_graph(nfa, &suf, spot);
+    }
+    spot
+}
+
+/// Adds a path spelling out `ir` to `nfa`, starting from `spot`, and returns the node it ends on.
+fn add_to_graph(nfa: &mut Nfa, ir: &RefIR, spot: NodeIndex) -> NodeIndex {
+    match ir {
+        RefIR::Edge(None) => spot,
+        RefIR::Edge(Some(e)) => {
+            let to = nfa.graph.add_node(());
+            nfa.graph.add_edge(spot, to, NfaEdge::Token(e.clone()));
+            to
+        }
+        RefIR::Seq(ref seq) => {
+            let RefIrSeq {
+                ref contents,
+                ref affixes,
+                ref delimiter,
+                // TODO: use these
+                formatting: _,
+                text_case: _,
+            } = *seq;
+            let delim = &RefIR::Edge(if !delimiter.is_empty() {
+                Some(EdgeData::Output(delimiter.clone()))
+            } else {
+                None
+            });
+            graph_with_stack(nfa, affixes.as_ref(), spot, |nfa, mut spot| {
+                let mut seen = false;
+                for x in contents {
+                    if !matches!(x, RefIR::Edge(None)) {
+                        if seen {
+                            spot = add_to_graph(nfa, delim, spot);
+                        }
+                        seen = true;
+                    }
+                    spot = add_to_graph(nfa, x, spot);
+                }
+                spot
+            })
+        }
+    }
+}
+
+#[test]
+fn label_in_macro_with_mock_db() {
+    // use std::str::FromStr;
+    // let style = r#"<style class="note" version="1.0">
+    //   <macro name="a"><label variable="locator"/></macro>
+    //   <citation><layout> <text macro="a"/> </layout></citation>
+    // </style>"#;
+    // let style = Style::from_str(style).unwrap(); dbg!(&style);
+    let style = Style {
+        macros: {
+            let mut map = MacroTable::default();
+            map.insert(
+                "a".into(),
+                vec![Element::Label(LabelElement {
+                    variable: NumberVariable::Locator,
+                    form: TermForm::Long,
+                    formatting: Default::default(),
+                    affixes: Default::default(),
+                    strip_periods: false,
+                    text_case: Default::default(),
+                    plural: false,
+                })],
+            );
+            map
+        },
+        citation: Citation {
+            layout: Layout {
+                elements: vec![Element::Text(TextElement {
+                    source: TextSource::Macro("a".into()),
+                    formatting: None,
+                    affixes: None,
+                    quotes: Default::default(),
+                    strip_periods: Default::default(),
+                    text_case: TextCase,
+                    display: None,
+                })],
+                delimiter: None,
+                affixes: None,
+                locale: vec![],
+            },
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    // dbg!(&style);
+    let db = MockDbForSegfault {
+        style: Arc::new(style.clone()),
+    };
+    let reference = Reference::empty("ITEM-1", "book");
+    let ctx = context(&style, &reference);
+    let (ir, _) = crate::disamb::element_ref_ir_impl(
+        &db.style.citation.layout.elements[0],
+        &db,
+        &ctx,
+        &mut Default::default(),
+    )
+    .unwrap();
+    match &ir {
+        RefIR::Seq(seq) => assert_eq!(
+            seq.contents,
+            vec![RefIR::Edge(Some(EdgeData::LocatorLabel))]
+        ),
+        other => panic!("expected a sequence, got {:?}", other),
+    }
+    assert_eq!(format!("{:?}", ir), format!("{:?}", ir.clone()));
+    assert_round_trips(&ir, &db);
+
+    let mut nfa = Nfa::new();
+    let first = nfa.graph.add_node(());
+    nfa.start.insert(first);
+    let last = add_to_graph(&mut nfa, &ir, first);
+    nfa.accepting.insert(last);
+    assert!(nfa.brzozowski_minimise().accepts(&[EdgeData::LocatorLabel]));
+}
+
+/// The README's scenario, but with the label any number of macros down.
+#[test]
+fn label_in_nested_macros() {
+    let reference = Reference::empty("ITEM-1", "book");
+    for &depth in &[1, 2, 3, 8, 32, 64] {
+        let style = nested_macros(depth, r#"<label variable="locator"/>"#);
+        let db = MockDbForSegfault {
+            style: Arc::new(style.clone()),
+        };
+        let ctx = context(&style, &reference);
+        let (ir, _) = crate::disamb::element_ref_ir_impl(
+            &style.citation.layout.elements[0],
+            &db,
+            &ctx,
+            &mut Default::default(),
+        )
+        .unwrap();
+        let mut expected = RefIR::Edge(Some(EdgeData::LocatorLabel));
+        for _ in 0..=depth {
+            expected = seq(vec![expected]);
+        }
+        assert_eq!(contents_only(&ir), expected, "depth {}", depth);
+        let debug = format!("{:?}", ir);
+        assert_eq!(debug.matches("Seq(").count(), depth + 1);
+        assert_eq!(debug.matches("LocatorLabel").count(), 1);
+        assert_round_trips(&ir, &db);
+    }
+}
+
+/// The same through the real database, which keeps the IR of the macros for reuse.
+#[test]
+fn value_in_nested_macros_with_database() {
+    for &depth in &[1, 8, 64] {
+        let mut db = Database::new(nested_macros(depth, r#"<text value="leaf"/>"#));
+        db.set_references(vec![Reference::empty("ITEM-1", "book")]);
+        let ir = db.ref_ir("ITEM-1").unwrap();
+        let leaf = r#"Output("leaf")"#;
+        let brackets = depth + 2;
+        let expected = format!("{}{}{}", "[".repeat(brackets), leaf, "]".repeat(brackets));
+        assert_eq!(ir.debug(&db), expected, "depth {}", depth);
+        assert_round_trips(&ir, &db);
+        db.set_cluster(Cluster::new(
+            ClusterId(1),
+            vec![crate::cluster::Cite::basic(CiteId(1), "ITEM-1")],
+        ));
+        assert_eq!(*db.render_cluster(ClusterId(1)).unwrap(), "leaf");
+    }
+}
+
+/// Trees built by hand, wide as well as deep, with every kind of edge at the leaves.
+#[test]
+fn wide_and_deep_sequences() {
+    fn tree(depth: usize, next: &mut usize) -> RefIR {
+        if depth == 0 {
+            *next += 1;
+            return match *next % (UNIT_EDGES.len() + 2) {
+                0 => RefIR::Edge(None),
+                1 => RefIR::Edge(Some(EdgeData::Output(format!("out {}", next)))),
+                n => RefIR::Edge(Some(UNIT_EDGES[n - 2].clone())),
+            };
+        }
+        let width = depth % 3 + 1;
+        seq((0..width).map(|_| tree(depth - 1, next)).collect())
+    }
+    let db = MockDbForSegfault {
+        style: Arc::new(Style::default()),
+    };
+    for depth in 0..12 {
+        let ir = tree(depth, &mut 0);
+        assert_round_trips(&ir, &db);
+    }
+    // A long chain, with an edge at every level
+    let mut chain = RefIR::Edge(Some(EdgeData::LocatorLabel));
+    for level in 0..100 {
+        chain = seq(vec![
+            RefIR::Edge(Some(UNIT_EDGES[level % UNIT_EDGES.len()].clone())),
+            chain,
+        ]);
+    }
+    assert_round_trips(&chain, &db);
+    assert_eq!(parse_debug("[]"), seq(vec![]));
+    assert_eq!(
+        parse_debug(r#"[None,[Locator,Output("x")]]"#),
+        seq(vec![
+            RefIR::Edge(None),
+            seq(vec![
+                RefIR::Edge(Some(EdgeData::Locator)),
+                RefIR::Edge(Some(EdgeData::Output("x".into()))),
+            ]),
+        ])
+    );
+}
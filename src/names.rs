@@ -0,0 +1,737 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Copyright © 2018 Corporation for Digital Scholarship
+
+use crate::disamb::names::{GivenLevel, NameDisamb};
+use crate::disamb::{element_ref_ir_impl, eval_conditions, IrState};
+use crate::element::*;
+use crate::locale::Locale;
+use crate::parse::StyleError;
+use crate::reference::{PersonName, Reference};
+use crate::{IrDatabase, RefContext, RefIR};
+use std::sync::Arc;
+
+/// Everything outside the `<name>` element that changes how a name renders.
+#[derive(Debug, Copy, Clone)]
+pub struct NameContext<'a> {
+    pub locale: &'a Locale,
+    pub initialize_with_hyphen: bool,
+    pub demote_non_dropping_particle: DemoteNonDroppingParticle,
+    /// Rendering a sort key, which may demote particles where the displayed name wouldn't.
+    pub sorting: bool,
+}
+
+impl<'a> NameContext<'a> {
+    pub fn new(style: &Style, locale: &'a Locale, sorting: bool) -> Self {
+        NameContext {
+            locale,
+            initialize_with_hyphen: style.initialize_with_hyphen,
+            demote_non_dropping_particle: style.demote_non_dropping_particle,
+            sorting,
+        }
+    }
+
+    /// Whether an inverted name puts the non-dropping particle after the given name, as in
+    /// `Gogh, Vincent van`, rather than before the family name, as in `van Gogh, Vincent`.
+    fn demote_particle(&self) -> bool {
+        match self.demote_non_dropping_particle {
+            DemoteNonDroppingParticle::Never => false,
+            DemoteNonDroppingParticle::SortOnly => self.sorting,
+            DemoteNonDroppingParticle::DisplayAndSort => true,
+        }
+    }
+}
+
+/// Renders one name variable's list of names.
+///
+/// `name_el` should already have been merged with everything it inherits from.
+pub fn render_names(
+    cx: &NameContext,
+    name_el: &Name,
+    names: &[PersonName],
+    disamb: &NameDisamb,
+) -> String {
+    if name_el.form == Some(NameForm::Count) {
+        return name_count(name_el, names, disamb).to_string();
+    }
+    render_name_parts(cx, name_el, names, disamb).join()
+}
+
+/// How many names `form="count"` counts: those that would be shown before the et-al term.
+pub fn name_count(name_el: &Name, names: &[PersonName], disamb: &NameDisamb) -> usize {
+    shown_count(name_el, names.len(), disamb)
+}
+
+fn shown_count(name_el: &Name, total: usize, disamb: &NameDisamb) -> usize {
+    match (name_el.et_al_min, name_el.et_al_use_first) {
+        (Some(min), Some(first)) if total >= min as usize => {
+            total.min((first + disamb.add_names) as usize)
+        }
+        _ => total,
+    }
+}
+
+/// One name variable's names, rendered separately so they can be substituted one at a time.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RenderedNames {
+    pub names: Vec<String>,
+    /// Per name, whether it was written family name first.
+    inverted: Vec<bool>,
+    delimiter: String,
+    /// The `and` term, if `<name>` asks for one.
+    and: Option<String>,
+    delimiter_precedes_last: DelimiterPrecedes,
+    delimiter_precedes_et_al: DelimiterPrecedes,
+    /// The et-al term, if names were cut off.
+    et_al: Option<String>,
+    /// With `et-al-use-last`, the last name, which goes after an ellipsis instead of the term.
+    last: Option<String>,
+    affixes: Option<Affixes>,
+}
+
+impl RenderedNames {
+    pub fn join(&self) -> String {
+        let count = self.names.len();
+        let mut out = String::new();
+        if let Some(affixes) = &self.affixes {
+            out.push_str(&affixes.prefix);
+        }
+        for (i, name) in self.names.iter().enumerate() {
+            if i > 0 {
+                let before_last = i == count - 1 && self.et_al.is_none() && self.last.is_none();
+                match &self.and {
+                    Some(and) if before_last => {
+                        let rule = self.delimiter_precedes_last;
+                        if use_delimiter(rule, count, 3, self.inverted[i - 1]) {
+                            out.push_str(&self.delimiter);
+                        } else {
+                            out.push(' ');
+                        }
+                        out.push_str(and);
+                        out.push(' ');
+                    }
+                    _ => out.push_str(&self.delimiter),
+                }
+            }
+            out.push_str(name);
+        }
+        if let Some(last) = &self.last {
+            out.push_str(&self.delimiter);
+            out.push_str("… ");
+            out.push_str(last);
+        } else if let Some(et_al) = &self.et_al {
+            let prev_inverted = self.inverted.last().cloned().unwrap_or(false);
+            if use_delimiter(self.delimiter_precedes_et_al, count, 2, prev_inverted) {
+                out.push_str(&self.delimiter);
+            } else {
+                out.push(' ');
+            }
+            out.push_str(et_al);
+        }
+        if let Some(affixes) = &self.affixes {
+            out.push_str(&affixes.suffix);
+        }
+        out
+    }
+}
+
+/// For `delimiter-precedes-last` and `delimiter-precedes-et-al`. `contextual` uses the
+/// delimiter once there are `threshold` names, counting the last one for `-last`, and only
+/// those shown for `-et-al`.
+fn use_delimiter(
+    rule: DelimiterPrecedes,
+    count: usize,
+    threshold: usize,
+    prev_inverted: bool,
+) -> bool {
+    match rule {
+        DelimiterPrecedes::Contextual => count >= threshold,
+        DelimiterPrecedes::AfterInvertedName => prev_inverted,
+        DelimiterPrecedes::Always => true,
+        DelimiterPrecedes::Never => false,
+    }
+}
+
+pub fn render_name_parts(
+    cx: &NameContext,
+    name_el: &Name,
+    names: &[PersonName],
+    disamb: &NameDisamb,
+) -> RenderedNames {
+    let total = names.len();
+    let shown = shown_count(name_el, total, disamb);
+    let base = base_level(name_el);
+    let render = |i: usize, name: &PersonName| {
+        let level = disamb.level_for(i, name).map_or(base, |l| l.max(base));
+        let sort_order = name.literal.is_none()
+            && match name_el.name_as_sort_order {
+                Some(NameAsSortOrder::All) => true,
+                Some(NameAsSortOrder::First) => i == 0,
+                None => false,
+            };
+        (
+            render_person(cx, name, level, sort_order, name_el),
+            sort_order,
+        )
+    };
+    let (rendered, inverted) = names[..shown]
+        .iter()
+        .enumerate()
+        .map(|(i, name)| render(i, name))
+        .unzip();
+    let use_last = name_el.et_al_use_last == Some(true) && shown > 0 && total >= shown + 2;
+    let and = name_el.and.map(|and| match and {
+        NameAnd::Text => cx
+            .locale
+            .misc_term(MiscTerm::And)
+            .unwrap_or("and")
+            .to_string(),
+        NameAnd::Symbol => "&".to_string(),
+    });
+    let et_al = Some(cx.locale.misc_term(MiscTerm::EtAl).unwrap_or("et al."))
+        .filter(|_| shown < total && !use_last)
+        .map(String::from);
+    RenderedNames {
+        names: rendered,
+        inverted,
+        delimiter: name_el
+            .delimiter
+            .as_ref()
+            .map_or(", ", |d| d.0.as_str())
+            .to_string(),
+        and,
+        delimiter_precedes_last: name_el
+            .delimiter_precedes_last
+            .unwrap_or(DelimiterPrecedes::Contextual),
+        delimiter_precedes_et_al: name_el
+            .delimiter_precedes_et_al
+            .unwrap_or(DelimiterPrecedes::Contextual),
+        et_al,
+        last: if use_last {
+            Some(render(total - 1, &names[total - 1]).0)
+        } else {
+            None
+        },
+        affixes: name_el.affixes.clone(),
+    }
+}
+
+/// The output of the first `<names>` element that renders anything.
+#[derive(Debug, Clone)]
+pub struct NamesRun {
+    /// Per variable
+    lists: Vec<Vec<PersonName>>,
+    rendered: Vec<RenderedNames>,
+    delimiter: String,
+}
+
+impl NamesRun {
+    pub fn text(&self) -> String {
+        self.join(self.rendered.iter().map(|r| r.join()))
+    }
+
+    fn join(&self, parts: impl Iterator<Item = String>) -> String {
+        parts.collect::<Vec<_>>().join(&self.delimiter)
+    }
+
+    /// What to put in place of `text()`, if anything, given the names of the entry before.
+    pub fn substituted(
+        &self,
+        prev: &NamesRun,
+        rule: SubsequentAuthorSubstituteRule,
+        substitute: &str,
+    ) -> Option<String> {
+        use SubsequentAuthorSubstituteRule::*;
+        let all_match = self.lists == prev.lists;
+        // How many of the leading names, across all the variables, are the same people
+        let leading = self
+            .lists
+            .iter()
+            .flatten()
+            .zip(prev.lists.iter().flatten())
+            .take_while(|(a, b)| a == b)
+            .count();
+        let replace_first = match rule {
+            CompleteAll if all_match => return Some(substitute.to_string()),
+            CompleteEach if all_match => usize::MAX,
+            PartialEach if leading > 0 => leading,
+            PartialFirst if leading > 0 => 1,
+            _ => return None,
+        };
+        let mut seen = 0;
+        let parts = self.rendered.iter().map(|rendered| {
+            let mut rendered = rendered.clone();
+            for name in rendered.names.iter_mut() {
+                if seen < replace_first {
+                    *name = substitute.to_string();
+                }
+                seen += 1;
+            }
+            rendered.join()
+        });
+        Some(self.join(parts))
+    }
+}
+
+/// Finds the names that `subsequent-author-substitute` and cite grouping look at, following
+/// macros, groups and the branches of `<choose>` that are taken. Fails like the IR builder on a
+/// macro that is undefined or calls itself.
+pub fn first_names(
+    db: &dyn IrDatabase,
+    ctx: &RefContext,
+    elements: &[Element],
+) -> Result<Option<NamesRun>, StyleError> {
+    first_names_within(db, ctx, elements, &mut Vec::new())
+}
+
+/// `macros` are the macros `elements` are inside, outermost first.
+fn first_names_within(
+    db: &dyn IrDatabase,
+    ctx: &RefContext,
+    elements: &[Element],
+    macros: &mut Vec<String>,
+) -> Result<Option<NamesRun>, StyleError> {
+    for el in elements {
+        let found = match el {
+            Element::Text(TextElement {
+                source: TextSource::Macro(call),
+                ..
+            }) => {
+                let elements =
+                    ctx.macro_elements(call)
+                        .ok_or_else(|| StyleError::UndefinedMacro {
+                            name: call.name.clone(),
+                            location: None,
+                        })?;
+                if let Some(ix) = macros.iter().position(|called| *called == call.name) {
+                    let mut chain = macros[ix..].to_vec();
+                    chain.push(call.name.clone());
+                    return Err(StyleError::MacroCycle { chain });
+                }
+                macros.push(call.name.clone());
+                let found = first_names_within(db, ctx, elements, macros);
+                macros.pop();
+                found?
+            }
+            Element::Group(group) => first_names_within(db, ctx, &group.elements, macros)?,
+            Element::Choose(choose) => {
+                let Choose(head, rest, Else(otherwise)) = &**choose;
+                let branch = std::iter::once(head)
+                    .chain(rest.iter())
+                    .find(|IfThen(conditions, _)| eval_conditions(conditions, ctx).0)
+                    .map_or(otherwise, |IfThen(_, elements)| elements);
+                first_names_within(db, ctx, branch, macros)?
+            }
+            Element::Names(names) => first_names_of(db, ctx, names, macros)?,
+            _ => None,
+        };
+        if found.is_some() {
+            return Ok(found);
+        }
+    }
+    Ok(None)
+}
+
+fn first_names_of(
+    db: &dyn IrDatabase,
+    ctx: &RefContext,
+    names: &Names,
+    macros: &mut Vec<String>,
+) -> Result<Option<NamesRun>, StyleError> {
+    let name_el = match &names.name {
+        Some(local) => ctx.name_el.merge(local),
+        None => (*ctx.name_el).clone(),
+    }
+    .for_position(ctx.position);
+    let lists: Vec<Vec<PersonName>> = names
+        .variables
+        .iter()
+        .filter_map(|&var| name_list(ctx.reference, var))
+        .map(|list| list.to_vec())
+        .collect();
+    if lists.is_empty() {
+        // Whichever substitute renders, which may not be names at all
+        let substitute = match &names.substitute {
+            Some(substitute) => substitute,
+            None => return Ok(None),
+        };
+        for el in substitute_elements(names, substitute) {
+            let (ir, _) = element_ref_ir_impl(&el, db, ctx, &mut IrState::default())?;
+            if ir != RefIR::Edge(None) {
+                return first_names_within(db, ctx, &[el], macros);
+            }
+        }
+        return Ok(None);
+    }
+    let locale = db.locale(&ctx.style.default_locale);
+    let cx = NameContext::new(ctx.style, &locale, ctx.in_sort_key);
+    let rendered = lists
+        .iter()
+        .map(|list| render_name_parts(&cx, &name_el, list, &ctx.name_disamb))
+        .collect();
+    let delimiter = names
+        .delimiter
+        .as_ref()
+        .or(ctx.names_delimiter.as_ref())
+        .map_or("", |d| d.0.as_str())
+        .to_string();
+    Ok(Some(NamesRun {
+        lists,
+        rendered,
+        delimiter,
+    }))
+}
+
+/// A variable's names, if it has any. `dummy` never does.
+pub fn name_list(reference: &Reference, var: NameVariable) -> Option<&[PersonName]> {
+    if var == NameVariable::Dummy {
+        return None;
+    }
+    reference
+        .name
+        .get(&var)
+        .filter(|list| !list.is_empty())
+        .map(|list| list.as_slice())
+}
+
+/// The elements of `<substitute>`, where a bare `<names variable="..."/>` is shorthand for one
+/// with the same `<name>` as the `<names>` it substitutes for.
+pub fn substitute_elements(names: &Names, substitute: &Substitute) -> Vec<Element> {
+    substitute
+        .0
+        .iter()
+        .map(|el| match el {
+            Element::Names(inner) if inner.name.is_none() => Element::Names(Arc::new(Names {
+                name: names.name.clone(),
+                ..(**inner).clone()
+            })),
+            el => el.clone(),
+        })
+        .collect()
+}
+
+/// The given name expansion a `<name>` asks for before any disambiguation.
+pub fn base_level(name_el: &Name) -> GivenLevel {
+    match name_el.form {
+        Some(NameForm::Short) | Some(NameForm::Count) => GivenLevel::Family,
+        _ if name_el.initialize_with.is_some() && name_el.initialize != Some(false) => {
+            GivenLevel::Initials
+        }
+        _ => GivenLevel::Full,
+    }
+}
+
+/// Writes one person's name at `level`.
+///
+/// `level` can be more than `name_el` asks for, when disambiguation expanded the name. A name
+/// with no given name to show is just the family name, with its non-dropping particle.
+fn render_person(
+    cx: &NameContext,
+    name: &PersonName,
+    level: GivenLevel,
+    sort_order: bool,
+    name_el: &Name,
+) -> String {
+    if let Some(literal) = &name.literal {
+        return literal.clone();
+    }
+    let non_dropping = name.non_dropping_particle.as_deref();
+    let family = name.family.as_deref();
+    let given = match (level, name.given.as_deref()) {
+        (GivenLevel::Initials, Some(given)) => {
+            let with = name_el.initialize_with.as_deref().unwrap_or(". ");
+            Some(initialize(given, with, cx.initialize_with_hyphen))
+        }
+        (GivenLevel::Full, Some(given)) => Some(given.to_string()),
+        _ => None,
+    };
+    let part_affixes = |part: &Option<NamePart>, text: String| match part
+        .as_ref()
+        .and_then(|p| p.affixes.as_ref())
+    {
+        Some(a) if !text.is_empty() => format!("{}{}{}", a.prefix, text, a.suffix),
+        _ => text,
+    };
+    let family_with_particle = words(&[non_dropping, family]);
+    let given = match given {
+        Some(given) => given,
+        None => return part_affixes(&name_el.name_part_family, family_with_particle),
+    };
+    let dropping = name.dropping_particle.as_deref();
+    let suffix = name.suffix.as_deref();
+    if sort_order {
+        // Gogh, Vincent van / van Gogh, Vincent, then the suffix, as in King, Martin Luther, Jr.
+        let sep = name_el.sort_separator.as_deref().unwrap_or(", ");
+        let (family_part, given_part) = if cx.demote_particle() {
+            (
+                words(&[family]),
+                words(&[Some(&given), dropping, non_dropping]),
+            )
+        } else {
+            (family_with_particle, words(&[Some(&given), dropping]))
+        };
+        let mut out = part_affixes(&name_el.name_part_family, family_part);
+        for part in [
+            part_affixes(&name_el.name_part_given, given_part),
+            suffix.unwrap_or("").to_string(),
+        ]
+        .iter()
+        .filter(|p| !p.is_empty())
+        {
+            out.push_str(sep);
+            out.push_str(part);
+        }
+        out
+    } else {
+        // Vincent van Gogh Jr.
+        let given_part = part_affixes(&name_el.name_part_given, words(&[Some(&given), dropping]));
+        let family_part = part_affixes(&name_el.name_part_family, family_with_particle);
+        words(&[Some(&given_part), Some(&family_part), suffix])
+    }
+}
+
+/// Joins the parts that are there with spaces.
+fn words(parts: &[Option<&str>]) -> String {
+    parts
+        .iter()
+        .flatten()
+        .filter(|p| !p.is_empty())
+        .cloned()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// `initials("John Paul", ". ") == "J. P."`
+pub fn initials(given: &str, with: &str) -> String {
+    initialize(given, with, true)
+}
+
+/// Reduces each word of a given name to its first letter followed by `with`. The parts of a
+/// hyphenated name keep the hyphen between them unless `hyphen` is false:
+/// `Jean-Paul` => `J.-P.` or `J.P.`.
+pub fn initialize(given: &str, with: &str, hyphen: bool) -> String {
+    let with_trimmed = with.trim_end();
+    let spacing = &with[with_trimmed.len()..];
+    let mut out = String::new();
+    for word in given.split_whitespace() {
+        let parts: Vec<String> = word
+            .split('-')
+            .filter_map(|part| part.chars().next())
+            .map(|c| format!("{}{}", c, with_trimmed))
+            .collect();
+        if parts.is_empty() {
+            continue;
+        }
+        out.push_str(&parts.join(if hyphen { "-" } else { "" }));
+        out.push_str(spacing);
+    }
+    out.trim_end().to_string()
+}
+
+#[cfg(test)]
+fn person(given: &str, family: &str) -> PersonName {
+    PersonName::new(given, family)
+}
+
+#[cfg(test)]
+fn render(name_el: &Name, names: &[PersonName]) -> String {
+    let locale = Locale::en_us();
+    let cx = NameContext::new(&Style::default(), &locale, false);
+    render_names(
+        &cx,
+        &Name::root_default().merge(name_el),
+        names,
+        &NameDisamb::default(),
+    )
+}
+
+#[test]
+fn forms_and_initials() {
+    let names = [person("John Paul", "Smith"), person("Jean-Luc", "Picard")];
+    assert_eq!(
+        render(&Name::empty(), &names),
+        "John Paul Smith, Jean-Luc Picard"
+    );
+    let short = Name {
+        form: Some(NameForm::Short),
+        ..Name::empty()
+    };
+    assert_eq!(render(&short, &names), "Smith, Picard");
+    let count = Name {
+        form: Some(NameForm::Count),
+        ..Name::empty()
+    };
+    assert_eq!(render(&count, &names), "2");
+    let initialized = Name {
+        initialize_with: Some(". ".into()),
+        ..Name::empty()
+    };
+    assert_eq!(render(&initialized, &names), "J. P. Smith, J.-L. Picard");
+    assert_eq!(initialize("Jean-Luc", ".", false), "J.L.");
+    assert_eq!(initialize("Jean-Luc Marie", "", true), "J-LM");
+}
+
+#[test]
+fn particles_and_inversion() {
+    let mut van_gogh = person("Vincent", "Gogh");
+    van_gogh.non_dropping_particle = Some("van".into());
+    let mut de_gaulle = person("Charles", "Gaulle");
+    de_gaulle.dropping_particle = Some("de".into());
+    let mut king = person("Martin Luther", "King");
+    king.suffix = Some("Jr.".into());
+    let names = [van_gogh.clone(), de_gaulle, king];
+
+    assert_eq!(
+        render(&Name::empty(), &names),
+        "Vincent van Gogh, Charles de Gaulle, Martin Luther King Jr."
+    );
+    let inverted = Name {
+        name_as_sort_order: Some(NameAsSortOrder::All),
+        delimiter: Some(Delimiter("; ".into())),
+        ..Name::empty()
+    };
+    assert_eq!(
+        render(&inverted, &names),
+        "Gogh, Vincent van; Gaulle, Charles de; King, Martin Luther, Jr."
+    );
+    let short = Name {
+        form: Some(NameForm::Short),
+        ..Name::empty()
+    };
+    assert_eq!(render(&short, &names), "van Gogh, Gaulle, King");
+
+    let locale = Locale::en_us();
+    let never = Style {
+        demote_non_dropping_particle: DemoteNonDroppingParticle::SortOnly,
+        ..Style::default()
+    };
+    let name_el = Name::root_default().merge(&inverted);
+    let disamb = NameDisamb::default();
+    let display = NameContext::new(&never, &locale, false);
+    let sorting = NameContext::new(&never, &locale, true);
+    let one = [van_gogh];
+    assert_eq!(
+        render_names(&display, &name_el, &one, &disamb),
+        "van Gogh, Vincent"
+    );
+    assert_eq!(
+        render_names(&sorting, &name_el, &one, &disamb),
+        "Gogh, Vincent van"
+    );
+}
+
+#[test]
+fn and_and_delimiter_precedes_last() {
+    let two = [person("A", "One"), person("B", "Two")];
+    let three = [person("A", "One"), person("B", "Two"), person("C", "Three")];
+    let and = |precedes| Name {
+        and: Some(NameAnd::Text),
+        delimiter_precedes_last: precedes,
+        form: Some(NameForm::Short),
+        ..Name::empty()
+    };
+    assert_eq!(render(&and(None), &two), "One and Two");
+    assert_eq!(render(&and(None), &three), "One, Two, and Three");
+    assert_eq!(
+        render(&and(Some(DelimiterPrecedes::Never)), &three),
+        "One, Two and Three"
+    );
+    assert_eq!(
+        render(&and(Some(DelimiterPrecedes::Always)), &two),
+        "One, and Two"
+    );
+    let after_inverted = Name {
+        name_as_sort_order: Some(NameAsSortOrder::First),
+        delimiter_precedes_last: Some(DelimiterPrecedes::AfterInvertedName),
+        and: Some(NameAnd::Symbol),
+        ..Name::empty()
+    };
+    assert_eq!(render(&after_inverted, &two), "One, A, & B Two");
+    assert_eq!(render(&after_inverted, &three), "One, A, B Two & C Three");
+}
+
+#[test]
+fn et_al() {
+    let names: Vec<PersonName> = ["One", "Two", "Three", "Four", "Five"]
+        .iter()
+        .map(|f| person("A", f))
+        .collect();
+    let et_al = |first, precedes, use_last| Name {
+        et_al_min: Some(3),
+        et_al_use_first: Some(first),
+        et_al_use_last: Some(use_last),
+        delimiter_precedes_et_al: precedes,
+        form: Some(NameForm::Short),
+        ..Name::empty()
+    };
+    assert_eq!(render(&et_al(1, None, false), &names), "One et al.");
+    assert_eq!(render(&et_al(2, None, false), &names), "One, Two, et al.");
+    assert_eq!(
+        render(&et_al(2, Some(DelimiterPrecedes::Never), false), &names),
+        "One, Two et al."
+    );
+    assert_eq!(
+        render(&et_al(1, Some(DelimiterPrecedes::Always), false), &names),
+        "One, et al."
+    );
+    assert_eq!(render(&et_al(2, None, true), &names), "One, Two, … Five");
+    // Not enough names left out to bother with the ellipsis
+    assert_eq!(
+        render(&et_al(4, None, true), &names),
+        "One, Two, Three, Four, et al."
+    );
+    assert_eq!(render(&et_al(2, None, false), &names[..2]), "One, Two");
+}
+
+#[test]
+fn substitute_suppresses_what_it_used() {
+    use crate::cluster::{Cite, Cluster, ClusterId};
+    use crate::CiteId;
+    let style: Style = r#"<style class="in-text">
+      <citation><layout delimiter="; ">
+        <group delimiter=", ">
+          <names variable="author">
+            <name form="short"/>
+            <substitute><names variable="editor"/><text variable="title"/></substitute>
+          </names>
+          <names variable="editor" prefix="ed. "/>
+          <text variable="title"/>
+        </group>
+      </layout></citation>
+    </style>"#
+        .parse()
+        .unwrap();
+    let mut db = crate::db::Database::new(style);
+    let make = |id: &str, author: Option<&str>, editor: Option<&str>| {
+        let mut r = Reference::empty(id, "book");
+        r.ordinary.insert(Variable::Title, format!("Title {}", id));
+        for (var, family) in &[
+            (NameVariable::Author, author),
+            (NameVariable::Editor, editor),
+        ] {
+            if let Some(family) = family {
+                r.name.insert(*var, vec![person("Jo", family)]);
+            }
+        }
+        r
+    };
+    db.set_references(vec![
+        make("a", None, Some("Jones")),
+        make("b", Some("Smith"), Some("Brown")),
+        make("c", None, None),
+    ]);
+    db.set_cluster(Cluster::new(
+        ClusterId(1),
+        vec![
+            Cite::basic(CiteId(1), "a"),
+            Cite::basic(CiteId(2), "b"),
+            Cite::basic(CiteId(3), "c"),
+        ],
+    ));
+    assert_eq!(
+        *db.render_cluster(ClusterId(1)).unwrap(),
+        "Jones, Title a; Smith, ed. Jo Brown, Title b; Title c"
+    );
+}
@@ -0,0 +1,337 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Copyright © 2019 Corporation for Digital Scholarship
+
+//! Numeric variables, for `<number>` and `is-numeric`.
+//!
+//! [Spec](https://docs.citationstyles.org/en/stable/specification.html#is-numeric): a value is
+//! numeric if it is only numbers, which may have letters stuck to either side (`D2`, `2b`), and
+//! separators between them (`2, 3`, `2-4`, `2 & 4`). So `2nd` is numeric, but `second`,
+//! `2nd edition` and `vol. 3` are not. Lowercase and uppercase roman numerals count as numbers.
+
+use crate::element::NumericForm;
+use crate::locale::Locale;
+use std::convert::TryFrom;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NumericToken {
+    /// `L2d` is `Num { prefix: "L", value: 2, suffix: "d" }`.
+    Num {
+        prefix: String,
+        value: u32,
+        suffix: String,
+    },
+    /// `xiv`, as written, and as a number.
+    Roman {
+        text: String,
+        value: u32,
+    },
+    Hyphen,
+    Comma,
+    Ampersand,
+    /// A word that isn't a number, like `vol.`. Any of these make the value non-numeric.
+    Text(String),
+}
+
+pub fn parse_numeric(value: &str) -> Vec<NumericToken> {
+    let mut tokens = Vec::new();
+    let mut word = String::new();
+    for c in value.chars() {
+        let separator = match c {
+            '-' | '–' => Some(NumericToken::Hyphen),
+            ',' => Some(NumericToken::Comma),
+            '&' => Some(NumericToken::Ampersand),
+            _ => None,
+        };
+        if separator.is_some() || c.is_whitespace() {
+            if !word.is_empty() {
+                tokens.push(word_token(&word));
+                word.clear();
+            }
+            tokens.extend(separator);
+        } else {
+            word.push(c);
+        }
+    }
+    if !word.is_empty() {
+        tokens.push(word_token(&word));
+    }
+    tokens
+}
+
+fn word_token(word: &str) -> NumericToken {
+    if let Some(value) = parse_roman(word) {
+        return NumericToken::Roman {
+            text: word.into(),
+            value,
+        };
+    }
+    let start = word.find(|c: char| c.is_ascii_digit());
+    let end = word.rfind(|c: char| c.is_ascii_digit()).map(|ix| ix + 1);
+    if let (Some(start), Some(end)) = (start, end) {
+        let (prefix, digits, suffix) = (&word[..start], &word[start..end], &word[end..]);
+        let letters = |s: &str| s.chars().all(char::is_alphabetic);
+        if letters(prefix) && letters(suffix) {
+            if let Ok(value) = digits.parse() {
+                return NumericToken::Num {
+                    prefix: prefix.into(),
+                    value,
+                    suffix: suffix.into(),
+                };
+            }
+        }
+    }
+    NumericToken::Text(word.into())
+}
+
+/// At least one number, no words, and separators only between numbers.
+pub fn is_numeric(tokens: &[NumericToken]) -> bool {
+    let is_number =
+        |t: &NumericToken| matches!(t, NumericToken::Num { .. } | NumericToken::Roman { .. });
+    let mut expect_number = true;
+    for token in tokens {
+        match token {
+            NumericToken::Text(_) => return false,
+            t if is_number(t) => {
+                if !expect_number {
+                    return false;
+                }
+                expect_number = false;
+            }
+            _ => {
+                if expect_number {
+                    return false;
+                }
+                expect_number = true;
+            }
+        }
+    }
+    !tokens.is_empty() && !expect_number
+}
+
+/// Renders a numeric value in `form`. A value that isn't numeric is written out as it was.
+pub fn render_numeric(value: &str, form: NumericForm, locale: &Locale) -> String {
+    let tokens = parse_numeric(value);
+    if !is_numeric(&tokens) {
+        return value.to_string();
+    }
+    let mut out = String::new();
+    for token in &tokens {
+        match token {
+            // A number that already has letters after it, like `2nd`, can't take an ordinal.
+            NumericToken::Num {
+                prefix,
+                value,
+                suffix,
+            } => {
+                out.push_str(prefix);
+                if suffix.is_empty() {
+                    out.push_str(&render_number(*value, form, locale));
+                } else {
+                    out.push_str(&value.to_string());
+                    out.push_str(suffix);
+                }
+            }
+            NumericToken::Roman { text, value } => match form {
+                NumericForm::Numeric => out.push_str(text),
+                _ => out.push_str(&render_number(*value, form, locale)),
+            },
+            NumericToken::Hyphen => out.push('–'),
+            NumericToken::Comma => out.push_str(", "),
+            NumericToken::Ampersand => out.push_str(" & "),
+            NumericToken::Text(text) => out.push_str(text),
+        }
+    }
+    out
+}
+
+fn render_number(n: u32, form: NumericForm, locale: &Locale) -> String {
+    match form {
+        NumericForm::Numeric => n.to_string(),
+        NumericForm::Ordinal => format!("{}{}", n, locale.ordinal_suffix(n)),
+        NumericForm::LongOrdinal => match locale.long_ordinal(n) {
+            Some(word) => word.to_string(),
+            None => format!("{}{}", n, locale.ordinal_suffix(n)),
+        },
+        NumericForm::Roman => to_roman(n).unwrap_or_else(|| n.to_string()),
+    }
+}
+
+/// Lowercase. `None` for zero, and for anything over 3999, which has no standard form.
+pub fn to_roman(mut n: u32) -> Option<String> {
+    const NUMERALS: &[(u32, &str)] = &[
+        (1000, "m"),
+        (900, "cm"),
+        (500, "d"),
+        (400, "cd"),
+        (100, "c"),
+        (90, "xc"),
+        (50, "l"),
+        (40, "xl"),
+        (10, "x"),
+        (9, "ix"),
+        (5, "v"),
+        (4, "iv"),
+        (1, "i"),
+    ];
+    if n == 0 || n > 3999 {
+        return None;
+    }
+    let mut out = String::new();
+    for &(value, numeral) in NUMERALS {
+        while n >= value {
+            out.push_str(numeral);
+            n -= value;
+        }
+    }
+    Some(out)
+}
+
+/// Only accepts numerals in their standard form, so words like `dim` or `did` are not numbers.
+fn parse_roman(s: &str) -> Option<u32> {
+    let lower = s.to_lowercase();
+    if s != lower && s != s.to_uppercase() {
+        return None;
+    }
+    let digit = |c| match c {
+        'i' => Some(1),
+        'v' => Some(5),
+        'x' => Some(10),
+        'l' => Some(50),
+        'c' => Some(100),
+        'd' => Some(500),
+        'm' => Some(1000),
+        _ => None,
+    };
+    let digits: Vec<u32> = lower.chars().map(digit).collect::<Option<_>>()?;
+    let mut total: i64 = 0;
+    for (ix, &d) in digits.iter().enumerate() {
+        match digits.get(ix + 1) {
+            Some(&next) if next > d => total -= d as i64,
+            _ => total += d as i64,
+        }
+    }
+    let value = u32::try_from(total).ok()?;
+    if to_roman(value).as_deref() == Some(lower.as_str()) {
+        Some(value)
+    } else {
+        None
+    }
+}
+
+#[test]
+fn tokenises_values() {
+    use NumericToken::*;
+    let num = |value| Num {
+        prefix: "".into(),
+        value,
+        suffix: "".into(),
+    };
+    assert_eq!(
+        parse_numeric("2-5 & 7"),
+        vec![num(2), Hyphen, num(5), Ampersand, num(7)]
+    );
+    assert_eq!(parse_numeric("vol. 3"), vec![Text("vol.".into()), num(3)]);
+    assert_eq!(
+        parse_numeric("xiv"),
+        vec![Roman {
+            text: "xiv".into(),
+            value: 14
+        }]
+    );
+    assert_eq!(
+        parse_numeric("L2d"),
+        vec![Num {
+            prefix: "L".into(),
+            value: 2,
+            suffix: "d".into()
+        }]
+    );
+}
+
+#[test]
+fn numeric_values() {
+    let numeric = |s| is_numeric(&parse_numeric(s));
+    for yes in &[
+        "2",
+        "2nd",
+        "D2",
+        "2, 3",
+        "2-4",
+        "2 & 4",
+        "xiv",
+        "XIV",
+        "12-15, 20",
+    ] {
+        assert!(numeric(yes), "{} should be numeric", yes);
+    }
+    for no in &[
+        "second",
+        "2nd edition",
+        "vol. 3",
+        "",
+        "2-",
+        "& 3",
+        "dim",
+        "Xiv",
+    ] {
+        assert!(!numeric(no), "{} should not be numeric", no);
+    }
+}
+
+#[test]
+fn renders_forms() {
+    let locale = Locale::en_us();
+    let render = |value, form| render_numeric(value, form, &locale);
+    assert_eq!(render("2-5 & 7", NumericForm::Numeric), "2–5 & 7");
+    assert_eq!(
+        render("1, 2, 3, 4, 11, 12, 13, 21, 102", NumericForm::Ordinal),
+        "1st, 2nd, 3rd, 4th, 11th, 12th, 13th, 21st, 102nd"
+    );
+    assert_eq!(render("2", NumericForm::LongOrdinal), "second");
+    assert_eq!(render("12", NumericForm::LongOrdinal), "12th");
+    assert_eq!(render("14", NumericForm::Roman), "xiv");
+    assert_eq!(render("xiv", NumericForm::Ordinal), "14th");
+    assert_eq!(render("2nd", NumericForm::Ordinal), "2nd");
+    assert_eq!(render("vol. 3", NumericForm::Ordinal), "vol. 3");
+}
+
+#[test]
+fn numbers_in_styles() {
+    use crate::cluster::{Cite, Cluster, ClusterId};
+    use crate::element::{NumberVariable, Style};
+    use crate::reference::Reference;
+    use crate::CiteId;
+    let style: Style = r#"<style class="in-text">
+      <citation><layout delimiter="; ">
+        <choose>
+          <if is-numeric="edition"><number variable="edition" form="ordinal" suffix=" ed."/></if>
+          <else><text variable="edition"/></else>
+        </choose>
+        <number variable="volume" form="roman" prefix=", vol. "/>
+      </layout></citation>
+    </style>"#
+        .parse()
+        .unwrap();
+    let mut db = crate::db::Database::new(style);
+    let make = |id: &str, edition: &str, volume: &str| {
+        let mut r = Reference::empty(id, "book");
+        r.number.insert(NumberVariable::Edition, edition.into());
+        r.number.insert(NumberVariable::Volume, volume.into());
+        r
+    };
+    db.set_references(vec![
+        make("a", "2", "4"),
+        make("b", "Revised edition", "vol. 3"),
+    ]);
+    db.set_cluster(Cluster::new(
+        ClusterId(1),
+        vec![Cite::basic(CiteId(1), "a"), Cite::basic(CiteId(2), "b")],
+    ));
+    assert_eq!(
+        *db.render_cluster(ClusterId(1)).unwrap(),
+        "2nd ed., vol. iv; Revised edition, vol. vol. 3"
+    );
+}
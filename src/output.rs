@@ -0,0 +1,225 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Copyright © 2019 Corporation for Digital Scholarship
+
+//! Wraps rendered citations and bibliography entries up as a document in one of the output
+//! formats the command line offers.
+//!
+//! The processor renders plain text, so these only escape it and lay out the document. Nothing
+//! is italicised or bolded yet.
+
+use crate::bibliography::{BibEntry, BibliographyMeta};
+use crate::cluster::ClusterId;
+use std::fmt::Write;
+use std::str::FromStr;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum OutputFormat {
+    Html,
+    Rtf,
+    Plain,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "html" => Ok(OutputFormat::Html),
+            "rtf" => Ok(OutputFormat::Rtf),
+            "plain" => Ok(OutputFormat::Plain),
+            _ => Err(format!(
+                "unknown format `{}`, expected html, rtf or plain",
+                s
+            )),
+        }
+    }
+}
+
+impl OutputFormat {
+    pub fn escape(self, text: &str) -> String {
+        let mut out = String::with_capacity(text.len());
+        for c in text.chars() {
+            match (self, c) {
+                (OutputFormat::Html, '&') => out.push_str("&amp;"),
+                (OutputFormat::Html, '<') => out.push_str("&lt;"),
+                (OutputFormat::Html, '>') => out.push_str("&gt;"),
+                (OutputFormat::Rtf, '\\' | '{' | '}') => {
+                    out.push('\\');
+                    out.push(c);
+                }
+                // RTF is ASCII, with each UTF-16 unit of anything else as a signed 16-bit number
+                (OutputFormat::Rtf, _) if !c.is_ascii() => {
+                    for unit in c.encode_utf16(&mut [0; 2]) {
+                        write!(out, "\\u{}?", *unit as i16).unwrap();
+                    }
+                }
+                _ => out.push(c),
+            }
+        }
+        out
+    }
+
+    /// Every cluster on its own line, in document order, then the bibliography if it has any
+    /// entries, laid out as `meta` says. Plain text only follows its entry spacing, and no format
+    /// does anything with `second-field-align` yet.
+    pub fn document(
+        self,
+        clusters: &[(ClusterId, String)],
+        bibliography: &[BibEntry],
+        meta: &BibliographyMeta,
+    ) -> String {
+        let clusters = clusters.iter().map(|(_, text)| self.escape(text));
+        let entries = bibliography.iter().map(|entry| self.escape(&entry.output));
+        let mut out = String::new();
+        match self {
+            OutputFormat::Plain => {
+                for cluster in clusters {
+                    writeln!(out, "{}", cluster).unwrap();
+                }
+                if !bibliography.is_empty() {
+                    if !out.is_empty() {
+                        out.push('\n');
+                    }
+                    let gap = "\n".repeat(meta.entry_spacing.saturating_sub(1) as usize);
+                    for (ix, entry) in entries.enumerate() {
+                        if ix > 0 {
+                            out.push_str(&gap);
+                        }
+                        writeln!(out, "{}", entry).unwrap();
+                    }
+                }
+            }
+            OutputFormat::Html => {
+                for cluster in clusters {
+                    writeln!(out, "<p class=\"csl-citation\">{}</p>", cluster).unwrap();
+                }
+                if !bibliography.is_empty() {
+                    let mut style = String::new();
+                    if meta.line_spacing != 1 {
+                        write!(style, "line-height: {}; ", meta.line_spacing).unwrap();
+                    }
+                    if meta.hanging_indent {
+                        style.push_str("padding-left: 2em; text-indent: -2em; ");
+                    }
+                    match style.trim_end() {
+                        "" => out.push_str("<div class=\"csl-bib-body\">\n"),
+                        style => writeln!(out, "<div class=\"csl-bib-body\" style=\"{}\">", style)
+                            .unwrap(),
+                    }
+                    let entry_class = match meta.entry_spacing {
+                        0 | 1 => "\"csl-entry\"".to_string(),
+                        n => format!("\"csl-entry\" style=\"margin-bottom: {}em;\"", n - 1),
+                    };
+                    for entry in entries {
+                        writeln!(out, "  <div class={}>{}</div>", entry_class, entry).unwrap();
+                    }
+                    out.push_str("</div>\n");
+                }
+            }
+            OutputFormat::Rtf => {
+                out.push_str("{\\rtf1\\ansi\\deff0\n");
+                for cluster in clusters {
+                    writeln!(out, "{}\\par", cluster).unwrap();
+                }
+                // In twips, where a line is 240 and the indent is half an inch
+                let mut format = String::new();
+                if meta.line_spacing != 1 {
+                    write!(format, "\\sl{}\\slmult1", 240 * meta.line_spacing).unwrap();
+                }
+                if meta.entry_spacing > 1 {
+                    write!(format, "\\sa{}", 240 * (meta.entry_spacing - 1)).unwrap();
+                }
+                if meta.hanging_indent {
+                    format.push_str("\\li720\\fi-720");
+                }
+                if !format.is_empty() && !bibliography.is_empty() {
+                    writeln!(out, "\\pard{}", format).unwrap();
+                }
+                for entry in entries {
+                    writeln!(out, "{}\\par", entry).unwrap();
+                }
+                out.push_str("}\n");
+            }
+        }
+        out
+    }
+}
+
+#[test]
+fn escapes_each_format() {
+    let text = "<Smith & Doe> {ed.} \\ Müller 😀";
+    assert_eq!(
+        OutputFormat::Html.escape(text),
+        "&lt;Smith &amp; Doe&gt; {ed.} \\ Müller 😀"
+    );
+    assert_eq!(
+        OutputFormat::Rtf.escape(text),
+        "<Smith & Doe> \\{ed.\\} \\\\ M\\u252?ller \\u-10179?\\u-8704?"
+    );
+    assert_eq!(OutputFormat::Plain.escape(text), text);
+    assert!("markdown".parse::<OutputFormat>().is_err());
+}
+
+#[test]
+fn lays_out_documents() {
+    let clusters = vec![(ClusterId(1), "(Doe 2001)".to_string())];
+    let bib = vec![BibEntry {
+        ref_id: "doe".into(),
+        output: "Doe, J. A & B. 2001".into(),
+    }];
+    let meta = BibliographyMeta::new(&Default::default());
+    assert_eq!(
+        OutputFormat::Plain.document(&clusters, &bib, &meta),
+        "(Doe 2001)\n\nDoe, J. A & B. 2001\n"
+    );
+    assert_eq!(
+        OutputFormat::Html.document(&clusters, &bib, &meta),
+        "<p class=\"csl-citation\">(Doe 2001)</p>\n\
+         <div class=\"csl-bib-body\">\n  \
+         <div class=\"csl-entry\">Doe, J. A &amp; B. 2001</div>\n\
+         </div>\n"
+    );
+    assert_eq!(
+        OutputFormat::Rtf.document(&clusters, &[], &meta),
+        "{\\rtf1\\ansi\\deff0\n(Doe 2001)\\par\n}\n"
+    );
+    assert_eq!(
+        OutputFormat::Plain.document(&[], &bib, &meta),
+        "Doe, J. A & B. 2001\n"
+    );
+}
+
+#[test]
+fn follows_bibliography_layout() {
+    use crate::element::Bibliography;
+    let entry = |output: &str| BibEntry {
+        ref_id: output.into(),
+        output: output.into(),
+    };
+    let bib = vec![entry("Doe"), entry("Roe")];
+    let meta = BibliographyMeta::new(&Bibliography {
+        hanging_indent: true,
+        line_spacing: Some(2),
+        entry_spacing: Some(2),
+        ..Default::default()
+    });
+    assert_eq!(
+        OutputFormat::Plain.document(&[], &bib, &meta),
+        "Doe\n\nRoe\n"
+    );
+    assert_eq!(
+        OutputFormat::Html.document(&[], &bib, &meta),
+        "<div class=\"csl-bib-body\" style=\"line-height: 2; padding-left: 2em; \
+         text-indent: -2em;\">\n  \
+         <div class=\"csl-entry\" style=\"margin-bottom: 1em;\">Doe</div>\n  \
+         <div class=\"csl-entry\" style=\"margin-bottom: 1em;\">Roe</div>\n\
+         </div>\n"
+    );
+    assert_eq!(
+        OutputFormat::Rtf.document(&[], &bib, &meta),
+        "{\\rtf1\\ansi\\deff0\n\\pard\\sl480\\slmult1\\sa240\\li720\\fi-720\n\
+         Doe\\par\nRoe\\par\n}\n"
+    );
+}
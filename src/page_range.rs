@@ -0,0 +1,256 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Copyright © 2019 Corporation for Digital Scholarship
+
+//! `page-range-format`.
+//!
+//! A value like `321-8, 401-10 & xii-xiv` is split at its commas and ampersands, and each piece
+//! that is a range of two page numbers is rewritten. Abbreviated ends are expanded first, so
+//! every format starts from `321–328`. Page numbers can have a letter prefix, as in `S12-19`,
+//! which is only repeated on the second number when every digit is. Roman numerals are never
+//! abbreviated, and anything that isn't recognisably a page range is left as it was.
+
+use crate::element::{PageRangeFormat, Style};
+use crate::locale::Locale;
+
+/// Applies the style's `page-range-format`, if it has one, to the value of `page`, or to a
+/// locator of type `page`.
+pub fn format_pages(style: &Style, locale: &Locale, value: &str) -> String {
+    match style.page_range_format {
+        Some(format) => format_page_range(value, format, locale.page_range_delimiter()),
+        None => value.to_string(),
+    }
+}
+
+pub fn format_page_range(value: &str, format: PageRangeFormat, delimiter: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let mut rest = value;
+    while !rest.is_empty() {
+        let end = rest.find([',', '&']).unwrap_or(rest.len());
+        let (piece, after) = rest.split_at(end);
+        out.push_str(&format_piece(piece, format, delimiter));
+        // The separator itself
+        let sep_len = after.chars().next().map_or(0, char::len_utf8);
+        out.push_str(&after[..sep_len]);
+        rest = &after[sep_len..];
+    }
+    out
+}
+
+/// Keeps the whitespace around `piece`, so separators stay spaced the way they were.
+fn format_piece(piece: &str, format: PageRangeFormat, delimiter: &str) -> String {
+    let trimmed = piece.trim();
+    let leading = &piece[..piece.len() - piece.trim_start().len()];
+    let trailing = &piece[piece.trim_end().len()..];
+    let mut parts = trimmed.split(['-', '–']);
+    let range = match (parts.next(), parts.next(), parts.next()) {
+        (Some(first), Some(last), None) => format_range(first.trim(), last.trim(), format),
+        _ => None,
+    };
+    match range {
+        Some((first, last)) => format!("{}{}{}{}{}", leading, first, delimiter, last, trailing),
+        None => piece.to_string(),
+    }
+}
+
+/// The two ends of the range, as they should be written, or `None` to leave it alone.
+fn format_range(first: &str, last: &str, format: PageRangeFormat) -> Option<(String, String)> {
+    if is_roman(first) && is_roman(last) {
+        return Some((first.into(), last.into()));
+    }
+    let (prefix, first_digits) = split_prefix(first)?;
+    let (last_prefix, last_digits) = split_prefix(last)?;
+    if !last_prefix.is_empty() && last_prefix != prefix {
+        return None;
+    }
+    let expanded = expand(first_digits, last_digits)?;
+    let keep = digits_to_keep(first_digits, &expanded, format);
+    let last = if keep >= expanded.len() {
+        format!("{}{}", prefix, expanded)
+    } else {
+        expanded[expanded.len() - keep..].to_string()
+    };
+    Some((first.into(), last))
+}
+
+/// `S12` => `("S", "12")`. `None` unless it's letters then at least one digit.
+fn split_prefix(page: &str) -> Option<(&str, &str)> {
+    let digits_start = page
+        .find(|c: char| c.is_ascii_digit())
+        .filter(|&ix| page[..ix].chars().all(char::is_alphabetic))?;
+    let digits = &page[digits_start..];
+    if digits.chars().all(|c| c.is_ascii_digit()) {
+        Some((&page[..digits_start], digits))
+    } else {
+        None
+    }
+}
+
+/// Writes out an abbreviated second number in full, `("321", "8")` => `"328"`. `None` if the
+/// result would go backwards.
+fn expand(first: &str, last: &str) -> Option<String> {
+    let full = if last.len() < first.len() {
+        format!("{}{}", &first[..first.len() - last.len()], last)
+    } else {
+        last.to_string()
+    };
+    let value = |s: &str| s.parse::<u64>().ok();
+    if value(&full)? < value(first)? {
+        return None;
+    }
+    Some(full)
+}
+
+/// How many trailing digits of `last` to write.
+fn digits_to_keep(first: &str, last: &str, format: PageRangeFormat) -> usize {
+    if first.len() != last.len() {
+        return last.len();
+    }
+    // Digits from the first one that differs
+    let changed = first
+        .bytes()
+        .zip(last.bytes())
+        .position(|(a, b)| a != b)
+        .map_or(1, |ix| last.len() - ix);
+    match format {
+        PageRangeFormat::Expanded => last.len(),
+        PageRangeFormat::Minimal => changed,
+        PageRangeFormat::MinimalTwo => changed.max(2).min(last.len()),
+        PageRangeFormat::Chicago | PageRangeFormat::Chicago16 => {
+            let n: u64 = first.parse().unwrap_or(0);
+            if n < 100 || n.is_multiple_of(100) {
+                last.len()
+            } else if n % 100 < 10 {
+                changed
+            } else if format == PageRangeFormat::Chicago && last.len() == 4 && changed >= 3 {
+                // The 15th edition writes out four-digit numbers when three digits change
+                last.len()
+            } else {
+                changed.max(2).min(last.len())
+            }
+        }
+    }
+}
+
+fn is_roman(s: &str) -> bool {
+    !s.is_empty()
+        && (s.chars().all(|c| "ivxlcdm".contains(c)) || s.chars().all(|c| "IVXLCDM".contains(c)))
+}
+
+#[cfg(test)]
+fn ranges(format: PageRangeFormat, values: &[&str]) -> Vec<String> {
+    values
+        .iter()
+        .map(|v| format_page_range(v, format, "–"))
+        .collect()
+}
+
+#[test]
+fn expanded_and_minimal() {
+    let values = ["42-45", "321-328", "321-8", "2787-2816", "101-108"];
+    assert_eq!(
+        ranges(PageRangeFormat::Expanded, &values),
+        vec!["42–45", "321–328", "321–328", "2787–2816", "101–108"]
+    );
+    assert_eq!(
+        ranges(PageRangeFormat::Minimal, &values),
+        vec!["42–5", "321–8", "321–8", "2787–816", "101–8"]
+    );
+    assert_eq!(
+        ranges(PageRangeFormat::MinimalTwo, &values),
+        vec!["42–45", "321–28", "321–28", "2787–816", "101–08"]
+    );
+}
+
+#[test]
+fn chicago() {
+    let values = [
+        "3-10",
+        "71-72",
+        "100-104",
+        "1100-1123",
+        "101-108",
+        "1103-1104",
+        "321-328",
+        "498-532",
+        "1087-1089",
+        "1496-1500",
+        "11564-11615",
+        "12991-13001",
+    ];
+    assert_eq!(
+        ranges(PageRangeFormat::Chicago16, &values),
+        vec![
+            "3–10",
+            "71–72",
+            "100–104",
+            "1100–1123",
+            "101–8",
+            "1103–4",
+            "321–28",
+            "498–532",
+            "1087–89",
+            "1496–500",
+            "11564–615",
+            "12991–3001",
+        ]
+    );
+    assert_eq!(
+        ranges(
+            PageRangeFormat::Chicago,
+            &["1496-1504", "2787-2816", "1087-1089"]
+        ),
+        vec!["1496–1504", "2787–2816", "1087–89"]
+    );
+}
+
+#[test]
+fn lists_prefixes_and_roman_numerals() {
+    assert_eq!(
+        format_page_range("12-15, 101-8 & 20", PageRangeFormat::Minimal, "–"),
+        "12–5, 101–8 & 20"
+    );
+    assert_eq!(
+        ranges(
+            PageRangeFormat::Expanded,
+            &["S12-19", "S12-S19", "xii-xiv", "A1-B2", "5-3"]
+        ),
+        vec!["S12–S19", "S12–S19", "xii–xiv", "A1-B2", "5-3"]
+    );
+    assert_eq!(
+        ranges(PageRangeFormat::Minimal, &["S12-S19", "S12-S21", "S8-S12"]),
+        vec!["S12–9", "S12–S21", "S8–S12"]
+    );
+}
+
+#[test]
+fn formats_pages_and_page_locators() {
+    use crate::cluster::{Cite, Cluster, ClusterId};
+    use crate::element::{LocatorType, NumberVariable};
+    use crate::reference::Reference;
+    use crate::CiteId;
+    let style: Style = r#"<style class="in-text" page-range-format="minimal">
+      <citation><layout delimiter="; ">
+        <text variable="page"/><text variable="locator" prefix=", at "/>
+      </layout></citation>
+    </style>"#
+        .parse()
+        .unwrap();
+    let mut db = crate::db::Database::new(style);
+    let mut r = Reference::empty("a", "article-journal");
+    r.number.insert(NumberVariable::Page, "321-328".into());
+    db.set_references(vec![r]);
+    db.set_cluster(Cluster::new(
+        ClusterId(1),
+        vec![
+            Cite::basic(CiteId(1), "a").with_locator(LocatorType::Page, "101-108"),
+            Cite::basic(CiteId(2), "a").with_locator(LocatorType::Line, "101-108"),
+        ],
+    ));
+    assert_eq!(
+        *db.render_cluster(ClusterId(1)).unwrap(),
+        "321–8, at 101–8; 321–8, at 101-108"
+    );
+}
@@ -0,0 +1,1146 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Copyright © 2019 Corporation for Digital Scholarship
+
+//! Reading a `Style` from CSL.
+//!
+//! Attributes this doesn't know about are ignored, as are `<locale>` overrides and the
+//! formatting attributes, which aren't modelled yet. Unknown elements, unknown attribute values
+//! and conditions the processor can't evaluate are errors, so that a style never silently
+//! renders something other than what it says.
+
+use crate::attr::UnknownAttributeValue;
+use crate::element::*;
+use crate::jurisdiction::JurisdictionModule;
+use crate::locale::{DateFormat, Locale};
+use crate::xml::{self, TextPos, XmlElement, XmlError};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StyleError {
+    Xml(XmlError),
+    /// Well-formed XML, but not a style this processor can use.
+    Invalid {
+        message: String,
+        pos: TextPos,
+    },
+    /// A `<text macro="...">` or `<key macro="...">` naming a macro the style doesn't define.
+    /// `location` is `None` when the style wasn't read from CSL.
+    UndefinedMacro {
+        name: String,
+        location: Option<TextPos>,
+    },
+    /// Macros that call each other in a loop, from the first macro back round to itself.
+    MacroCycle { chain: Vec<String> },
+}
+
+impl fmt::Display for StyleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StyleError::Xml(e) => write!(f, "invalid XML: {}", e),
+            StyleError::Invalid { message, pos } => write!(f, "{} at {}", message, pos),
+            StyleError::UndefinedMacro { name, location } => {
+                write!(f, "undefined macro `{}`", name)?;
+                match location {
+                    Some(pos) => write!(f, " at {}", pos),
+                    None => Ok(()),
+                }
+            }
+            StyleError::MacroCycle { chain } => {
+                let chain: Vec<String> = chain.iter().map(|name| format!("`{}`", name)).collect();
+                write!(f, "macros call each other forever: {}", chain.join(" -> "))
+            }
+        }
+    }
+}
+
+impl From<XmlError> for StyleError {
+    fn from(e: XmlError) -> Self {
+        StyleError::Xml(e)
+    }
+}
+
+type Result<T> = std::result::Result<T, StyleError>;
+
+impl FromStr for Style {
+    type Err = StyleError;
+    fn from_str(s: &str) -> Result<Self> {
+        style(&xml::parse(s)?)
+    }
+}
+
+/// A jurisdiction module is written as a CSL-M style, but only its macros and `<law-module>`
+/// are used.
+impl FromStr for JurisdictionModule {
+    type Err = StyleError;
+    fn from_str(s: &str) -> Result<Self> {
+        let el = xml::parse(s)?;
+        if local_name(&el) != "style" {
+            return invalid(&el, format!("expected <style>, found <{}>", el.name));
+        }
+        let mut module = JurisdictionModule::default();
+        for child in el.elements() {
+            match local_name(child) {
+                "info" => {
+                    if let Some(law_module) = child.child("law-module") {
+                        module.types = law_module
+                            .attribute("types")
+                            .unwrap_or("")
+                            .split_whitespace()
+                            .map(String::from)
+                            .collect();
+                    }
+                }
+                "macro" => parse_macro(child, &mut module.macros)?,
+                _ => {}
+            }
+        }
+        Ok(module)
+    }
+}
+
+/// A CSL locale file, like `locales-de-DE.xml`. Terms a `Locale` has no room for, like the long
+/// forms of locators, are skipped.
+impl FromStr for Locale {
+    type Err = StyleError;
+    fn from_str(s: &str) -> Result<Self> {
+        let el = xml::parse(s)?;
+        if local_name(&el) != "locale" {
+            return invalid(&el, format!("expected <locale>, found <{}>", el.name));
+        }
+        let mut locale = match el.attribute("xml:lang") {
+            Some(lang) => Locale::new(lang),
+            None => return missing(&el, "xml:lang"),
+        };
+        for date in el.elements().filter(|child| local_name(child) == "date") {
+            let form = required(date, "form")?;
+            let parts = date
+                .elements()
+                .filter(|child| local_name(child) == "date-part")
+                .map(parse_date_part)
+                .collect::<Result<_>>()?;
+            let delimiter = delimiter(date, "delimiter");
+            locale.dates.insert(form, DateFormat { parts, delimiter });
+        }
+        let terms = el
+            .elements()
+            .filter(|child| local_name(child) == "terms")
+            .flat_map(|terms| terms.elements())
+            .filter(|child| local_name(child) == "term");
+        for term in terms {
+            let name = match term.attribute("name") {
+                Some(name) => name,
+                None => return missing(term, "name"),
+            };
+            // Always the singular, for terms that have both
+            let text = match term.child("single") {
+                Some(single) => single.text(),
+                None => term.text(),
+            };
+            let number = |prefix: &str| name.strip_prefix(prefix)?.parse::<u32>().ok();
+            match term.attribute("form").unwrap_or("long") {
+                "short" => {
+                    if let Ok(locator) = name.parse::<LocatorType>() {
+                        locale.locators.insert(locator, text);
+                    } else if let Some(n) = number("month-") {
+                        locale.short_months.insert(n, text);
+                    }
+                }
+                "long" => {
+                    if let Some(n) = number("month-") {
+                        locale.months.insert(n, text);
+                    } else if name == "ordinal" {
+                        locale.ordinal = Some(text);
+                    } else if let Some(n) = number("ordinal-") {
+                        locale.ordinals.insert(n, text);
+                    } else if let Some(n) = number("long-ordinal-") {
+                        locale.long_ordinals.insert(n, text);
+                    } else if let Ok(misc) = name.parse::<MiscTerm>() {
+                        locale.misc.insert(misc, text);
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(locale)
+    }
+}
+
+fn invalid<T>(el: &XmlElement, message: impl Into<String>) -> Result<T> {
+    Err(StyleError::Invalid {
+        message: message.into(),
+        pos: el.pos,
+    })
+}
+
+/// `cs:text` and `text` are the same thing.
+fn local_name(el: &XmlElement) -> &str {
+    el.name.rsplit(':').next().unwrap_or(&el.name)
+}
+
+fn attr<T: FromStr<Err = UnknownAttributeValue>>(el: &XmlElement, name: &str) -> Result<Option<T>> {
+    match el.attribute(name) {
+        None => Ok(None),
+        Some(value) => match value.parse() {
+            Ok(v) => Ok(Some(v)),
+            Err(UnknownAttributeValue(value)) => invalid(
+                el,
+                format!(
+                    "unknown value `{}` for `{}` on <{}>",
+                    value,
+                    name,
+                    local_name(el)
+                ),
+            ),
+        },
+    }
+}
+
+fn required<T: FromStr<Err = UnknownAttributeValue>>(el: &XmlElement, name: &str) -> Result<T> {
+    match attr(el, name)? {
+        Some(v) => Ok(v),
+        None => missing(el, name),
+    }
+}
+
+fn missing<T>(el: &XmlElement, name: &str) -> Result<T> {
+    invalid(
+        el,
+        format!("<{}> needs a `{}` attribute", local_name(el), name),
+    )
+}
+
+fn string_attr(el: &XmlElement, name: &str) -> Option<String> {
+    el.attribute(name).map(String::from)
+}
+
+fn bool_attr(el: &XmlElement, name: &str) -> Result<Option<bool>> {
+    match el.attribute(name) {
+        None => Ok(None),
+        Some("true") => Ok(Some(true)),
+        Some("false") => Ok(Some(false)),
+        Some(value) => invalid(
+            el,
+            format!("`{}` must be true or false, not `{}`", name, value),
+        ),
+    }
+}
+
+fn u32_attr(el: &XmlElement, name: &str) -> Result<Option<u32>> {
+    match el.attribute(name) {
+        None => Ok(None),
+        Some(value) => match value.trim().parse() {
+            Ok(n) => Ok(Some(n)),
+            Err(_) => invalid(
+                el,
+                format!("`{}` must be a whole number, not `{}`", name, value),
+            ),
+        },
+    }
+}
+
+/// Space-separated lists, like `variable="author editor"`.
+fn list_attr<T: FromStr<Err = UnknownAttributeValue>>(
+    el: &XmlElement,
+    name: &str,
+) -> Result<Vec<T>> {
+    let mut out = Vec::new();
+    for value in el.attribute(name).unwrap_or("").split_whitespace() {
+        match value.parse() {
+            Ok(v) => out.push(v),
+            Err(_) => return invalid(el, format!("unknown value `{}` in `{}`", value, name)),
+        }
+    }
+    Ok(out)
+}
+
+fn delimiter(el: &XmlElement, name: &str) -> Option<Delimiter> {
+    el.attribute(name).map(|d| Delimiter(d.into()))
+}
+
+fn affixes(el: &XmlElement) -> Option<Affixes> {
+    let prefix = el.attribute("prefix");
+    let suffix = el.attribute("suffix");
+    if prefix.is_none() && suffix.is_none() {
+        return None;
+    }
+    Some(Affixes {
+        prefix: prefix.unwrap_or("").into(),
+        suffix: suffix.unwrap_or("").into(),
+    })
+}
+
+const FORMATTING_ATTRIBUTES: &[&str] = &[
+    "font-style",
+    "font-variant",
+    "font-weight",
+    "text-decoration",
+    "vertical-align",
+];
+
+fn formatting(el: &XmlElement) -> Option<Formatting> {
+    if FORMATTING_ATTRIBUTES
+        .iter()
+        .any(|name| el.attribute(name).is_some())
+    {
+        Some(Formatting)
+    } else {
+        None
+    }
+}
+
+fn display(el: &XmlElement) -> Option<DisplayMode> {
+    el.attribute("display").map(|_| DisplayMode)
+}
+
+fn style(el: &XmlElement) -> Result<Style> {
+    if local_name(el) != "style" {
+        return invalid(el, format!("expected <style>, found <{}>", el.name));
+    }
+    let variant = match el.attribute("version") {
+        Some(version) if version.contains("mlz") => CslVariant::CslM,
+        _ => CslVariant::Csl,
+    };
+    if variant == CslVariant::Csl {
+        check_no_csl_m(el)?;
+    }
+    let mut style = Style {
+        class: required(el, "class")?,
+        version_req: attr(el, "version")?.unwrap_or_default(),
+        variant,
+        page_range_format: attr(el, "page-range-format")?,
+        demote_non_dropping_particle: attr(el, "demote-non-dropping-particle")?.unwrap_or_default(),
+        initialize_with_hyphen: bool_attr(el, "initialize-with-hyphen")?.unwrap_or(true),
+        name_inheritance: inheritable_name(el)?,
+        names_delimiter: delimiter(el, "names-delimiter"),
+        ..Style::default()
+    };
+    if let Some(lang) = el.attribute("default-locale") {
+        style.default_locale = lang.into();
+    }
+    let mut citation = None;
+    for child in el.elements() {
+        match local_name(child) {
+            "info" => style.info = info(child),
+            "features" => style.features = features(child)?,
+            "locale" => {}
+            "macro" => parse_macro(child, &mut style.macros)?,
+            "citation" if citation.is_none() => citation = Some(parse_citation(child)?),
+            "bibliography" if style.bibliography.is_none() => {
+                style.bibliography = Some(parse_bibliography(child)?)
+            }
+            other => return invalid(child, format!("unexpected <{}> in <style>", other)),
+        }
+    }
+    match citation {
+        Some(citation) => style.citation = citation,
+        None => return invalid(el, "a style needs a <citation>"),
+    }
+    check_macro_calls(el, &style.macros)?;
+    check_macro_cycles(&style.macros)?;
+    style.compile_macros();
+    Ok(style)
+}
+
+fn parse_macro(el: &XmlElement, macros: &mut MacroTable) -> Result<()> {
+    let name = match el.attribute("name") {
+        Some(name) => name.to_string(),
+        None => return missing(el, "name"),
+    };
+    let elements = elements(el)?;
+    if macros.insert(name.clone(), elements) {
+        return invalid(el, format!("macro `{}` is defined twice", name));
+    }
+    Ok(())
+}
+
+/// Every macro a `<text>` or `<key>` calls has to exist, so that rendering never goes looking
+/// for one that doesn't.
+fn check_macro_calls(el: &XmlElement, macros: &MacroTable) -> Result<()> {
+    if let ("text" | "key", Some(name)) = (local_name(el), el.attribute("macro")) {
+        if !macros.contains(name) {
+            return Err(StyleError::UndefinedMacro {
+                name: name.into(),
+                location: Some(el.pos),
+            });
+        }
+    }
+    el.elements()
+        .try_for_each(|child| check_macro_calls(child, macros))
+}
+
+/// A macro that ends up calling itself would never finish rendering. Reports the first cycle
+/// found, going through the macros in name order.
+fn check_macro_cycles(macros: &MacroTable) -> Result<()> {
+    let graph: HashMap<&str, Vec<&str>> = macros
+        .iter()
+        .map(|m| (m.name.as_str(), called_macros(&m.elements)))
+        .collect();
+    let mut names: Vec<&str> = graph.keys().copied().collect();
+    names.sort_unstable();
+    let mut done = HashSet::new();
+    for name in names {
+        find_cycle(&graph, name, &mut Vec::new(), &mut done)?;
+    }
+    Ok(())
+}
+
+/// Depth-first from `name`, where `chain` is how it was reached and `done` holds the macros
+/// already known not to lead to a cycle.
+fn find_cycle<'a>(
+    graph: &HashMap<&'a str, Vec<&'a str>>,
+    name: &'a str,
+    chain: &mut Vec<&'a str>,
+    done: &mut HashSet<&'a str>,
+) -> Result<()> {
+    if let Some(ix) = chain.iter().position(|&called| called == name) {
+        let mut cycle: Vec<String> = chain[ix..].iter().map(|&called| called.into()).collect();
+        cycle.push(name.into());
+        return Err(StyleError::MacroCycle { chain: cycle });
+    }
+    if done.contains(name) {
+        return Ok(());
+    }
+    chain.push(name);
+    for &callee in graph.get(name).into_iter().flatten() {
+        find_cycle(graph, callee, chain, done)?;
+    }
+    chain.pop();
+    done.insert(name);
+    Ok(())
+}
+
+/// A plain CSL style can't use CSL-M's variables, or its layouts per locale.
+fn check_no_csl_m(el: &XmlElement) -> Result<()> {
+    for (name, value) in &el.attributes {
+        if name != "variable" && name != "is-numeric" {
+            continue;
+        }
+        for var in value.split_whitespace() {
+            if var.parse::<AnyVariable>().is_ok_and(AnyVariable::is_csl_m_only) {
+                return invalid(el, format!("`{}` is only available in CSL-M", var));
+            }
+        }
+    }
+    if local_name(el) == "layout" && el.attribute("locale").is_some() {
+        return invalid(el, "`locale` on <layout> is only available in CSL-M");
+    }
+    el.elements().try_for_each(check_no_csl_m)
+}
+
+fn info(el: &XmlElement) -> Info {
+    let mut info = Info::default();
+    for child in el.elements() {
+        match local_name(child) {
+            "id" => info.id = child.text().trim().into(),
+            "title" => info.title = child.text().trim().into(),
+            "updated" => info.updated = child.text().trim().into(),
+            "link" if child.attribute("rel") == Some("independent-parent") => {
+                info.parent = string_attr(child, "href")
+            }
+            _ => {}
+        }
+    }
+    info
+}
+
+fn features(el: &XmlElement) -> Result<Features> {
+    let mut features = Features::default();
+    for child in el.elements() {
+        if local_name(child) != "feature" {
+            return invalid(child, format!("unexpected <{}> in <features>", child.name));
+        }
+        match child.attribute("name") {
+            Some(name) => features.enabled.push(name.into()),
+            None => return missing(child, "name"),
+        }
+    }
+    Ok(features)
+}
+
+/// On `<style>`, `<citation>` and `<bibliography>`, the `<name>` options are spelled the same
+/// except for `name-form` and `name-delimiter`.
+fn inheritable_name(el: &XmlElement) -> Result<Name> {
+    name_options(el, "name-form", "name-delimiter")
+}
+
+fn name_options(el: &XmlElement, form: &str, delimiter_attr: &str) -> Result<Name> {
+    Ok(Name {
+        and: attr(el, "and")?,
+        delimiter: delimiter(el, delimiter_attr),
+        delimiter_precedes_et_al: attr(el, "delimiter-precedes-et-al")?,
+        delimiter_precedes_last: attr(el, "delimiter-precedes-last")?,
+        et_al_min: u32_attr(el, "et-al-min")?,
+        et_al_use_first: u32_attr(el, "et-al-use-first")?,
+        et_al_use_last: bool_attr(el, "et-al-use-last")?,
+        et_al_subsequent_min: u32_attr(el, "et-al-subsequent-min")?,
+        et_al_subsequent_use_first: u32_attr(el, "et-al-subsequent-use-first")?,
+        form: attr(el, form)?,
+        initialize: bool_attr(el, "initialize")?,
+        initialize_with: string_attr(el, "initialize-with"),
+        name_as_sort_order: attr(el, "name-as-sort-order")?,
+        sort_separator: string_attr(el, "sort-separator"),
+        ..Name::empty()
+    })
+}
+
+/// The `<sort>` and `<layout>` of a `<citation>` or `<bibliography>`.
+/// The sort, the default layout, and any layouts for particular locales, which CSL-M puts
+/// before the default one.
+fn sort_and_layout(el: &XmlElement) -> Result<(Option<Sort>, Layout, Vec<Layout>)> {
+    let mut sort = None;
+    let mut layout = None;
+    let mut locale_layouts = Vec::new();
+    for child in el.elements() {
+        match local_name(child) {
+            "sort" if sort.is_none() => sort = Some(parse_sort(child)?),
+            "layout" if layout.is_none() => {
+                let parsed = parse_layout(child)?;
+                if parsed.locale.is_empty() {
+                    layout = Some(parsed);
+                } else {
+                    locale_layouts.push(parsed);
+                }
+            }
+            other => {
+                return invalid(
+                    child,
+                    format!("unexpected <{}> in <{}>", other, local_name(el)),
+                )
+            }
+        }
+    }
+    match layout {
+        Some(layout) => Ok((sort, layout, locale_layouts)),
+        None => invalid(el, format!("<{}> needs a <layout>", local_name(el))),
+    }
+}
+
+fn parse_citation(el: &XmlElement) -> Result<Citation> {
+    let (sort, layout, locale_layouts) = sort_and_layout(el)?;
+    Ok(Citation {
+        layout,
+        locale_layouts,
+        disambiguate_add_names: bool_attr(el, "disambiguate-add-names")?.unwrap_or(false),
+        disambiguate_add_givenname: bool_attr(el, "disambiguate-add-givenname")?.unwrap_or(false),
+        givenname_disambiguation_rule: attr(el, "givenname-disambiguation-rule")?
+            .unwrap_or_default(),
+        disambiguate_add_year_suffix: bool_attr(el, "disambiguate-add-year-suffix")?
+            .unwrap_or(false),
+        near_note_distance: u32_attr(el, "near-note-distance")?,
+        sort,
+        collapse: attr(el, "collapse")?,
+        cite_group_delimiter: delimiter(el, "cite-group-delimiter"),
+        year_suffix_delimiter: delimiter(el, "year-suffix-delimiter"),
+        after_collapse_delimiter: delimiter(el, "after-collapse-delimiter"),
+        name_inheritance: inheritable_name(el)?,
+        names_delimiter: delimiter(el, "names-delimiter"),
+    })
+}
+
+fn parse_bibliography(el: &XmlElement) -> Result<Bibliography> {
+    let (sort, layout, locale_layouts) = sort_and_layout(el)?;
+    Ok(Bibliography {
+        layout,
+        locale_layouts,
+        sort,
+        subsequent_author_substitute: string_attr(el, "subsequent-author-substitute"),
+        subsequent_author_substitute_rule: attr(el, "subsequent-author-substitute-rule")?
+            .unwrap_or_default(),
+        hanging_indent: bool_attr(el, "hanging-indent")?.unwrap_or(false),
+        second_field_align: attr(el, "second-field-align")?,
+        line_spacing: u32_attr(el, "line-spacing")?,
+        entry_spacing: u32_attr(el, "entry-spacing")?,
+        name_inheritance: inheritable_name(el)?,
+        names_delimiter: delimiter(el, "names-delimiter"),
+    })
+}
+
+fn parse_sort(el: &XmlElement) -> Result<Sort> {
+    let mut keys = Vec::new();
+    for key in el.elements() {
+        if local_name(key) != "key" {
+            return invalid(key, format!("unexpected <{}> in <sort>", key.name));
+        }
+        let source = match (attr(key, "variable")?, key.attribute("macro")) {
+            (Some(var), None) => SortSource::Variable(var),
+            (None, Some(name)) => SortSource::Macro(name.into()),
+            _ => return invalid(key, "<key> needs one of `variable` or `macro`"),
+        };
+        keys.push(SortKey {
+            source,
+            direction: attr(key, "sort")?.unwrap_or_default(),
+        });
+    }
+    Ok(Sort { keys })
+}
+
+fn parse_layout(el: &XmlElement) -> Result<Layout> {
+    Ok(Layout {
+        elements: elements(el)?,
+        delimiter: delimiter(el, "delimiter"),
+        affixes: affixes(el),
+        locale: el
+            .attribute("locale")
+            .unwrap_or("")
+            .split_whitespace()
+            .map(String::from)
+            .collect(),
+    })
+}
+
+fn elements(el: &XmlElement) -> Result<Vec<Element>> {
+    el.elements().map(element).collect()
+}
+
+fn element(el: &XmlElement) -> Result<Element> {
+    Ok(match local_name(el) {
+        "text" => Element::Text(parse_text(el)?),
+        "label" => Element::Label(LabelElement {
+            variable: required(el, "variable")?,
+            form: attr(el, "form")?.unwrap_or(TermForm::Long),
+            formatting: formatting(el),
+            affixes: affixes(el),
+            strip_periods: bool_attr(el, "strip-periods")?.unwrap_or(false),
+            text_case: TextCase,
+            plural: el.attribute("plural") == Some("always"),
+        }),
+        "number" => Element::Number(NumberElement {
+            variable: required(el, "variable")?,
+            form: attr(el, "form")?.unwrap_or_default(),
+            formatting: formatting(el),
+            affixes: affixes(el),
+            text_case: TextCase,
+            display: display(el),
+        }),
+        "group" => Element::Group(Group {
+            formatting: formatting(el),
+            delimiter: delimiter(el, "delimiter").unwrap_or_default(),
+            affixes: affixes(el),
+            elements: elements(el)?,
+            display: display(el),
+        }),
+        "choose" => Element::Choose(Arc::new(parse_choose(el)?)),
+        "names" => Element::Names(Arc::new(parse_names(el)?)),
+        "date" => Element::Date(Arc::new(parse_date(el)?)),
+        other => return invalid(el, format!("unknown element <{}>", other)),
+    })
+}
+
+fn parse_text(el: &XmlElement) -> Result<TextElement> {
+    let source = if let Some(name) = el.attribute("macro") {
+        TextSource::Macro(name.into())
+    } else if let Some(value) = el.attribute("value") {
+        TextSource::Value(value.into())
+    } else if el.attribute("variable").is_some() {
+        TextSource::Variable(
+            required(el, "variable")?,
+            attr(el, "form")?.unwrap_or(VariableForm::Long),
+        )
+    } else if let Some(term) = el.attribute("term") {
+        let plural = bool_attr(el, "plural")?.unwrap_or(false);
+        TextSource::Term(term_selector(el, term)?, plural)
+    } else {
+        return invalid(
+            el,
+            "<text> needs one of `macro`, `value`, `variable` or `term`",
+        );
+    };
+    Ok(TextElement {
+        source,
+        formatting: formatting(el),
+        affixes: affixes(el),
+        quotes: bool_attr(el, "quotes")?.unwrap_or(false),
+        strip_periods: bool_attr(el, "strip-periods")?.unwrap_or(false),
+        text_case: TextCase,
+        display: display(el),
+    })
+}
+
+fn term_selector(el: &XmlElement, term: &str) -> Result<TextTermSelector> {
+    if let Ok(misc) = term.parse::<MiscTerm>() {
+        let form = attr(el, "form")?.unwrap_or(TermFormExtended::Long);
+        return Ok(TextTermSelector::Simple(SimpleTermSelector::Misc(
+            misc, form,
+        )));
+    }
+    if term.ends_with("quote") {
+        return Ok(TextTermSelector::Simple(SimpleTermSelector::Quote));
+    }
+    if term.parse::<NameVariable>().is_ok() {
+        return Ok(TextTermSelector::Role);
+    }
+    if term.parse::<LocatorType>().is_ok() || term.parse::<NumberVariable>().is_ok() {
+        return Ok(TextTermSelector::Gendered);
+    }
+    invalid(el, format!("unknown term `{}`", term))
+}
+
+fn parse_date(el: &XmlElement) -> Result<DateElement> {
+    let parts = el
+        .elements()
+        .map(|child| match local_name(child) {
+            "date-part" => parse_date_part(child),
+            other => invalid(child, format!("unexpected <{}> in <date>", other)),
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let form = attr(el, "form")?;
+    if form.is_none() && parts.is_empty() {
+        return invalid(el, "<date> needs a `form` or a <date-part>");
+    }
+    Ok(DateElement {
+        variable: required(el, "variable")?,
+        form,
+        date_parts: attr(el, "date-parts")?.unwrap_or_default(),
+        parts,
+        delimiter: delimiter(el, "delimiter"),
+        formatting: formatting(el),
+        affixes: affixes(el),
+        text_case: TextCase,
+        display: display(el),
+    })
+}
+
+fn parse_date_part(el: &XmlElement) -> Result<DatePart> {
+    Ok(DatePart {
+        name: required(el, "name")?,
+        form: attr(el, "form")?,
+        formatting: formatting(el),
+        affixes: affixes(el),
+        strip_periods: bool_attr(el, "strip-periods")?.unwrap_or(false),
+        text_case: TextCase,
+    })
+}
+
+fn parse_choose(el: &XmlElement) -> Result<Choose> {
+    let mut branches = el.elements();
+    let head = match branches.next() {
+        Some(b) if local_name(b) == "if" => if_then(b)?,
+        _ => return invalid(el, "<choose> must start with <if>"),
+    };
+    let mut rest = Vec::new();
+    let mut otherwise = Else::default();
+    let mut saw_else = false;
+    for branch in branches {
+        match local_name(branch) {
+            "else-if" if !saw_else => rest.push(if_then(branch)?),
+            "else" if !saw_else => {
+                otherwise = Else(elements(branch)?);
+                saw_else = true;
+            }
+            other => return invalid(branch, format!("unexpected <{}> in <choose>", other)),
+        }
+    }
+    Ok(Choose(head, rest, otherwise))
+}
+
+fn if_then(el: &XmlElement) -> Result<IfThen> {
+    // References don't say whether their dates are uncertain
+    if el.attribute("is-uncertain-date").is_some() {
+        return invalid(el, "`is-uncertain-date` conditions are not supported");
+    }
+    let mut conds: Vec<Cond> = el
+        .attribute("type")
+        .unwrap_or("")
+        .split_whitespace()
+        .map(|csl_type| Cond::Type(csl_type.into()))
+        .collect();
+    conds.extend(
+        list_attr::<AnyVariable>(el, "variable")?
+            .into_iter()
+            .map(Cond::Variable),
+    );
+    conds.extend(
+        list_attr::<Position>(el, "position")?
+            .into_iter()
+            .map(Cond::Position),
+    );
+    conds.extend(
+        list_attr::<AnyVariable>(el, "is-numeric")?
+            .into_iter()
+            .map(Cond::IsNumeric),
+    );
+    if let Some(d) = bool_attr(el, "disambiguate")? {
+        conds.push(Cond::Disambiguate(d));
+    }
+    conds.extend(
+        list_attr::<LocatorType>(el, "locator")?
+            .into_iter()
+            .map(Cond::Locator),
+    );
+    if conds.is_empty() {
+        return invalid(el, format!("<{}> has no conditions", local_name(el)));
+    }
+    let match_type = attr(el, "match")?.unwrap_or(Match::All);
+    Ok(IfThen(Conditions(match_type, conds), elements(el)?))
+}
+
+fn parse_names(el: &XmlElement) -> Result<Names> {
+    let variables = list_attr(el, "variable")?;
+    if variables.is_empty() {
+        return missing(el, "variable");
+    }
+    let mut name = None;
+    let mut substitute = None;
+    for child in el.elements() {
+        match local_name(child) {
+            "name" => name = Some(parse_name(child)?),
+            "substitute" => substitute = Some(Substitute(elements(child)?)),
+            // TODO: <et-al> and <label>
+            "et-al" | "label" => {}
+            other => return invalid(child, format!("unexpected <{}> in <names>", other)),
+        }
+    }
+    Ok(Names {
+        variables,
+        name,
+        delimiter: delimiter(el, "delimiter"),
+        formatting: formatting(el),
+        affixes: affixes(el),
+        display: display(el),
+        substitute,
+    })
+}
+
+fn parse_name(el: &XmlElement) -> Result<Name> {
+    let mut name = name_options(el, "form", "delimiter")?;
+    name.formatting = formatting(el);
+    name.affixes = affixes(el);
+    for part in el.elements() {
+        if local_name(part) != "name-part" {
+            return invalid(part, format!("unexpected <{}> in <name>", part.name));
+        }
+        let name_part = NamePart {
+            name: required(part, "name")?,
+            affixes: affixes(part),
+            text_case: TextCase,
+            formatting: formatting(part),
+        };
+        match name_part.name {
+            NamePartName::Given => name.name_part_given = Some(name_part),
+            NamePartName::Family => name.name_part_family = Some(name_part),
+        }
+    }
+    Ok(name)
+}
+
+#[test]
+fn parses_style_options() {
+    let style: Style = r#"<style xmlns="http://purl.org/net/xbiblio/csl" class="note"
+        version="1.0.1" default-locale="de-DE" page-range-format="minimal-two"
+        demote-non-dropping-particle="sort-only" initialize-with-hyphen="false"
+        et-al-min="4" et-al-use-first="1" name-form="short" names-delimiter="; ">
+      <info>
+        <title>Test Style</title>
+        <id>http://example.com/test</id>
+        <link rel="independent-parent" href="http://example.com/parent"/>
+        <updated>2019-01-01T00:00:00+00:00</updated>
+      </info>
+      <features><feature name="consistent-ordering"/></features>
+      <citation et-al-min="6" name-delimiter=" / ">
+        <layout><names variable="author"/></layout>
+      </citation>
+    </style>"#
+        .parse()
+        .unwrap();
+    assert_eq!(style.class, StyleClass::Note);
+    assert_eq!(
+        style.version_req,
+        CslVersionReq {
+            major: 1,
+            minor: 0,
+            patch: 1
+        }
+    );
+    assert_eq!(style.default_locale, "de-DE");
+    assert_eq!(style.page_range_format, Some(PageRangeFormat::MinimalTwo));
+    assert_eq!(
+        style.demote_non_dropping_particle,
+        DemoteNonDroppingParticle::SortOnly
+    );
+    assert!(!style.initialize_with_hyphen);
+    assert_eq!(style.info.title, "Test Style");
+    assert_eq!(
+        style.info.parent.as_deref(),
+        Some("http://example.com/parent")
+    );
+    assert!(style.features.is_enabled("consistent-ordering"));
+    assert_eq!(style.names_delimiter, Some(Delimiter("; ".into())));
+
+    let name = style.inherited_name(&style.citation.name_inheritance);
+    assert_eq!(name.et_al_min, Some(6));
+    assert_eq!(name.et_al_use_first, Some(1));
+    assert_eq!(name.form, Some(NameForm::Short));
+    assert_eq!(name.delimiter, Some(Delimiter(" / ".into())));
+    assert_eq!(name.sort_separator, Some(", ".into()));
+}
+
+#[test]
+fn parses_layout_elements() {
+    let style: Style = r#"<style class="in-text">
+      <macro name="author">
+        <names variable="author editor" delimiter=", ">
+          <name and="text" initialize-with=". " name-as-sort-order="first">
+            <name-part name="family" suffix="!"/>
+          </name>
+          <substitute><text variable="title"/></substitute>
+        </names>
+      </macro>
+      <citation collapse="year" disambiguate-add-year-suffix="true">
+        <sort><key macro="author"/><key variable="issued" sort="descending"/></sort>
+        <layout prefix="(" suffix=")" delimiter="; ">
+          <group delimiter=" ">
+            <text macro="author"/>
+            <choose>
+              <if position="ibid subsequent" match="any"><text term="ibid" form="short"/></if>
+              <else-if variable="locator"><label variable="locator" form="short"/></else-if>
+              <else><number variable="volume" prefix="v"/></else>
+            </choose>
+          </group>
+        </layout>
+      </citation>
+    </style>"#
+        .parse()
+        .unwrap();
+    assert_eq!(style.citation.collapse, Some(Collapse::Year));
+    assert!(style.citation.disambiguate_add_year_suffix);
+    let keys = &style.citation.sort.as_ref().unwrap().keys;
+    let author = MacroRef {
+        name: "author".into(),
+        id: Some(MacroId(0)),
+    };
+    assert_eq!(keys[0].source, SortSource::Macro(author));
+    assert_eq!(keys[1].direction, SortDirection::Descending);
+    let layout = &style.citation.layout;
+    assert_eq!(layout.delimiter, Some(Delimiter("; ".into())));
+    let group = match &layout.elements[0] {
+        Element::Group(group) => group,
+        other => panic!("expected a group, got {:?}", other),
+    };
+    match &group.elements[1] {
+        Element::Choose(choose) => {
+            let Choose(IfThen(Conditions(m, conds), _), rest, Else(otherwise)) = &**choose;
+            assert_eq!(*m, Match::Any);
+            assert_eq!(
+                conds,
+                &vec![
+                    Cond::Position(Position::Ibid),
+                    Cond::Position(Position::Subsequent)
+                ]
+            );
+            assert_eq!(rest.len(), 1);
+            assert_eq!(otherwise.len(), 1);
+        }
+        other => panic!("expected a choose, got {:?}", other),
+    }
+    match &style.macros.get("author").unwrap()[0] {
+        Element::Names(names) => {
+            assert_eq!(
+                names.variables,
+                vec![NameVariable::Author, NameVariable::Editor]
+            );
+            let name = names.name.as_ref().unwrap();
+            assert_eq!(name.and, Some(NameAnd::Text));
+            assert_eq!(name.name_as_sort_order, Some(NameAsSortOrder::First));
+            assert_eq!(
+                name.name_part_family.as_ref().map(|p| p.name),
+                Some(NamePartName::Family)
+            );
+            assert_eq!(
+                names.substitute.as_ref().map(|s| s.0.len()),
+                Some(1)
+            );
+        }
+        other => panic!("expected names, got {:?}", other),
+    }
+}
+
+#[test]
+fn reports_invalid_styles() {
+    let err = |s: &str| s.parse::<Style>().unwrap_err();
+    assert!(matches!(err("<style"), StyleError::Xml(_)));
+    assert_eq!(
+        err(r#"<style class="in-text"/>"#).to_string(),
+        "a style needs a <citation> at 1:1"
+    );
+    assert_eq!(
+        err("<style class=\"in-text\">\n<citation><layout><text variable=\"nope\"/></layout></citation></style>")
+            .to_string(),
+        "unknown value `nope` for `variable` on <text> at 2:19"
+    );
+    assert!(matches!(
+        err(r#"<style class="sideways"><citation><layout/></citation></style>"#),
+        StyleError::Invalid { .. }
+    ));
+    assert_eq!(
+        err(r#"<style class="in-text"><citation><layout>
+          <date variable="issued"/>
+        </layout></citation></style>"#)
+            .to_string(),
+        "<date> needs a `form` or a <date-part> at 2:11"
+    );
+    assert_eq!(
+        err(r#"<style class="in-text"><citation><layout>
+          <choose><if is-uncertain-date="issued"><text value="ca."/></if></choose>
+        </layout></citation></style>"#)
+            .to_string(),
+        "`is-uncertain-date` conditions are not supported at 2:19"
+    );
+}
+
+#[test]
+fn undefined_macros() {
+    let err = |s: &str| s.parse::<Style>().unwrap_err();
+    let in_layout = err(r#"<style class="in-text">
+      <macro name="title"><text variable="title"/></macro>
+      <citation><layout><group><text macro="titel"/></group></layout></citation>
+    </style>"#);
+    assert_eq!(
+        in_layout,
+        StyleError::UndefinedMacro {
+            name: "titel".into(),
+            location: Some(TextPos { row: 3, col: 32 }),
+        }
+    );
+    assert_eq!(in_layout.to_string(), "undefined macro `titel` at 3:32");
+    let in_sort = err(r#"<style class="in-text">
+      <citation><sort><key macro="author"/></sort><layout/></citation>
+    </style>"#);
+    assert!(matches!(in_sort, StyleError::UndefinedMacro { ref name, .. } if name == "author"));
+    // Macros can call macros defined after them
+    assert!(r#"<style class="in-text">
+      <macro name="a"><text macro="b"/></macro>
+      <macro name="b"><text variable="title"/></macro>
+      <citation><layout><text macro="a"/></layout></citation>
+    </style>"#
+        .parse::<Style>()
+        .is_ok());
+    let unparsed = StyleError::UndefinedMacro {
+        name: "a".into(),
+        location: None,
+    };
+    assert_eq!(unparsed.to_string(), "undefined macro `a`");
+}
+
+#[test]
+fn macro_cycles() {
+    let err = |macros: &str| {
+        let layout = r#"<citation><layout><text macro="a"/></layout></citation>"#;
+        format!(r#"<style class="in-text">{}{}</style>"#, macros, layout)
+            .parse::<Style>()
+            .map(|_| ())
+    };
+    let cycle = err(r#"
+      <macro name="a"><group><text macro="title"/><text macro="b"/></group></macro>
+      <macro name="b"><choose><if variable="note"/><else><text macro="c"/></else></choose></macro>
+      <macro name="c">
+        <names variable="author"><substitute><text macro="a"/></substitute></names>
+      </macro>
+      <macro name="title"><text variable="title"/></macro>"#)
+    .unwrap_err();
+    assert_eq!(
+        cycle,
+        StyleError::MacroCycle {
+            chain: vec!["a".into(), "b".into(), "c".into(), "a".into()]
+        }
+    );
+    assert_eq!(
+        cycle.to_string(),
+        "macros call each other forever: `a` -> `b` -> `c` -> `a`"
+    );
+    assert_eq!(
+        err(r#"<macro name="a"><text macro="a"/></macro>"#),
+        Err(StyleError::MacroCycle {
+            chain: vec!["a".into(), "a".into()]
+        })
+    );
+    // Calling the same macro twice isn't a cycle
+    assert_eq!(
+        err(r#"
+          <macro name="a"><text macro="b"/><text macro="c"/></macro>
+          <macro name="b"><text macro="c"/></macro>
+          <macro name="c"><text variable="title"/></macro>"#),
+        Ok(())
+    );
+}
+
+#[test]
+fn csl_m_only_in_csl_m_styles() {
+    let plain = r#"<style class="note" version="1.0">
+      <citation><layout><text variable="hereinafter"/></layout></citation>
+    </style>"#;
+    assert_eq!(
+        plain.parse::<Style>().unwrap_err().to_string(),
+        "`hereinafter` is only available in CSL-M at 2:25"
+    );
+    let style: Style = r#"<style class="note" version="1.1mlz1">
+      <citation>
+        <layout locale="de fr"><text variable="committee"/></layout>
+        <layout><text variable="hereinafter"/></layout>
+      </citation>
+    </style>"#
+        .parse()
+        .unwrap();
+    assert_eq!(style.variant, CslVariant::CslM);
+    assert_eq!(style.citation.locale_layouts.len(), 1);
+    assert_eq!(style.citation.locale_layouts[0].locale, vec!["de", "fr"]);
+    assert_eq!(
+        style.citation.layout_for(Some("de-AT")),
+        &style.citation.locale_layouts[0]
+    );
+    assert_eq!(style.citation.layout_for(Some("en")), &style.citation.layout);
+    assert_eq!(style.citation.layout_for(None), &style.citation.layout);
+}
+
+#[test]
+fn parses_locale_files() {
+    let locale: Locale = r#"<?xml version="1.0" encoding="utf-8"?>
+    <locale xmlns="http://purl.org/net/xbiblio/csl" version="1.0" xml:lang="de-DE">
+      <style-options punctuation-in-quote="false"/>
+      <date form="numeric" delimiter=".">
+        <date-part name="day" form="numeric-leading-zeros"/>
+        <date-part name="month" form="numeric-leading-zeros"/>
+        <date-part name="year"/>
+      </date>
+      <terms>
+        <term name="and">und</term>
+        <term name="et-al">u. a.</term>
+        <term name="page-range-delimiter">-</term>
+        <term name="page"><single>Seite</single><multiple>Seiten</multiple></term>
+        <term name="page" form="short"><single>S.</single><multiple>S.</multiple></term>
+        <term name="and" form="symbol">&amp;</term>
+        <term name="ordinal">.</term>
+        <term name="long-ordinal-01">erster</term>
+        <term name="month-01">Januar</term>
+        <term name="month-01" form="short">Jan.</term>
+      </terms>
+    </locale>"#
+        .parse()
+        .unwrap();
+    assert_eq!(locale.lang, "de-DE");
+    assert_eq!(locale.misc_term(MiscTerm::And), Some("und"));
+    assert_eq!(locale.misc_term(MiscTerm::EtAl), Some("u. a."));
+    assert_eq!(locale.page_range_delimiter(), "-");
+    assert_eq!(locale.locator_term(&LocatorType::Page), Some("S."));
+    assert_eq!(locale.ordinal_suffix(3), ".");
+    assert_eq!(locale.long_ordinal(1), Some("erster"));
+    assert_eq!(locale.locators.len(), 1);
+    assert_eq!(locale.month(1, false), Some("Januar"));
+    assert_eq!(locale.month(1, true), Some("Jan."));
+    let numeric = &locale.dates[&DateForm::Numeric];
+    assert_eq!(numeric.delimiter, Some(Delimiter(".".into())));
+    let names: Vec<_> = numeric.parts.iter().map(|part| part.name).collect();
+    assert_eq!(
+        names,
+        vec![DatePartName::Day, DatePartName::Month, DatePartName::Year]
+    );
+    assert!(!locale.dates.contains_key(&DateForm::Text));
+
+    let err = "<locale><terms/></locale>".parse::<Locale>().unwrap_err();
+    assert_eq!(err.to_string(), "<locale> needs a `xml:lang` attribute at 1:1");
+}
@@ -49,26 +49,72 @@ pub struct RefIrSeq {
 }
 
 impl RefIR {
+    #[allow(clippy::only_used_in_recursion)]
     pub fn debug(&self, db: &dyn IrDatabase) -> String {
         match self {
             RefIR::Edge(Some(e)) => format!("{:?}", e),
             RefIR::Edge(None) => "None".into(),
             RefIR::Seq(seq) => {
                 let mut s = String::new();
-                s.push_str("[");
+                s.push('[');
                 let mut seen = false;
                 for x in &seq.contents {
                     if seen {
-                        s.push_str(",");
+                        s.push(',');
                     }
                     seen = true;
                     s.push_str(&x.debug(db));
                 }
-                s.push_str("]");
+                s.push(']');
                 s
             }
         }
     }
 
+    /// The sequence of edges a cite rendered from this IR would produce, with affixes and
+    /// delimiters written out as `EdgeData::Output`. Adjacent outputs are joined, so two IRs that
+    /// render the same text flatten to the same tokens regardless of how they were built.
+    pub fn flatten(&self) -> Vec<EdgeData> {
+        let mut tokens = Vec::new();
+        self.flatten_into(&mut tokens);
+        tokens
+    }
+
+    fn flatten_into(&self, tokens: &mut Vec<EdgeData>) {
+        match self {
+            RefIR::Edge(None) => {}
+            RefIR::Edge(Some(e)) => push_token(tokens, e.clone()),
+            RefIR::Seq(seq) => {
+                if let Some(affixes) = &seq.affixes {
+                    push_token(tokens, EdgeData::Output(affixes.prefix.clone()));
+                }
+                let mut seen = false;
+                for x in seq.contents.iter().filter(|x| **x != RefIR::Edge(None)) {
+                    if seen {
+                        push_token(tokens, EdgeData::Output(seq.delimiter.clone()));
+                    }
+                    seen = true;
+                    x.flatten_into(tokens);
+                }
+                if let Some(affixes) = &seq.affixes {
+                    push_token(tokens, EdgeData::Output(affixes.suffix.clone()));
+                }
+            }
+        }
+    }
+}
+
+/// Pushes a token, joining it onto the previous one if both are outputs, and dropping empty ones.
+/// Where two outputs meet, a period is never doubled, so initials followed by a `". "` delimiter
+/// come out as `D. ` rather than `D.. `.
+pub fn push_token(tokens: &mut Vec<EdgeData>, token: EdgeData) {
+    match (tokens.last_mut(), token) {
+        (_, EdgeData::Output(s)) if s.is_empty() => {}
+        (Some(EdgeData::Output(last)), EdgeData::Output(s)) => match s.strip_prefix('.') {
+            Some(rest) if last.ends_with('.') => last.push_str(rest),
+            _ => last.push_str(&s),
+        },
+        (_, token) => tokens.push(token),
+    }
 }
 
@@ -0,0 +1,99 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Copyright © 2018 Corporation for Digital Scholarship
+
+use crate::element::{AnyVariable, DateVariable, NameVariable, NumberVariable, Variable};
+use std::collections::HashMap;
+
+/// A single item in the library, as read from CSL-JSON.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reference {
+    pub id: String,
+    pub csl_type: String,
+    pub ordinary: HashMap<Variable, String>,
+    pub number: HashMap<NumberVariable, String>,
+    pub name: HashMap<NameVariable, Vec<PersonName>>,
+    pub date: HashMap<DateVariable, Date>,
+}
+
+impl Reference {
+    pub fn empty(id: impl Into<String>, csl_type: impl Into<String>) -> Self {
+        Reference {
+            id: id.into(),
+            csl_type: csl_type.into(),
+            ordinary: Default::default(),
+            number: Default::default(),
+            name: Default::default(),
+            date: Default::default(),
+        }
+    }
+
+    /// Whether the variable has a non-empty value. Doesn't know about the variables that are
+    /// only supplied by a cite, like `locator`; `RefContext` handles those.
+    pub fn has_variable(&self, var: AnyVariable) -> bool {
+        match var {
+            AnyVariable::Ordinary(v) => self.ordinary.get(&v).is_some_and(|s| !s.is_empty()),
+            AnyVariable::Number(v) => self.number.get(&v).is_some_and(|s| !s.is_empty()),
+            AnyVariable::Name(v) => self.name.get(&v).is_some_and(|ns| !ns.is_empty()),
+            AnyVariable::Date(v) => self.date.contains_key(&v),
+        }
+    }
+
+    /// The `language` the reference is written in, like `en-GB`, if known.
+    pub fn language(&self) -> Option<&str> {
+        self.ordinary
+            .get(&Variable::Language)
+            .map(|s| s.as_str())
+            .filter(|s| !s.is_empty())
+    }
+}
+
+/// Either a structured personal name, or (with only `literal` set) an institution or other name
+/// that must be rendered verbatim.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Hash)]
+pub struct PersonName {
+    pub family: Option<String>,
+    pub given: Option<String>,
+    pub non_dropping_particle: Option<String>,
+    pub dropping_particle: Option<String>,
+    pub suffix: Option<String>,
+    pub literal: Option<String>,
+}
+
+impl PersonName {
+    pub fn new(given: &str, family: &str) -> Self {
+        PersonName {
+            given: Some(given.into()),
+            family: Some(family.into()),
+            ..Default::default()
+        }
+    }
+
+    pub fn literal(literal: &str) -> Self {
+        PersonName {
+            literal: Some(literal.into()),
+            ..Default::default()
+        }
+    }
+}
+
+/// A date as CSL-JSON's `date-parts` gives it. Missing parts are 0, so the derived ordering
+/// sorts by parts, with less specific dates first.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Date {
+    pub year: i32,
+    pub month: u32,
+    pub day: u32,
+}
+
+impl Date {
+    pub fn new(year: i32, month: u32, day: u32) -> Self {
+        Date { year, month, day }
+    }
+
+    pub fn year(year: i32) -> Self {
+        Date::new(year, 0, 0)
+    }
+}
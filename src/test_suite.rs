@@ -0,0 +1,554 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Copyright © 2019 Corporation for Digital Scholarship
+
+//! Runs fixtures written in the format of the CSL test suite's `processor-tests/humans`, like:
+//!
+//! ```text
+//! >>===== MODE =====>>
+//! citation
+//! <<===== MODE =====<<
+//! ```
+//!
+//! with a `RESULT`, a `CSL` style and an `INPUT` library too, and optionally `CITATION-ITEMS`
+//! or `CITATIONS` saying what to cite. Without either, one cluster cites every reference.
+//!
+//! `citation` mode renders each cluster on its own line, and `bibliography` mode renders the
+//! bibliography as HTML. Other modes, and fixtures using sections like `BIBSECTION` or
+//! `ABBREVIATIONS`, are skipped.
+//!
+//! A snapshot lists the fixtures that passed last time, so that `minimal test-suite` and the
+//! tests can tell when one stops passing.
+
+use crate::cluster::{Cluster, ClusterId};
+use crate::csl_json;
+use crate::db::Database;
+use crate::element::Style;
+use crate::json::{self, Value};
+use crate::output::OutputFormat;
+use crate::parse::StyleError;
+use crate::IrDatabase;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::io;
+use std::path::Path;
+
+/// Sections that only describe the fixture.
+const IGNORED_SECTIONS: [&str; 3] = ["DESCRIPTION", "VERSION", "KEYS"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fixture {
+    /// The file name without `.txt`, like `name_AndText`.
+    pub name: String,
+    pub mode: String,
+    pub result: String,
+    pub csl: String,
+    pub input: String,
+    pub citation_items: Option<String>,
+    pub citations: Option<String>,
+    /// Sections this runner doesn't support.
+    pub unsupported: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Passed,
+    Failed {
+        expected: String,
+        actual: String,
+    },
+    /// The fixture couldn't be run, like when its style or JSON doesn't parse.
+    Errored(String),
+    /// The fixture needs a mode or section this runner doesn't support.
+    Skipped(String),
+}
+
+impl Fixture {
+    pub fn parse(name: &str, text: &str) -> Result<Fixture, String> {
+        let mut sections: HashMap<String, String> = HashMap::new();
+        let mut open: Option<(String, Vec<&str>)> = None;
+        for (ix, line) in text.lines().enumerate() {
+            let trimmed = line.trim();
+            if let Some(section) = delimited(trimmed, ">>", "=>>") {
+                if let Some((outer, _)) = &open {
+                    return Err(format!(
+                        "`{}` starts inside `{}` on line {}",
+                        section,
+                        outer,
+                        ix + 1
+                    ));
+                }
+                open = Some((section.into(), Vec::new()));
+            } else if let Some(section) = delimited(trimmed, "<<", "=<<") {
+                match open.take() {
+                    Some((name, lines)) if name == section => {
+                        sections.insert(name, lines.join("\n"));
+                    }
+                    _ => {
+                        return Err(format!(
+                            "`{}` ends on line {} without starting",
+                            section,
+                            ix + 1
+                        ))
+                    }
+                }
+            } else if let Some((_, lines)) = &mut open {
+                lines.push(line);
+            }
+        }
+        if let Some((section, _)) = open {
+            return Err(format!("`{}` never ends", section));
+        }
+        let mut take = |section: &str| sections.remove(section);
+        let mut required = |section: &str| {
+            take(section).ok_or_else(|| format!("there is no `{}` section", section))
+        };
+        let mut fixture = Fixture {
+            name: name.into(),
+            mode: required("MODE")?.trim().into(),
+            result: required("RESULT")?,
+            csl: required("CSL")?,
+            input: required("INPUT")?,
+            citation_items: take("CITATION-ITEMS"),
+            citations: take("CITATIONS"),
+            unsupported: Vec::new(),
+        };
+        fixture.unsupported = sections
+            .into_keys()
+            .filter(|section| !IGNORED_SECTIONS.contains(&section.as_str()))
+            .collect();
+        fixture.unsupported.sort();
+        Ok(fixture)
+    }
+
+    pub fn run(&self) -> Outcome {
+        if let Some(section) = self.unsupported.first() {
+            return Outcome::Skipped(format!("uses `{}`", section));
+        }
+        let actual = match self.mode.as_str() {
+            "citation" => self.citation_output(),
+            "bibliography" => self.bibliography_output(),
+            mode => return Outcome::Skipped(format!("`{}` mode", mode)),
+        };
+        match actual {
+            Ok(actual) if actual.trim() == self.result.trim() => Outcome::Passed,
+            Ok(actual) => Outcome::Failed {
+                expected: self.result.trim().into(),
+                actual: actual.trim().into(),
+            },
+            Err(message) => Outcome::Errored(message),
+        }
+    }
+
+    fn database(&self) -> Result<Database, String> {
+        let style: Style = self
+            .csl
+            .parse()
+            .map_err(|e| format!("the style is invalid: {}", e))?;
+        let input = parse_json("INPUT", &self.input)?;
+        let references = csl_json::references(&input).map_err(|e| format!("INPUT: {}", e))?;
+        let mut db = Database::new(style);
+        db.set_references(references);
+        Ok(db)
+    }
+
+    fn citation_output(&self) -> Result<String, String> {
+        let mut db = self.database()?;
+        if let Some(citations) = &self.citations {
+            return run_citations(&mut db, &parse_json("CITATIONS", citations)?);
+        }
+        let cites = match &self.citation_items {
+            Some(items) => {
+                let items = parse_json("CITATION-ITEMS", items)?;
+                match items {
+                    Value::Array(clusters) => clusters,
+                    _ => return Err("CITATION-ITEMS should be an array".into()),
+                }
+            }
+            None => {
+                let every = db.reference_ids().iter().map(|id| id_only(id)).collect();
+                vec![Value::Array(every)]
+            }
+        };
+        let clusters = cites
+            .into_iter()
+            .map(|cites| Value::Object(vec![("cites".into(), cites)]))
+            .collect();
+        for cluster in clusters_from("CITATION-ITEMS", clusters)? {
+            db.set_cluster(cluster);
+        }
+        let rendered: Vec<String> = db
+            .cluster_ids()
+            .iter()
+            .map(|&id| Ok(OutputFormat::Html.escape(&db.render_cluster(id)?)))
+            .collect::<Result<_, StyleError>>()
+            .map_err(|e| e.to_string())?;
+        Ok(rendered.join("\n"))
+    }
+
+    fn bibliography_output(&self) -> Result<String, String> {
+        let db = self.database()?;
+        let entries = db.render_bibliography().map_err(|e| e.to_string())?;
+        Ok(OutputFormat::Html.document(&[], &entries, &db.bibliography_meta()))
+    }
+}
+
+/// `">>===== MODE =====>>"` gives `MODE`.
+fn delimited<'a>(line: &'a str, start: &str, end: &str) -> Option<&'a str> {
+    if line.len() > start.len() + end.len() && line.starts_with(start) && line.ends_with(end) {
+        let inner = &line[start.len()..line.len() - end.len()];
+        Some(inner.trim_matches('=').trim())
+    } else {
+        None
+    }
+}
+
+fn parse_json(section: &str, text: &str) -> Result<Value, String> {
+    json::parse(text).map_err(|e| format!("{}: {}", section, e))
+}
+
+fn id_only(id: &str) -> Value {
+    Value::Object(vec![("id".into(), Value::String(id.into()))])
+}
+
+/// Reads clusters written as in a cites file (see `csl_json`).
+fn clusters_from(section: &str, clusters: Vec<Value>) -> Result<Vec<Cluster>, String> {
+    csl_json::clusters(&Value::Array(clusters)).map_err(|e| format!("{}: {}", section, e))
+}
+
+/// `CITATIONS` is a list of calls, each adding or replacing a cluster and giving the document's
+/// order with the clusters before it and after it:
+///
+/// ```json
+/// [[{"citationID": "C-2", "citationItems": [{"id": "ITEM-1"}], "properties": {"noteIndex": 2}},
+///   [["C-1", 1]], []]]
+/// ```
+///
+/// The result is every cluster, in order, after the last call. Those the last call changed or
+/// added start with `>>`, and the rest with `..`.
+fn run_citations(db: &mut Database, citations: &Value) -> Result<String, String> {
+    let invalid = |what: &str| Err(format!("CITATIONS: {}", what));
+    let calls = match citations.as_array() {
+        Some(calls) => calls,
+        None => return invalid("should be an array"),
+    };
+    let mut citation_ids = Vec::new();
+    let mut clusters = Vec::new();
+    let mut orders = Vec::new();
+    for call in calls {
+        let (citation, pre, post) = match call.as_array() {
+            Some([citation, pre, post]) => (citation, pre, post),
+            _ => return invalid("each call should be [citation, before, after]"),
+        };
+        let citation_id = match citation.get("citationID").and_then(Value::as_text) {
+            Some(id) => id,
+            None => return invalid("a citation has no `citationID`"),
+        };
+        let note = citation
+            .get("properties")
+            .and_then(|p| p.get("noteIndex"))
+            .cloned()
+            .unwrap_or(Value::Null);
+        let items = citation
+            .get("citationItems")
+            .cloned()
+            .unwrap_or(Value::Array(Vec::new()));
+        let mut cluster = vec![("cites".to_string(), items)];
+        if note.as_text().is_some_and(|n| n != "0") {
+            cluster.push(("note".into(), note));
+        }
+        clusters.push(Value::Object(cluster));
+        let mut order = Vec::new();
+        for (ix, list) in [pre, post].iter().enumerate() {
+            if ix == 1 {
+                order.push((citation_id.to_string(), None));
+            }
+            for entry in list.as_array().unwrap_or(&[]) {
+                match entry.as_array() {
+                    Some([id, note]) if id.as_text().is_some() => {
+                        let note = note.as_text().and_then(|n| n.parse().ok());
+                        order.push((id.as_text().unwrap().to_string(), note));
+                    }
+                    _ => return invalid("before and after should be lists of [id, noteIndex]"),
+                }
+            }
+        }
+        citation_ids.push(citation_id.to_string());
+        orders.push(order);
+    }
+
+    // Clusters are numbered in the order their citation ids first appear
+    let mut numbers: HashMap<String, ClusterId> = HashMap::new();
+    let mut number = |id: &str| {
+        let next = ClusterId(numbers.len() as u32 + 1);
+        *numbers.entry(id.to_string()).or_insert(next)
+    };
+    let mut before = HashMap::new();
+    let last = calls.len().saturating_sub(1);
+    let clusters = clusters_from("CITATIONS", clusters)?;
+    for (ix, ((mut cluster, citation_id), order)) in clusters
+        .into_iter()
+        .zip(citation_ids)
+        .zip(orders)
+        .enumerate()
+    {
+        if ix == last {
+            before = render_all(db)?;
+        }
+        cluster.id = number(&citation_id);
+        let id = cluster.id;
+        db.set_cluster(cluster);
+        let mut ids = Vec::new();
+        for (other, note) in order {
+            let other_id = number(&other);
+            if other_id != id && db.cluster_ids().contains(&other_id) {
+                let mut existing = (*db.cluster(other_id)).clone();
+                let note_number = note.filter(|&n| n != 0);
+                if existing.note_number != note_number {
+                    existing.note_number = note_number;
+                    db.set_cluster(existing);
+                }
+            }
+            ids.push(other_id);
+        }
+        db.set_cluster_order(ids);
+    }
+    let after = render_all(db)?;
+    let mut out = String::new();
+    for (ix, id) in db.cluster_ids().iter().enumerate() {
+        let text = &after[id];
+        let marker = if before.get(id) == Some(text) {
+            ".."
+        } else {
+            ">>"
+        };
+        writeln!(out, "{}[{}] {}", marker, ix, text).unwrap();
+    }
+    Ok(out)
+}
+
+fn render_all(db: &Database) -> Result<HashMap<ClusterId, String>, String> {
+    db.cluster_ids()
+        .iter()
+        .map(|&id| match db.render_cluster(id) {
+            Ok(text) => Ok((id, OutputFormat::Html.escape(&text))),
+            Err(e) => Err(e.to_string()),
+        })
+        .collect()
+}
+
+/// Runs every `.txt` fixture in `dir`, sorted by name.
+pub fn run_dir(dir: &Path) -> io::Result<Vec<(String, Outcome)>> {
+    let mut paths = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "txt") {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    let mut results = Vec::new();
+    for path in paths {
+        let name = path.file_stem().unwrap().to_string_lossy().into_owned();
+        let text = std::fs::read_to_string(&path)?;
+        let outcome = match Fixture::parse(&name, &text) {
+            Ok(fixture) => fixture.run(),
+            Err(message) => Outcome::Errored(message),
+        };
+        results.push((name, outcome));
+    }
+    Ok(results)
+}
+
+/// Every failure with a diff of what it should have rendered, then a count of each outcome.
+pub fn report(results: &[(String, Outcome)]) -> String {
+    let mut out = String::new();
+    let (mut passed, mut failed, mut skipped) = (0, 0, 0);
+    for (name, outcome) in results {
+        match outcome {
+            Outcome::Passed => passed += 1,
+            Outcome::Skipped(_) => skipped += 1,
+            Outcome::Failed { expected, actual } => {
+                failed += 1;
+                writeln!(out, "FAIL {}", name).unwrap();
+                for line in diff(expected, actual).lines() {
+                    writeln!(out, "    {}", line).unwrap();
+                }
+            }
+            Outcome::Errored(message) => {
+                failed += 1;
+                writeln!(out, "FAIL {}: {}", name, message).unwrap();
+            }
+        }
+    }
+    writeln!(
+        out,
+        "{} passed, {} failed, {} skipped",
+        passed, failed, skipped
+    )
+    .unwrap();
+    out
+}
+
+/// A line diff, with `- ` before what only `expected` has and `+ ` before what only `actual`
+/// has.
+pub fn diff(expected: &str, actual: &str) -> String {
+    let a: Vec<&str> = expected.lines().collect();
+    let b: Vec<&str> = actual.lines().collect();
+    // common[i][j] is how many lines a[i..] and b[j..] have in common, in order
+    let mut common = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            common[i][j] = if a[i] == b[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+    let mut out = String::new();
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        if i < a.len() && j < b.len() && a[i] == b[j] {
+            writeln!(out, "  {}", a[i]).unwrap();
+            i += 1;
+            j += 1;
+        } else if j == b.len() || (i < a.len() && common[i + 1][j] >= common[i][j + 1]) {
+            writeln!(out, "- {}", a[i]).unwrap();
+            i += 1;
+        } else {
+            writeln!(out, "+ {}", b[j]).unwrap();
+            j += 1;
+        }
+    }
+    out
+}
+
+/// The fixture names in a snapshot, one per line. Blank lines and `#` comments are ignored.
+pub fn read_snapshot(text: &str) -> Vec<String> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(String::from)
+        .collect()
+}
+
+const SNAPSHOT_HEADER: &str = "# Fixtures that pass. Written by `minimal test-suite --update`.";
+
+/// The snapshot of the fixtures that passed. Comments in `previous`, the snapshot it replaces,
+/// are notes someone wrote, like why a fixture doesn't pass yet, so they're kept.
+pub fn write_snapshot(results: &[(String, Outcome)], previous: &str) -> String {
+    let mut out = format!("{}\n", SNAPSHOT_HEADER);
+    for line in previous.lines() {
+        if line.starts_with('#') && line != SNAPSHOT_HEADER {
+            writeln!(out, "{}", line).unwrap();
+        }
+    }
+    for (name, outcome) in results {
+        if *outcome == Outcome::Passed {
+            writeln!(out, "{}", name).unwrap();
+        }
+    }
+    out
+}
+
+/// The fixtures in the snapshot that didn't pass this time, including any that are gone.
+pub fn regressions(results: &[(String, Outcome)], snapshot: &[String]) -> Vec<String> {
+    snapshot
+        .iter()
+        .filter(|name| {
+            !results
+                .iter()
+                .any(|(n, outcome)| n == *name && *outcome == Outcome::Passed)
+        })
+        .cloned()
+        .collect()
+}
+
+#[test]
+fn parses_fixtures() {
+    let text = "\
+>>===== MODE =====>>
+citation
+<<===== MODE =====<<
+
+>>===== RESULT =====>>
+Doe &amp; Roe
+<<===== RESULT =====<<
+
+>>==== CSL ====>>
+<style/>
+<<==== CSL ====<<
+
+>>===== INPUT =====>>
+[]
+<<===== INPUT =====<<
+
+>>===== ABBREVIATIONS =====>>
+{}
+<<===== ABBREVIATIONS =====<<
+
+>>===== VERSION =====>>
+1.0
+<<===== VERSION =====<<
+";
+    let fixture = Fixture::parse("name_Example", text).unwrap();
+    assert_eq!(fixture.mode, "citation");
+    assert_eq!(fixture.result, "Doe &amp; Roe");
+    assert_eq!(fixture.csl, "<style/>");
+    assert_eq!(fixture.citation_items, None);
+    assert_eq!(fixture.unsupported, vec!["ABBREVIATIONS".to_string()]);
+    assert_eq!(
+        fixture.run(),
+        Outcome::Skipped("uses `ABBREVIATIONS`".into())
+    );
+    assert_eq!(
+        Fixture::parse("x", ">>== MODE ==>>\ncitation\n").unwrap_err(),
+        "`MODE` never ends"
+    );
+    assert_eq!(
+        Fixture::parse("x", ">>== MODE ==>>\ncitation\n<<== MODE ==<<\n").unwrap_err(),
+        "there is no `RESULT` section"
+    );
+}
+
+#[test]
+fn snapshots_keep_comments() {
+    let results = vec![
+        ("a_Passes".to_string(), Outcome::Passed),
+        ("b_Skipped".to_string(), Outcome::Skipped("uses `BIBSECTION`".into())),
+    ];
+    let previous = format!("{}\n# b_Skipped needs sections\nold_Gone\n", SNAPSHOT_HEADER);
+    let snapshot = write_snapshot(&results, &previous);
+    assert_eq!(
+        snapshot,
+        format!("{}\n# b_Skipped needs sections\na_Passes\n", SNAPSHOT_HEADER)
+    );
+    assert_eq!(read_snapshot(&snapshot), vec!["a_Passes".to_string()]);
+}
+
+#[test]
+fn diffs_lines() {
+    assert_eq!(
+        diff("a\nb\nc\nd", "a\nc\nx\nd\ne"),
+        "  a\n- b\n  c\n+ x\n  d\n+ e\n"
+    );
+    assert_eq!(diff("", "a"), "+ a\n");
+}
+
+/// The fixtures in `tests/fixtures` that passed before should still pass.
+#[test]
+fn fixtures_have_not_regressed() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests");
+    let results = run_dir(&root.join("fixtures")).unwrap();
+    let snapshot = read_snapshot(&std::fs::read_to_string(root.join("passing.txt")).unwrap());
+    let regressed = regressions(&results, &snapshot);
+    assert!(
+        regressed.is_empty(),
+        "no longer passing: {}\n\n{}",
+        regressed.join(", "),
+        report(&results)
+    );
+}
@@ -0,0 +1,355 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Copyright © 2019 Corporation for Digital Scholarship
+
+//! Just enough of an XML parser to read CSL styles and locales into a tree.
+//!
+//! Namespaces are not resolved: a prefixed name like `cs:text` keeps its prefix, and `xmlns`
+//! declarations are left in with the other attributes. There is no DTD support, so the only
+//! entities are the five predefined ones and character references.
+
+use std::fmt;
+
+/// 1-based, counted in chars.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct TextPos {
+    pub row: u32,
+    pub col: u32,
+}
+
+impl fmt::Display for TextPos {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.row, self.col)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct XmlError {
+    pub message: String,
+    pub pos: TextPos,
+}
+
+impl fmt::Display for XmlError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at {}", self.message, self.pos)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum XmlNode {
+    Element(XmlElement),
+    /// With entities already replaced. Whitespace is kept as it was.
+    Text(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct XmlElement {
+    pub name: String,
+    pub attributes: Vec<(String, String)>,
+    pub children: Vec<XmlNode>,
+    /// Where the start tag begins.
+    pub pos: TextPos,
+}
+
+impl XmlElement {
+    pub fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// The child elements, skipping text.
+    pub fn elements(&self) -> impl Iterator<Item = &XmlElement> {
+        self.children.iter().filter_map(|node| match node {
+            XmlNode::Element(el) => Some(el),
+            XmlNode::Text(_) => None,
+        })
+    }
+
+    /// The first child element called `name`, whatever its namespace prefix.
+    pub fn child(&self, name: &str) -> Option<&XmlElement> {
+        self.elements()
+            .find(|el| el.name.rsplit(':').next() == Some(name))
+    }
+
+    /// All the text directly inside this element.
+    pub fn text(&self) -> String {
+        self.children
+            .iter()
+            .filter_map(|node| match node {
+                XmlNode::Text(text) => Some(text.as_str()),
+                XmlNode::Element(_) => None,
+            })
+            .collect()
+    }
+}
+
+/// Parses a whole document, returning its root element.
+pub fn parse(input: &str) -> Result<XmlElement, XmlError> {
+    let mut parser = Parser {
+        rest: input,
+        pos: TextPos { row: 1, col: 1 },
+    };
+    parser.skip_misc()?;
+    if !parser.rest.starts_with('<') {
+        return Err(parser.error("expected a root element"));
+    }
+    let root = parser.element()?;
+    parser.skip_misc()?;
+    if !parser.rest.is_empty() {
+        return Err(parser.error("unexpected content after the root element"));
+    }
+    Ok(root)
+}
+
+struct Parser<'a> {
+    rest: &'a str,
+    pos: TextPos,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, message: impl Into<String>) -> XmlError {
+        XmlError {
+            message: message.into(),
+            pos: self.pos,
+        }
+    }
+
+    fn advance(&mut self, bytes: usize) -> &'a str {
+        let (taken, rest) = self.rest.split_at(bytes);
+        for c in taken.chars() {
+            if c == '\n' {
+                self.pos.row += 1;
+                self.pos.col = 1;
+            } else {
+                self.pos.col += 1;
+            }
+        }
+        self.rest = rest;
+        taken
+    }
+
+    fn eat(&mut self, prefix: &str) -> bool {
+        if self.rest.starts_with(prefix) {
+            self.advance(prefix.len());
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, prefix: &str) -> Result<(), XmlError> {
+        if self.eat(prefix) {
+            Ok(())
+        } else {
+            Err(self.error(format!("expected `{}`", prefix)))
+        }
+    }
+
+    /// Consumes everything up to and including `end`, returning what came before it.
+    fn until(&mut self, end: &str, what: &str) -> Result<&'a str, XmlError> {
+        match self.rest.find(end) {
+            Some(ix) => {
+                let taken = self.advance(ix);
+                self.advance(end.len());
+                Ok(taken)
+            }
+            None => Err(self.error(format!("unterminated {}", what))),
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        let len = self.rest.len() - self.rest.trim_start().len();
+        self.advance(len);
+    }
+
+    /// Whitespace, comments, processing instructions and the doctype, outside the root element.
+    fn skip_misc(&mut self) -> Result<(), XmlError> {
+        loop {
+            self.skip_whitespace();
+            if self.eat("<!--") {
+                self.until("-->", "comment")?;
+            } else if self.eat("<?") {
+                self.until("?>", "processing instruction")?;
+            } else if self.eat("<!DOCTYPE") {
+                self.until(">", "doctype")?;
+            } else {
+                return Ok(());
+            }
+        }
+    }
+
+    fn name(&mut self) -> Result<String, XmlError> {
+        let len = self
+            .rest
+            .find(|c: char| c.is_whitespace() || matches!(c, '=' | '/' | '>'))
+            .unwrap_or(self.rest.len());
+        if len == 0 {
+            return Err(self.error("expected a name"));
+        }
+        Ok(self.advance(len).to_string())
+    }
+
+    fn element(&mut self) -> Result<XmlElement, XmlError> {
+        let pos = self.pos;
+        self.expect("<")?;
+        let name = self.name()?;
+        let mut attributes = Vec::new();
+        loop {
+            self.skip_whitespace();
+            if self.eat("/>") {
+                return Ok(XmlElement {
+                    name,
+                    attributes,
+                    children: Vec::new(),
+                    pos,
+                });
+            }
+            if self.eat(">") {
+                break;
+            }
+            let attr = self.name()?;
+            self.skip_whitespace();
+            self.expect("=")?;
+            self.skip_whitespace();
+            let quote = if self.rest.starts_with('"') {
+                "\""
+            } else {
+                "'"
+            };
+            self.expect(quote)?;
+            let value_pos = self.pos;
+            let raw = self.until(quote, "attribute value")?;
+            let value = unescape(raw).map_err(|message| XmlError {
+                message,
+                pos: value_pos,
+            })?;
+            if attributes.iter().any(|(a, _)| *a == attr) {
+                return Err(XmlError {
+                    message: format!("duplicate attribute `{}`", attr),
+                    pos: value_pos,
+                });
+            }
+            attributes.push((attr, value));
+        }
+        let mut children = Vec::new();
+        loop {
+            if self.eat("</") {
+                let end_pos = self.pos;
+                let end = self.name()?;
+                if end != name {
+                    return Err(XmlError {
+                        message: format!("expected `</{}>`, found `</{}>`", name, end),
+                        pos: end_pos,
+                    });
+                }
+                self.skip_whitespace();
+                self.expect(">")?;
+                return Ok(XmlElement {
+                    name,
+                    attributes,
+                    children,
+                    pos,
+                });
+            } else if self.eat("<!--") {
+                self.until("-->", "comment")?;
+            } else if self.eat("<![CDATA[") {
+                let text = self.until("]]>", "CDATA section")?;
+                push_text(&mut children, text.to_string());
+            } else if self.eat("<?") {
+                self.until("?>", "processing instruction")?;
+            } else if self.rest.starts_with('<') {
+                children.push(XmlNode::Element(self.element()?));
+            } else if self.rest.is_empty() {
+                return Err(self.error(format!("unclosed element `{}`", name)));
+            } else {
+                let text_pos = self.pos;
+                let len = self.rest.find('<').unwrap_or(self.rest.len());
+                let raw = self.advance(len);
+                let text = unescape(raw).map_err(|message| XmlError {
+                    message,
+                    pos: text_pos,
+                })?;
+                push_text(&mut children, text);
+            }
+        }
+    }
+}
+
+/// Adjacent text, e.g. either side of a comment, is merged.
+fn push_text(children: &mut Vec<XmlNode>, text: String) {
+    if let Some(XmlNode::Text(prev)) = children.last_mut() {
+        prev.push_str(&text);
+    } else {
+        children.push(XmlNode::Text(text));
+    }
+}
+
+fn unescape(raw: &str) -> Result<String, String> {
+    let mut out = String::with_capacity(raw.len());
+    let mut rest = raw;
+    while let Some(amp) = rest.find('&') {
+        out.push_str(&rest[..amp]);
+        rest = &rest[amp + 1..];
+        let semi = rest
+            .find(';')
+            .ok_or_else(|| "unterminated entity".to_string())?;
+        let entity = &rest[..semi];
+        let c = match entity {
+            "amp" => '&',
+            "lt" => '<',
+            "gt" => '>',
+            "quot" => '"',
+            "apos" => '\'',
+            _ => {
+                let code = if let Some(hex) = entity.strip_prefix("#x") {
+                    u32::from_str_radix(hex, 16).ok()
+                } else if let Some(dec) = entity.strip_prefix('#') {
+                    dec.parse().ok()
+                } else {
+                    None
+                };
+                code.and_then(std::char::from_u32)
+                    .ok_or_else(|| format!("unknown entity `&{};`", entity))?
+            }
+        };
+        out.push(c);
+        rest = &rest[semi + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+#[test]
+fn parses_elements_attributes_and_text() {
+    let doc = parse(
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<!-- a comment -->
+<style class="note" xmlns="http://purl.org/net/xbiblio/csl">
+  <info><title>A &amp; B &#8211; &#x2014;</title></info>
+  <macro name='a'><text value="&quot;x&quot;"/></macro>
+</style>"#,
+    )
+    .unwrap();
+    assert_eq!(doc.name, "style");
+    assert_eq!(doc.attribute("class"), Some("note"));
+    assert_eq!(doc.pos, TextPos { row: 3, col: 1 });
+    let title = doc.child("info").and_then(|i| i.child("title")).unwrap();
+    assert_eq!(title.text(), "A & B – —");
+    let text = doc.child("macro").unwrap().elements().next().unwrap();
+    assert_eq!(text.attribute("value"), Some("\"x\""));
+    assert_eq!(text.pos, TextPos { row: 5, col: 19 });
+}
+
+#[test]
+fn reports_errors_with_positions() {
+    let err = parse("<style>\n  <citation></style>").unwrap_err();
+    assert_eq!(err.pos, TextPos { row: 2, col: 15 });
+    assert!(err.message.contains("</citation>"));
+    assert!(parse("<a b=\"1\" b=\"2\"/>").is_err());
+    assert!(parse("<a>&nope;</a>").is_err());
+    assert!(parse("<a/><b/>").is_err());
+}